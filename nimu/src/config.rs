@@ -0,0 +1,343 @@
+use std::path::PathBuf;
+
+use r4300i_rs::cop0::faults::NandFaults;
+use r4300i_rs::cop0::nand::NandDevice;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::hooks::{Dump, Expr, Hook, Hooks, Memory, Source, Template};
+use crate::patch::{Check, Patch, Patches, Trigger};
+use crate::symbols::Symbols;
+
+// used when no config file is given; it holds the hooks needed to boot
+pub const DEFAULT: &str = include_str!("../default.toml");
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error(transparent)]
+    Toml(#[from] toml::de::Error),
+    #[error("hook {0} ({1}): {2}")]
+    Hook(usize, String, String),
+    #[error("patch {0}: {1}")]
+    Patch(String, String),
+    #[error("nand_faults: {0}")]
+    NandFaults(String),
+    #[error("nand_device: {0}")]
+    NandDevice(String),
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    // symbol files, in the same form as --symbols
+    #[serde(default)]
+    pub symbols: Vec<String>,
+    // rhai scripts, in the same form as --script
+    #[serde(default)]
+    pub scripts: Vec<PathBuf>,
+    #[serde(default, rename = "hook")]
+    hooks: Vec<HookConfig>,
+    #[serde(default, rename = "patch")]
+    patches: Vec<PatchConfig>,
+    // the card to model, in the same form as --nand-device
+    nand_device: Option<String>,
+    #[serde(default)]
+    nand_faults: NandFaultsConfig,
+    // cartridge save memory on domain 2, backed by a file
+    pub save: Option<SaveConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HookConfig {
+    at: String,
+    mode: Option<String>,
+    bootrom: Option<bool>,
+    #[serde(default)]
+    once: bool,
+    print: Option<String>,
+    dump: Option<DumpConfig>,
+    log: Option<bool>,
+    #[serde(default)]
+    halt: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DumpConfig {
+    file: String,
+    memory: Option<String>,
+    address: Option<String>,
+    length: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PatchConfig {
+    name: String,
+    address: Option<String>,
+    physical: Option<u32>,
+    original: Option<Vec<u32>>,
+    value: Vec<u32>,
+    check: Option<CheckConfig>,
+    // applies the patch over whatever is there, for when neither the original
+    // words nor a check are known yet
+    #[serde(default)]
+    unchecked: bool,
+    at: Option<String>,
+    #[serde(default)]
+    loaded: bool,
+    stage: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NandFaultsConfig {
+    #[serde(default)]
+    bad_blocks: Vec<usize>,
+    #[serde(default)]
+    failing_blocks: Vec<usize>,
+    #[serde(default)]
+    fail_rate: f64,
+    #[serde(default)]
+    flip_rate: f64,
+    #[serde(default)]
+    flips: Vec<FlipConfig>,
+    #[serde(default)]
+    seed: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FlipConfig {
+    address: usize,
+    bit: u8,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SaveConfig {
+    pub file: PathBuf,
+    pub address: Option<u32>,
+    pub size: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CheckConfig {
+    address: String,
+    length: u32,
+    crc32: u32,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        Ok(toml::from_str(text)?)
+    }
+
+    // addresses can name symbols, so this needs the symbol files loaded first
+    pub fn hooks(&self, symbols: &Symbols) -> Result<Hooks, ConfigError> {
+        let mut hooks = Hooks::new();
+        for (index, config) in self.hooks.iter().enumerate() {
+            let hook = config
+                .compile(symbols)
+                .map_err(|e| ConfigError::Hook(index, config.at.clone(), e))?;
+            hooks.add(hook);
+        }
+        Ok(hooks)
+    }
+
+    pub fn nand_device(&self) -> Result<Option<NandDevice>, ConfigError> {
+        self.nand_device
+            .as_deref()
+            .map(str::parse)
+            .transpose()
+            .map_err(ConfigError::NandDevice)
+    }
+
+    pub fn nand_faults(&self) -> Result<NandFaults, ConfigError> {
+        let config = &self.nand_faults;
+        let faults = NandFaults {
+            bad_blocks: config.bad_blocks.clone(),
+            failing_blocks: config.failing_blocks.clone(),
+            fail_rate: config.fail_rate,
+            flip_rate: config.flip_rate,
+            flips: config
+                .flips
+                .iter()
+                .map(|flip| (flip.address, flip.bit))
+                .collect(),
+            seed: config.seed,
+        };
+        check_nand_faults(&faults).map_err(ConfigError::NandFaults)?;
+        Ok(faults)
+    }
+
+    pub fn patches(&self, symbols: &Symbols) -> Result<Patches, ConfigError> {
+        let mut patches = Patches::new();
+        for config in &self.patches {
+            let patch = config
+                .compile(symbols)
+                .map_err(|e| ConfigError::Patch(config.name.clone(), e))?;
+            patches.add(patch);
+        }
+        Ok(patches)
+    }
+}
+
+impl HookConfig {
+    fn compile(&self, symbols: &Symbols) -> Result<Hook, String> {
+        let secure = match self.mode.as_deref() {
+            None => None,
+            Some("secure") => Some(true),
+            Some("non-secure") => Some(false),
+            Some(mode) => return Err(format!("unknown mode: {mode}")),
+        };
+
+        let dump = match &self.dump {
+            None => None,
+            Some(dump) => {
+                let source = match (&dump.memory, &dump.address, dump.length) {
+                    (Some(memory), None, None) => Source::Memory(Memory::parse(memory)?),
+                    (None, Some(address), Some(length)) => {
+                        Source::Range(Expr::parse(address, symbols)?, length)
+                    }
+                    _ => return Err("a dump needs either memory, or address and length".into()),
+                };
+                Some(Dump {
+                    file: Template::parse(&dump.file, symbols)?,
+                    source,
+                })
+            }
+        };
+
+        Ok(Hook {
+            at: symbols.resolve(&self.at, secure)?,
+            secure,
+            bootrom: self.bootrom,
+            once: self.once,
+            print: self
+                .print
+                .as_deref()
+                .map(|print| Template::parse(print, symbols))
+                .transpose()?,
+            dump,
+            log: self.log,
+            halt: self.halt,
+        })
+    }
+}
+
+impl PatchConfig {
+    fn compile(&self, symbols: &Symbols) -> Result<Patch, String> {
+        // physical addresses go through kseg1, which maps them directly
+        let address = match (&self.address, self.physical) {
+            (Some(address), None) => symbols.resolve(address, None)?,
+            (None, Some(physical)) if physical < 0x20000000 => 0xA0000000 | physical,
+            (None, Some(physical)) => {
+                return Err(format!("physical address {physical:08X} is out of range"))
+            }
+            _ => return Err("a patch needs either address or physical".into()),
+        };
+        if address % 4 != 0 {
+            return Err(format!("address {address:08X} isn't word aligned"));
+        }
+        if self.value.is_empty() {
+            return Err("value is empty".into());
+        }
+        if let Some(original) = &self.original {
+            if original.len() != self.value.len() {
+                return Err("original and value are different lengths".into());
+            }
+        }
+
+        match (
+            self.original.is_some() || self.check.is_some(),
+            self.unchecked,
+        ) {
+            (false, false) => {
+                return Err("a patch needs original or check, or unchecked = true".into())
+            }
+            (true, true) => return Err("an unchecked patch can't have original or check".into()),
+            _ => {}
+        }
+
+        let trigger = match (&self.at, self.loaded, &self.stage) {
+            (Some(at), false, None) => Trigger::At(symbols.resolve(at, None)?),
+            (None, true, None) if self.original.is_none() => {
+                return Err("a loaded patch needs the original words".into())
+            }
+            (None, true, None) => Trigger::Loaded,
+            (None, false, Some(stage)) => Trigger::Stage(stage.parse()?),
+            _ => return Err("a patch needs exactly one of at, loaded or stage".into()),
+        };
+
+        let check = self
+            .check
+            .as_ref()
+            .map(|check| {
+                Ok::<_, String>(Check {
+                    address: symbols.resolve(&check.address, None)?,
+                    length: check.length,
+                    crc32: check.crc32,
+                })
+            })
+            .transpose()?;
+
+        Ok(Patch {
+            name: self.name.clone(),
+            address,
+            original: self.original.clone(),
+            value: self.value.clone(),
+            check,
+            trigger,
+        })
+    }
+}
+
+// also used for faults given on the command line
+pub fn check_nand_faults(faults: &NandFaults) -> Result<(), String> {
+    for (name, rate) in [
+        ("fail_rate", faults.fail_rate),
+        ("flip_rate", faults.flip_rate),
+    ] {
+        if !(0.0..=1.0).contains(&rate) {
+            return Err(format!("{name} {rate} isn't between 0 and 1"));
+        }
+    }
+    if let Some((address, bit)) = faults.flips.iter().find(|(_, bit)| *bit > 7) {
+        return Err(format!("bit {bit} of {address:08X} doesn't exist"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patches(config: &str) -> Result<Patches, ConfigError> {
+        Config::parse(config)?.patches(&Symbols::new())
+    }
+
+    #[test]
+    fn the_default_config_compiles() {
+        let config = Config::parse(DEFAULT).unwrap();
+        config.hooks(&Symbols::new()).unwrap();
+        config.patches(&Symbols::new()).unwrap();
+    }
+
+    #[test]
+    fn patches_are_checked_unless_they_say_otherwise() {
+        let patch = |extra| {
+            format!("[[patch]]\nname = \"p\"\naddress = \"0x80000100\"\nvalue = [0]\nat = \"0x80000000\"\n{extra}")
+        };
+        assert!(patches(&patch("")).is_err());
+        assert!(patches(&patch("original = [1]")).is_ok());
+        assert!(patches(&patch(
+            "check = { address = \"0x80000100\", length = 4, crc32 = 0 }"
+        ))
+        .is_ok());
+        assert!(patches(&patch("unchecked = true")).is_ok());
+        assert!(patches(&patch("unchecked = true\noriginal = [1]")).is_err());
+    }
+}
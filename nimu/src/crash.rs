@@ -0,0 +1,156 @@
+use std::fs::{create_dir_all, write, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use r4300i_rs::cop0::Device;
+use r4300i_rs::instruction::Instruction;
+use r4300i_rs::R4300i;
+
+use crate::monitor::{write_cop0, write_regs, write_tlb, Monitor};
+
+const DEVICES: [Device; 9] = [
+    Device::Mi,
+    Device::Pi,
+    Device::Vi,
+    Device::Sp,
+    Device::Ai,
+    Device::Si,
+    Device::Usb0,
+    Device::Usb1,
+    Device::Virage,
+];
+
+// everything needed to look into a failure after the fact: text files for the
+// cpu and device state, and raw dumps of each memory
+pub fn write_bundle(
+    dir: &Path,
+    cpu: &R4300i,
+    monitor: &Monitor,
+    reason: &str,
+    instructions: u64,
+) -> io::Result<()> {
+    create_dir_all(dir)?;
+
+    let mut out = BufWriter::new(File::create(dir.join("summary.txt"))?);
+    writeln!(out, "{reason}")?;
+    writeln!(out, "pc: {:08X}", cpu.get_pc() as u32)?;
+    writeln!(out, "instructions: {instructions}")?;
+    writeln!(
+        out,
+        "mode: {}",
+        if cpu.get_secure_mode() {
+            "secure"
+        } else {
+            "non-secure"
+        }
+    )?;
+    out.flush()?;
+
+    let mut out = BufWriter::new(File::create(dir.join("registers.txt"))?);
+    write_regs(&mut out, cpu)?;
+    writeln!(out)?;
+    write_cop0(&mut out, cpu)?;
+    writeln!(out)?;
+    write_tlb(&mut out, cpu)?;
+    out.flush()?;
+
+    let mut out = BufWriter::new(File::create(dir.join("devices.txt"))?);
+    for device in DEVICES {
+        writeln!(out, "{device}:")?;
+        for (name, value) in cpu.get_device_registers(device) {
+            writeln!(out, "{name:>20}: {value:08X}")?;
+        }
+    }
+    out.flush()?;
+
+    // the mode each instruction ran in isn't kept, so any symbol table can match
+    let symbols = monitor.symbols();
+    let mut out = BufWriter::new(File::create(dir.join("instructions.txt"))?);
+    for (pc, opcode) in cpu.history() {
+        if let Some(label) = symbols.label(pc, None) {
+            writeln!(out, "{label}:")?;
+        }
+        writeln!(
+            out,
+            "{pc:08X}: {opcode:08X}  {}",
+            Instruction::new(opcode).disassemble(pc)
+        )?;
+    }
+    out.flush()?;
+
+    let mut out = BufWriter::new(File::create(dir.join("backtrace.txt"))?);
+    monitor.write_backtrace(&mut out, cpu, usize::MAX)?;
+    out.flush()?;
+
+    write(dir.join("ram.bin"), cpu.get_ram())?;
+    write(dir.join("bootram.bin"), cpu.get_bootram())?;
+    for index in 0..3 {
+        write(
+            dir.join(format!("virage{index}.bin")),
+            cpu.get_virage(index),
+        )?;
+    }
+    write(dir.join("pi-buffer.bin"), cpu.get_pi_buffer())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::{metadata, read_to_string, remove_dir_all};
+    use std::process;
+
+    use r4300i_rs::cop0::nand::{NandDevice, NandImage};
+    use r4300i_rs::cop0::{NAND_BLOCK_SIZE, NAND_SPARE_SIZE};
+    use r4300i_rs::instruction::asm::assemble;
+
+    use super::*;
+
+    #[test]
+    fn bundles_have_every_file() {
+        let bootrom = assemble("addiu t0, zero, 1\naddiu t1, zero, 2\nnop\n", 0xBFC00000)
+            .unwrap()
+            .iter()
+            .flat_map(|instr| instr.to_be_bytes())
+            .collect();
+        let device: NandDevice = "64m".parse().unwrap();
+        let blocks = device.size() / NAND_BLOCK_SIZE;
+        let nand = NandImage::split(
+            vec![0xFF; device.size()],
+            vec![0xFF; blocks * NAND_SPARE_SIZE],
+            Some(device),
+        )
+        .unwrap();
+        let mut cpu = R4300i::new(bootrom, vec![], vec![], vec![], nand);
+        cpu.set_history_len(16);
+        cpu.start();
+        for _ in 0..2 {
+            cpu.try_step().unwrap();
+        }
+
+        let dir = temp_dir().join(format!("nimu-crash-{}", process::id()));
+        let _ = remove_dir_all(&dir);
+        write_bundle(&dir, &cpu, &Monitor::new(), "test failure", 2).unwrap();
+
+        let summary = read_to_string(dir.join("summary.txt")).unwrap();
+        assert!(summary.starts_with("test failure\n"));
+        assert!(summary.contains("pc: BFC00008"));
+        assert!(summary.contains("instructions: 2"));
+        for text in ["registers", "devices", "instructions", "backtrace"] {
+            assert!(dir.join(format!("{text}.txt")).is_file(), "{text}");
+        }
+        let instructions = read_to_string(dir.join("instructions.txt")).unwrap();
+        assert!(instructions.starts_with("BFC00000: 24080001"));
+
+        let size = |name: &str| metadata(dir.join(name)).unwrap().len();
+        assert_eq!(size("ram.bin"), 0x800000);
+        assert_eq!(size("bootram.bin"), 0x10000);
+        assert_eq!(size("virage0.bin"), 0x40);
+        assert_eq!(size("virage1.bin"), 0x40);
+        assert_eq!(size("virage2.bin"), 0x100);
+        assert_eq!(size("pi-buffer.bin"), 0x500);
+
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,362 @@
+use std::collections::HashMap;
+use std::fs::write;
+
+use r4300i_rs::{R4300i, Register};
+
+use crate::monitor::parse_address;
+use crate::symbols::Symbols;
+
+// a value worked out when a hook fires: `0x80300000`, `osStopThread`, `a0`,
+// `sp+0x10`, or `[expr]` for the word at an address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Const(u32),
+    Reg(u8, u32),
+    Deref(Box<Expr>),
+}
+
+impl Expr {
+    pub fn parse(s: &str, symbols: &Symbols) -> Result<Self, String> {
+        let s = s.trim();
+        if let Some(inner) = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            return Ok(Self::Deref(Box::new(Self::parse(inner, symbols)?)));
+        }
+
+        let (base, offset) = match s.split_once('+') {
+            Some((base, offset)) => (base.trim(), parse_address(offset.trim())? as u32),
+            None => (s, 0),
+        };
+        match (0..32).find(|&r| Register::from(r).to_string() == base) {
+            Some(r) => Ok(Self::Reg(r, offset)),
+            None => Ok(Self::Const(
+                symbols.resolve(base, None)?.wrapping_add(offset),
+            )),
+        }
+    }
+
+    pub fn eval(&self, cpu: &mut R4300i) -> Option<u32> {
+        match self {
+            Self::Const(value) => Some(*value),
+            Self::Reg(r, offset) => Some((cpu.get_reg(*r) as u32).wrapping_add(*offset)),
+            Self::Deref(address) => {
+                let address = address.eval(cpu)?;
+                cpu.peek::<u32>(address)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Hex(Expr),
+    Str(Expr),
+}
+
+// text with `{expr}` replaced by the value in hex, and `{str:expr}` by the
+// nul-terminated string at that address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template(Vec<Part>);
+
+impl Template {
+    pub fn parse(s: &str, symbols: &Symbols) -> Result<Self, String> {
+        let mut parts = vec![];
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start != 0 {
+                parts.push(Part::Text(rest[..start].into()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed {{ in {s:?}"))?
+                + start;
+            let field = &rest[start + 1..end];
+            parts.push(match field.strip_prefix("str:") {
+                Some(expr) => Part::Str(Expr::parse(expr, symbols)?),
+                None => Part::Hex(Expr::parse(field, symbols)?),
+            });
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.into()));
+        }
+        Ok(Self(parts))
+    }
+
+    pub fn render(&self, cpu: &mut R4300i) -> String {
+        let mut out = String::new();
+        for part in &self.0 {
+            match part {
+                Part::Text(text) => out.push_str(text),
+                Part::Hex(expr) => match expr.eval(cpu) {
+                    Some(value) => out.push_str(&format!("{value:08X}")),
+                    None => out.push_str("????????"),
+                },
+                Part::Str(expr) => match expr.eval(cpu) {
+                    Some(address) => out.push_str(&read_string(cpu, address)),
+                    None => out.push_str("<unreadable>"),
+                },
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Memory {
+    Ram,
+    Bootram,
+    PiBuffer,
+    Virage(usize),
+}
+
+impl Memory {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "ram" => Ok(Self::Ram),
+            "bootram" => Ok(Self::Bootram),
+            "pi-buffer" => Ok(Self::PiBuffer),
+            "virage0" => Ok(Self::Virage(0)),
+            "virage1" => Ok(Self::Virage(1)),
+            "virage2" => Ok(Self::Virage(2)),
+            _ => Err(format!("unknown memory: {s}")),
+        }
+    }
+
+    pub fn read(&self, cpu: &R4300i) -> Vec<u8> {
+        match self {
+            Self::Ram => cpu.get_ram().to_vec(),
+            Self::Bootram => cpu.get_bootram().to_vec(),
+            Self::PiBuffer => cpu.get_pi_buffer().to_vec(),
+            Self::Virage(index) => cpu.get_virage(*index),
+        }
+    }
+}
+
+// stops at a nul or the first unreadable byte
+pub fn read_string(cpu: &mut R4300i, mut address: u32) -> String {
+    let mut out = String::new();
+    while let Some(c) = cpu.peek::<u8>(address).filter(|c| *c != 0) {
+        out.push(c as char);
+        address = address.wrapping_add(1);
+    }
+    out
+}
+
+// unreadable bytes come out as zero
+pub fn read_range(cpu: &mut R4300i, address: u32, length: u32) -> Vec<u8> {
+    (0..length)
+        .map(|i| cpu.peek::<u8>(address.wrapping_add(i)).unwrap_or(0))
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Memory(Memory),
+    Range(Expr, u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dump {
+    pub file: Template,
+    pub source: Source,
+}
+
+// what to do when the pc reaches `at`; actions run in the order the fields
+// are listed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hook {
+    pub at: u32,
+    pub secure: Option<bool>,
+    pub bootrom: Option<bool>,
+    pub once: bool,
+    pub print: Option<Template>,
+    pub dump: Option<Dump>,
+    pub log: Option<bool>,
+    pub halt: bool,
+}
+
+impl Hook {
+    fn applies(&self, cpu: &R4300i) -> bool {
+        self.secure
+            .is_none_or(|secure| cpu.get_secure_mode() == secure)
+            && self
+                .bootrom
+                .is_none_or(|bootrom| cpu.get_mi_mapping() == bootrom)
+    }
+
+    fn run(&self, cpu: &mut R4300i) {
+        if let Some(print) = &self.print {
+            println!("{}", print.render(cpu));
+        }
+
+        if let Some(dump) = &self.dump {
+            let data = match &dump.source {
+                Source::Memory(memory) => memory.read(cpu),
+                Source::Range(address, length) => {
+                    let address = address.eval(cpu).unwrap_or(0);
+                    read_range(cpu, address, *length)
+                }
+            };
+            let file = dump.file.render(cpu);
+            if let Err(e) = write(&file, data) {
+                println!("couldn't write {file}: {e}");
+            }
+        }
+
+        match self.log {
+            Some(true) => cpu.start_logging(),
+            Some(false) => cpu.stop_logging(),
+            None => {}
+        }
+
+        if self.halt {
+            cpu.halt();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+    hooks: HashMap<u32, Vec<(Hook, bool)>>,
+}
+
+impl Hooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, hook: Hook) {
+        self.hooks.entry(hook.at).or_default().push((hook, false));
+    }
+
+    // runs the hooks for the current pc
+    pub fn run(&mut self, cpu: &mut R4300i) {
+        let Some(hooks) = self.hooks.get_mut(&(cpu.get_pc() as u32)) else {
+            return;
+        };
+        for (hook, fired) in hooks {
+            if (hook.once && *fired) || !hook.applies(cpu) {
+                continue;
+            }
+            *fired = true;
+            hook.run(cpu);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use r4300i_rs::cop0::nand::{NandDevice, NandImage};
+    use r4300i_rs::cop0::{NAND_BLOCK_SIZE, NAND_SPARE_SIZE};
+
+    use super::*;
+    use crate::symbols::SymbolTable;
+
+    fn symbols() -> Symbols {
+        let mut symbols = Symbols::new();
+        symbols.add(SymbolTable::parse(b"80001000 osStopThread\n80000100 name\n").unwrap());
+        symbols
+    }
+
+    fn machine() -> R4300i {
+        let device: NandDevice = "64m".parse().unwrap();
+        let blocks = device.size() / NAND_BLOCK_SIZE;
+        let nand = NandImage::split(
+            vec![0xFF; device.size()],
+            vec![0xFF; blocks * NAND_SPARE_SIZE],
+            Some(device),
+        )
+        .unwrap();
+        let mut cpu = R4300i::new(vec![], vec![], vec![], vec![], nand);
+        for (i, c) in b"idle\0".iter().enumerate() {
+            cpu.write::<u8>(0x80000100 + i as u32, *c);
+        }
+        cpu.write::<u32>(0x80000200, 0x80000100);
+        cpu.set_reg(4, 0x80000200);
+        cpu.set_reg(29, 0x801FFFF0);
+        cpu
+    }
+
+    #[test]
+    fn exprs() {
+        let symbols = symbols();
+        let parse = |s| Expr::parse(s, &symbols);
+
+        assert_eq!(parse("0x80300000"), Ok(Expr::Const(0x80300000)));
+        assert_eq!(parse("osStopThread"), Ok(Expr::Const(0x80001000)));
+        assert_eq!(parse("osStopThread+0x8"), Ok(Expr::Const(0x80001008)));
+        assert_eq!(parse("a0"), Ok(Expr::Reg(4, 0)));
+        assert_eq!(parse(" sp + 10 "), Ok(Expr::Reg(29, 0x10)));
+        assert_eq!(
+            parse("[[a0]]"),
+            Ok(Expr::Deref(Box::new(Expr::Deref(Box::new(Expr::Reg(
+                4, 0
+            ))))))
+        );
+        assert!(parse("nowhere").is_err());
+        assert!(parse("a0+zz").is_err());
+
+        let mut cpu = machine();
+        assert_eq!(parse("sp+0x10").unwrap().eval(&mut cpu), Some(0x80200000));
+        assert_eq!(parse("[a0]").unwrap().eval(&mut cpu), Some(0x80000100));
+        assert_eq!(parse("[0xA4600070]").unwrap().eval(&mut cpu), None);
+    }
+
+    #[test]
+    fn templates() {
+        let symbols = symbols();
+        let template = Template::parse("thread {a0} is {str:[a0]} ({str:name})", &symbols).unwrap();
+        assert_eq!(
+            template,
+            Template(vec![
+                Part::Text("thread ".into()),
+                Part::Hex(Expr::Reg(4, 0)),
+                Part::Text(" is ".into()),
+                Part::Str(Expr::Deref(Box::new(Expr::Reg(4, 0)))),
+                Part::Text(" (".into()),
+                Part::Str(Expr::Const(0x80000100)),
+                Part::Text(")".into()),
+            ])
+        );
+
+        let mut cpu = machine();
+        assert_eq!(template.render(&mut cpu), "thread 80000200 is idle (idle)");
+
+        let unreadable = Template::parse("{[0xA4600070]} {str:[0xA4600070]}", &symbols).unwrap();
+        assert_eq!(unreadable.render(&mut cpu), "???????? <unreadable>");
+
+        assert!(Template::parse("{a0", &symbols).is_err());
+        assert!(Template::parse("{str:nowhere}", &symbols).is_err());
+    }
+
+    #[test]
+    fn once() {
+        let hook = |once| Hook {
+            at: 0xBFC00000,
+            secure: None,
+            bootrom: Some(true),
+            once,
+            print: None,
+            dump: None,
+            log: Some(true),
+            halt: true,
+        };
+        let mut hooks = Hooks::new();
+        hooks.add(hook(true));
+
+        let mut cpu = machine();
+        cpu.start();
+        hooks.run(&mut cpu);
+        assert!(cpu.halted);
+
+        // a once hook doesn't fire a second time
+        cpu.halted = false;
+        hooks.run(&mut cpu);
+        assert!(!cpu.halted);
+
+        hooks.add(hook(false));
+        hooks.run(&mut cpu);
+        assert!(cpu.halted);
+    }
+}
@@ -1,247 +1,754 @@
-use std::fs::write;
+use std::cell::RefCell;
+use std::fs::{write, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-use r4300i_rs::R4300i;
+use r4300i_rs::cop0::faults::NandFaults;
+use r4300i_rs::cop0::mmio::MmioLog;
+use r4300i_rs::cop0::nand::NandImage;
+use r4300i_rs::cop0::save::SaveMemory;
+use r4300i_rs::snapshot::SnapshotError;
+use r4300i_rs::{trace, R4300i};
+
+pub mod config;
+pub mod crash;
+pub mod hooks;
+pub mod monitor;
+pub mod movie;
+pub mod nand;
+pub mod patch;
+mod reader;
+pub mod rewind;
+pub mod save;
+pub mod script;
+pub mod symbols;
+
+use hooks::Hooks;
+use monitor::{Monitor, MonitorAction};
+use movie::Movie;
+use nand::NandStore;
+use patch::Patches;
+use rewind::{Frontend, Rewind};
+use save::SaveStore;
+use script::{Requests, ScriptError, Scripts, SharedCpu};
+use symbols::Symbols;
 
 #[derive(Debug)]
 pub struct Nimu {
-    cpu: R4300i,
+    cpu: SharedCpu,
+    monitor: Monitor,
+    save_state_at: Option<u32>,
+    rewind: Rewind,
+    instructions: u64,
+    movie: Movie,
+    trace: Option<TraceOutput>,
+    injection: Option<Injection>,
+    nand_store: NandStore,
+    save_store: Option<SaveStore>,
+    hooks: Hooks,
+    patches: Patches,
+    scripts: Scripts,
+    // the instructions after which a script changed the machine; replaying
+    // across one can't reproduce it, so rewinds stop short of them
+    script_changes: Vec<u64>,
+}
+
+// payloads to write into memory and where to start running them, either
+// before the first instruction or when the pc first reaches `at`
+#[derive(Debug, Clone, Default)]
+pub struct Injection {
+    pub payloads: Vec<(u32, Vec<u8>)>,
+    pub entry: Option<u32>,
+    pub at: Option<u32>,
 }
 
-//const PAYLOAD: &[u8] = include_bytes!(r"C:\Users\Jay\Documents\bbbs\payload");
+#[derive(Debug)]
+struct TraceOutput {
+    out: BufWriter<File>,
+    start: Option<u32>,
+    stop: Option<u32>,
+}
 
 impl Nimu {
-    pub fn new(
-        bootrom: Vec<u8>,
-        v0: Vec<u8>,
-        v1: Vec<u8>,
-        v2: Vec<u8>,
-        nand: Vec<u8>,
-        spare: Vec<u8>,
-    ) -> Self {
+    pub fn new(bootrom: Vec<u8>, v0: Vec<u8>, v1: Vec<u8>, v2: Vec<u8>, nand: NandImage) -> Self {
+        let cpu = Rc::new(RefCell::new(R4300i::new(bootrom, v0, v1, v2, nand)));
         Self {
-            cpu: R4300i::new(bootrom, v0, v1, v2, nand, spare),
+            cpu: cpu.clone(),
+            monitor: Monitor::new(),
+            save_state_at: None,
+            rewind: Rewind::disabled(),
+            instructions: 0,
+            movie: Movie::default(),
+            trace: None,
+            injection: None,
+            nand_store: NandStore::Volatile,
+            save_store: None,
+            hooks: Hooks::new(),
+            patches: Patches::new(),
+            scripts: Scripts::new(cpu),
+            script_changes: vec![],
+        }
+    }
+
+    pub fn monitor_interrupt(&self) -> Arc<AtomicBool> {
+        self.monitor.interrupt_flag()
+    }
+
+    pub fn add_breakpoint(&mut self, address: u32) {
+        self.monitor.add_breakpoint(address);
+    }
+
+    // how many recent instructions a crash bundle shows
+    pub fn set_crash_history(&mut self, len: usize) {
+        self.cpu.borrow_mut().set_history_len(len);
+    }
+
+    pub fn set_nand_faults(&mut self, faults: NandFaults) {
+        self.cpu.borrow_mut().set_nand_faults(faults);
+    }
+
+    pub fn set_nand_store(&mut self, store: NandStore) {
+        self.nand_store = store;
+    }
+
+    // maps the save memory onto domain 2, writing it back through the store
+    pub fn set_save(&mut self, save: SaveMemory, store: SaveStore) {
+        self.cpu.borrow_mut().set_save_memory(Some(save));
+        self.save_store = Some(store);
+    }
+
+    pub fn set_hooks(&mut self, hooks: Hooks) {
+        self.hooks = hooks;
+    }
+
+    pub fn set_patches(&mut self, patches: Patches) {
+        self.patches = patches;
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.scripts.set_symbols(symbols.clone());
+        self.monitor.set_symbols(symbols);
+    }
+
+    // load after set_symbols so the script can use symbol names
+    pub fn load_script(&mut self, path: &Path) -> Result<(), ScriptError> {
+        let requests = self.scripts.load(path)?;
+        self.apply(requests);
+        Ok(())
+    }
+
+    fn apply(&mut self, requests: Requests) {
+        for address in requests.breakpoints {
+            self.monitor.add_breakpoint(address);
         }
+        if requests.enter_monitor {
+            self.monitor.interrupt_flag().store(true, Ordering::SeqCst);
+        }
+    }
+
+    pub fn inject(&mut self, injection: Injection) {
+        self.injection = Some(injection);
+    }
+
+    pub fn save_state_at(&mut self, address: u32) {
+        self.save_state_at = Some(address);
+    }
+
+    pub fn enable_rewind(&mut self, interval: u64, capacity: usize) {
+        self.rewind = Rewind::new(interval, capacity);
+    }
+
+    pub fn seed(&mut self, seed: u64) {
+        self.movie.seed = seed;
+        self.cpu.borrow_mut().seed_random(seed);
+    }
+
+    pub fn replay(&mut self, movie: Movie) {
+        self.cpu.borrow_mut().seed_random(movie.seed);
+        self.movie = movie;
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    // without a start address, tracing begins straight away
+    pub fn trace_to(
+        &mut self,
+        file: File,
+        start: Option<u32>,
+        stop: Option<u32>,
+    ) -> io::Result<()> {
+        let mut out = BufWriter::new(file);
+        out.write_all(&trace::header())?;
+        if start.is_none() {
+            self.cpu.borrow_mut().start_trace();
+        }
+        self.trace = Some(TraceOutput { out, start, stop });
+        Ok(())
+    }
+
+    pub fn set_mmio_log(&mut self, log: MmioLog) {
+        self.cpu.borrow_mut().set_mmio_log(Some(log));
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        self.cpu.borrow_mut().load_state(data)
     }
 
     pub fn run(&mut self) {
-        /*self.cpu.write::<u32>(0x80300000, 0x8006B940);
-        self.cpu.write::<u32>(0x80300004, 0x00000000);
-        self.cpu.write::<u32>(0x80300008, 0xA006C934);
-        self.cpu.write::<u32>(0x8030000C, 0x24020030);
-        self.cpu.write::<u32>(0x80300010, 0x3C01A460);
-        self.cpu.write::<u32>(0x80300014, 0xAC220060);
-        self.cpu.write::<u32>(0x80300018, 0x1000FFFF);
-        self.cpu.write::<u32>(0x8030001C, 0x00000000);*/
-
-        /*for (index, i) in PAYLOAD.iter().enumerate() {
-            self.cpu.write(0x80300000 + index as u32, *i);
-        }*/
-
-        self.cpu.start();
+        self.start();
         //self.cpu.start_logging();
-        while !self.cpu.halted {
-            self.cpu.step();
-            if self.cpu.get_pc() as u32 == 0x9fc00000 && !self.cpu.get_mi_mapping() {
-                write("kernel.bin", self.cpu.get_bootram()).unwrap();
-                self.cpu.start_logging();
+        while !self.halted() {
+            if self.save_state_at == Some(self.pc()) {
+                self.save_state_at = None;
+                let path = format!("state-{:08X}.bin", self.pc());
+                write(&path, self.cpu.borrow_mut().save_state()).unwrap();
+                println!("saved state to {path}");
             }
-            if self.cpu.get_pc() as u32 == 0x9fc00ea8 && !self.cpu.get_mi_mapping() {
-                self.cpu.start_logging();
+            let should_break = self.monitor.should_break(&self.cpu.borrow());
+            if should_break && self.enter_monitor() == MonitorAction::Quit {
+                break;
             }
-            if self.cpu.get_pc() as u32 == 0x80002000 {
-                write("ram.bin", self.cpu.get_ram()).unwrap();
-            }
-            /*if self.cpu.get_pc() as u32 == 0x9FC01F30 {
-                self.cpu.start_logging();
-            }*/
-            if self.cpu.get_pc() as u32 == 0x9fc407c8 {
-                println!(
-                    "sk hash from v2: {:08X}{:08X}{:08X}{:08X}{:08X}",
-                    self.cpu.read::<u32>(0xBFCA0000).unwrap(),
-                    self.cpu.read::<u32>(0xBFCA0004).unwrap(),
-                    self.cpu.read::<u32>(0xBFCA0008).unwrap(),
-                    self.cpu.read::<u32>(0xBFCA000C).unwrap(),
-                    self.cpu.read::<u32>(0xBFCA0010).unwrap()
-                );
+            self.record_checkpoint();
+            self.step();
+        }
+        self.cpu.borrow_mut().stop();
 
-                let sp = self.cpu.get_reg(29) as u32;
+        if self.halted() {
+            println!("halted at {:08X}", self.pc());
+            self.monitor
+                .print_backtrace(&self.cpu.borrow(), Monitor::BACKTRACE_LIMIT);
+        }
 
-                println!(
-                    "calculated: {:08X}{:08X}{:08X}{:08X}{:08X}",
-                    self.cpu.read::<u32>(sp + 0x90).unwrap(),
-                    self.cpu.read::<u32>(sp + 0x94).unwrap(),
-                    self.cpu.read::<u32>(sp + 0x98).unwrap(),
-                    self.cpu.read::<u32>(sp + 0x9C).unwrap(),
-                    self.cpu.read::<u32>(sp + 0xA0).unwrap()
-                );
+        if let Some(trace) = &mut self.trace {
+            trace.out.flush().unwrap();
+        }
+    }
+
+    fn pc(&self) -> u32 {
+        self.cpu.borrow().get_pc() as u32
+    }
+
+    fn halted(&self) -> bool {
+        self.cpu.borrow().halted
+    }
+
+    fn start(&mut self) {
+        self.cpu.borrow_mut().start();
+        self.check_injection(true);
+    }
+
+    // applies the injection once the pc reaches its trigger, or straight away
+    // without one; steps check after every instruction, replayed ones included
+    fn check_injection(&mut self, report: bool) {
+        let pc = self.pc();
+        let Some(injection) = self
+            .injection
+            .take_if(|injection| injection.at.is_none_or(|at| at == pc))
+        else {
+            return;
+        };
+
+        let mut cpu = self.cpu.borrow_mut();
+        for (address, payload) in injection.payloads {
+            for (i, byte) in payload.iter().enumerate() {
+                cpu.write::<u8>(address.wrapping_add(i as u32), *byte);
             }
-            /*if self.cpu.get_pc() as u32 == 0x9fc032cc {
-                println!(
-                    "rsa_verify_signature -> {:08X}",
-                    self.cpu.get_reg(31) as u32
-                );
-                self.cpu.stop_logging();
-            }*/
-            /*if self.cpu.get_pc() as u32 == 0x9fc03890 {
-                println!("param: {:016X}", self.cpu.get_reg(4));
+            if report {
+                println!("injected {:#X} bytes at {address:08X}", payload.len());
             }
-            if self.cpu.get_pc() as u32 == 0x9fc01b60 {
-                println!("link from: {:016X}", self.cpu.get_reg(4));
+        }
+        if let Some(entry) = injection.entry {
+            if report {
+                println!("jumping to {entry:08X}");
             }
-            if self.cpu.get_pc() as u32 == 0x9fc01c1c {
-                println!("good block: {:016X}", self.cpu.get_reg(2));
-            }*/
-            /*if self.cpu.get_pc() as u32 == 0x9fc037fc {
-                let (key, iv) = (self.cpu.get_reg(4), self.cpu.get_reg(5));
-                println!("{key:016X}, {iv:016X}");
-                let mut key_buf = [0; 0x14];
-                let mut iv_buf = [0; 0x14];
-                for (index, (k, i)) in key_buf.iter_mut().zip(iv_buf.iter_mut()).enumerate() {
-                    *k = self.cpu.read::<u8>(key as u32 + index as u32).unwrap_or(0);
-                    *i = self.cpu.read::<u8>(iv as u32 + index as u32).unwrap_or(0);
-                }
+            cpu.jump(entry as i32 as u64);
+        }
+    }
 
-                println!("{key_buf:02X?}\n{iv_buf:02X?}");
-            }*/
-            if self.cpu.get_pc() as u32 == 0x9fc02458 {
-                let sp = self.cpu.get_reg(29);
-                let ptr = sp + 0x10;
-                let addr = self.cpu.read::<u32>(ptr as _).unwrap_or(0);
-                println!("load addr: {addr:08X}");
-                let mut sa1_buf = [0; 0x1C000];
-                for (index, p) in sa1_buf.iter_mut().enumerate() {
-                    *p = self.cpu.read::<u8>(addr + index as u32).unwrap_or(0);
+    fn enter_monitor(&mut self) -> MonitorAction {
+        loop {
+            let action = self.monitor.enter(&mut self.cpu.borrow_mut());
+            match action {
+                MonitorAction::ReverseStep(n) => {
+                    let target = self.instructions.saturating_sub(n as u64);
+                    if self.rewind_to(target) {
+                        println!("rewound to instruction {}", self.instructions);
+                    }
                 }
-                write("sysapp.bin", sa1_buf).unwrap();
-            }
-            /*if self.cpu.get_pc() as u32 == 0x9fc03b1c && self.cpu.get_reg(31) as u32 == 0x9FC02270 {
-                let (known, calculated) = (self.cpu.get_reg(4), self.cpu.get_reg(5));
-                println!("{known:016X}, {calculated:016X}");
-                let mut known_buf = [0; 0x14];
-                let mut calculated_buf = [0; 0x14];
-                for (index, (k, c)) in known_buf
-                    .iter_mut()
-                    .zip(calculated_buf.iter_mut())
-                    .enumerate()
-                {
-                    *k = self
-                        .cpu
-                        .read::<u8>(known as u32 + index as u32)
-                        .unwrap_or(0);
-                    *c = self
-                        .cpu
-                        .read::<u8>(calculated as u32 + index as u32)
-                        .unwrap_or(0);
+                MonitorAction::ReverseContinue => {
+                    if self.reverse_continue() {
+                        println!("rewound to instruction {}", self.instructions);
+                    }
                 }
-
-                println!("{known_buf:02X?}\n{calculated_buf:02X?}");
-            }*/
-            if self.cpu.get_pc() as u32 == 0xBFC03F00 {
-                self.cpu.halt();
+                MonitorAction::Inject(stimulus) => {
+                    self.cpu.borrow_mut().apply(&stimulus);
+                    self.movie.record(self.instructions, stimulus);
+                }
+                MonitorAction::Bundle(dir) => {
+                    self.write_crash_bundle(&dir, "requested from the monitor");
+                }
+                action => return action,
             }
-            if self.cpu.get_pc() as u32 == 0xBFC00A7C {
+        }
+    }
+
+    // reloads the closest checkpoint and runs forward to the given instruction
+    fn rewind_to(&mut self, target: u64) -> bool {
+        if !self.rewind.is_enabled() {
+            println!("rewind is disabled (run with --rewind)");
+            return false;
+        }
+
+        let Some(count) = self.rewind.checkpoints_before(target + 1).first().copied() else {
+            println!("no checkpoint before instruction {target}");
+            return false;
+        };
+        if let Some(at) = self
+            .script_changes
+            .iter()
+            .find(|at| (count + 1..=target).contains(at))
+        {
+            println!("can't rewind to {target}: a script changed the machine at instruction {at}");
+            return false;
+        }
+
+        // the rewind needs to know about every nand block written so far
+        self.write_back();
+        let (_, frontend) = self
+            .rewind
+            .restore(target, &mut self.cpu.borrow_mut())
+            .unwrap();
+        let frontend = frontend.clone();
+        self.patches = frontend.patches;
+        self.hooks = frontend.hooks;
+        self.injection = frontend.injection;
+        self.save_state_at = frontend.save_state_at;
+        self.instructions = count;
+        self.movie.seek(count);
+
+        while self.instructions < target && !self.halted() {
+            self.record_checkpoint();
+            self.replay_step();
+        }
+        self.rewind.discard_after(self.instructions);
+        self.script_changes.retain(|at| *at <= target);
+        self.write_back();
+
+        true
+    }
+
+    // goes back to the last time a breakpoint was hit, searching one checkpoint
+    // interval at a time from the most recent
+    fn reverse_continue(&mut self) -> bool {
+        if !self.rewind.is_enabled() {
+            println!("rewind is disabled (run with --rewind)");
+            return false;
+        }
+
+        let mut range_end = self.instructions;
+        // the search can't replay across a script's changes
+        let floor = self.script_changes.last().copied().unwrap_or(0);
+
+        for start in self.rewind.checkpoints_before(self.instructions) {
+            if start < floor {
                 println!(
-                    "ram at 0x80300000: {:08X} {:08X} {:08X} {:08X}",
-                    self.cpu.read::<u32>(0x80300000).unwrap(),
-                    self.cpu.read::<u32>(0x80300004).unwrap(),
-                    self.cpu.read::<u32>(0x80300008).unwrap(),
-                    self.cpu.read::<u32>(0x8030000C).unwrap()
+                    "a script changed the machine at instruction {floor}; searching no further"
                 );
+                break;
+            }
+            self.rewind_to(start);
 
-                let mut a0 = self.cpu.get_reg(4);
-
-                loop {
-                    let c = self.cpu.read::<u8>(a0 as u32).unwrap_or(0);
-                    a0 += 1;
-                    if c == 0 {
-                        break;
-                    }
-                    print!("{}", c as char);
+            let mut hit = None;
+            while self.instructions < range_end && !self.halted() {
+                if self.monitor.is_breakpoint(self.pc()) {
+                    hit = Some(self.instructions);
                 }
+                self.replay_step();
             }
-            if self.cpu.get_pc() as u32 == 0xBFC00380 {
-                println!("ra: {:08X}", self.cpu.get_reg(31));
-            }
-            if self.cpu.get_pc() as u32 == 0x9fc03fe8 {
-                //self.cpu.start_logging();
-                println!("virage write {:016X}", self.cpu.get_reg(4));
-            }
-            if self.cpu.get_pc() as u32 == 0x9fc022a8 {
-                //self.cpu.start_logging()
-            }
-            if self.cpu.get_pc() as u32 == 0x8000F654 {
-                /*println!(
-                    "sw <{:08X}>, 16({:08X})",
-                    self.cpu.get_reg(0x10) as u32,
-                    self.cpu.get_reg(0x1D) as u32
-                );*/
-            }
-            if self.cpu.get_pc() as u32 == 0x800051b0 {
-                //println!("osGetCount");
-            }
-            if self.cpu.get_pc() as u32 == 0x80005780 {
-                println!("osStopThread");
-            }
-            if self.cpu.get_pc() as u32 == 0x800074CC {
-                let thread_ptr = self.cpu.get_reg(26);
-                println!("__osDispatchThread: {:016X}", thread_ptr);
-
-                /*match thread_ptr as u32 {
-                    0x8001AF48 | 0x8002B2A8 | 0x8006B818 => self.cpu.stop_logging(),
-                    _ => self.cpu.start_logging(),
-                }*/
+
+            if let Some(hit) = hit {
+                return self.rewind_to(hit);
             }
-            if self.cpu.get_pc() as u32 == 0x800074FC {
-                let k0 = self.cpu.get_reg(26) as u32;
+            range_end = start;
+        }
 
-                let mut dump = [0; 0x100];
+        // nothing found, so stop at the oldest checkpoint
+        println!("no earlier breakpoint hit");
+        self.rewind_to(range_end)
+    }
 
-                for (index, i) in dump.iter_mut().enumerate() {
-                    *i = self.cpu.read::<u8>(k0 + index as u32).unwrap_or(0xEE);
-                }
+    fn write_crash_bundle(&self, dir: &str, reason: &str) {
+        match crash::write_bundle(
+            Path::new(dir),
+            &self.cpu.borrow(),
+            &self.monitor,
+            reason,
+            self.instructions,
+        ) {
+            Ok(()) => println!("wrote crash bundle to {dir}"),
+            Err(e) => println!("couldn't write crash bundle to {dir}: {e}"),
+        }
+    }
 
-                write(format!("dump-{k0:08X}.bin"), dump).unwrap();
+    // runs one instruction, along with any patches, hooks and injection at the new pc
+    fn step(&mut self) {
+        while let Some(stimulus) = self.movie.pending(self.instructions) {
+            self.cpu.borrow_mut().apply(&stimulus);
+        }
+        if let Some(trace) = &self.trace {
+            let pc = Some(self.pc());
+            if pc == trace.start {
+                self.cpu.borrow_mut().start_trace();
+            } else if pc == trace.stop {
+                self.cpu.borrow_mut().stop_trace();
             }
-            if self.cpu.get_pc() as u32 == 0x8000ad40 {
-                let mut a0 = self.cpu.get_reg(4);
-
-                loop {
-                    let c = self.cpu.read::<u8>(a0 as u32).unwrap_or(0);
-                    a0 += 1;
-                    if c == 0 {
-                        break;
-                    }
-                    print!("{}", c as char);
-                }
+        }
+
+        let result = self.cpu.borrow_mut().try_step();
+        if let Err(e) = result {
+            println!("emulator error: {e}");
+            let dir = format!("crash-{:08X}-{}", self.pc(), self.instructions);
+            self.write_crash_bundle(&dir, &e.to_string());
+            self.cpu.borrow_mut().halt();
+        }
+        self.instructions += 1;
+        self.write_back();
+
+        let mut cpu = self.cpu.borrow_mut();
+        if let Some(trace) = &mut self.trace {
+            cpu.flush_trace(&mut trace.out).unwrap();
+        }
+
+        self.patches.run(&mut cpu);
+        self.hooks.run(&mut cpu);
+        drop(cpu);
+        if !self.scripts.is_empty() {
+            let requests = self.scripts.run();
+            if requests.changed_machine {
+                self.script_changes.push(self.instructions);
             }
-            if self.cpu.get_pc() as u32 == 0x80002050 {
-                // patch sa1 to not check BBID
-                // it's incredibly annoying that this is needed, but i can't
-                // quite figure out interrupts properly without a rewrite
-                self.cpu.write::<u32>(0x80002100, 0x00000000);
-
-                // patch sa1 to not wait for the interrupt reading sa2 to complete
-                self.cpu.write::<u32>(0x800083ac, 0x00000000);
+            self.apply(requests);
+        }
+        self.check_injection(true);
+    }
+
+    // reruns a step on the way to a rewind target: only what the machine saw
+    // the first time, the movie's stimuli, the patches and the injection,
+    // without the hooks, scripts, trace output or write-back. scripts that
+    // changed the machine can't be replayed, so rewinds never cross them
+    fn replay_step(&mut self) {
+        let mut cpu = self.cpu.borrow_mut();
+        while let Some(stimulus) = self.movie.pending(self.instructions) {
+            cpu.apply(&stimulus);
+        }
+        if cpu.try_step().is_err() {
+            cpu.halt();
+        }
+        // the trace already has these
+        cpu.flush_trace(&mut io::sink()).unwrap();
+        self.instructions += 1;
+        self.patches.replay(&mut cpu);
+        drop(cpu);
+        self.check_injection(false);
+    }
+
+    fn record_checkpoint(&mut self) {
+        if !self.rewind.is_due(self.instructions) {
+            return;
+        }
+        // so the checkpoint has every nand block written so far
+        self.write_back();
+        self.rewind
+            .record(self.instructions, &mut self.cpu.borrow_mut(), || Frontend {
+                patches: self.patches.clone(),
+                hooks: self.hooks.clone(),
+                injection: self.injection.clone(),
+                save_state_at: self.save_state_at,
+            });
+    }
+
+    // brings the nand and save files up to date with the machine
+    fn write_back(&mut self) {
+        let mut cpu = self.cpu.borrow_mut();
+        let dirty = cpu.take_dirty_nand_blocks();
+        self.rewind.nand_changed(&dirty);
+        if let Err(e) = self.nand_store.flush(&cpu, &dirty) {
+            println!("couldn't write back nand: {e}");
+        }
+        if let Some(store) = &mut self.save_store {
+            if let Err(e) = store.flush(&mut cpu) {
+                println!("couldn't write back save memory: {e}");
             }
-            /*if self.cpu.get_pc() as u32 == 0x80004f8c {
-                if self.cpu.get_reg(3) as u32 == 0xA0006068 {
-                    /*println!(
-                        "ram at the place:\n{:08X} {:08X} {:08X} {:08X}\n{:08X} {:08X} {:08X} {:08X}",
-                        self.cpu.read::<u32>(0xA006B934).unwrap(),
-                        self.cpu.read::<u32>(0xA006B938).unwrap(),
-                        self.cpu.read::<u32>(0xA006B93C).unwrap(),
-                        self.cpu.read::<u32>(0xA006B940).unwrap(),
-                        self.cpu.read::<u32>(0xA006B944).unwrap(),
-                        self.cpu.read::<u32>(0xA006B948).unwrap(),
-                        self.cpu.read::<u32>(0xA006B94C).unwrap(),
-                        self.cpu.read::<u32>(0xA006B950).unwrap(),
-                    );*/
-                    self.cpu.start_logging();
-                    //self.cpu.trigger_interrupt();
-                    self.cpu.set_pc(0x80000000);
-                }
-            }*/
         }
-        self.cpu.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::{read, remove_file};
+    use std::process;
+
+    use r4300i_rs::cop0::nand::NandDevice;
+    use r4300i_rs::cop0::{NAND_BLOCK_SIZE, NAND_SPARE_SIZE};
+    use r4300i_rs::instruction::asm::assemble;
+
+    use super::*;
+    use crate::patch::{Patch, Trigger};
+
+    // erases nand block 1, then spins at 0xBFC00020
+    const PROGRAM: &str = "
+        lui t0, 0xA460
+        addiu t1, zero, 0x4000
+        sw t1, 0x70(t0)
+        lui t2, 0x8060
+        ori t2, t2, 0x0400
+        sw t2, 0x48(t0)
+        lui t2, 0x80D0
+        sw t2, 0x48(t0)
+    loop:
+        beq zero, zero, loop
+        nop
+    ";
+    const LOOP: u32 = 0xBFC00020;
+
+    fn nimu(overlay: &Path) -> Nimu {
+        let bootrom = assemble(PROGRAM, 0xBFC00000)
+            .unwrap()
+            .iter()
+            .flat_map(|instr| instr.to_be_bytes())
+            .collect();
+        let device: NandDevice = "64m".parse().unwrap();
+        let blocks = device.size() / NAND_BLOCK_SIZE;
+        let mut image = NandImage::split(
+            vec![0; device.size()],
+            vec![0; blocks * NAND_SPARE_SIZE],
+            Some(device),
+        )
+        .unwrap();
+        let store = NandStore::overlay(overlay, &mut image).unwrap();
+
+        let mut nimu = Nimu::new(bootrom, vec![], vec![], vec![], image);
+        nimu.set_nand_store(store);
+        let mut patches = Patches::new();
+        patches.add(Patch {
+            name: "marker".into(),
+            address: 0x80000100,
+            original: None,
+            value: vec![0x12345678],
+            check: None,
+            trigger: Trigger::At(LOOP),
+        });
+        nimu.set_patches(patches);
+        nimu.enable_rewind(4, 16);
+        nimu.start();
+        nimu
+    }
+
+    fn run(nimu: &mut Nimu, steps: u64) {
+        for _ in 0..steps {
+            nimu.record_checkpoint();
+            nimu.step();
+        }
+    }
+
+    fn marker(nimu: &mut Nimu) -> Option<u32> {
+        nimu.cpu.borrow_mut().peek::<u32>(0x80000100)
+    }
+
+    fn erased(nimu: &Nimu) -> bool {
+        nimu.cpu
+            .borrow()
+            .get_nand_block(1)
+            .0
+            .iter()
+            .all(|byte| *byte == 0xFF)
+    }
+
+    // what the overlay file has for block 1, if anything
+    fn overlay_block(path: &Path) -> Option<Vec<u8>> {
+        let blocks = nand::load(&read(path).ok()?).unwrap();
+        blocks
+            .get(&1)
+            .map(|block| block[..NAND_BLOCK_SIZE].to_vec())
+    }
+
+    #[test]
+    fn rewinds_across_a_patch_and_a_nand_write() {
+        let path = temp_dir().join(format!("nimu-rewind-{}.nand", process::id()));
+        let _ = remove_file(&path);
+        let mut nimu = nimu(&path);
+
+        run(&mut nimu, 20);
+        assert!(erased(&nimu));
+        assert_eq!(marker(&mut nimu), Some(0x12345678));
+        assert_eq!(overlay_block(&path), Some(vec![0xFF; NAND_BLOCK_SIZE]));
+
+        // back before the erase, the patch hasn't fired and the file follows
+        assert!(nimu.rewind_to(2));
+        assert_eq!(nimu.instructions, 2);
+        assert!(!erased(&nimu));
+        assert_eq!(marker(&mut nimu), Some(0));
+        assert_eq!(overlay_block(&path), Some(vec![0; NAND_BLOCK_SIZE]));
+
+        // replaying to the loop applies the patch just as running did
+        assert!(nimu.rewind_to(19));
+        assert!(erased(&nimu));
+        assert_eq!(marker(&mut nimu), Some(0x12345678));
+        assert_eq!(overlay_block(&path), Some(vec![0xFF; NAND_BLOCK_SIZE]));
+
+        // and running on from an earlier point gets there again
+        assert!(nimu.rewind_to(2));
+        run(&mut nimu, 20);
+        assert!(erased(&nimu));
+        assert_eq!(marker(&mut nimu), Some(0x12345678));
+
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn checkpoints_share_unchanged_nand_blocks() {
+        let path = temp_dir().join(format!("nimu-shared-nand-{}.nand", process::id()));
+        let _ = remove_file(&path);
+        let mut nimu = nimu(&path);
+        run(&mut nimu, 20);
+
+        // only block 1 is erased, between the first and last checkpoints
+        let first = nimu.rewind.nand_at(0).unwrap();
+        let last = nimu.rewind.nand_at(16).unwrap();
+        for (block, (a, b)) in first.iter().zip(last).enumerate() {
+            assert_eq!(Rc::ptr_eq(a, b), block != 1, "block {block}");
+        }
+        assert!(last[1][..NAND_BLOCK_SIZE].iter().all(|byte| *byte == 0xFF));
+
+        // rewinding past the erase brings the block back, and later
+        // checkpoints go on sharing with the restored one
+        assert!(nimu.rewind_to(2));
+        assert!(!erased(&nimu));
+        run(&mut nimu, 6);
+        let first = nimu.rewind.nand_at(0).unwrap();
+        let again = nimu.rewind.nand_at(4).unwrap();
+        assert!(first.iter().zip(again).all(|(a, b)| Rc::ptr_eq(a, b)));
+
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rewinds_stop_short_of_script_changes() {
+        let path = temp_dir().join(format!("nimu-script-rewind-{}.nand", process::id()));
+        let script = path.with_extension("rhai");
+        let _ = remove_file(&path);
+        write(&script, "hook(0xBFC00008, || poke32(0x80000200, 1));").unwrap();
+        let mut nimu = nimu(&path);
+        nimu.load_script(&script).unwrap();
+        nimu.add_breakpoint(0xBFC00004);
+
+        // the script pokes memory after instruction 2
+        run(&mut nimu, 20);
+        assert_eq!(nimu.script_changes, [2]);
+
+        // which a rewind from checkpoint 0 would have to replay
+        assert!(nimu.rewind_to(10));
+        assert!(!nimu.rewind_to(3));
+        assert!(!nimu.rewind_to(2));
+        assert_eq!(nimu.instructions, 10);
+
+        // and the search for the breakpoint doesn't go past it
+        assert!(nimu.reverse_continue());
+        assert_eq!(nimu.instructions, 4);
+
+        // before it is fine, and forgets the change
+        assert!(nimu.rewind_to(1));
+        assert!(nimu.script_changes.is_empty());
+        run(&mut nimu, 2);
+        assert_eq!(nimu.script_changes, [2]);
+
+        remove_file(&path).unwrap();
+        remove_file(&script).unwrap();
+    }
+
+    // stores 0x55 to 0x80000300, then spins at 0x8030000C
+    const PAYLOAD: &str = "
+        lui t0, 0x8000
+        addiu t1, zero, 0x55
+        sw t1, 0x300(t0)
+    loop:
+        beq zero, zero, loop
+        nop
+    ";
+    const ENTRY: u32 = 0x80300000;
+
+    fn injection(at: Option<u32>) -> Injection {
+        let payload = assemble(PAYLOAD, ENTRY)
+            .unwrap()
+            .iter()
+            .flat_map(|instr| instr.to_be_bytes())
+            .collect();
+        Injection {
+            payloads: vec![(ENTRY, payload)],
+            entry: Some(ENTRY),
+            at,
+        }
+    }
+
+    fn injected(nimu: &mut Nimu) -> bool {
+        nimu.cpu.borrow_mut().peek::<u32>(ENTRY) == Some(0x3C088000)
+    }
+
+    #[test]
+    fn injects_straight_away() {
+        let path = temp_dir().join(format!("nimu-inject-{}.nand", process::id()));
+        let _ = remove_file(&path);
+        let mut nimu = nimu(&path);
+        nimu.inject(injection(None));
+        nimu.start();
+        assert!(injected(&mut nimu));
+        assert_eq!(nimu.pc(), ENTRY);
+        assert!(nimu.injection.is_none());
+
+        run(&mut nimu, 6);
+        assert_eq!(nimu.cpu.borrow_mut().peek::<u32>(0x80000300), Some(0x55));
+        assert!((ENTRY + 0xC..=ENTRY + 0x10).contains(&nimu.pc()));
+
+        let _ = remove_file(&path);
+    }
+
+    #[test]
+    fn injects_at_the_trigger_and_again_on_replay() {
+        let path = temp_dir().join(format!("nimu-inject-at-{}.nand", process::id()));
+        let _ = remove_file(&path);
+        let mut nimu = nimu(&path);
+        nimu.inject(injection(Some(LOOP)));
+        nimu.start();
+
+        // the bootrom program reaches the loop after 8 instructions
+        run(&mut nimu, 7);
+        assert!(!injected(&mut nimu));
+        assert!(nimu.injection.is_some());
+        run(&mut nimu, 1);
+        assert!(injected(&mut nimu));
+        assert_eq!(nimu.pc(), ENTRY);
+
+        run(&mut nimu, 7);
+        let state = nimu.cpu.borrow_mut().save_state();
+        assert_eq!(nimu.cpu.borrow_mut().peek::<u32>(0x80000300), Some(0x55));
+        run(&mut nimu, 5);
+
+        // before the trigger, the payload is gone and still to come
+        assert!(nimu.rewind_to(5));
+        assert!(!injected(&mut nimu));
+        assert!(nimu.injection.is_some());
+
+        // and replaying across it ends up where running did
+        assert!(nimu.rewind_to(15));
+        assert!(nimu.injection.is_none());
+        assert_eq!(nimu.cpu.borrow_mut().save_state(), state);
+
+        let _ = remove_file(&path);
     }
 }
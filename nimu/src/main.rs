@@ -1,52 +1,553 @@
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{anyhow, bail, Result};
+use clap::{Args, Parser, Subcommand};
 
-use std::fs::read;
-use std::path::PathBuf;
+use std::collections::VecDeque;
+use std::fs::{read, read_to_string, write, File};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
 
-use nimu::Nimu;
+use nimu::config::{self, Config};
+use nimu::monitor::{parse_address, parse_count};
+use nimu::movie::Movie;
+use nimu::nand::NandStore;
+use nimu::save::{self, SaveStore};
+use nimu::symbols::{SymbolTable, Symbols};
+use nimu::{Injection, Nimu};
+use r4300i_rs::cop0::mmio::{MmioLog, MmioMode};
+use r4300i_rs::cop0::nand::{NandDevice, NandImage};
+use r4300i_rs::cop0::save::SaveMemory;
+use r4300i_rs::cop0::{Device, NAND_BLOCK_SIZE};
+use r4300i_rs::ecc::{self, EccResult, ECC_BLOCK_SIZE};
+use r4300i_rs::instruction::Instruction;
+use r4300i_rs::trace::{Reader, Record};
 
 #[derive(Debug, Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    run: Option<RunArgs>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Disassemble a raw big-endian binary
+    Disasm(DisasmArgs),
+
+    /// Find the first point where two execution traces disagree
+    TraceDiff(TraceDiffArgs),
+
+    /// Check the ECC of the first page of every NAND block against its spare area.
+    /// The spare file has no codes for the other pages, so damage to them isn't
+    /// detected
+    CheckNand(CheckNandArgs),
+}
+
+#[derive(Debug, Args)]
+struct CheckNandArgs {
+    /// NAND file: the data of a split image, or a combined image with each
+    /// block's spare area after its data
+    #[arg(short, long)]
+    nand: PathBuf,
+
+    /// Spare data file of a split image
+    #[arg(short, long)]
+    spare: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct DisasmArgs {
+    /// Binary file
+    file: PathBuf,
+
+    /// Address the start of the file is loaded at
+    #[arg(short, long, default_value = "0", value_parser = parse_address)]
+    base: u64,
+
+    /// Offset into the file to start disassembling from
+    #[arg(short, long, default_value = "0", value_parser = parse_address)]
+    offset: u64,
+
+    /// Number of bytes to disassemble
+    #[arg(short, long, value_parser = parse_address)]
+    length: Option<u64>,
+
+    /// Label functions from an ELF or `addr name` map file
+    #[arg(long = "symbols", value_name = "FILE")]
+    symbols: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+struct TraceDiffArgs {
+    /// First trace file
+    a: PathBuf,
+
+    /// Second trace file
+    b: PathBuf,
+
+    /// Number of matching records to show before the divergence
+    #[arg(short, long, default_value = "4")]
+    context: usize,
+
+    /// Show function names from an ELF or `addr name` map file
+    #[arg(long = "symbols", value_name = "FILE")]
+    symbols: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+struct RunArgs {
     /// Bootrom file
     #[arg(short, long)]
     bootrom: PathBuf,
 
     /// Virage0 file
-    #[arg(short, long)]
+    #[arg(long)]
     virage0: PathBuf,
 
     /// Virage1 file
-    #[arg(short, long)]
+    #[arg(long)]
     virage1: PathBuf,
 
     /// Virage2 file
-    #[arg(short, long)]
+    #[arg(long)]
     virage2: PathBuf,
 
-    /// NAND file
+    /// NAND file: the data of a split image, or a combined image with each
+    /// block's spare area after its data
     #[arg(short, long)]
     nand: PathBuf,
 
-    /// Spare data file
+    /// Spare data file of a split image
+    #[arg(short, long)]
+    spare: Option<PathBuf>,
+
+    /// Write flash programs and erases back to the NAND (and spare) files
+    #[arg(long, conflicts_with = "nand_overlay")]
+    nand_write_through: bool,
+
+    /// Keep flash programs and erases in this file, applying it on startup, so
+    /// the NAND and spare files stay untouched
+    #[arg(long, value_name = "FILE")]
+    nand_overlay: Option<PathBuf>,
+
+    /// Card to model: 64m, 128m, or several chips like 2x64m (defaults to the
+    /// one matching the NAND file's size)
+    #[arg(long, value_name = "DEVICE")]
+    nand_device: Option<NandDevice>,
+
+    /// Mark a NAND block bad in its spare area, and fail its programs and erases
+    #[arg(long = "bad-block", value_name = "BLOCK")]
+    bad_blocks: Vec<usize>,
+
+    /// Fail every program and erase of a NAND block
+    #[arg(long = "failing-block", value_name = "BLOCK")]
+    failing_blocks: Vec<usize>,
+
+    /// Chance of any NAND program or erase failing
+    #[arg(long, value_name = "RATE")]
+    nand_fail_rate: Option<f64>,
+
+    /// Chance of a NAND page read coming back with a bit flipped
+    #[arg(long, value_name = "RATE")]
+    nand_flip_rate: Option<f64>,
+
+    /// Flip a bit of a NAND byte every time it's read
+    #[arg(long = "nand-flip", value_name = "ADDR:BIT", value_parser = parse_flip)]
+    nand_flips: Vec<(usize, u8)>,
+
+    /// Seed for the random NAND faults
+    #[arg(long, value_name = "N", value_parser = parse_count)]
+    nand_fault_seed: Option<u64>,
+
+    /// Cartridge save memory file, mapped onto domain 2 and written back as the
+    /// guest changes it (replaces the config's [save] file)
+    #[arg(long, value_name = "FILE")]
+    save: Option<PathBuf>,
+
+    /// Enter the monitor before the first instruction
     #[arg(short, long)]
-    spare: PathBuf,
+    monitor: bool,
+
+    /// Hooks, dumps and patches to apply while running (see default.toml)
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Load symbols from an ELF or `addr name` map file, optionally only for one
+    /// mode or address range (FILE[@secure|@non-secure][@START-END])
+    #[arg(long = "symbols", value_name = "SPEC")]
+    symbols: Vec<String>,
+
+    /// Run a Rhai script that can hook addresses (see scripts/investigations.rhai)
+    #[arg(long = "script", value_name = "FILE")]
+    scripts: Vec<PathBuf>,
+
+    /// Number of recently executed instructions to include in crash bundles
+    #[arg(long, value_name = "N", default_value = "64")]
+    crash_history: usize,
+
+    /// Enter the monitor when the PC reaches this address or symbol
+    #[arg(long = "break", value_name = "ADDR")]
+    breakpoints: Vec<String>,
+
+    /// Write a file into memory (FILE@ADDR); the address can be a symbol
+    #[arg(long = "inject", value_name = "FILE@ADDR")]
+    injections: Vec<String>,
+
+    /// Start running from this address instead of the reset vector
+    #[arg(long, value_name = "ADDR")]
+    entry: Option<String>,
+
+    /// Inject and jump to the entry when the PC first reaches this address,
+    /// rather than before the first instruction
+    #[arg(long, value_name = "ADDR")]
+    inject_at: Option<String>,
+
+    /// Save the machine state to state-<ADDR>.bin when the PC first reaches this address
+    #[arg(long, value_name = "ADDR")]
+    save_state_at: Option<String>,
+
+    /// Take a rewind checkpoint every this many instructions
+    #[arg(long, value_name = "INSTRUCTIONS")]
+    rewind: Option<u64>,
+
+    /// Number of rewind checkpoints to keep
+    #[arg(long, value_name = "N", default_value = "4")]
+    rewind_slots: usize,
+
+    /// Load a saved machine state before running
+    #[arg(long, value_name = "FILE")]
+    load_state: Option<PathBuf>,
+
+    /// Seed for the cop0 Random register
+    #[arg(long, value_name = "N", value_parser = parse_count)]
+    seed: Option<u64>,
+
+    /// Record every external input to this file when the run ends
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// Replay the inputs recorded in this file
+    #[arg(long, value_name = "FILE", conflicts_with = "seed")]
+    replay: Option<PathBuf>,
+
+    /// Write a binary execution trace to this file
+    #[arg(long, value_name = "FILE")]
+    trace: Option<PathBuf>,
+
+    /// Start tracing when the PC reaches this address
+    #[arg(long, value_name = "ADDR", requires = "trace")]
+    trace_start: Option<String>,
+
+    /// Stop tracing when the PC reaches this address
+    #[arg(long, value_name = "ADDR", requires = "trace")]
+    trace_stop: Option<String>,
+
+    /// Log device register accesses, optionally only for these devices (comma-separated)
+    #[arg(long, value_name = "DEVICES", num_args = 0.., value_delimiter = ',')]
+    mmio_log: Option<Vec<Device>>,
+
+    /// Only log register accesses made in this mode (all, secure, non-secure)
+    #[arg(long, value_name = "MODE", default_value = "all")]
+    mmio_mode: MmioMode,
+}
+
+fn parse_flip(s: &str) -> Result<(usize, u8), String> {
+    let (address, bit) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected ADDR:BIT: {s}"))?;
+    let bit = bit.parse().map_err(|_| format!("invalid bit: {bit}"))?;
+    Ok((parse_address(address)? as usize, bit))
+}
+
+fn load_symbols(specs: &[String]) -> Result<Symbols> {
+    let mut symbols = Symbols::new();
+    for spec in specs {
+        match SymbolTable::load(spec) {
+            Ok(table) => symbols.add(table),
+            Err(e) => bail!("{spec}: {e}"),
+        }
+    }
+    Ok(symbols)
+}
+
+fn disasm(args: DisasmArgs) -> Result<()> {
+    let data = read(args.file)?;
+    let symbols = load_symbols(&args.symbols)?;
+
+    let start = args.offset as usize;
+    let end = match args.length {
+        Some(length) => start + length as usize,
+        None => data.len(),
+    };
+
+    if start > data.len() || end > data.len() {
+        bail!("range {start:X}..{end:X} is outside the file");
+    }
+
+    for (index, chunk) in data[start..end].chunks_exact(4).enumerate() {
+        let address = (args.base as u32).wrapping_add((start + index * 4) as u32);
+        let opcode = u32::from_be_bytes(chunk.try_into().unwrap());
+        if let Some(label) = symbols.label(address, None) {
+            println!("{label}:");
+        }
+        println!(
+            "{address:08X}: {opcode:08X}  {}",
+            Instruction::new(opcode).disassemble(address)
+        );
+    }
+
+    Ok(())
+}
+
+fn trace_diff(args: TraceDiffArgs) -> Result<()> {
+    let symbols = load_symbols(&args.symbols)?;
+    let a = read(&args.a)?;
+    let b = read(&args.b)?;
+    let mut a = Reader::new(&a)?;
+    let mut b = Reader::new(&b)?;
+
+    let mut context = VecDeque::with_capacity(args.context);
+    let mut index = 0u64;
+
+    loop {
+        let ra = a.next().transpose()?;
+        let rb = b.next().transpose()?;
+        if ra.is_none() && rb.is_none() {
+            println!("traces match ({index} records)");
+            return Ok(());
+        }
+
+        if ra != rb {
+            println!("traces diverge at record {index}");
+            for (i, record) in context {
+                println!("  {i:>10}  {}", symbolize(&record, &symbols));
+            }
+            match ra {
+                Some(record) => println!("- {index:>10}  {}", symbolize(&record, &symbols)),
+                None => println!("- {index:>10}  (end of {})", args.a.display()),
+            }
+            match rb {
+                Some(record) => println!("+ {index:>10}  {}", symbolize(&record, &symbols)),
+                None => println!("+ {index:>10}  (end of {})", args.b.display()),
+            }
+            return Ok(());
+        }
+
+        if args.context != 0 {
+            if context.len() == args.context {
+                context.pop_front();
+            }
+            context.push_back((index, ra.unwrap()));
+        }
+        index += 1;
+    }
+}
+
+// a split image if there's a spare file, otherwise a combined one
+fn load_nand(nand: &Path, spare: Option<&Path>, device: Option<NandDevice>) -> Result<NandImage> {
+    let data = read(nand)?;
+    let spare = spare.map(read).transpose()?;
+    NandImage::load(data, spare, device).map_err(|e| anyhow!("{}: {e}", nand.display()))
+}
+
+// the spare file only has the first page's spare area for each block, so that's
+// the only page with a code to check
+fn check_nand(args: CheckNandArgs) -> Result<()> {
+    let image = load_nand(&args.nand, args.spare.as_deref(), None)?;
+
+    let blocks = image.blocks();
+    let (mut corrected, mut uncorrectable) = (0, 0);
+    for block in 0..blocks {
+        let data_offset = block * NAND_BLOCK_SIZE;
+        let (data, oob) = image.block(block).unwrap();
+        let mut page = data[..0x200].to_vec();
+
+        for (half, result) in ecc::correct_page(&mut page, oob).iter().enumerate() {
+            let half = half * ECC_BLOCK_SIZE;
+            match result {
+                EccResult::Ok => continue,
+                EccResult::Corrected(index, bit) => {
+                    corrected += 1;
+                    println!(
+                        "block {block:4} ({data_offset:08X}): bit {bit} of byte {:03X} is flipped",
+                        half + index
+                    );
+                }
+                EccResult::CodeError => {
+                    corrected += 1;
+                    println!(
+                        "block {block:4} ({data_offset:08X}): the code for {half:03X}-{:03X} has a flipped bit",
+                        half + ECC_BLOCK_SIZE - 1
+                    );
+                }
+                EccResult::Uncorrectable => {
+                    uncorrectable += 1;
+                    println!(
+                        "block {block:4} ({data_offset:08X}): {half:03X}-{:03X} is uncorrectable",
+                        half + ECC_BLOCK_SIZE - 1
+                    );
+                }
+            }
+        }
+    }
+
+    println!(
+        "{blocks} blocks checked (first page only): {corrected} correctable, {uncorrectable} uncorrectable"
+    );
+    Ok(())
+}
+
+// the mode isn't recorded in traces, so any table can match
+fn symbolize(record: &Record, symbols: &Symbols) -> String {
+    match symbols.symbolize(record.pc, None) {
+        Some(name) => format!("{record}  <{name}>"),
+        None => record.to_string(),
+    }
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
+    let cli = match (cli.command, cli.run) {
+        (Some(Command::Disasm(args)), _) => return disasm(args),
+        (Some(Command::TraceDiff(args)), _) => return trace_diff(args),
+        (Some(Command::CheckNand(args)), _) => return check_nand(args),
+        (None, Some(run)) => run,
+        (None, None) => unreachable!("clap requires the run arguments"),
+    };
+
     let bootrom = read(cli.bootrom)?;
     let v0 = read(cli.virage0)?;
     let v1 = read(cli.virage1)?;
     let v2 = read(cli.virage2)?;
-    let nand = read(cli.nand)?;
-    let spare = read(cli.spare)?;
+    let config = match cli.config {
+        Some(path) => Config::parse(&read_to_string(path)?)?,
+        None => Config::parse(config::DEFAULT)?,
+    };
+
+    let device = match cli.nand_device {
+        Some(device) => Some(device),
+        None => config.nand_device()?,
+    };
+    let mut nand = load_nand(&cli.nand, cli.spare.as_deref(), device)?;
+
+    let store = if let Some(path) = &cli.nand_overlay {
+        NandStore::overlay(path, &mut nand)?
+    } else if cli.nand_write_through {
+        NandStore::write_through(&cli.nand, cli.spare.as_deref())?
+    } else {
+        NandStore::Volatile
+    };
+
+    let mut nimu = Nimu::new(bootrom, v0, v1, v2, nand);
+    nimu.set_nand_store(store);
+
+    // --save keeps the config's address and size
+    let save_config = config.save.as_ref();
+    if let Some(path) = cli
+        .save
+        .or_else(|| save_config.map(|save| save.file.clone()))
+    {
+        let size = save_config.and_then(|save| save.size);
+        let address = save_config
+            .and_then(|save| save.address)
+            .unwrap_or(save::DEFAULT_ADDRESS);
+        let data = SaveStore::load(&path, size).map_err(|e| anyhow!("{}: {e}", path.display()))?;
+        let memory = SaveMemory::new(address, data).map_err(|e| anyhow!("save: {e}"))?;
+        let store = SaveStore::open(&path, memory.data())?;
+        nimu.set_save(memory, store);
+    }
 
-    let mut nimu = Nimu::new(bootrom, v0, v1, v2, nand, spare);
+    // addresses given on the command line or in the config can be symbol names
+    let symbols = load_symbols(&[config.symbols.clone(), cli.symbols].concat())?;
+    let resolve = |s: &str| symbols.resolve(s, None).map_err(anyhow::Error::msg);
+
+    if let Some(path) = cli.replay {
+        nimu.replay(Movie::load(&read(path)?)?);
+    } else if let Some(seed) = cli.seed {
+        nimu.seed(seed);
+    }
+
+    if let Some(interval) = cli.rewind {
+        nimu.enable_rewind(interval, cli.rewind_slots);
+    }
+
+    if let Some(path) = cli.trace {
+        nimu.trace_to(
+            File::create(path)?,
+            cli.trace_start.as_deref().map(resolve).transpose()?,
+            cli.trace_stop.as_deref().map(resolve).transpose()?,
+        )?;
+    }
+
+    if let Some(devices) = cli.mmio_log {
+        nimu.set_mmio_log(MmioLog {
+            devices,
+            mode: cli.mmio_mode,
+        });
+    }
+
+    if !cli.injections.is_empty() || cli.entry.is_some() {
+        let mut injection = Injection {
+            entry: cli.entry.as_deref().map(resolve).transpose()?,
+            at: cli.inject_at.as_deref().map(resolve).transpose()?,
+            ..Default::default()
+        };
+        for spec in &cli.injections {
+            let (path, address) = spec
+                .rsplit_once('@')
+                .ok_or_else(|| anyhow!("expected FILE@ADDR: {spec}"))?;
+            injection.payloads.push((resolve(address)?, read(path)?));
+        }
+        nimu.inject(injection);
+    }
+
+    if let Some(address) = cli.save_state_at {
+        nimu.save_state_at(resolve(&address)?);
+    }
+
+    for address in cli.breakpoints {
+        nimu.add_breakpoint(resolve(&address)?);
+    }
+
+    nimu.set_hooks(config.hooks(&symbols)?);
+    nimu.set_patches(config.patches(&symbols)?);
+
+    // faults on the command line add to the config's, or override its rates
+    let mut faults = config.nand_faults()?;
+    faults.bad_blocks.extend(cli.bad_blocks);
+    faults.failing_blocks.extend(cli.failing_blocks);
+    faults.flips.extend(cli.nand_flips);
+    faults.fail_rate = cli.nand_fail_rate.unwrap_or(faults.fail_rate);
+    faults.flip_rate = cli.nand_flip_rate.unwrap_or(faults.flip_rate);
+    faults.seed = cli.nand_fault_seed.unwrap_or(faults.seed);
+    config::check_nand_faults(&faults).map_err(anyhow::Error::msg)?;
+    nimu.set_nand_faults(faults);
+    nimu.set_symbols(symbols);
+    nimu.set_crash_history(cli.crash_history);
+
+    let interrupt = nimu.monitor_interrupt();
+    interrupt.store(cli.monitor, Ordering::SeqCst);
+    ctrlc::set_handler(move || interrupt.store(true, Ordering::SeqCst))?;
+
+    // scripts run their top level now, and can ask for the monitor straight away
+    for path in config.scripts.iter().chain(&cli.scripts) {
+        nimu.load_script(path)?;
+    }
+
+    // last, so nothing above (save memory, nand faults, scripts) can change
+    // the machine after it's restored
+    if let Some(path) = cli.load_state {
+        nimu.load_state(&read(path)?)?;
+    }
 
     nimu.run();
 
+    if let Some(path) = cli.record {
+        write(path, nimu.movie().save())?;
+    }
+
     Ok(())
 }
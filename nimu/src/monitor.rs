@@ -0,0 +1,678 @@
+use std::collections::BTreeSet;
+use std::fs::write;
+use std::io::{self, stdin, stdout, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use r4300i_rs::callstack::FrameKind;
+use r4300i_rs::cop0::mmio::{MmioLog, MmioMode};
+use r4300i_rs::cop0::{self, Device};
+use r4300i_rs::instruction::Instruction;
+use r4300i_rs::stimulus::Stimulus;
+use r4300i_rs::{ExceptionType, R4300i, Register};
+
+use crate::hooks::read_range;
+use crate::patch::crc32;
+use crate::symbols::{SymbolTable, Symbols};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MonitorAction {
+    Resume,
+    ReverseStep(usize),
+    ReverseContinue,
+    Inject(Stimulus),
+    Bundle(String),
+    Quit,
+}
+
+#[derive(Debug)]
+pub struct Monitor {
+    breakpoints: BTreeSet<u32>,
+    interrupt: Arc<AtomicBool>,
+    steps: Option<usize>,
+    symbols: Symbols,
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Monitor {
+    const HELP: &'static str = "\
+commands:
+  step [n]              execute n instructions (default 1)
+  continue              resume execution
+  reverse-stepi [n]     go back n instructions (default 1)
+  reverse-continue      go back to the last breakpoint hit
+  break [addr]          set a breakpoint, or list breakpoints
+  delete <addr>         remove a breakpoint
+  sym <addr|name>       look up a symbol by address or name
+  symbols <file>[@secure|@non-secure][@start-end]
+                        load an elf or `addr name` map file
+  bt [n]                show the innermost n call frames (default all)
+  regs                  show the cpu registers
+  cop0                  show the cop0 registers
+  tlb                   show the tlb entries
+  x/<n><b|h|w|d> <addr> examine memory (addresses can be symbol names)
+  dis <addr> [n]        disassemble n instructions (default 8)
+  dump <file> <addr> <len>
+                        write memory to a file
+  crc <addr> <len>      show the crc32 of memory, for a patch check
+  bundle <dir>          write registers, device state and memory dumps to a directory
+  mmio <device>         show device registers (sp, mi, vi, ai, pi, si, usb0, usb1, virage)
+  mmio-log <all|off|dev,...> [secure|non-secure]
+                        log device register accesses
+  reset                 cold reset the machine
+  button <press|release>
+                        press or release the power button
+  card <insert|remove>  insert or remove the card
+  intr                  raise the module interrupt
+  usb <n> <offset> <hex>
+                        receive bytes into a usb controller's buffer
+  quit                  stop the emulator";
+
+    // frames shown automatically; `bt` shows them all
+    pub const BACKTRACE_LIMIT: usize = 16;
+
+    pub fn new() -> Self {
+        Self {
+            breakpoints: BTreeSet::new(),
+            interrupt: Arc::new(AtomicBool::new(false)),
+            steps: None,
+            symbols: Symbols::new(),
+        }
+    }
+
+    pub fn interrupt_flag(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
+    }
+
+    pub fn add_breakpoint(&mut self, address: u32) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    pub fn is_breakpoint(&self, address: u32) -> bool {
+        self.breakpoints.contains(&address)
+    }
+
+    pub fn should_break(&mut self, cpu: &R4300i) -> bool {
+        if self.interrupt.swap(false, Ordering::SeqCst) {
+            return true;
+        }
+
+        if let Some(steps) = self.steps.as_mut() {
+            if *steps == 0 {
+                self.steps = None;
+                return true;
+            }
+            *steps -= 1;
+        }
+
+        self.breakpoints.contains(&(cpu.get_pc() as u32))
+    }
+
+    pub fn enter(&mut self, cpu: &mut R4300i) -> MonitorAction {
+        self.steps = None;
+
+        let pc = cpu.get_pc() as u32;
+        if self.breakpoints.contains(&pc) {
+            println!("breakpoint at {}", self.describe(cpu, pc));
+            self.print_backtrace(cpu, Self::BACKTRACE_LIMIT);
+        }
+        self.print_current(cpu);
+
+        loop {
+            print!("(nimu) ");
+            stdout().flush().unwrap();
+
+            let mut line = String::new();
+            match stdin().read_line(&mut line) {
+                Ok(0) | Err(_) => return MonitorAction::Quit,
+                Ok(_) => {}
+            }
+
+            let args = line.split_whitespace().collect::<Vec<_>>();
+            let Some((&command, args)) = args.split_first() else {
+                continue;
+            };
+
+            match self.command(cpu, command, args) {
+                Ok(Some(action)) => return action,
+                Ok(None) => {}
+                Err(e) => println!("{e}"),
+            }
+        }
+    }
+
+    fn command(
+        &mut self,
+        cpu: &mut R4300i,
+        command: &str,
+        args: &[&str],
+    ) -> Result<Option<MonitorAction>, String> {
+        match command {
+            "s" | "step" | "stepi" => {
+                let n = match args.first() {
+                    Some(n) => parse_count(n)? as usize,
+                    None => 1,
+                };
+                // the caller always executes one instruction after we return
+                self.steps = Some(n.saturating_sub(1));
+                Ok(Some(MonitorAction::Resume))
+            }
+
+            "c" | "continue" => Ok(Some(MonitorAction::Resume)),
+
+            "rs" | "reverse-stepi" => {
+                let n = match args.first() {
+                    Some(n) => parse_count(n)? as usize,
+                    None => 1,
+                };
+                Ok(Some(MonitorAction::ReverseStep(n)))
+            }
+
+            "rc" | "reverse-continue" => Ok(Some(MonitorAction::ReverseContinue)),
+
+            "b" | "break" => {
+                match args.first() {
+                    Some(address) => {
+                        let address = self.resolve(cpu, address)?;
+                        self.breakpoints.insert(address);
+                        println!("breakpoint set at {}", self.describe(cpu, address));
+                    }
+                    None => {
+                        for address in &self.breakpoints {
+                            println!("{}", self.describe(cpu, *address));
+                        }
+                    }
+                }
+                Ok(None)
+            }
+
+            "d" | "delete" => {
+                let address = self.resolve(cpu, args.first().ok_or("usage: delete <addr>")?)?;
+                if !self.breakpoints.remove(&address) {
+                    println!("no breakpoint at {address:08X}");
+                }
+                Ok(None)
+            }
+
+            "sym" => {
+                let arg = args.first().ok_or("usage: sym <addr|name>")?;
+                let address = self.resolve(cpu, arg)?;
+                match self.symbols.symbolize(address, Some(cpu.get_secure_mode())) {
+                    Some(name) => println!("{address:08X} <{name}>"),
+                    None => println!("no symbol at {address:08X}"),
+                }
+                Ok(None)
+            }
+
+            "symbols" => {
+                let spec = args.first().ok_or("usage: symbols <file>")?;
+                let table = SymbolTable::load(spec).map_err(|e| format!("{spec}: {e}"))?;
+                println!("loaded {} symbols from {spec}", table.len());
+                self.symbols.add(table);
+                Ok(None)
+            }
+
+            "bt" | "backtrace" => {
+                let limit = match args.first() {
+                    Some(n) => parse_count(n)? as usize,
+                    None => usize::MAX,
+                };
+                self.print_backtrace(cpu, limit);
+                Ok(None)
+            }
+
+            "r" | "regs" => {
+                write_regs(&mut stdout(), cpu).map_err(|e| e.to_string())?;
+                Ok(None)
+            }
+
+            "cop0" => {
+                write_cop0(&mut stdout(), cpu).map_err(|e| e.to_string())?;
+                Ok(None)
+            }
+
+            "tlb" => {
+                write_tlb(&mut stdout(), cpu).map_err(|e| e.to_string())?;
+                Ok(None)
+            }
+
+            "dis" => {
+                let address = match args.first() {
+                    Some(address) => self.resolve(cpu, address)?,
+                    None => cpu.get_pc() as u32,
+                };
+                let count = match args.get(1) {
+                    Some(count) => parse_count(count)? as u32,
+                    None => 8,
+                };
+                for i in 0..count {
+                    self.print_instruction(cpu, address.wrapping_add(i * 4));
+                }
+                Ok(None)
+            }
+
+            "dump" => {
+                let [file, address, length] = args else {
+                    return Err("usage: dump <file> <addr> <len>".into());
+                };
+                let address = self.resolve(cpu, address)?;
+                let length = parse_address(length)? as u32;
+                let data = (0..length)
+                    .map(|i| cpu.peek::<u8>(address.wrapping_add(i)).unwrap_or(0))
+                    .collect::<Vec<_>>();
+                write(file, data).map_err(|e| e.to_string())?;
+                Ok(None)
+            }
+
+            "crc" => {
+                let [address, length] = args else {
+                    return Err("usage: crc <addr> <len>".into());
+                };
+                let address = self.resolve(cpu, address)?;
+                let length = parse_address(length)? as u32;
+                println!("{:08X}", crc32(&read_range(cpu, address, length)));
+                Ok(None)
+            }
+
+            "bundle" => {
+                let dir = args.first().ok_or("usage: bundle <dir>")?;
+                Ok(Some(MonitorAction::Bundle(dir.to_string())))
+            }
+
+            "mmio" => {
+                let device = args
+                    .first()
+                    .ok_or("usage: mmio <device>")?
+                    .parse::<Device>()?;
+                for (name, value) in cpu.get_device_registers(device) {
+                    println!("{name:>20}: {value:08X}");
+                }
+                Ok(None)
+            }
+
+            "mmio-log" => {
+                let log = match args.first() {
+                    Some(&"off") => None,
+                    Some(&"all") => Some(vec![]),
+                    Some(devices) => Some(devices.split(',').map(str::parse).collect::<Result<
+                        Vec<Device>,
+                        _,
+                    >>(
+                    )?),
+                    None => {
+                        return Err("usage: mmio-log <all|off|dev,...> [secure|non-secure]".into())
+                    }
+                };
+                let mode = match args.get(1) {
+                    Some(mode) => mode.parse()?,
+                    None => MmioMode::All,
+                };
+                cpu.set_mmio_log(log.map(|devices| MmioLog { devices, mode }));
+                Ok(None)
+            }
+
+            "reset" => Ok(Some(MonitorAction::Inject(Stimulus::Reset))),
+
+            "button" => {
+                let pressed = match args.first() {
+                    Some(&"press") => true,
+                    Some(&"release") => false,
+                    _ => return Err("usage: button <press|release>".into()),
+                };
+                Ok(Some(MonitorAction::Inject(Stimulus::Button(pressed))))
+            }
+
+            "card" => {
+                let present = match args.first() {
+                    Some(&"insert") => true,
+                    Some(&"remove") => false,
+                    _ => return Err("usage: card <insert|remove>".into()),
+                };
+                Ok(Some(MonitorAction::Inject(Stimulus::Card(present))))
+            }
+
+            "intr" => Ok(Some(MonitorAction::Inject(Stimulus::Interrupt))),
+
+            "usb" => {
+                let [controller, offset, hex] = args else {
+                    return Err("usage: usb <n> <offset> <hex>".into());
+                };
+                let controller = match *controller {
+                    "0" => 0,
+                    "1" => 1,
+                    _ => return Err(format!("no usb controller {controller}")),
+                };
+                let offset = parse_address(offset)? as usize;
+                let data = parse_hex(hex)?;
+                Ok(Some(MonitorAction::Inject(Stimulus::Usb(
+                    controller, offset, data,
+                ))))
+            }
+
+            "q" | "quit" => Ok(Some(MonitorAction::Quit)),
+
+            "h" | "help" => {
+                println!("{}", Self::HELP);
+                Ok(None)
+            }
+
+            _ if command == "x" || command.starts_with("x/") => {
+                let (count, size) = parse_examine(command.strip_prefix("x/").unwrap_or(""))?;
+                let address =
+                    self.resolve(cpu, args.first().ok_or("usage: x/<n><b|h|w|d> <addr>")?)?;
+                self.examine(cpu, count, size, address);
+                Ok(None)
+            }
+
+            _ => Err(format!("unknown command: {command} (try \"help\")")),
+        }
+    }
+
+    fn examine(&self, cpu: &mut R4300i, count: u32, size: char, address: u32) {
+        let (width, per_line) = match size {
+            'b' => (1, 16),
+            'h' => (2, 8),
+            'w' => (4, 4),
+            _ => (8, 2),
+        };
+
+        for i in 0..count {
+            let address = address.wrapping_add(i * width);
+            if i % per_line == 0 {
+                if i != 0 {
+                    println!();
+                }
+                print!("{address:08X}:");
+            }
+            let value = match size {
+                'b' => cpu.peek::<u8>(address).map(|v| format!("{v:02X}")),
+                'h' => cpu.peek::<u16>(address).map(|v| format!("{v:04X}")),
+                'w' => cpu.peek::<u32>(address).map(|v| format!("{v:08X}")),
+                _ => cpu.peek::<u64>(address).map(|v| format!("{v:016X}")),
+            };
+            print!(
+                " {}",
+                value.unwrap_or_else(|| "?".repeat(width as usize * 2))
+            );
+        }
+        println!();
+    }
+
+    // symbol names are looked up in the tables for the current mode first
+    fn resolve(&self, cpu: &R4300i, s: &str) -> Result<u32, String> {
+        self.symbols.resolve(s, Some(cpu.get_secure_mode()))
+    }
+
+    // `80005780 <osStopThread>`, or just the address without a symbol
+    fn describe(&self, cpu: &R4300i, address: u32) -> String {
+        match self.symbols.symbolize(address, Some(cpu.get_secure_mode())) {
+            Some(name) => format!("{address:08X} <{name}>"),
+            None => format!("{address:08X}"),
+        }
+    }
+
+    // one line per frame, innermost first, with the exceptions between them; code
+    // below a secure trap ran in non-secure mode, which matters for symbol lookup
+    pub fn print_backtrace(&self, cpu: &R4300i, limit: usize) {
+        self.write_backtrace(&mut stdout(), cpu, limit).unwrap();
+    }
+
+    pub fn write_backtrace<W: Write>(
+        &self,
+        out: &mut W,
+        cpu: &R4300i,
+        limit: usize,
+    ) -> io::Result<()> {
+        let frames = cpu.backtrace();
+        let mut secure = cpu.get_secure_mode();
+        let mut location = cpu.get_pc() as u32;
+
+        for index in 0..=frames.len() {
+            if index == limit {
+                writeln!(out, "    ({} more frames)", frames.len() + 1 - index)?;
+                break;
+            }
+
+            let frame = frames.get(index);
+            let name = self.symbols.symbolize(location, Some(secure)).or_else(|| {
+                let function = frame?.to;
+                (location >= function)
+                    .then(|| format!("{function:08X}+0x{:X}", location - function))
+            });
+            match name {
+                Some(name) => writeln!(out, "#{index:<3} {location:08X} <{name}>")?,
+                None => writeln!(out, "#{index:<3} {location:08X}")?,
+            }
+
+            let Some(frame) = frame else {
+                break;
+            };
+            if let FrameKind::Exception(exception) = frame.kind {
+                writeln!(out, "    -- {exception:?} exception --")?;
+                if exception == ExceptionType::Trap {
+                    secure = false;
+                }
+            }
+            location = frame.from;
+        }
+
+        Ok(())
+    }
+
+    fn print_instruction(&self, cpu: &mut R4300i, address: u32) {
+        if let Some(label) = self.symbols.label(address, Some(cpu.get_secure_mode())) {
+            println!("{label}:");
+        }
+        match cpu.peek::<u32>(address) {
+            Some(opcode) => println!(
+                "{address:08X}: {opcode:08X}  {}",
+                Instruction::new(opcode).disassemble(address)
+            ),
+            None => println!("{address:08X}: ????????"),
+        }
+    }
+
+    fn print_current(&self, cpu: &mut R4300i) {
+        let pc = cpu.get_pc() as u32;
+        if let Some((name, offset)) = self.symbols.lookup(pc, Some(cpu.get_secure_mode())) {
+            if offset != 0 {
+                println!("in {name}+0x{offset:X}");
+            }
+        }
+        self.print_instruction(cpu, pc);
+    }
+}
+
+pub fn write_regs<W: Write>(out: &mut W, cpu: &R4300i) -> io::Result<()> {
+    for i in 0..32 {
+        write!(
+            out,
+            "{:>4}: {:016X}",
+            format!("{:?}", Register::from(i)).to_lowercase(),
+            cpu.get_reg(i)
+        )?;
+        if i % 4 == 3 {
+            writeln!(out)?;
+        } else {
+            write!(out, "  ")?;
+        }
+    }
+    writeln!(
+        out,
+        "  pc: {:016X}    hi: {:016X}    lo: {:016X}",
+        cpu.get_pc(),
+        cpu.get_hi(),
+        cpu.get_lo()
+    )
+}
+
+pub fn write_cop0<W: Write>(out: &mut W, cpu: &R4300i) -> io::Result<()> {
+    for i in 0..32 {
+        write!(
+            out,
+            "{:>8}: {:08X}",
+            format!("{:?}", cop0::Register::from(i)),
+            cpu.get_cop0_reg(i)
+        )?;
+        if i % 4 == 3 {
+            writeln!(out)?;
+        } else {
+            write!(out, "  ")?;
+        }
+    }
+    Ok(())
+}
+
+pub fn write_tlb<W: Write>(out: &mut W, cpu: &R4300i) -> io::Result<()> {
+    for (index, entry) in cpu.get_tlb().iter().enumerate() {
+        writeln!(
+            out,
+            "{index:02}: mask {:08X} hi {:08X} lo0 {:08X} lo1 {:08X}",
+            u32::from(entry.page_mask()),
+            u32::from(entry.entry_hi()),
+            u32::from(entry.entry_lo_0()),
+            u32::from(entry.entry_lo_1())
+        )?;
+    }
+    Ok(())
+}
+
+// addresses are hex by default, counts are decimal by default
+pub fn parse_address(s: &str) -> Result<u64, String> {
+    let hex = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    u64::from_str_radix(hex, 16).map_err(|_| format!("invalid address: {s}"))
+}
+
+pub fn parse_count(s: &str) -> Result<u64, String> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("invalid count: {s}"))
+}
+
+// the `<n><b|h|w|d>` after `x/`, each part optional: one word by default
+fn parse_examine(format: &str) -> Result<(u32, char), String> {
+    let (count, size) = match format.chars().last() {
+        Some(c @ ('b' | 'h' | 'w' | 'd')) => (&format[..format.len() - 1], c),
+        _ => (format, 'w'),
+    };
+    let count = if count.is_empty() {
+        1
+    } else {
+        parse_count(count)? as u32
+    };
+    Ok((count, size))
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    if !s.len().is_multiple_of(2) {
+        return Err(format!("odd number of hex digits: {s}"));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or_else(|| format!("invalid hex: {s}"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use r4300i_rs::cop0::nand::{NandDevice, NandImage};
+    use r4300i_rs::cop0::{NAND_BLOCK_SIZE, NAND_SPARE_SIZE};
+
+    use super::*;
+
+    fn machine() -> R4300i {
+        let device: NandDevice = "64m".parse().unwrap();
+        let blocks = device.size() / NAND_BLOCK_SIZE;
+        let nand = NandImage::split(
+            vec![0xFF; device.size()],
+            vec![0xFF; blocks * NAND_SPARE_SIZE],
+            Some(device),
+        )
+        .unwrap();
+        R4300i::new(vec![], vec![], vec![], vec![], nand)
+    }
+
+    #[test]
+    fn addresses_are_hex() {
+        assert_eq!(parse_address("80000100"), Ok(0x80000100));
+        assert_eq!(parse_address("0x80000100"), Ok(0x80000100));
+        assert_eq!(parse_address("0XbfC00000"), Ok(0xBFC00000));
+        assert!(parse_address("").is_err());
+        assert!(parse_address("0x").is_err());
+        assert!(parse_address("osStopThread").is_err());
+    }
+
+    #[test]
+    fn counts_are_decimal() {
+        assert_eq!(parse_count("10"), Ok(10));
+        assert_eq!(parse_count("0x10"), Ok(0x10));
+        assert!(parse_count("").is_err());
+        assert!(parse_count("1f").is_err());
+        assert!(parse_count("-1").is_err());
+    }
+
+    #[test]
+    fn examine_formats() {
+        assert_eq!(parse_examine(""), Ok((1, 'w')));
+        assert_eq!(parse_examine("b"), Ok((1, 'b')));
+        assert_eq!(parse_examine("16"), Ok((16, 'w')));
+        assert_eq!(parse_examine("4h"), Ok((4, 'h')));
+        assert_eq!(parse_examine("0x10d"), Ok((16, 'd')));
+        assert!(parse_examine("4q").is_err());
+        assert!(parse_examine("w4").is_err());
+    }
+
+    #[test]
+    fn examine_needs_a_slash() {
+        let mut cpu = machine();
+        let mut monitor = Monitor::new();
+        assert_eq!(monitor.command(&mut cpu, "x", &["80000000"]), Ok(None));
+        assert_eq!(monitor.command(&mut cpu, "x/4b", &["80000000"]), Ok(None));
+        assert!(monitor.command(&mut cpu, "xyz", &["80000000"]).is_err());
+        assert!(monitor.command(&mut cpu, "x4", &["80000000"]).is_err());
+        assert!(monitor.command(&mut cpu, "x/4q", &["80000000"]).is_err());
+        assert!(monitor.command(&mut cpu, "x", &[]).is_err());
+    }
+
+    #[test]
+    fn breakpoints_are_added_and_deleted() {
+        let mut cpu = machine();
+        let mut monitor = Monitor::new();
+        assert_eq!(monitor.command(&mut cpu, "break", &["80000100"]), Ok(None));
+        assert_eq!(monitor.command(&mut cpu, "b", &["0x80000200"]), Ok(None));
+        assert!(monitor.is_breakpoint(0x80000100));
+        assert!(monitor.is_breakpoint(0x80000200));
+
+        assert_eq!(monitor.command(&mut cpu, "delete", &["80000100"]), Ok(None));
+        assert!(!monitor.is_breakpoint(0x80000100));
+        assert!(monitor.is_breakpoint(0x80000200));
+
+        // deleting one that isn't there only says so
+        assert_eq!(monitor.command(&mut cpu, "d", &["80000100"]), Ok(None));
+        assert!(monitor.command(&mut cpu, "delete", &[]).is_err());
+        assert!(monitor.command(&mut cpu, "break", &["nowhere"]).is_err());
+        assert!(!monitor.is_breakpoint(0x80000100));
+    }
+}
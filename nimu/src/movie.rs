@@ -0,0 +1,261 @@
+use r4300i_rs::stimulus::Stimulus;
+use thiserror::Error;
+
+use crate::reader::{Reader, Truncated};
+
+pub const MAGIC: [u8; 8] = *b"NIMUMOVI";
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Error)]
+pub enum MovieError {
+    #[error("not a movie file")]
+    BadMagic,
+    #[error("unsupported movie version {0} (expected {VERSION})")]
+    Version(u32),
+    #[error("movie is truncated")]
+    Truncated,
+    #[error("unknown stimulus kind {0}")]
+    Kind(u8),
+    #[error("usb controller {0} does not exist")]
+    Controller(usize),
+    #[error("movie events are out of order")]
+    Order,
+}
+
+// every external stimulus along with the instruction count it arrived at; since
+// nothing else feeds into the emulator, replaying these reproduces a run exactly
+#[derive(Debug, Default)]
+pub struct Movie {
+    pub seed: u64,
+    events: Vec<(u64, Stimulus)>,
+    next: usize,
+}
+
+impl Movie {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            events: vec![],
+            next: 0,
+        }
+    }
+
+    // the next stimulus due at this instruction, if any
+    pub fn pending(&mut self, instructions: u64) -> Option<Stimulus> {
+        match self.events.get(self.next) {
+            Some((count, stimulus)) if *count <= instructions => {
+                self.next += 1;
+                Some(stimulus.clone())
+            }
+            _ => None,
+        }
+    }
+
+    // a new stimulus replaces whatever the old timeline had from here on
+    pub fn record(&mut self, instructions: u64, stimulus: Stimulus) {
+        self.events.truncate(self.next);
+        self.events.push((instructions, stimulus));
+        self.next = self.events.len();
+    }
+
+    // after rewinding, events from the checkpoint onwards are due again
+    pub fn seek(&mut self, instructions: u64) {
+        self.next = self
+            .events
+            .partition_point(|(count, _)| *count < instructions);
+    }
+
+    pub fn save(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(&self.seed.to_le_bytes());
+        out.extend_from_slice(&(self.events.len() as u64).to_le_bytes());
+
+        for (count, stimulus) in &self.events {
+            out.extend_from_slice(&count.to_le_bytes());
+            match stimulus {
+                Stimulus::Reset => out.push(0),
+                Stimulus::Button(pressed) => out.extend_from_slice(&[1, *pressed as u8]),
+                Stimulus::Card(present) => out.extend_from_slice(&[2, *present as u8]),
+                Stimulus::Interrupt => out.push(3),
+                Stimulus::Usb(controller, offset, data) => {
+                    out.extend_from_slice(&[4, *controller as u8]);
+                    out.extend_from_slice(&(*offset as u32).to_le_bytes());
+                    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                    out.extend_from_slice(data);
+                }
+            }
+        }
+
+        out
+    }
+
+    pub fn load(data: &[u8]) -> Result<Self, MovieError> {
+        let mut reader = Reader::new(data);
+
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(MovieError::BadMagic);
+        }
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(MovieError::Version(version));
+        }
+
+        let mut movie = Self::new(reader.u64()?);
+        let len = reader.u64()?;
+
+        for _ in 0..len {
+            let count = reader.u64()?;
+            let stimulus = match reader.u8()? {
+                0 => Stimulus::Reset,
+                1 => Stimulus::Button(reader.u8()? != 0),
+                2 => Stimulus::Card(reader.u8()? != 0),
+                3 => Stimulus::Interrupt,
+                4 => {
+                    let controller = reader.u8()? as usize;
+                    if controller > 1 {
+                        return Err(MovieError::Controller(controller));
+                    }
+                    let offset = reader.u32()? as usize;
+                    let len = reader.u32()? as usize;
+                    Stimulus::Usb(controller, offset, reader.take(len)?.to_vec())
+                }
+                kind => return Err(MovieError::Kind(kind)),
+            };
+
+            if movie.events.last().is_some_and(|(last, _)| *last > count) {
+                return Err(MovieError::Order);
+            }
+            movie.events.push((count, stimulus));
+        }
+
+        Ok(movie)
+    }
+}
+
+impl From<Truncated> for MovieError {
+    fn from(_: Truncated) -> Self {
+        Self::Truncated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use r4300i_rs::cop0::nand::{NandDevice, NandImage};
+    use r4300i_rs::cop0::{NAND_BLOCK_SIZE, NAND_SPARE_SIZE};
+    use r4300i_rs::R4300i;
+
+    use super::*;
+
+    fn stimuli() -> Vec<(u64, Stimulus)> {
+        vec![
+            (3, Stimulus::Button(true)),
+            (3, Stimulus::Interrupt),
+            (10, Stimulus::Usb(1, 0x20, vec![1, 2, 3])),
+            (15, Stimulus::Card(false)),
+            (20, Stimulus::Card(true)),
+            (40, Stimulus::Reset),
+        ]
+    }
+
+    fn machine(seed: u64) -> R4300i {
+        let device: NandDevice = "64m".parse().unwrap();
+        let blocks = device.size() / NAND_BLOCK_SIZE;
+        let nand = NandImage::split(
+            vec![0xFF; device.size()],
+            vec![0xFF; blocks * NAND_SPARE_SIZE],
+            Some(device),
+        )
+        .unwrap();
+        let mut cpu = R4300i::new(vec![], vec![], vec![], vec![], nand);
+        cpu.seed_random(seed);
+        cpu.start();
+        cpu
+    }
+
+    #[test]
+    fn save_load_round_trips() {
+        let mut movie = Movie::new(0x1234);
+        for (count, stimulus) in stimuli() {
+            movie.record(count, stimulus);
+        }
+
+        let loaded = Movie::load(&movie.save()).unwrap();
+        assert_eq!(loaded.seed, 0x1234);
+        assert_eq!(loaded.events, stimuli());
+        assert_eq!(loaded.next, 0);
+    }
+
+    #[test]
+    fn pending_and_seek() {
+        let mut movie = Movie::load(&{
+            let mut movie = Movie::new(0);
+            for (count, stimulus) in stimuli() {
+                movie.record(count, stimulus);
+            }
+            movie.save()
+        })
+        .unwrap();
+
+        assert_eq!(movie.pending(2), None);
+        assert_eq!(movie.pending(3), Some(Stimulus::Button(true)));
+        assert_eq!(movie.pending(3), Some(Stimulus::Interrupt));
+        assert_eq!(movie.pending(3), None);
+
+        movie.seek(3);
+        assert_eq!(movie.pending(3), Some(Stimulus::Button(true)));
+
+        // recording after a seek drops the rest of the old timeline
+        movie.seek(10);
+        movie.record(12, Stimulus::Interrupt);
+        assert_eq!(movie.events.len(), 3);
+        assert_eq!(movie.pending(100), None);
+    }
+
+    #[test]
+    fn bad_movies() {
+        let mut data = Movie::new(0).save();
+        assert!(matches!(
+            Movie::load(&data[..10]),
+            Err(MovieError::Truncated)
+        ));
+        data[0] = b'X';
+        assert!(matches!(Movie::load(&data), Err(MovieError::BadMagic)));
+
+        let mut movie = Movie::new(0);
+        movie.record(5, Stimulus::Usb(2, 0, vec![]));
+        assert!(matches!(
+            Movie::load(&movie.save()),
+            Err(MovieError::Controller(2))
+        ));
+
+        let mut movie = Movie::new(0);
+        movie.events = vec![(5, Stimulus::Reset), (4, Stimulus::Reset)];
+        assert!(matches!(Movie::load(&movie.save()), Err(MovieError::Order)));
+    }
+
+    #[test]
+    fn replay_is_deterministic() {
+        let mut stimuli = stimuli().into_iter().peekable();
+        let mut recorded = Movie::new(7);
+        let mut cpu = machine(7);
+        for instructions in 0..50 {
+            while let Some((_, stimulus)) = stimuli.next_if(|(count, _)| *count == instructions) {
+                cpu.apply(&stimulus);
+                recorded.record(instructions, stimulus);
+            }
+            cpu.step();
+        }
+
+        let mut movie = Movie::load(&recorded.save()).unwrap();
+        let mut replayed = machine(movie.seed);
+        for instructions in 0..50 {
+            while let Some(stimulus) = movie.pending(instructions) {
+                replayed.apply(&stimulus);
+            }
+            replayed.step();
+        }
+
+        assert_eq!(replayed.save_state(), cpu.save_state());
+    }
+}
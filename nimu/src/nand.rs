@@ -0,0 +1,322 @@
+use std::collections::BTreeMap;
+use std::fs::{read, rename, write, File, OpenOptions};
+use std::io::{self, ErrorKind, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use r4300i_rs::cop0::nand::{NandImage, COMBINED_BLOCK_SIZE};
+use r4300i_rs::cop0::{NAND_BLOCK_SIZE, NAND_SPARE_SIZE};
+use r4300i_rs::R4300i;
+use thiserror::Error;
+
+use crate::reader::{Reader, Truncated};
+
+pub const MAGIC: [u8; 8] = *b"NIMUNAND";
+pub const VERSION: u32 = 1;
+
+// the magic, version and record count, then each record's block number, data
+// and spare area
+const HEADER_SIZE: u64 = MAGIC.len() as u64 + 8;
+const RECORD_SIZE: u64 = 4 + (NAND_BLOCK_SIZE + NAND_SPARE_SIZE) as u64;
+
+#[derive(Debug, Error)]
+pub enum NandError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("not a nand overlay file")]
+    BadMagic,
+    #[error("unsupported nand overlay version {0} (expected {VERSION})")]
+    Version(u32),
+    #[error("nand overlay is truncated")]
+    Truncated,
+    #[error("nand overlay has block {0}, which is past the end of the card")]
+    Block(u32),
+}
+
+// where blocks the guest programs or erases end up; volatile keeps them in
+// memory only, write-through updates the image files (just the one for a
+// combined image), and an overlay keeps them in a separate file so the images
+// stay pristine. a block already in the overlay is rewritten in place and a
+// new one is appended, so a flush costs the blocks it writes
+#[derive(Debug, Default)]
+pub enum NandStore {
+    #[default]
+    Volatile,
+    WriteThrough {
+        nand: File,
+        spare: Option<File>,
+    },
+    Overlay {
+        file: File,
+        // where each block's record starts
+        records: BTreeMap<u32, u64>,
+    },
+}
+
+impl NandStore {
+    // a combined image has no spare file
+    pub fn write_through(nand: &Path, spare: Option<&Path>) -> io::Result<Self> {
+        let open = |path| OpenOptions::new().write(true).open(path);
+        Ok(Self::WriteThrough {
+            nand: open(nand)?,
+            spare: spare.map(open).transpose()?,
+        })
+    }
+
+    // applies an existing overlay to the image, or starts an empty one. the
+    // file is compacted on the way, which drops anything a crash left after
+    // the last complete record
+    pub fn overlay(path: &Path, image: &mut NandImage) -> Result<Self, NandError> {
+        let blocks = match read(path) {
+            Ok(data) => load(&data)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        for (block, contents) in &blocks {
+            let (data, spare) = image
+                .block_mut(*block as usize)
+                .ok_or(NandError::Block(*block))?;
+            let (new_data, new_spare) = contents.split_at(NAND_BLOCK_SIZE);
+            data.copy_from_slice(new_data);
+            spare.copy_from_slice(new_spare);
+        }
+
+        // a crash mid-write leaves the old file rather than half a new one
+        let temp = path.with_extension("tmp");
+        write(&temp, save(&blocks))?;
+        rename(temp, path)?;
+
+        Ok(Self::Overlay {
+            file: OpenOptions::new().write(true).open(path)?,
+            records: blocks
+                .keys()
+                .enumerate()
+                .map(|(i, block)| (*block, HEADER_SIZE + i as u64 * RECORD_SIZE))
+                .collect(),
+        })
+    }
+
+    // writes back the given blocks, the ones the machine says it has changed
+    // since the last flush
+    pub fn flush(&mut self, cpu: &R4300i, dirty: &[usize]) -> io::Result<()> {
+        if dirty.is_empty() {
+            return Ok(());
+        }
+
+        match self {
+            Self::Volatile => {}
+            Self::WriteThrough {
+                nand,
+                spare: Some(spare),
+            } => {
+                for &block in dirty {
+                    let (data, spare_data) = cpu.get_nand_block(block);
+                    nand.seek(SeekFrom::Start((block * NAND_BLOCK_SIZE) as u64))?;
+                    nand.write_all(data)?;
+                    spare.seek(SeekFrom::Start((block * NAND_SPARE_SIZE) as u64))?;
+                    spare.write_all(spare_data)?;
+                }
+                nand.flush()?;
+                spare.flush()?;
+            }
+            Self::WriteThrough { nand, spare: None } => {
+                for &block in dirty {
+                    let (data, spare_data) = cpu.get_nand_block(block);
+                    nand.seek(SeekFrom::Start((block * COMBINED_BLOCK_SIZE) as u64))?;
+                    nand.write_all(data)?;
+                    nand.write_all(spare_data)?;
+                }
+                nand.flush()?;
+            }
+            Self::Overlay { file, records } => {
+                for &block in dirty {
+                    let (data, spare_data) = cpu.get_nand_block(block);
+                    let number = block as u32;
+                    let end = HEADER_SIZE + records.len() as u64 * RECORD_SIZE;
+                    let new = !records.contains_key(&number);
+                    let offset = *records.entry(number).or_insert(end);
+                    file.seek(SeekFrom::Start(offset))?;
+                    file.write_all(&number.to_le_bytes())?;
+                    file.write_all(data)?;
+                    file.write_all(spare_data)?;
+                    // the count goes up once the record is all there
+                    if new {
+                        file.seek(SeekFrom::Start(HEADER_SIZE - 4))?;
+                        file.write_all(&(records.len() as u32).to_le_bytes())?;
+                    }
+                }
+                file.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn save(blocks: &BTreeMap<u32, Vec<u8>>) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&(blocks.len() as u32).to_le_bytes());
+    for (block, contents) in blocks {
+        out.extend_from_slice(&block.to_le_bytes());
+        out.extend_from_slice(contents);
+    }
+    out
+}
+
+pub(crate) fn load(data: &[u8]) -> Result<BTreeMap<u32, Vec<u8>>, NandError> {
+    let mut reader = Reader::new(data);
+
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(NandError::BadMagic);
+    }
+    let version = reader.u32()?;
+    if version != VERSION {
+        return Err(NandError::Version(version));
+    }
+
+    let mut blocks = BTreeMap::new();
+    for _ in 0..reader.u32()? {
+        let block = reader.u32()?;
+        let contents = reader.take(NAND_BLOCK_SIZE + NAND_SPARE_SIZE)?;
+        blocks.insert(block, contents.to_vec());
+    }
+    Ok(blocks)
+}
+
+impl From<Truncated> for NandError {
+    fn from(_: Truncated) -> Self {
+        Self::Truncated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::{metadata, remove_file};
+    use std::process;
+
+    use r4300i_rs::cop0::nand::NandDevice;
+
+    use super::*;
+
+    fn blank() -> NandImage {
+        let device: NandDevice = "64m".parse().unwrap();
+        let blocks = device.size() / NAND_BLOCK_SIZE;
+        NandImage::split(
+            vec![0; device.size()],
+            vec![0; blocks * NAND_SPARE_SIZE],
+            Some(device),
+        )
+        .unwrap()
+    }
+
+    fn flush(store: &mut NandStore, cpu: &mut R4300i) {
+        let dirty = cpu.take_dirty_nand_blocks();
+        store.flush(cpu, &dirty).unwrap();
+    }
+
+    // erases a block through the pi's flash registers
+    fn erase(cpu: &mut R4300i, block: u32) {
+        cpu.write::<u32>(0xA4600070, block * NAND_BLOCK_SIZE as u32);
+        cpu.write::<u32>(0xA4600048, 0x80600400);
+        cpu.write::<u32>(0xA4600048, 0x80D00000);
+    }
+
+    #[test]
+    fn overlays_write_back_and_reload() {
+        let path = temp_dir().join(format!("nimu-overlay-{}.nand", process::id()));
+        let _ = remove_file(&path);
+
+        let mut image = blank();
+        let mut store = NandStore::overlay(&path, &mut image).unwrap();
+        let mut cpu = R4300i::new(vec![], vec![], vec![], vec![], image);
+        erase(&mut cpu, 7);
+        flush(&mut store, &mut cpu);
+        erase(&mut cpu, 2);
+        flush(&mut store, &mut cpu);
+        assert!(!path.with_extension("tmp").exists());
+        let len = HEADER_SIZE + 2 * RECORD_SIZE;
+        assert_eq!(metadata(&path).unwrap().len(), len);
+
+        // a block that's already there is rewritten in place
+        erase(&mut cpu, 7);
+        flush(&mut store, &mut cpu);
+        assert_eq!(metadata(&path).unwrap().len(), len);
+
+        let blocks = load(&read(&path).unwrap()).unwrap();
+        assert_eq!(blocks.keys().copied().collect::<Vec<_>>(), [2, 7]);
+
+        // applied to a fresh image, only those blocks change
+        let mut image = blank();
+        NandStore::overlay(&path, &mut image).unwrap();
+        for block in 0..8 {
+            let (data, spare) = image.block_mut(block).unwrap();
+            let erased = block == 2 || block == 7;
+            assert_eq!(data.iter().all(|byte| *byte == 0xFF), erased);
+            assert_eq!(spare.iter().all(|byte| *byte == 0xFF), erased);
+        }
+
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn overlays_are_compacted_on_open() {
+        let path = temp_dir().join(format!("nimu-overlay-crash-{}.nand", process::id()));
+        let _ = remove_file(&path);
+
+        // a record appended before its count went up, as a crash would leave it
+        let mut data = save(&BTreeMap::from([(
+            4,
+            vec![0; NAND_BLOCK_SIZE + NAND_SPARE_SIZE],
+        )]));
+        data.extend_from_slice(&[5, 0, 0, 0, 0xFF, 0xFF]);
+        write(&path, &data).unwrap();
+
+        let mut image = blank();
+        let mut store = NandStore::overlay(&path, &mut image).unwrap();
+        assert_eq!(metadata(&path).unwrap().len(), HEADER_SIZE + RECORD_SIZE);
+
+        // so the next record goes where the partial one was
+        let mut cpu = R4300i::new(vec![], vec![], vec![], vec![], image);
+        erase(&mut cpu, 5);
+        flush(&mut store, &mut cpu);
+        let blocks = load(&read(&path).unwrap()).unwrap();
+        assert_eq!(blocks.keys().copied().collect::<Vec<_>>(), [4, 5]);
+        assert!(blocks[&5].iter().all(|byte| *byte == 0xFF));
+
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bad_overlays() {
+        let mut data = save(&BTreeMap::from([(
+            1,
+            vec![0; NAND_BLOCK_SIZE + NAND_SPARE_SIZE],
+        )]));
+        assert!(matches!(
+            load(&data[..data.len() - 1]),
+            Err(NandError::Truncated)
+        ));
+
+        data[8] = 2;
+        assert!(matches!(load(&data), Err(NandError::Version(2))));
+        data[0] = b'X';
+        assert!(matches!(load(&data), Err(NandError::BadMagic)));
+
+        let path = temp_dir().join(format!("nimu-bad-overlay-{}.nand", process::id()));
+        let block = 0x10000;
+        write(
+            &path,
+            save(&BTreeMap::from([(
+                block,
+                vec![0; NAND_BLOCK_SIZE + NAND_SPARE_SIZE],
+            )])),
+        )
+        .unwrap();
+        assert!(matches!(
+            NandStore::overlay(&path, &mut blank()),
+            Err(NandError::Block(0x10000))
+        ));
+        remove_file(&path).unwrap();
+    }
+}
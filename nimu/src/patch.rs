@@ -0,0 +1,383 @@
+use std::fmt;
+use std::str::FromStr;
+
+use r4300i_rs::R4300i;
+
+use crate::hooks::read_range;
+
+// which part of the boot a step belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Bootrom,
+    Sk,
+    App,
+}
+
+impl Stage {
+    pub fn of(cpu: &R4300i) -> Self {
+        if cpu.get_mi_mapping() {
+            Self::Bootrom
+        } else if cpu.get_secure_mode() {
+            Self::Sk
+        } else {
+            Self::App
+        }
+    }
+}
+
+impl FromStr for Stage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bootrom" => Ok(Self::Bootrom),
+            "sk" => Ok(Self::Sk),
+            "app" => Ok(Self::App),
+            _ => Err(format!("unknown stage: {s} (expected bootrom, sk or app)")),
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bootrom => write!(f, "bootrom"),
+            Self::Sk => write!(f, "sk"),
+            Self::App => write!(f, "app"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    // the pc reaches an address
+    At(u32),
+    // the original words show up at the target
+    Loaded,
+    // the machine enters a boot stage
+    Stage(Stage),
+}
+
+// a crc32 of the code around the target, to tell versions apart when the
+// original words alone aren't enough
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Check {
+    pub address: u32,
+    pub length: u32,
+    pub crc32: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Patch {
+    pub name: String,
+    pub address: u32,
+    pub original: Option<Vec<u32>>,
+    pub value: Vec<u32>,
+    pub check: Option<Check>,
+    pub trigger: Trigger,
+}
+
+impl Patch {
+    fn read(&self, cpu: &mut R4300i) -> Vec<Option<u32>> {
+        (0..self.value.len() as u32)
+            .map(|i| cpu.peek::<u32>(self.address.wrapping_add(i * 4)))
+            .collect()
+    }
+
+    fn is(words: &[Option<u32>], expected: &[u32]) -> bool {
+        words.iter().copied().eq(expected.iter().copied().map(Some))
+    }
+
+    fn is_loaded(&self, cpu: &mut R4300i) -> bool {
+        let original = self.original.as_deref().unwrap_or_default();
+        Self::is(&self.read(cpu), original)
+    }
+
+    // returns whether the patch is in place afterwards; `report` prints why
+    // it was or wasn't applied
+    fn apply(&self, cpu: &mut R4300i, report: bool) -> bool {
+        let current = self.read(cpu);
+        if Self::is(&current, &self.value) {
+            return true;
+        }
+
+        if let Some(original) = &self.original {
+            if !Self::is(&current, original) {
+                if !report {
+                    return false;
+                }
+                println!(
+                    "patch {}: found {} at {:08X}, not the original words; not applied",
+                    self.name,
+                    Self::format(&current),
+                    self.address
+                );
+                return false;
+            }
+        }
+
+        if let Some(check) = &self.check {
+            let crc = crc32(&read_range(cpu, check.address, check.length));
+            if crc != check.crc32 {
+                if !report {
+                    return false;
+                }
+                println!(
+                    "patch {}: crc32 of {:08X}+{:X} is {crc:08X}, expected {:08X}; not applied",
+                    self.name, check.address, check.length, check.crc32
+                );
+                return false;
+            }
+        }
+
+        for (i, word) in self.value.iter().enumerate() {
+            cpu.write::<u32>(self.address.wrapping_add(i as u32 * 4), *word);
+        }
+        if report {
+            println!("applied patch {} at {:08X}", self.name, self.address);
+            // so the words can be copied into the config
+            if self.original.is_none() && self.check.is_none() {
+                println!(
+                    "patch {}: replaced {} without checking them; set them as `original` to check",
+                    self.name,
+                    Self::format(&current)
+                );
+            }
+        }
+        true
+    }
+
+    fn format(words: &[Option<u32>]) -> String {
+        words
+            .iter()
+            .map(|word| word.map_or("????????".into(), |word| format!("{word:08X}")))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Patches {
+    // a loaded patch that fails its check isn't retried until the original
+    // words go away and come back
+    patches: Vec<(Patch, bool)>,
+    stage: Option<Stage>,
+}
+
+impl Patches {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, patch: Patch) {
+        self.patches.push((patch, false));
+    }
+
+    // applies the patches triggered by the current step
+    pub fn run(&mut self, cpu: &mut R4300i) {
+        self.apply_triggered(cpu, true);
+    }
+
+    // the same, without printing anything, for replaying steps already run
+    pub fn replay(&mut self, cpu: &mut R4300i) {
+        self.apply_triggered(cpu, false);
+    }
+
+    fn apply_triggered(&mut self, cpu: &mut R4300i, report: bool) {
+        if self.patches.is_empty() {
+            return;
+        }
+
+        let stage = Stage::of(cpu);
+        let entered = self.stage.replace(stage) != Some(stage);
+        let pc = cpu.get_pc() as u32;
+
+        for (patch, rejected) in &mut self.patches {
+            match patch.trigger {
+                Trigger::At(address) if address == pc => {
+                    patch.apply(cpu, report);
+                }
+                Trigger::Stage(target) if entered && target == stage => {
+                    patch.apply(cpu, report);
+                }
+                Trigger::Loaded => {
+                    if !patch.is_loaded(cpu) {
+                        *rejected = false;
+                    } else if !*rejected {
+                        *rejected = !patch.apply(cpu, report);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+// the usual (zlib/ethernet) crc32
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use r4300i_rs::cop0::nand::{NandDevice, NandImage};
+    use r4300i_rs::cop0::{NAND_BLOCK_SIZE, NAND_SPARE_SIZE};
+
+    use super::*;
+
+    const TARGET: u32 = 0x80000100;
+    const CODE: [u32; 4] = [0x27BDFFE8, 0xAFBF0014, 0x0C000800, 0x00000000];
+
+    fn machine() -> R4300i {
+        let device: NandDevice = "64m".parse().unwrap();
+        let blocks = device.size() / NAND_BLOCK_SIZE;
+        let nand = NandImage::split(
+            vec![0xFF; device.size()],
+            vec![0xFF; blocks * NAND_SPARE_SIZE],
+            Some(device),
+        )
+        .unwrap();
+        R4300i::new(vec![], vec![], vec![], vec![], nand)
+    }
+
+    fn load(cpu: &mut R4300i, code: &[u32]) {
+        for (i, word) in code.iter().enumerate() {
+            cpu.write::<u32>(TARGET + i as u32 * 4, *word);
+        }
+    }
+
+    fn word(cpu: &mut R4300i) -> u32 {
+        cpu.peek::<u32>(TARGET + 8).unwrap()
+    }
+
+    fn patch(trigger: Trigger) -> Patch {
+        Patch {
+            name: "test".into(),
+            address: TARGET + 8,
+            original: Some(vec![0x0C000800]),
+            value: vec![0x00000000],
+            check: None,
+            trigger,
+        }
+    }
+
+    fn patches(patch: Patch) -> Patches {
+        let mut patches = Patches::new();
+        patches.add(patch);
+        patches
+    }
+
+    fn code_bytes(code: &[u32]) -> Vec<u8> {
+        code.iter().flat_map(|word| word.to_be_bytes()).collect()
+    }
+
+    #[test]
+    fn original_mismatch() {
+        let mut cpu = machine();
+        load(&mut cpu, &[0, 0, 0x0C000900]);
+        let mut patches = patches(patch(Trigger::At(0xBFC00000)));
+        patches.run(&mut cpu);
+        assert_eq!(word(&mut cpu), 0x0C000900);
+
+        load(&mut cpu, &CODE);
+        patches.run(&mut cpu);
+        assert_eq!(word(&mut cpu), 0);
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        let check = |crc32| Check {
+            address: TARGET,
+            length: 16,
+            crc32,
+        };
+        let mut cpu = machine();
+        load(&mut cpu, &CODE);
+
+        let mut wrong = patch(Trigger::At(0xBFC00000));
+        wrong.check = Some(check(crc32(&code_bytes(&CODE)) ^ 1));
+        patches(wrong).run(&mut cpu);
+        assert_eq!(word(&mut cpu), 0x0C000800);
+
+        let mut right = patch(Trigger::At(0xBFC00000));
+        right.check = Some(check(crc32(&code_bytes(&CODE))));
+        patches(right).run(&mut cpu);
+        assert_eq!(word(&mut cpu), 0);
+    }
+
+    #[test]
+    fn loaded_trigger() {
+        let mut cpu = machine();
+        let mut patches = patches(Patch {
+            check: Some(Check {
+                address: TARGET,
+                length: 4,
+                crc32: crc32(&code_bytes(&CODE[..1])),
+            }),
+            ..patch(Trigger::Loaded)
+        });
+
+        // nothing there yet
+        patches.run(&mut cpu);
+        assert_eq!(word(&mut cpu), 0);
+
+        // the original words show up, but with different code around them
+        load(&mut cpu, &[0, 0, 0x0C000800]);
+        patches.run(&mut cpu);
+        assert_eq!(word(&mut cpu), 0x0C000800);
+
+        // a rejected patch isn't retried while the same words stay
+        load(&mut cpu, &CODE[..1]);
+        patches.run(&mut cpu);
+        assert_eq!(word(&mut cpu), 0x0C000800);
+
+        // but is once they go away and come back
+        load(&mut cpu, &[0, 0, 0]);
+        patches.run(&mut cpu);
+        load(&mut cpu, &CODE);
+        patches.run(&mut cpu);
+        assert_eq!(word(&mut cpu), 0);
+    }
+
+    #[test]
+    fn stage_trigger() {
+        let mut cpu = machine();
+        load(&mut cpu, &CODE);
+        let mut patches = patches(patch(Trigger::Stage(Stage::Sk)));
+
+        assert_eq!(Stage::of(&cpu), Stage::Bootrom);
+        patches.run(&mut cpu);
+        assert_eq!(word(&mut cpu), 0x0C000800);
+
+        // leaving the bootrom mapping, still in secure mode
+        cpu.write::<u32>(0xA4300014, 0x1);
+        assert_eq!(Stage::of(&cpu), Stage::Sk);
+        patches.run(&mut cpu);
+        assert_eq!(word(&mut cpu), 0);
+
+        // only on entry, so putting the words back sticks
+        load(&mut cpu, &CODE);
+        patches.run(&mut cpu);
+        assert_eq!(word(&mut cpu), 0x0C000800);
+
+        assert_eq!("app".parse(), Ok(Stage::App));
+        assert!("kernel".parse::<Stage>().is_err());
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(b""), 0);
+    }
+}
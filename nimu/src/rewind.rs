@@ -0,0 +1,174 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::mem::take;
+use std::rc::Rc;
+
+use r4300i_rs::cop0::NAND_BLOCK_SIZE;
+use r4300i_rs::R4300i;
+
+use crate::hooks::Hooks;
+use crate::patch::Patches;
+use crate::Injection;
+
+// the parts of the front end that change as the machine runs, kept with each
+// checkpoint so a rewind puts them back too
+#[derive(Debug, Clone)]
+pub struct Frontend {
+    pub patches: Patches,
+    pub hooks: Hooks,
+    pub injection: Option<Injection>,
+    pub save_state_at: Option<u32>,
+}
+
+#[derive(Debug)]
+struct Checkpoint {
+    instructions: u64,
+    // everything but the nand
+    state: Vec<u8>,
+    // each block's data and spare area, shared with the previous checkpoint
+    // where the block hasn't changed since
+    nand: Vec<Rc<[u8]>>,
+    frontend: Frontend,
+}
+
+// a ring buffer of snapshots taken every `interval` instructions; execution is
+// deterministic, so any earlier instruction can be reached by loading the closest
+// checkpoint before it and running forward
+#[derive(Debug)]
+pub struct Rewind {
+    interval: u64,
+    capacity: usize,
+    checkpoints: VecDeque<Checkpoint>,
+    // nand blocks changed since the latest checkpoint
+    changed: BTreeSet<usize>,
+}
+
+impl Rewind {
+    pub fn new(interval: u64, capacity: usize) -> Self {
+        Self {
+            interval,
+            capacity,
+            checkpoints: VecDeque::new(),
+            changed: BTreeSet::new(),
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self::new(0, 0)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.interval != 0 && self.capacity != 0
+    }
+
+    // whether record takes a checkpoint at this instruction
+    pub fn is_due(&self, instructions: u64) -> bool {
+        self.is_enabled()
+            && instructions.is_multiple_of(self.interval)
+            // unless there's already one from before a rewind
+            && self
+                .checkpoints
+                .back()
+                .is_none_or(|last| last.instructions < instructions)
+    }
+
+    // the nand blocks the machine has written since the last call, which must
+    // all be passed in before a checkpoint is taken
+    pub fn nand_changed(&mut self, blocks: &[usize]) {
+        if self.is_enabled() {
+            self.changed.extend(blocks);
+        }
+    }
+
+    pub fn record(
+        &mut self,
+        instructions: u64,
+        cpu: &mut R4300i,
+        frontend: impl FnOnce() -> Frontend,
+    ) {
+        if !self.is_due(instructions) {
+            return;
+        }
+
+        let block = |block| -> Rc<[u8]> {
+            let (data, spare) = cpu.get_nand_block(block);
+            [data, spare].concat().into()
+        };
+        let nand = match self.checkpoints.back() {
+            Some(last) => {
+                let mut nand = last.nand.clone();
+                for changed in take(&mut self.changed) {
+                    // rewinds write blocks back as they were, which can go on being shared
+                    let contents = block(changed);
+                    if contents != nand[changed] {
+                        nand[changed] = contents;
+                    }
+                }
+                nand
+            }
+            None => {
+                self.changed.clear();
+                let blocks = cpu.get_nand_device().size() / NAND_BLOCK_SIZE;
+                (0..blocks).map(block).collect()
+            }
+        };
+
+        if self.checkpoints.len() == self.capacity {
+            self.checkpoints.pop_front();
+        }
+        self.checkpoints.push_back(Checkpoint {
+            instructions,
+            state: cpu.save_state_without_nand(),
+            nand,
+            frontend: frontend(),
+        });
+    }
+
+    // loads the latest checkpoint at or before the given instruction into the
+    // machine, giving back its instruction count and front end. only the nand
+    // blocks that have changed since are copied back, and marked dirty
+    pub fn restore(&mut self, instructions: u64, cpu: &mut R4300i) -> Option<(u64, &Frontend)> {
+        let checkpoint = self
+            .checkpoints
+            .iter()
+            .rev()
+            .find(|checkpoint| checkpoint.instructions <= instructions)?;
+        let latest = self.checkpoints.back()?;
+
+        cpu.load_state_without_nand(&checkpoint.state)
+            .expect("checkpoints are always valid snapshots");
+        for (block, contents) in checkpoint.nand.iter().enumerate() {
+            if self.changed.contains(&block) || !Rc::ptr_eq(contents, &latest.nand[block]) {
+                let (data, spare) = contents.split_at(NAND_BLOCK_SIZE);
+                cpu.set_nand_block(block, data, spare);
+            }
+        }
+        // the restored blocks come back through nand_changed as they're written back
+        self.changed.clear();
+
+        Some((checkpoint.instructions, &checkpoint.frontend))
+    }
+
+    // instruction counts of every checkpoint before the given instruction, newest first
+    pub fn checkpoints_before(&self, instructions: u64) -> Vec<u64> {
+        self.checkpoints
+            .iter()
+            .rev()
+            .map(|checkpoint| checkpoint.instructions)
+            .filter(|count| *count < instructions)
+            .collect()
+    }
+
+    #[cfg(test)]
+    pub fn nand_at(&self, instructions: u64) -> Option<&[Rc<[u8]>]> {
+        self.checkpoints
+            .iter()
+            .find(|checkpoint| checkpoint.instructions == instructions)
+            .map(|checkpoint| &checkpoint.nand[..])
+    }
+
+    // after rewinding, the old future no longer applies
+    pub fn discard_after(&mut self, instructions: u64) {
+        self.checkpoints
+            .retain(|checkpoint| checkpoint.instructions <= instructions);
+    }
+}
@@ -0,0 +1,68 @@
+use std::fs::{read, write, File, OpenOptions};
+use std::io::{self, ErrorKind, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use r4300i_rs::R4300i;
+use thiserror::Error;
+
+// where a config without an address or size puts the save memory: 32K of
+// sram at the start of domain 2's second window
+pub const DEFAULT_ADDRESS: u32 = 0x08000000;
+pub const DEFAULT_SIZE: usize = 0x8000;
+
+#[derive(Debug, Error)]
+pub enum SaveError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("save file is {len:#X} bytes, but the save memory is {size:#X}")]
+    Size { len: usize, size: usize },
+}
+
+// the file behind the guest's save memory, updated as the guest writes it
+#[derive(Debug)]
+pub struct SaveStore {
+    file: File,
+}
+
+impl SaveStore {
+    // an existing file gives the size unless one is set; a missing one reads
+    // as erased, like new flash
+    pub fn load(path: &Path, size: Option<usize>) -> Result<Vec<u8>, SaveError> {
+        match read(path) {
+            Ok(data) => match size {
+                Some(size) if size != data.len() => Err(SaveError::Size {
+                    len: data.len(),
+                    size,
+                }),
+                _ => Ok(data),
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Ok(vec![0xFF; size.unwrap_or(DEFAULT_SIZE)])
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // creates the file from `data` if it's missing
+    pub fn open(path: &Path, data: &[u8]) -> io::Result<Self> {
+        if !path.exists() {
+            write(path, data)?;
+        }
+        let file = OpenOptions::new().write(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    // writes back the bytes changed since the last flush
+    pub fn flush(&mut self, cpu: &mut R4300i) -> io::Result<()> {
+        let Some(dirty) = cpu.take_dirty_save() else {
+            return Ok(());
+        };
+        let Some(save) = cpu.get_save_memory() else {
+            return Ok(());
+        };
+
+        self.file.seek(SeekFrom::Start(dirty.start as u64))?;
+        self.file.write_all(&save.data()[dirty])?;
+        self.file.flush()
+    }
+}
@@ -0,0 +1,429 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{read, write};
+use std::mem::take;
+use std::path::Path;
+use std::rc::Rc;
+
+use r4300i_rs::{R4300i, Register};
+use rhai::{Blob, Dynamic, Engine, EvalAltResult, FnPtr, AST, INT};
+use thiserror::Error;
+
+use crate::hooks::{read_range, read_string, Memory};
+use crate::symbols::Symbols;
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+// the cpu scripts act on, shared with the front end, which never holds a
+// borrow while a script runs
+pub type SharedCpu = Rc<RefCell<R4300i>>;
+
+fn with_cpu<T>(cpu: &SharedCpu, f: impl FnOnce(&mut R4300i) -> T) -> T {
+    f(&mut cpu.borrow_mut())
+}
+
+// for the functions that change the machine, which a rewind can't replay
+fn change_cpu<T>(cpu: &SharedCpu, shared: &RefCell<Shared>, f: impl FnOnce(&mut R4300i) -> T) -> T {
+    shared.borrow_mut().requests.changed_machine = true;
+    with_cpu(cpu, f)
+}
+
+#[derive(Debug, Error)]
+#[error("{path}: {message}")]
+pub struct ScriptError {
+    path: String,
+    message: String,
+}
+
+// things a script asked for that the cpu can't do by itself
+#[derive(Debug, Default)]
+pub struct Requests {
+    pub breakpoints: Vec<u32>,
+    pub enter_monitor: bool,
+    // registers, memory or the run state were changed
+    pub changed_machine: bool,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    symbols: Symbols,
+    // hooks added by the script being loaded
+    hooks: Vec<(u32, FnPtr)>,
+    requests: Requests,
+}
+
+// rhai scripts that register functions to call when the pc reaches an address;
+// see scripts/investigations.rhai
+#[derive(Debug)]
+pub struct Scripts {
+    engine: Engine,
+    asts: Vec<AST>,
+    hooks: HashMap<u32, Vec<(usize, FnPtr)>>,
+    shared: Rc<RefCell<Shared>>,
+    cpu: SharedCpu,
+}
+
+impl Scripts {
+    pub fn new(cpu: SharedCpu) -> Self {
+        let shared = Rc::new(RefCell::new(Shared::default()));
+        Self {
+            engine: engine(&shared, &cpu),
+            asts: vec![],
+            hooks: HashMap::new(),
+            shared,
+            cpu,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.asts.is_empty()
+    }
+
+    // used to resolve symbol names passed to scripts
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.shared.borrow_mut().symbols = symbols;
+    }
+
+    // runs the top level of the script, which registers its hooks
+    pub fn load(&mut self, path: &Path) -> Result<Requests, ScriptError> {
+        let error = |e: Box<EvalAltResult>| ScriptError {
+            path: path.display().to_string(),
+            message: e.to_string(),
+        };
+
+        let ast = self.engine.compile_file(path.into()).map_err(error)?;
+        self.engine.run_ast(&ast).map_err(error)?;
+
+        let index = self.asts.len();
+        self.asts.push(ast);
+        let mut shared = self.shared.borrow_mut();
+        for (address, f) in shared.hooks.drain(..) {
+            self.hooks.entry(address).or_default().push((index, f));
+        }
+        Ok(take(&mut shared.requests))
+    }
+
+    // runs the hooks for the current pc
+    pub fn run(&mut self) -> Requests {
+        let pc = self.cpu.borrow().get_pc() as u32;
+        if let Some(hooks) = self.hooks.get(&pc) {
+            for (index, f) in hooks {
+                if let Err(e) = f.call::<Dynamic>(&self.engine, &self.asts[*index], ()) {
+                    println!("script error at {pc:08X}: {e}");
+                }
+            }
+        }
+        take(&mut self.shared.borrow_mut().requests)
+    }
+}
+
+fn register(name: &str) -> ScriptResult<u8> {
+    (0..32)
+        .find(|&r| Register::from(r).to_string() == name)
+        .ok_or_else(|| format!("unknown register: {name}").into())
+}
+
+fn resolve(shared: &RefCell<Shared>, name: &str) -> ScriptResult<u32> {
+    Ok(shared.borrow().symbols.resolve(name, None)?)
+}
+
+// registers and memory are seen as 32 bits wide, the way the guest code uses
+// them; values written to registers are sign extended
+fn engine(shared: &Rc<RefCell<Shared>>, cpu: &SharedCpu) -> Engine {
+    let mut engine = Engine::new();
+
+    let s = shared.clone();
+    engine.register_fn("hook", move |at: INT, f: FnPtr| {
+        s.borrow_mut().hooks.push((at as u32, f));
+    });
+    let s = shared.clone();
+    engine.register_fn("hook", move |at: &str, f: FnPtr| -> ScriptResult<()> {
+        let at = resolve(&s, at)?;
+        s.borrow_mut().hooks.push((at, f));
+        Ok(())
+    });
+
+    let c = cpu.clone();
+    engine.register_fn("pc", move || with_cpu(&c, |cpu| cpu.get_pc() as u32 as INT));
+    let s = shared.clone();
+    let c = cpu.clone();
+    engine.register_fn("set_pc", move |pc: INT| {
+        change_cpu(&c, &s, |cpu| cpu.set_pc(pc as i32 as u64))
+    });
+    let c = cpu.clone();
+    engine.register_fn("reg", move |r: INT| -> ScriptResult<INT> {
+        if !(0..32).contains(&r) {
+            return Err(format!("no register {r}").into());
+        }
+        Ok(with_cpu(&c, |cpu| cpu.get_reg(r as u8) as u32 as INT))
+    });
+    let c = cpu.clone();
+    engine.register_fn("reg", move |name: &str| -> ScriptResult<INT> {
+        let r = register(name)?;
+        Ok(with_cpu(&c, |cpu| cpu.get_reg(r) as u32 as INT))
+    });
+    let s = shared.clone();
+    let c = cpu.clone();
+    engine.register_fn("set_reg", move |r: INT, value: INT| -> ScriptResult<()> {
+        if !(0..32).contains(&r) {
+            return Err(format!("no register {r}").into());
+        }
+        change_cpu(&c, &s, |cpu| cpu.set_reg(r as u8, value as i32 as u64));
+        Ok(())
+    });
+    let s = shared.clone();
+    let c = cpu.clone();
+    engine.register_fn(
+        "set_reg",
+        move |name: &str, value: INT| -> ScriptResult<()> {
+            let r = register(name)?;
+            change_cpu(&c, &s, |cpu| cpu.set_reg(r, value as i32 as u64));
+            Ok(())
+        },
+    );
+
+    // unreadable addresses give ()
+    let c = cpu.clone();
+    engine.register_fn("peek8", move |address: INT| {
+        with_cpu(&c, |cpu| cpu.peek::<u8>(address as u32))
+            .map_or(Dynamic::UNIT, |v| (v as INT).into())
+    });
+    let c = cpu.clone();
+    engine.register_fn("peek16", move |address: INT| {
+        with_cpu(&c, |cpu| cpu.peek::<u16>(address as u32))
+            .map_or(Dynamic::UNIT, |v| (v as INT).into())
+    });
+    let c = cpu.clone();
+    engine.register_fn("peek32", move |address: INT| {
+        with_cpu(&c, |cpu| cpu.peek::<u32>(address as u32))
+            .map_or(Dynamic::UNIT, |v| (v as INT).into())
+    });
+    let c = cpu.clone();
+    engine.register_fn("peek64", move |address: INT| {
+        with_cpu(&c, |cpu| cpu.peek::<u64>(address as u32))
+            .map_or(Dynamic::UNIT, |v| (v as INT).into())
+    });
+    let s = shared.clone();
+    let c = cpu.clone();
+    engine.register_fn("poke8", move |address: INT, value: INT| {
+        change_cpu(&c, &s, |cpu| cpu.write::<u8>(address as u32, value as u8))
+    });
+    let s = shared.clone();
+    let c = cpu.clone();
+    engine.register_fn("poke16", move |address: INT, value: INT| {
+        change_cpu(&c, &s, |cpu| cpu.write::<u16>(address as u32, value as u16))
+    });
+    let s = shared.clone();
+    let c = cpu.clone();
+    engine.register_fn("poke32", move |address: INT, value: INT| {
+        change_cpu(&c, &s, |cpu| cpu.write::<u32>(address as u32, value as u32))
+    });
+    let s = shared.clone();
+    let c = cpu.clone();
+    engine.register_fn("poke64", move |address: INT, value: INT| {
+        change_cpu(&c, &s, |cpu| cpu.write::<u64>(address as u32, value as u64))
+    });
+    let c = cpu.clone();
+    engine.register_fn("read_string", move |address: INT| {
+        with_cpu(&c, |cpu| read_string(cpu, address as u32))
+    });
+    let c = cpu.clone();
+    engine.register_fn("read_bytes", move |address: INT, length: INT| -> Blob {
+        with_cpu(&c, |cpu| read_range(cpu, address as u32, length as u32))
+    });
+    engine.register_fn("hex", |value: INT| format!("{:08X}", value as u32));
+
+    let c = cpu.clone();
+    engine.register_fn(
+        "dump",
+        move |file: &str, address: INT, length: INT| -> ScriptResult<()> {
+            let data = with_cpu(&c, |cpu| read_range(cpu, address as u32, length as u32));
+            write(file, data).map_err(|e| format!("couldn't write {file}: {e}").into())
+        },
+    );
+    let c = cpu.clone();
+    engine.register_fn(
+        "dump",
+        move |file: &str, memory: &str| -> ScriptResult<()> {
+            let data = with_cpu(&c, |cpu| {
+                Memory::parse(memory).map(|memory| memory.read(cpu))
+            })?;
+            write(file, data).map_err(|e| format!("couldn't write {file}: {e}").into())
+        },
+    );
+    let c = cpu.clone();
+    engine.register_fn("save_state", move |file: &str| -> ScriptResult<()> {
+        let data = with_cpu(&c, |cpu| cpu.save_state());
+        write(file, data).map_err(|e| format!("couldn't write {file}: {e}").into())
+    });
+    let s = shared.clone();
+    let c = cpu.clone();
+    engine.register_fn("load_state", move |file: &str| -> ScriptResult<()> {
+        let data = read(file).map_err(|e| format!("couldn't read {file}: {e}"))?;
+        change_cpu(&c, &s, |cpu| cpu.load_state(&data)).map_err(|e| format!("{file}: {e}").into())
+    });
+
+    let s = shared.clone();
+    engine.register_fn("break_at", move |address: INT| {
+        s.borrow_mut().requests.breakpoints.push(address as u32);
+    });
+    let s = shared.clone();
+    engine.register_fn("break_at", move |name: &str| -> ScriptResult<()> {
+        let address = resolve(&s, name)?;
+        s.borrow_mut().requests.breakpoints.push(address);
+        Ok(())
+    });
+    let s = shared.clone();
+    engine.register_fn("break_now", move || {
+        s.borrow_mut().requests.enter_monitor = true;
+    });
+    let s = shared.clone();
+    let c = cpu.clone();
+    engine.register_fn("halt", move || change_cpu(&c, &s, |cpu| cpu.halt()));
+    let c = cpu.clone();
+    engine.register_fn("log", move |on: bool| {
+        with_cpu(&c, |cpu| {
+            if on {
+                cpu.start_logging()
+            } else {
+                cpu.stop_logging()
+            }
+        })
+    });
+    let c = cpu.clone();
+    engine.register_fn("secure", move || with_cpu(&c, |cpu| cpu.get_secure_mode()));
+    let c = cpu.clone();
+    engine.register_fn("bootrom", move || with_cpu(&c, |cpu| cpu.get_mi_mapping()));
+
+    let s = shared.clone();
+    let c = cpu.clone();
+    engine.register_fn("symbol", move |address: INT| {
+        let secure = with_cpu(&c, |cpu| cpu.get_secure_mode());
+        s.borrow()
+            .symbols
+            .symbolize(address as u32, Some(secure))
+            .map_or(Dynamic::UNIT, Dynamic::from)
+    });
+    let s = shared.clone();
+    engine.register_fn("address_of", move |name: &str| -> ScriptResult<INT> {
+        Ok(resolve(&s, name)? as INT)
+    });
+
+    engine
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::process;
+
+    use r4300i_rs::cop0::nand::{NandDevice, NandImage};
+    use r4300i_rs::cop0::{NAND_BLOCK_SIZE, NAND_SPARE_SIZE};
+
+    use super::*;
+
+    fn machine() -> SharedCpu {
+        let device: NandDevice = "64m".parse().unwrap();
+        let blocks = device.size() / NAND_BLOCK_SIZE;
+        let nand = NandImage::split(
+            vec![0xFF; device.size()],
+            vec![0xFF; blocks * NAND_SPARE_SIZE],
+            Some(device),
+        )
+        .unwrap();
+        let mut cpu = R4300i::new(vec![], vec![], vec![], vec![], nand);
+        cpu.write::<u32>(0x80000100, 0x11223344);
+        cpu.write::<u32>(0x80000104, 0x55667788);
+        cpu.set_reg(5, 0x1234);
+        Rc::new(RefCell::new(cpu))
+    }
+
+    #[test]
+    fn hooks_reach_the_machine() {
+        let dir = temp_dir().join(format!("nimu-script-{}", process::id()));
+        create_dir_all(&dir).unwrap();
+        let script = dir.join("test.rhai");
+        let dump = dir.join("dump.bin");
+        let state = dir.join("state.bin");
+        write(
+            &script,
+            format!(
+                r#"
+                break_at(0x80001000);
+                hook(0x80000400, || {{
+                    set_reg("a0", reg("a1") + 1);
+                    poke32(0x80000100, peek32(0x80000104) + 1);
+                    if peek32(0xA4100000) == () {{
+                        set_reg(6, 1);
+                    }}
+                    dump({dump:?}, 0x80000100, 8);
+                    save_state({state:?});
+                    set_pc(0x80000800);
+                }});
+                "#
+            ),
+        )
+        .unwrap();
+
+        let cpu = machine();
+        let mut scripts = Scripts::new(cpu.clone());
+        let requests = scripts.load(&script).unwrap();
+        assert_eq!(requests.breakpoints, [0x80001000]);
+        assert!(!requests.changed_machine);
+
+        // nothing happens away from the hook
+        assert!(!scripts.run().changed_machine);
+
+        cpu.borrow_mut().set_pc(0x80000400);
+        let requests = scripts.run();
+        assert!(requests.changed_machine);
+        assert!(requests.breakpoints.is_empty());
+
+        let mut machine = cpu.borrow_mut();
+        assert_eq!(machine.get_reg(4), 0x1235);
+        assert_eq!(machine.get_reg(6), 1);
+        assert_eq!(machine.get_pc() as u32, 0x80000800);
+        assert_eq!(machine.peek::<u32>(0x80000100), Some(0x55667789));
+        assert_eq!(
+            read(&dump).unwrap(),
+            [0x55, 0x66, 0x77, 0x89, 0x55, 0x66, 0x77, 0x88]
+        );
+
+        // the state was saved before the jump
+        machine.load_state(&read(&state).unwrap()).unwrap();
+        assert_eq!(machine.get_pc() as u32, 0x80000400);
+
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn errors_are_reported() {
+        let dir = temp_dir().join(format!("nimu-script-errors-{}", process::id()));
+        create_dir_all(&dir).unwrap();
+        let script = dir.join("test.rhai");
+        let mut scripts = Scripts::new(machine());
+
+        // at load time they come back with the path
+        write(&script, "hook(0x80000400, || {").unwrap();
+        let e = scripts.load(&script).unwrap_err();
+        assert!(e.to_string().starts_with(&script.display().to_string()));
+        write(&script, "reg(40);").unwrap();
+        let e = scripts.load(&script).unwrap_err();
+        assert!(e.to_string().contains("no register 40"), "{e}");
+        assert!(scripts.is_empty());
+
+        // and in a hook they're printed, leaving the machine alone
+        write(
+            &script,
+            "hook(0x80000400, || { set_reg(4, 1); set_reg(\"xx\", 2); set_reg(4, 3); });",
+        )
+        .unwrap();
+        scripts.load(&script).unwrap();
+        scripts.cpu.borrow_mut().set_pc(0x80000400);
+        scripts.run();
+        assert_eq!(scripts.cpu.borrow().get_reg(4), 1);
+
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::BTreeMap;
+use std::fs::read;
+use std::io;
+use std::ops::RangeInclusive;
+
+use object::{Object, ObjectSymbol, SymbolKind};
+use r4300i_rs::{is_kseg1, k1_to_k0};
+use thiserror::Error;
+
+use crate::monitor::parse_address;
+
+// how far past the last symbol of a map file an address can still belong to it
+const MAX_UNSIZED: u32 = 0x10000;
+
+#[derive(Debug, Error)]
+pub enum SymbolError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("invalid elf: {0}")]
+    Elf(#[from] object::Error),
+    #[error("line {0}: {1}")]
+    Line(usize, String),
+    #[error("unknown symbol file qualifier: {0}")]
+    Qualifier(String),
+}
+
+#[derive(Debug, Clone)]
+struct Symbol {
+    name: String,
+    // zero when the size isn't known, as with map files; such symbols run up to
+    // the next one
+    size: u32,
+}
+
+// the symbols from one file; sk, sa1 and apps all link at overlapping addresses,
+// so a table can be limited to one mode and/or address range
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    secure: Option<bool>,
+    region: Option<RangeInclusive<u32>>,
+    symbols: BTreeMap<u32, Symbol>,
+}
+
+impl SymbolTable {
+    // `file[@secure|@non-secure][@start-end]`
+    pub fn load(spec: &str) -> Result<Self, SymbolError> {
+        let mut parts = spec.split('@');
+        let path = parts.next().unwrap_or_default();
+
+        let mut table = Self::parse(&read(path)?)?;
+        for qualifier in parts {
+            match qualifier.to_ascii_lowercase().as_str() {
+                "secure" => table.secure = Some(true),
+                "non-secure" => table.secure = Some(false),
+                range => {
+                    let (start, end) = range
+                        .split_once('-')
+                        .ok_or_else(|| SymbolError::Qualifier(qualifier.into()))?;
+                    let parse =
+                        |s| parse_address(s).map_err(|_| SymbolError::Qualifier(qualifier.into()));
+                    let (start, end) = (parse(start)?, parse(end)?);
+                    table.region = Some(start as u32..=end as u32);
+                }
+            }
+        }
+
+        Ok(table)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, SymbolError> {
+        if data.starts_with(b"\x7FELF") {
+            Self::parse_elf(data)
+        } else {
+            Self::parse_map(&String::from_utf8_lossy(data))
+        }
+    }
+
+    fn parse_elf(data: &[u8]) -> Result<Self, SymbolError> {
+        let file = object::File::parse(data)?;
+        let mut table = Self::default();
+
+        for symbol in file.symbols() {
+            if !symbol.is_definition()
+                || !matches!(symbol.kind(), SymbolKind::Text | SymbolKind::Data)
+            {
+                continue;
+            }
+            let Ok(name) = symbol.name() else {
+                continue;
+            };
+            if name.is_empty() || name.starts_with(".L") {
+                continue;
+            }
+            table.insert(symbol.address() as u32, name, symbol.size() as u32);
+        }
+
+        Ok(table)
+    }
+
+    // one `addr name` per line; `addr type name` as printed by nm also works
+    fn parse_map(text: &str) -> Result<Self, SymbolError> {
+        let mut table = Self::default();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (address, name) = match fields[..] {
+                [] => continue,
+                [address, name] | [address, _, name] => (address, name),
+                _ => return Err(SymbolError::Line(index + 1, "expected `addr name`".into())),
+            };
+            let address = parse_address(address).map_err(|e| SymbolError::Line(index + 1, e))?;
+            table.insert(address as u32, name, 0);
+        }
+
+        Ok(table)
+    }
+
+    fn insert(&mut self, address: u32, name: &str, size: u32) {
+        // keep the first name for aliased addresses, but fill in a missing size
+        let symbol = self.symbols.entry(address).or_insert_with(|| Symbol {
+            name: name.into(),
+            size,
+        });
+        if symbol.size == 0 {
+            symbol.size = size;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    // a table with no mode applies in both, and `None` matches any table
+    fn applies(&self, secure: Option<bool>) -> bool {
+        match (self.secure, secure) {
+            (Some(table), Some(mode)) => table == mode,
+            _ => true,
+        }
+    }
+
+    // the symbol containing this address, and how far into it the address is
+    fn containing(&self, address: u32) -> Option<(&Symbol, u32)> {
+        if self.region.as_ref().is_some_and(|r| !r.contains(&address)) {
+            return None;
+        }
+        let (start, symbol) = self.symbols.range(..=address).next_back()?;
+        let offset = address - start;
+        let size = match symbol.size {
+            0 => match self.symbols.range(start + 1..).next() {
+                Some((next, _)) => next - start,
+                None => MAX_UNSIZED,
+            },
+            size => size,
+        };
+        (offset < size).then_some((symbol, offset))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    tables: Vec<SymbolTable>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, table: SymbolTable) {
+        self.tables.push(table);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tables.iter().all(SymbolTable::is_empty)
+    }
+
+    // pass `None` for the mode when it isn't known, e.g. for trace files
+    pub fn lookup(&self, address: u32, secure: Option<bool>) -> Option<(&str, u32)> {
+        // code is usually linked for kseg0 but sometimes runs uncached from kseg1
+        self.lookup_exact(address, secure).or_else(|| {
+            is_kseg1(address)
+                .then(|| self.lookup_exact(k1_to_k0(address), secure))
+                .flatten()
+        })
+    }
+
+    fn lookup_exact(&self, address: u32, secure: Option<bool>) -> Option<(&str, u32)> {
+        self.tables
+            .iter()
+            .filter(|table| table.applies(secure))
+            .filter_map(|table| table.containing(address))
+            .min_by_key(|(_, offset)| *offset)
+            .map(|(symbol, offset)| (symbol.name.as_str(), offset))
+    }
+
+    // `name` or `name+0x1C`
+    pub fn symbolize(&self, address: u32, secure: Option<bool>) -> Option<String> {
+        self.lookup(address, secure)
+            .map(|(name, offset)| match offset {
+                0 => name.to_string(),
+                _ => format!("{name}+0x{offset:X}"),
+            })
+    }
+
+    // the name of the symbol starting exactly here
+    pub fn label(&self, address: u32, secure: Option<bool>) -> Option<&str> {
+        match self.lookup(address, secure) {
+            Some((name, 0)) => Some(name),
+            _ => None,
+        }
+    }
+
+    // tables for the current mode are searched first
+    pub fn address_of(&self, name: &str, secure: Option<bool>) -> Option<u32> {
+        let find = |table: &SymbolTable| {
+            table
+                .symbols
+                .iter()
+                .find(|(_, symbol)| symbol.name == name)
+                .map(|(address, _)| *address)
+        };
+        self.tables
+            .iter()
+            .filter(|table| table.applies(secure))
+            .find_map(find)
+            .or_else(|| self.tables.iter().find_map(find))
+    }
+
+    // a symbol name, `name+offset`, or a plain hex address
+    pub fn resolve(&self, s: &str, secure: Option<bool>) -> Result<u32, String> {
+        let (name, offset) = match s.split_once('+') {
+            Some((name, offset)) => (name, parse_address(offset)? as u32),
+            None => (s, 0),
+        };
+        match self.address_of(name, secure) {
+            Some(address) => Ok(address.wrapping_add(offset)),
+            None => parse_address(s)
+                .map(|address| address as u32)
+                .map_err(|_| format!("unknown symbol or address: {s}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::{remove_file, write};
+    use std::process;
+
+    use super::*;
+
+    const SK: &str = "
+        # the secure kernel
+        9FC00000 skEntry
+        9FC00100 T skHandler
+        9FC00100 skAlias
+    ";
+    const APP: &str = "
+        80001000 main
+        80001040 t helper
+    ";
+
+    fn table(text: &str, secure: Option<bool>) -> SymbolTable {
+        SymbolTable {
+            secure,
+            ..SymbolTable::parse(text.as_bytes()).unwrap()
+        }
+    }
+
+    #[test]
+    fn map_files() {
+        let table = table(SK, None);
+        assert_eq!(table.len(), 2);
+        let (symbol, offset) = table.containing(0x9FC00104).unwrap();
+        assert_eq!((symbol.name.as_str(), offset), ("skHandler", 4));
+
+        // unsized symbols run up to the next one, and a bit past the last
+        assert_eq!(table.containing(0x9FC000FC).unwrap().0.name, "skEntry");
+        assert!(table.containing(0x9FC00100 + MAX_UNSIZED).is_none());
+        assert!(table.containing(0x9FBFFFFC).is_none());
+
+        assert!(matches!(
+            SymbolTable::parse(b"80001000\n"),
+            Err(SymbolError::Line(1, _))
+        ));
+        assert!(matches!(
+            SymbolTable::parse(b"80001000 main\nmain 80001000\n"),
+            Err(SymbolError::Line(2, _))
+        ));
+    }
+
+    #[test]
+    fn secure_and_non_secure() {
+        let mut symbols = Symbols::new();
+        symbols.add(table(SK, Some(true)));
+        symbols.add(table("9FC00000 saEntry", Some(false)));
+        symbols.add(table(APP, None));
+
+        assert_eq!(
+            symbols.symbolize(0x9FC00000, Some(true)).unwrap(),
+            "skEntry"
+        );
+        assert_eq!(
+            symbols.symbolize(0x9FC00000, Some(false)).unwrap(),
+            "saEntry"
+        );
+        assert_eq!(symbols.label(0x80001040, Some(true)), Some("helper"));
+        assert_eq!(
+            symbols.symbolize(0xA0001044, Some(false)).unwrap(),
+            "helper+0x4"
+        );
+
+        // the current mode's tables come first, then the rest
+        assert_eq!(symbols.resolve("skEntry", Some(false)), Ok(0x9FC00000));
+        assert_eq!(symbols.resolve("main+0x10", None), Ok(0x80001010));
+        assert_eq!(symbols.resolve("0x80002000", None), Ok(0x80002000));
+        assert!(symbols.resolve("nowhere", None).is_err());
+    }
+
+    #[test]
+    fn qualifiers() {
+        let path = temp_dir().join(format!("nimu-symbols-{}.map", process::id()));
+        write(&path, APP).unwrap();
+        let path = path.to_str().unwrap();
+
+        let table = SymbolTable::load(&format!("{path}@non-secure@80001000-8000103F")).unwrap();
+        assert_eq!(table.secure, Some(false));
+        assert!(table.containing(0x80001000).is_some());
+        assert!(table.containing(0x80001040).is_none());
+
+        assert!(matches!(
+            SymbolTable::load(&format!("{path}@sometimes")),
+            Err(SymbolError::Qualifier(_))
+        ));
+
+        remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::VecDeque;
+
+use crate::snapshot::{Snapshot, SnapshotError, Snapshotter};
+use crate::types::*;
+use crate::{ExceptionType, EXCEPTION_TYPES};
+
+// code that calls without ever returning (e.g. a kernel entering a thread) would
+// otherwise grow the stack forever
+const MAX_DEPTH: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Call,
+    Exception(ExceptionType),
+}
+
+// `from` is the call site or epc, `to` is the function or vector entered, and
+// `ret` is where control comes back to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    pub from: word,
+    pub to: word,
+    pub ret: word,
+}
+
+// a shadow of the guest's call stack, built from the calls and returns it executes
+#[derive(Debug, Clone, Default)]
+pub struct CallStack {
+    frames: VecDeque<Frame>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&mut self, frame: Frame) {
+        if self.frames.len() == MAX_DEPTH {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(frame);
+    }
+
+    pub(crate) fn call(&mut self, from: word, to: word) {
+        self.push(Frame {
+            kind: FrameKind::Call,
+            from,
+            to,
+            ret: from.wrapping_add(8),
+        });
+    }
+
+    pub(crate) fn exception(&mut self, exception: ExceptionType, epc: word, vector: word) {
+        self.push(Frame {
+            kind: FrameKind::Exception(exception),
+            from: epc,
+            to: vector,
+            ret: epc,
+        });
+    }
+
+    // pops back to the call that returns here; returns that don't match a call in
+    // the current exception level (longjmp, hand-built stacks) are ignored
+    pub(crate) fn ret(&mut self, target: word) {
+        for index in (0..self.frames.len()).rev() {
+            match self.frames[index] {
+                Frame {
+                    kind: FrameKind::Exception(_),
+                    ..
+                } => return,
+                Frame { ret, .. } if ret == target => {
+                    self.frames.truncate(index);
+                    return;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // an eret with no exception to return from is the kernel launching something,
+    // which leaves nothing below it to unwind to
+    pub(crate) fn eret(&mut self) {
+        let index = self
+            .frames
+            .iter()
+            .rposition(|frame| matches!(frame.kind, FrameKind::Exception(_)))
+            .unwrap_or(0);
+        self.frames.truncate(index);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    // innermost first
+    pub fn frames(&self) -> impl Iterator<Item = &Frame> {
+        self.frames.iter().rev()
+    }
+}
+
+impl Snapshot for CallStack {
+    fn snapshot(&mut self, s: &mut Snapshotter) -> Result<(), SnapshotError> {
+        s.section("CALL")?;
+
+        let mut len = self.frames.len();
+        s.usize(&mut len)?;
+        if s.is_loading() {
+            if len > MAX_DEPTH {
+                return Err(SnapshotError::Invalid("call stack too deep"));
+            }
+            self.frames.clear();
+            self.frames.resize(
+                len,
+                Frame {
+                    kind: FrameKind::Call,
+                    from: 0,
+                    to: 0,
+                    ret: 0,
+                },
+            );
+        }
+
+        for frame in &mut self.frames {
+            // exception frames are stored as the exception type plus one
+            let mut kind = match frame.kind {
+                FrameKind::Call => 0,
+                FrameKind::Exception(exception) => exception as byte + 1,
+            };
+            s.value(&mut kind)?;
+            frame.kind = match kind {
+                0 => FrameKind::Call,
+                _ => FrameKind::Exception(
+                    *EXCEPTION_TYPES
+                        .get(kind as usize - 1)
+                        .ok_or(SnapshotError::Invalid("exception type out of range"))?,
+                ),
+            };
+            s.value(&mut frame.from)?;
+            s.value(&mut frame.to)?;
+            s.value(&mut frame.ret)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets(stack: &CallStack) -> Vec<word> {
+        stack.frames().map(|frame| frame.to).collect()
+    }
+
+    #[test]
+    fn calls_and_returns() {
+        let mut stack = CallStack::new();
+        stack.call(0x80001000, 0x80002000);
+        stack.call(0x80002010, 0x80003000);
+        stack.call(0x80003010, 0x80004000);
+        assert_eq!(targets(&stack), [0x80004000, 0x80003000, 0x80002000]);
+
+        // a return that skips a frame unwinds both
+        stack.ret(0x80002018);
+        assert_eq!(targets(&stack), [0x80002000]);
+
+        // one that doesn't match anything is left alone
+        stack.ret(0x12345678);
+        assert_eq!(targets(&stack), [0x80002000]);
+    }
+
+    #[test]
+    fn exceptions() {
+        let mut stack = CallStack::new();
+        stack.call(0x80001000, 0x80002000);
+        stack.exception(ExceptionType::Interrupt, 0x80002004, 0x80000180);
+        stack.call(0x80000190, 0x80005000);
+
+        // returns don't cross into the interrupted code
+        stack.ret(0x80001008);
+        assert_eq!(targets(&stack), [0x80005000, 0x80000180, 0x80002000]);
+
+        stack.eret();
+        assert_eq!(targets(&stack), [0x80002000]);
+
+        // with nothing to return from, an eret starts over
+        stack.eret();
+        assert!(targets(&stack).is_empty());
+    }
+
+    #[test]
+    fn depth_is_limited() {
+        let mut stack = CallStack::new();
+        for index in 0..MAX_DEPTH as word + 10 {
+            stack.call(index * 4, index);
+        }
+        assert_eq!(stack.frames().count(), MAX_DEPTH);
+        assert_eq!(stack.frames().last().unwrap().to, 10);
+    }
+
+    #[test]
+    fn snapshots() {
+        let mut stack = CallStack::new();
+        stack.call(0x80001000, 0x80002000);
+        stack.exception(ExceptionType::TLBMissRead, 0x80002004, 0x80000000);
+
+        let mut s = Snapshotter::save();
+        stack.snapshot(&mut s).unwrap();
+        let data = s.into_saved();
+
+        let mut loaded = CallStack::new();
+        loaded.snapshot(&mut Snapshotter::load(&data)).unwrap();
+        assert_eq!(loaded.frames, stack.frames);
+    }
+}
@@ -0,0 +1,95 @@
+// ways to make the nand behave like a worn card
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NandFaults {
+    // blocks marked bad in their spare area when the card goes in; their
+    // programs and erases fail too, so the mark stays
+    pub bad_blocks: Vec<usize>,
+    // blocks whose programs and erases always fail
+    pub failing_blocks: Vec<usize>,
+    // chance of any program or erase failing
+    pub fail_rate: f64,
+    // chance of a page read coming back with one bit flipped
+    pub flip_rate: f64,
+    // (byte address, bit) pairs that read back flipped every time
+    pub flips: Vec<(usize, u8)>,
+    pub seed: u64,
+}
+
+impl NandFaults {
+    pub(crate) fn fails(&self, block: usize) -> bool {
+        self.bad_blocks.contains(&block) || self.failing_blocks.contains(&block)
+    }
+}
+
+// xorshift64*, so a seed gives the same faults on every run
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FaultRng(pub(crate) u64);
+
+impl FaultRng {
+    pub(crate) fn new(seed: u64) -> Self {
+        // the state can't be zero
+        Self((seed ^ 0x9E3779B97F4A7C15).max(1))
+    }
+
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    // true with the given chance
+    pub(crate) fn chance(&mut self, chance: f64) -> bool {
+        chance > 0.0 && ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < chance
+    }
+
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+// the spare area byte that's anything but 0xFF on a bad block
+pub const BAD_BLOCK_BYTE: usize = 5;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draws(seed: u64) -> Vec<u64> {
+        let mut rng = FaultRng::new(seed);
+        (0..16).map(|_| rng.next()).collect()
+    }
+
+    #[test]
+    fn seeds_repeat() {
+        assert_eq!(draws(42), draws(42));
+        assert_ne!(draws(42), draws(43));
+
+        // the seed that would zero the state still gives numbers
+        let mut rng = FaultRng::new(0x9E3779B97F4A7C15);
+        assert_ne!(rng.next(), 0);
+    }
+
+    #[test]
+    fn chances() {
+        let mut rng = FaultRng::new(7);
+        assert!((0..1000).all(|_| !rng.chance(0.0)));
+        assert!((0..1000).all(|_| rng.chance(1.0)));
+
+        let hits = (0..10000).filter(|_| rng.chance(0.25)).count();
+        assert!((2000..3000).contains(&hits));
+        assert!((0..1000).all(|_| rng.below(10) < 10));
+    }
+
+    #[test]
+    fn failing_blocks() {
+        let faults = NandFaults {
+            bad_blocks: vec![1],
+            failing_blocks: vec![4],
+            ..Default::default()
+        };
+        assert!(faults.fails(1));
+        assert!(faults.fails(4));
+        assert!(!faults.fails(2));
+    }
+}
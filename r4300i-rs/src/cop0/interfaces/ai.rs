@@ -1,3 +1,5 @@
+use crate::cop0::mmio::{register, RegisterInfo};
+use crate::snapshot::{Snapshot, SnapshotError, Snapshotter};
 use crate::types::*;
 
 use modular_bitfield::prelude::*;
@@ -40,6 +42,15 @@ pub struct BitRate {
     __: B28,
 }
 
+pub const REGISTERS: &[RegisterInfo] = &[
+    register!("AI_DRAM_ADDR", 0x04500000),
+    register!("AI_LEN", 0x04500004),
+    register!("AI_CONTROL", 0x04500008, Ctrl),
+    register!("AI_STATUS", 0x0450000C, Status, -),
+    register!("AI_DACRATE", 0x04500010, DacRate),
+    register!("AI_BITRATE", 0x04500014, BitRate),
+];
+
 #[derive(Debug)]
 pub struct Ai {
     control: Ctrl,
@@ -58,6 +69,20 @@ impl Ai {
         }
     }
 
+    pub fn registers(&self) -> Vec<(&'static str, word)> {
+        vec![
+            ("AI_CONTROL", self.control.into()),
+            (
+                "AI_STATUS",
+                self.status
+                    .with_full_2_electric_boogaloo(self.status.full())
+                    .into(),
+            ),
+            ("AI_DACRATE", self.dac_rate.into()),
+            ("AI_BITRATE", self.bit_rate.into()),
+        ]
+    }
+
     pub fn read_phys_addr(&mut self, address: word) -> byte {
         match address {
             0x04500008..=0x0450000B => {
@@ -115,3 +140,14 @@ impl Ai {
         }
     }
 }
+
+impl Snapshot for Ai {
+    fn snapshot(&mut self, s: &mut Snapshotter) -> Result<(), SnapshotError> {
+        s.section("AI")?;
+        s.bits(&mut self.control)?;
+        s.bits(&mut self.status)?;
+        s.bits(&mut self.dac_rate)?;
+        s.bits(&mut self.bit_rate)?;
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+use crate::cop0::mmio::{register, RegisterInfo};
+use crate::snapshot::{Snapshot, SnapshotError, Snapshotter};
 use crate::{types::*, SecureTrapType};
 
 use modular_bitfield::prelude::*;
@@ -191,6 +193,20 @@ pub struct EIntrMaskWrite {
     __: B4,
 }
 
+pub const REGISTERS: &[RegisterInfo] = &[
+    register!("MI_MODE", 0x04300000, Mode),
+    register!("MI_VERSION", 0x04300004),
+    register!("MI_INTR", 0x04300008, Intr),
+    register!("MI_INTR_MASK", 0x0430000C, IntrMask, IntrMaskWrite),
+    register!("MI_CTRL", 0x04300010, Ctrl),
+    register!("MI_SEC_MODE", 0x04300014, SecMode),
+    register!("MI_SEC_TIMER", 0x04300018, SecTimer),
+    register!("MI_SEC_VTIMER", 0x0430001C, SecTimer),
+    register!("MI_AVCTRL", 0x04300030, AVCtrl),
+    register!("MI_EINTR", 0x04300038, EIntr, -),
+    register!("MI_EINTR_MASK", 0x0430003C, EIntrMask, EIntrMaskWrite),
+];
+
 #[derive(Debug)]
 pub struct Mi {
     mode: Mode,
@@ -240,6 +256,39 @@ impl Mi {
         }
     }
 
+    pub fn registers(&self) -> Vec<(&'static str, word)> {
+        vec![
+            (
+                "MI_MODE",
+                self.mode
+                    .with_clear_init_mode(self.init_mode)
+                    .with_set_init_mode(self.ebus_test_mode)
+                    .into(),
+            ),
+            ("MI_INTR", self.intr.into()),
+            ("MI_INTR_MASK", self.intr_mask.into()),
+            (
+                "MI_CTRL",
+                self.ctrl
+                    .with_cold_reset(false)
+                    .with_warm_reset(false)
+                    .into(),
+            ),
+            ("MI_SEC_MODE", self.sec_mode.into()),
+            (
+                "MI_SEC_TIMER",
+                self.sec_timer.with_prescale(self.sec_timer_count).into(),
+            ),
+            (
+                "MI_SEC_VTIMER",
+                self.sec_vtimer.with_prescale(self.sec_vtimer_count).into(),
+            ),
+            ("MI_AVCTRL", self.av_control.into()),
+            ("MI_EINTR", self.eintr.into()),
+            ("MI_EINTR_MASK", self.eintr_mask.into()),
+        ]
+    }
+
     pub fn get_sec_mode_map(&self) -> bool {
         self.sec_mode.map()
     }
@@ -262,6 +311,12 @@ impl Mi {
         }
     }
 
+    // the aes engine holds its interrupt until PI_AES_CTRL is written again
+    pub fn set_aes_intr(&mut self, pending: bool) -> bool {
+        self.eintr.set_pi_aes(pending);
+        pending && self.intr_mask.pi() && self.eintr_mask.pi_aes()
+    }
+
     pub fn set_pi_intr(&mut self) -> bool {
         if self.intr_mask.pi() {
             self.intr.set_pi(true);
@@ -283,6 +338,29 @@ impl Mi {
         self.eintr.module()
     }
 
+    // returns whether the press should also take a secure trap
+    pub fn set_button(&mut self, pressed: bool) -> bool {
+        self.eintr.set_button(pressed);
+        pressed && self.sec_mode.enable_button()
+    }
+
+    pub fn button_intr(&self) -> bool {
+        self.eintr.button() && self.eintr_mask.button()
+    }
+
+    pub fn trigger_usb_intr(&mut self, controller: usize) {
+        match controller {
+            0 => self.eintr.set_usb0(true),
+            1 => self.eintr.set_usb1(true),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn usb_intr(&self) -> bool {
+        (self.eintr.usb0() && self.eintr_mask.usb0())
+            || (self.eintr.usb1() && self.eintr_mask.usb1())
+    }
+
     pub fn set_secure_trap(&mut self, trap: SecureTrapType) {
         match trap {
             SecureTrapType::Button => self.sec_mode.set_button(true),
@@ -492,14 +570,6 @@ impl Mi {
                 if eintr_mask_write.set_module() {
                     self.eintr_mask.set_module(true);
                 }
-
-                if address & 3 == 3 {
-                    println!(
-                        "write eintr_mask: {:#?}, {:08X}",
-                        self.eintr_mask,
-                        u32::from_le_bytes(self.eintr_mask.bytes)
-                    );
-                }
             }
 
             _ => {
@@ -509,3 +579,25 @@ impl Mi {
         }
     }
 }
+
+impl Snapshot for Mi {
+    fn snapshot(&mut self, s: &mut Snapshotter) -> Result<(), SnapshotError> {
+        s.section("MI")?;
+        s.bits(&mut self.mode)?;
+        s.bool(&mut self.init_mode)?;
+        s.bool(&mut self.ebus_test_mode)?;
+        s.bits(&mut self.intr)?;
+        s.bits(&mut self.intr_mask)?;
+        s.bits(&mut self.ctrl)?;
+        s.bits(&mut self.sec_mode)?;
+        s.bits(&mut self.sec_timer)?;
+        s.value(&mut self.sec_timer_count)?;
+        s.bits(&mut self.sec_vtimer)?;
+        s.value(&mut self.sec_vtimer_count)?;
+        s.bool(&mut self.mapping_changed)?;
+        s.bits(&mut self.av_control)?;
+        s.bits(&mut self.eintr)?;
+        s.bits(&mut self.eintr_mask)?;
+        Ok(())
+    }
+}
@@ -1,7 +1,19 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt;
+use std::mem::take;
+use std::ops::Range;
+
+use crate::cop0::faults::{FaultRng, NandFaults, BAD_BLOCK_BYTE};
+use crate::cop0::mmio::{register, RegisterInfo};
+use crate::cop0::nand::{NandDevice, NandImage};
+use crate::cop0::save::SaveMemory;
+use crate::ecc::{self, EccResult};
+use crate::snapshot::{Snapshot, SnapshotError, Snapshotter};
 use crate::types::*;
 
 use modular_bitfield::prelude::*;
 use soft_aes::aes::aes_dec_cbc;
+use thiserror::Error;
 
 use super::DramAddr;
 
@@ -183,15 +195,79 @@ pub struct ATBEntry {
 }
 
 impl ATBEntry {
-    fn end(&self) -> hword {
-        self.vaddr() + (1 << self.size())
+    // the block after the last one mapped; can be past the end of the 16 bit
+    // block numbers
+    fn end(&self) -> word {
+        self.vaddr() as word + (1 << self.size())
     }
+}
+
+// atb entry permission bits
+const ATB_PERM_DMA: u8 = 0x1;
+const ATB_PERM_CPU: u8 = 0x2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtbAccess {
+    Dma,
+    Cpu,
+}
 
-    fn end_block(&self) -> hword {
-        self.paddr() + (1 << self.size()) - 1
+impl fmt::Display for AtbAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Dma => write!(f, "dma"),
+            Self::Cpu => write!(f, "cpu"),
+        }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum AtbError {
+    #[error("no atb entry maps {0:08X}")]
+    Miss(word),
+    #[error("atb entry {1} doesn't allow {2} reads of {0:08X}")]
+    Denied(word, usize, AtbAccess),
+    #[error("atb entry {1} maps {0:08X} past the end of nand device {2}")]
+    OutOfRange(word, usize, u8),
+}
+
+// enough for code running from cartridge space to jump around without
+// decrypting blocks over and over
+const ATB_CACHE_BLOCKS: usize = 32;
+
+// a block the atb mapped: where it is in the nand, and the iv to decrypt it with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct AtbBlock {
+    offset: usize,
+    iv: [byte; 0x10],
+}
+
+pub const REGISTERS: &[RegisterInfo] = &[
+    register!("PI_DRAM_ADDR", 0x04600000, DramAddr),
+    register!("PI_CART_ADDR", 0x04600004, CartAddr),
+    register!("PI_RD_LEN", 0x04600008, TransferLen),
+    register!("PI_WR_LEN", 0x0460000C, TransferLen),
+    register!("PI_STATUS", 0x04600010, Status),
+    register!("PI_DOM1_LAT", 0x04600014, Latency),
+    register!("PI_DOM1_PWD", 0x04600018, PulseWidth),
+    register!("PI_DOM1_PGS", 0x0460001C, PageSize),
+    register!("PI_DOM1_RLS", 0x04600020, Release),
+    register!("PI_DOM2_LAT", 0x04600024, Latency),
+    register!("PI_DOM2_PWD", 0x04600028, PulseWidth),
+    register!("PI_DOM2_PGS", 0x0460002C, PageSize),
+    register!("PI_DOM2_RLS", 0x04600030, Release),
+    register!("PI_ATBU", 0x04600040),
+    register!("PI_FLASH_CTRL", 0x04600048, FlashCtrl),
+    register!("PI_FLASH_CONFIG", 0x0460004C, FlashConfig),
+    register!("PI_AES_CTRL", 0x04600050, AesCtrl),
+    register!("PI_ACCESS", 0x04600054, Access),
+    register!("PI_DMA_BUFFER_RD_LEN", 0x04600058, TransferLen),
+    register!("PI_DMA_BUFFER_WR_LEN", 0x0460005C, TransferLen),
+    register!("PI_GPIO", 0x04600060, Gpio),
+    register!("PI_IDE_CONFIG", 0x04600064, IDEConfig),
+    register!("PI_FLASH_ADDR", 0x04600070, FlashAddress),
+];
+
 #[derive(Debug)]
 pub struct Pi {
     dram_addr: DramAddr,
@@ -223,6 +299,9 @@ pub struct Pi {
     flash_single_error: bool,
     flash_interrupt: bool,
     flash_busy: bool,
+    flash_pending: FlashPending,
+    flash_page: [byte; NAND_PAGE_SIZE + NAND_SPARE_SIZE],
+    flash_status: byte,
 
     flash_config: FlashConfig,
     flash_cycle_end_time: byte,
@@ -230,6 +309,9 @@ pub struct Pi {
     aes_ctrl: AesCtrl,
     aes_interrupt: bool,
     aes_busy: bool,
+    // steps until the running decryption finishes
+    aes_timer: usize,
+    // the last ciphertext block of the previous run, for chaining
     last_block: [byte; 0x10],
 
     access: Access,
@@ -242,9 +324,44 @@ pub struct Pi {
     ide: [[byte; 4]; 4],
 
     atb: [ATBEntry; 192],
+    // decrypted blocks with the key they were decrypted with, most recently
+    // used last; not part of snapshots
+    atb_cache: VecDeque<(AtbBlock, [byte; 0x10], Vec<byte>)>,
 
     nand: Vec<byte>,
     spare: Vec<byte>,
+    nand_device: NandDevice,
+    // blocks changed since the host last wrote them back
+    nand_dirty: BTreeSet<usize>,
+    nand_faults: NandFaults,
+    fault_rng: FaultRng,
+
+    save: Option<SaveMemory>,
+    // the bytes changed since the host last wrote them back
+    save_dirty: Option<Range<usize>>,
+
+    card_present: bool,
+}
+
+// how long the aes engine takes for each 16 byte block; not measured
+const AES_STEPS_PER_BLOCK: usize = 16;
+
+// nand geometry; the spare file keeps one page's spare area per block
+const NAND_PAGE_SIZE: usize = 0x200;
+pub const NAND_SPARE_SIZE: usize = 0x10;
+pub const NAND_BLOCK_SIZE: usize = 0x4000;
+
+// nand status register bits
+const STATUS_FAIL: byte = 0x01;
+const STATUS_READY: byte = 0x40;
+const STATUS_NOT_PROTECTED: byte = 0x80;
+
+// the first half of a multi-cycle program or erase, waiting for its confirm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FlashPending {
+    None,
+    Program(usize),
+    Erase(usize),
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -256,14 +373,12 @@ pub enum Dma {
 }
 
 impl Pi {
-    pub fn new(mut nand: Vec<byte>, mut spare: Vec<byte>) -> Self {
-        let nand_size = if nand.len() < (96 * 1024 * 1024) {
-            64 * 1024 * 1024
-        } else {
-            128 * 1024 * 1024
-        };
-        nand.resize(nand_size, 0);
-        spare.resize(nand_size / 1024, 0);
+    pub fn new(image: NandImage) -> Self {
+        let NandImage {
+            data: nand,
+            spare,
+            device: nand_device,
+        } = image;
         Self {
             dram_addr: DramAddr::new(),
             cart_addr: CartAddr::new(),
@@ -294,6 +409,9 @@ impl Pi {
             flash_single_error: false,
             flash_interrupt: false,
             flash_busy: false,
+            flash_pending: FlashPending::None,
+            flash_page: [0xFF; NAND_PAGE_SIZE + NAND_SPARE_SIZE],
+            flash_status: STATUS_NOT_PROTECTED | STATUS_READY,
 
             flash_config: FlashConfig::new(),
             flash_cycle_end_time: 0,
@@ -301,6 +419,7 @@ impl Pi {
             aes_ctrl: AesCtrl::new(),
             aes_interrupt: false,
             aes_busy: false,
+            aes_timer: 0,
             last_block: [0; 0x10],
 
             access: Access::new(),
@@ -313,18 +432,175 @@ impl Pi {
             ide: [[0; 4]; 4],
 
             atb: [ATBEntry::new(); 192],
+            atb_cache: VecDeque::new(),
 
             nand,
             spare,
+            nand_device,
+            nand_dirty: BTreeSet::new(),
+            nand_faults: NandFaults::default(),
+            fault_rng: FaultRng::new(0),
+
+            save: None,
+            save_dirty: None,
+
+            card_present: true,
+        }
+    }
+
+    pub fn get_buffer(&self) -> &[byte] {
+        &self.buf
+    }
+
+    pub fn retrieve_nand(&self) -> NandImage {
+        NandImage {
+            data: self.nand.clone(),
+            spare: self.spare.clone(),
+            device: self.nand_device,
+        }
+    }
+
+    pub fn nand_device(&self) -> NandDevice {
+        self.nand_device
+    }
+
+    pub fn nand_faults(&self) -> &NandFaults {
+        &self.nand_faults
+    }
+
+    // marks the bad blocks straight away; the spare file isn't written back, so
+    // the marks only last as long as the faults are set
+    pub fn set_nand_faults(&mut self, faults: NandFaults) {
+        for block in &faults.bad_blocks {
+            let offset = block * NAND_SPARE_SIZE + BAD_BLOCK_BYTE;
+            if offset < self.spare.len() {
+                self.spare[offset] = 0x00;
+                println!("nand fault: block {block} marked bad");
+            }
+        }
+        self.fault_rng = FaultRng::new(faults.seed);
+        self.nand_faults = faults;
+    }
+
+    // blocks programmed or erased since the last call
+    pub fn take_dirty_blocks(&mut self) -> Vec<usize> {
+        take(&mut self.nand_dirty).into_iter().collect()
+    }
+
+    pub fn save_memory(&self) -> Option<&SaveMemory> {
+        self.save.as_ref()
+    }
+
+    pub fn set_save_memory(&mut self, save: Option<SaveMemory>) {
+        self.save = save;
+        self.save_dirty = None;
+    }
+
+    // the bytes of save memory written since the last call
+    pub fn take_dirty_save(&mut self) -> Option<Range<usize>> {
+        self.save_dirty.take()
+    }
+
+    // after a snapshot load, the blocks that differ from what was there before
+    fn mark_changed_blocks(&mut self, nand: &[byte], spare: &[byte]) {
+        for block in 0..self.nand.len() / NAND_BLOCK_SIZE {
+            let (data, spare_data) = self.nand_block(block);
+            let data_range = block * NAND_BLOCK_SIZE..(block + 1) * NAND_BLOCK_SIZE;
+            let spare_range = block * NAND_SPARE_SIZE..(block + 1) * NAND_SPARE_SIZE;
+            if nand.get(data_range) != Some(data) || spare.get(spare_range) != Some(spare_data) {
+                self.nand_dirty.insert(block);
+            }
         }
     }
 
-    pub fn retrieve_nand(&self) -> Vec<byte> {
-        self.nand.clone()
+    // likewise for the save memory, which may not have been there at all
+    fn mark_changed_save(&mut self, previous: Option<&[byte]>) {
+        let Some(save) = &self.save else {
+            return;
+        };
+        let differs =
+            |offset: &usize| previous.and_then(|p| p.get(*offset)) != Some(&save.data[*offset]);
+        let Some(start) = (0..save.data.len()).find(differs) else {
+            return;
+        };
+        let end = (0..save.data.len()).rfind(differs).unwrap() + 1;
+        self.save_dirty = Some(match self.save_dirty.take() {
+            Some(dirty) => dirty.start.min(start)..dirty.end.max(end),
+            None => start..end,
+        });
     }
 
-    pub fn retrieve_spare(&self) -> Vec<byte> {
-        self.spare.clone()
+    // a block's data and spare area
+    pub fn nand_block(&self, block: usize) -> (&[byte], &[byte]) {
+        let data = block * NAND_BLOCK_SIZE;
+        let spare = block * NAND_SPARE_SIZE;
+        (
+            &self.nand[data..data + NAND_BLOCK_SIZE],
+            &self.spare[spare..spare + NAND_SPARE_SIZE],
+        )
+    }
+
+    pub fn set_nand_block(&mut self, block: usize, data: &[byte], spare: &[byte]) {
+        let offset = block * NAND_BLOCK_SIZE;
+        self.nand[offset..offset + NAND_BLOCK_SIZE].copy_from_slice(data);
+        let offset = block * NAND_SPARE_SIZE;
+        self.spare[offset..offset + NAND_SPARE_SIZE].copy_from_slice(spare);
+        self.nand_dirty.insert(block);
+        self.atb_cache.clear();
+    }
+
+    pub fn registers(&self) -> Vec<(&'static str, word)> {
+        vec![
+            ("PI_DRAM_ADDR", self.dram_addr.into()),
+            ("PI_CART_ADDR", self.cart_addr.into()),
+            ("PI_RD_LEN", self.read_len.into()),
+            ("PI_WR_LEN", self.write_len.into()),
+            (
+                "PI_STATUS",
+                self.status
+                    .with_reset(self.dma_busy)
+                    .with_clear(self.io_busy)
+                    .with_error(self.error)
+                    .into(),
+            ),
+            ("PI_DOM1_LAT", self.dom1_latency.into()),
+            ("PI_DOM1_PWD", self.dom1_pulse_width.into()),
+            ("PI_DOM1_PGS", self.dom1_page_size.into()),
+            ("PI_DOM1_RLS", self.dom1_release.into()),
+            ("PI_DOM2_LAT", self.dom2_latency.into()),
+            ("PI_DOM2_PWD", self.dom2_pulse_width.into()),
+            ("PI_DOM2_PGS", self.dom2_page_size.into()),
+            ("PI_DOM2_RLS", self.dom2_release.into()),
+            ("PI_ATBU", u32::from_be_bytes(self.atbu)),
+            (
+                "PI_FLASH_CTRL",
+                self.flash_ctrl
+                    .with_multi_cycle(self.flash_double_error)
+                    .with_ecc(self.flash_single_error)
+                    .with_interrupt(self.flash_interrupt)
+                    .with_run(self.flash_busy)
+                    .into(),
+            ),
+            (
+                "PI_FLASH_CONFIG",
+                self.flash_config
+                    .with_end_of_cycle_time(self.flash_cycle_end_time)
+                    .into(),
+            ),
+            (
+                "PI_AES_CTRL",
+                self.aes_ctrl
+                    .with_interrupt(self.aes_interrupt)
+                    .with_run(self.aes_busy)
+                    .into(),
+            ),
+            ("PI_ACCESS", self.access.into()),
+            ("PI_DMA_BUFFER_RD_LEN", self.buffer_read_len.into()),
+            ("PI_DMA_BUFFER_WR_LEN", self.buffer_write_len.into()),
+            ("PI_GPIO", self.gpio.into()),
+            ("PI_IDE_CONFIG", self.ide_config.into()),
+            ("PI_FLASH_ADDR", self.flash_addr.into()),
+        ]
     }
 
     pub fn dma_queued(&self) -> bool {
@@ -363,94 +639,185 @@ impl Pi {
         self.flash_ctrl.run()
     }
 
+    pub fn card_present(&self) -> bool {
+        self.card_present
+    }
+
+    pub fn set_card_present(&mut self, present: bool) {
+        self.card_present = present;
+    }
+
     pub fn clear_flash_command(&mut self) {
         self.flash_ctrl.set_run(false);
     }
 
-    pub fn bus_read(&self, address: word, length: word) -> Vec<byte> {
+    // none if the transfer failed
+    pub fn bus_read(&mut self, address: word, length: word) -> Option<Vec<byte>> {
+        let end = self.bus_end(address, length)?;
         match address {
-            0x00000000..=0x04FFFFFF => match address {
-                _ => unimplemented!("pi bus domain 1 read {address:08X}"),
-            },
-            0x05000000..=0x05FFFFFF => match address {
-                _ => unimplemented!("pi bus domain 2 read {address:08X}"),
-            },
-            0x06000000..=0x07FFFFFF => match address {
-                _ => unimplemented!("pi bus domain 1 read {address:08X}"),
-            },
-            0x08000000..=0x0FFFFFFF => match address {
-                _ => unimplemented!("pi bus domain 2 read {address:08X}"),
-            },
+            0x05000000..=0x0FFFFFFF if end <= 0x10000000 => Some(
+                (address..end)
+                    .map(|address| self.read_cart_phys_addr(address))
+                    .collect(),
+            ),
             0x10000000..=0xFFFFFFFF => {
                 let mut rv = vec![];
 
                 let mut cur_address = address & !0x3FFF;
 
                 println!("atb read {address:08X}:{length:08X}");
-                while cur_address < address + length {
-                    println!("atb: {cur_address:08X}");
-                    let (block_offset, iv) = self.atb_addr_to_block(cur_address);
-
-                    println!("block offset = {block_offset:08X}");
-
-                    let iv = iv.try_into().unwrap();
-                    let key = &self.buf[0x4C0..0x4D0];
-
-                    let enc = &self.nand[block_offset..block_offset + 0x4000];
-                    //println!("key: {key:02X?}, iv: {iv:02X?}");
-                    let dec = aes_dec_cbc(enc, key, &iv, None).expect("decryption failed");
+                while cur_address < end {
+                    let block = match self.atb_lookup(cur_address, AtbAccess::Dma) {
+                        Ok(block) => block,
+                        Err(e) => {
+                            // the dma stops and the error shows up in the status
+                            println!("pi dma: {e}");
+                            self.error = true;
+                            return None;
+                        }
+                    };
+                    let dec = self.atb_decrypt(&block);
 
                     let block_start = (address.max(cur_address) & 0x3FFF) as usize;
-                    let block_end =
-                        ((address + length - 1).min(cur_address + 0x3FFF) & 0x3FFF) as usize + 1;
+                    let block_end = ((end - 1).min(cur_address + 0x3FFF) & 0x3FFF) as usize + 1;
 
-                    println!("{block_start:08X}, {block_end:08X}");
                     rv.extend(&dec[block_start..block_end]);
 
                     cur_address += 0x4000;
                 }
 
-                println!("len(rv) = {:08X}", rv.len());
-
-                rv
+                Some(rv)
+            }
+            _ => {
+                self.bus_error(address, length);
+                None
             }
         }
     }
 
     pub fn bus_write(&mut self, address: word, length: word, data: &[byte]) {
+        let Some(end) = self.bus_end(address, length) else {
+            return;
+        };
         match address {
-            0x00000000..=0x04FFFFFF => match address {
-                _ => unimplemented!("pi bus domain 1 read {address:08X}"),
-            },
-            0x05000000..=0x05FFFFFF => match address {
-                _ => unimplemented!("pi bus domain 2 read {address:08X}"),
-            },
-            0x06000000..=0x07FFFFFF => match address {
-                _ => unimplemented!("pi bus domain 1 read {address:08X}"),
-            },
-            0x08000000..=0x0FFFFFFF => match address {
-                _ => unimplemented!("pi bus domain 2 read {address:08X}"),
-            },
-            0x10000000..=0xFFFFFFFF => match address {
-                _ => unimplemented!("pi bus domain 1 read {address:08X}"),
-            },
-        }
-    }
-
-    pub fn buf_read(&self, address: word, length: word) -> &[byte] {
-        match address {
-            0x00..=0x3FF => &self.buf[address as usize..(address + length) as usize],
-            _ => unimplemented!("pi buf read {address:08X}"),
+            0x05000000..=0x0FFFFFFF if end <= 0x10000000 => {
+                for (address, val) in (address..end).zip(data) {
+                    self.write_cart_phys_addr(address, *val);
+                }
+            }
+            0x10000000..=0xFFFFFFFF => {
+                // the atb only maps nand for reading
+                println!("pi dma: can't write {length:#X} bytes to atb window {address:08X}");
+                self.error = true;
+            }
+            _ => self.bus_error(address, length),
         }
     }
 
+    // where a transfer ends, or none if it runs off the end of the bus
+    fn bus_end(&mut self, address: word, length: word) -> Option<word> {
+        let end = address.checked_add(length);
+        if end.is_none() {
+            println!("pi dma: {length:#X} bytes at {address:08X} run past the end of the bus");
+            self.error = true;
+        }
+        end
+    }
+
+    // the pi only reaches the cartridge windows; rdram and the rcp registers
+    // below them aren't on its bus
+    fn bus_error(&mut self, address: word, length: word) {
+        println!("pi dma: no cartridge window for {length:#X} bytes at {address:08X}");
+        self.error = true;
+    }
+
+    // cartridge space outside the atb window: the save memory where there is
+    // some, and open bus, which reads back the low half of the address,
+    // everywhere else
+    pub fn read_cart_phys_addr(&self, address: word) -> byte {
+        self.peek_cart(address)
+            .unwrap_or(((address & 0xFFFC) >> (8 * (!address & 1))) as byte)
+    }
+
+    // the save memory alone, for the debugger
+    pub fn peek_cart(&self, address: word) -> Option<byte> {
+        let save = self.save.as_ref()?;
+        Some(save.data[save.offset(address)?])
+    }
+
+    pub fn write_cart_phys_addr(&mut self, address: word, val: byte) {
+        let Some(save) = &mut self.save else {
+            return;
+        };
+        let Some(offset) = save.offset(address) else {
+            return;
+        };
+        save.data[offset] = val;
+        self.save_dirty = Some(match self.save_dirty.take() {
+            Some(dirty) => dirty.start.min(offset)..dirty.end.max(offset + 1),
+            None => offset..offset + 1,
+        });
+    }
+
+    // how many steps the queued dma takes on the cartridge bus, counting a step
+    // as one cycle: each page the transfer touches costs the domain's latency,
+    // and each halfword its pulse width and release. the atb window is served
+    // from nand rather than a cartridge device, and buffer dmas stay inside
+    // the pi, so those take a single step
+    pub fn dma_steps(&self) -> usize {
+        let (address, len) = match self.dma_params() {
+            Dma::Read(_, address, len) | Dma::Write(_, address, len) => (address, len),
+            Dma::BufRead(..) | Dma::BufWrite(..) => return 1,
+        };
+        let (latency, pulse_width, page_size, release) = match address {
+            0x05000000..=0x05FFFFFF | 0x08000000..=0x0FFFFFFF => (
+                self.dom2_latency.latency(),
+                self.dom2_pulse_width.width(),
+                self.dom2_page_size.width(),
+                self.dom2_release.width(),
+            ),
+            0x06000000..=0x07FFFFFF => (
+                self.dom1_latency.latency(),
+                self.dom1_pulse_width.width(),
+                self.dom1_page_size.width(),
+                self.dom1_release.width(),
+            ),
+            _ => return 1,
+        };
+
+        // the transfer fails on the bus if it runs past the end
+        let Some(last) = len.checked_sub(1).and_then(|l| address.checked_add(l)) else {
+            return 1;
+        };
+
+        let page = 1usize << (page_size + 2);
+        let first = address as usize / page;
+        let last = last as usize / page;
+        let halfwords = (len as usize).div_ceil(2);
+        (last - first + 1) * (latency as usize + 1)
+            + halfwords * (pulse_width as usize + 1 + release as usize + 1)
+    }
+
+    // none if the transfer doesn't fit in the buffer
+    pub fn buf_read(&mut self, address: word, length: word) -> Option<&[byte]> {
+        let range = self.buf_range(address, length)?;
+        Some(&self.buf[range])
+    }
+
     pub fn buf_write(&mut self, address: word, length: word, data: &[byte]) {
-        match address {
-            0x00..=0x3FF => {
-                self.buf[address as usize..(address + length) as usize].copy_from_slice(data)
-            }
-            _ => unimplemented!("pi bus domain 1 read {address:08X}"),
+        if let Some(range) = self.buf_range(address, length) {
+            self.buf[range].copy_from_slice(data);
+        }
+    }
+
+    fn buf_range(&mut self, address: word, length: word) -> Option<Range<usize>> {
+        let range = address as usize..address as usize + length as usize;
+        if address > 0x3FF || range.end > self.buf.len() {
+            println!("pi dma: {length:#X} bytes at {address:08X} don't fit in the buffer");
+            self.error = true;
+            return None;
         }
+        Some(range)
     }
 
     pub fn read_phys_addr(&mut self, address: word) -> byte {
@@ -587,6 +954,9 @@ impl Pi {
 
             0x04600010..=0x04600013 => {
                 self.status = merge_byte(self.status.into(), address, val).into();
+                if self.status.reset() {
+                    self.error = false;
+                }
                 self.status.set_error(false);
             }
 
@@ -630,37 +1000,9 @@ impl Pi {
 
             0x04600048..=0x0460004B => {
                 self.flash_ctrl = merge_byte(self.flash_ctrl.into(), address, val).into();
-                if (address & 3) == 3 {
+                if (address & 3) == 3 && self.flash_ctrl.run() {
                     //println!("{:#X?}\n{:08X}", self.flash_ctrl, self.flash_addr.addr());
-                    if self.flash_ctrl.run() {
-                        match self.flash_ctrl.command() {
-                            0x00 => {
-                                //self.flash_busy = true;
-                                let nand_addr = self.flash_addr.addr() as usize;
-                                let spare_addr = (nand_addr / 0x4000) * 0x10;
-                                //println!("nand: {nand_addr:08X}, spare: {spare_addr:08X}");
-                                let buf_addr = (self.flash_ctrl.buf() as usize) * 0x200;
-                                let oob_addr = 0x400 + (self.flash_ctrl.buf() as usize) * 0x10;
-                                self.buf[buf_addr..buf_addr + 0x200]
-                                    .copy_from_slice(&self.nand[nand_addr..nand_addr + 0x200]);
-                                self.buf[oob_addr..oob_addr + 0x10]
-                                    .copy_from_slice(&self.spare[spare_addr..spare_addr + 0x10]);
-                            }
-
-                            0x90 => {
-                                let buf_addr = (self.flash_ctrl.buf() as usize) * 0x200;
-                                self.buf[buf_addr..buf_addr + 4]
-                                    .copy_from_slice(&[0xEC, 0x76, 0x00, 0x00]);
-                            }
-
-                            _ => {
-                                unimplemented!(
-                                    "unimplemented flash command: {:02X}",
-                                    self.flash_ctrl.command()
-                                )
-                            }
-                        }
-                    }
+                    self.run_flash_command();
                 }
             }
 
@@ -669,33 +1011,18 @@ impl Pi {
             }
 
             0x04600050..=0x04600053 => {
+                if self.aes_busy {
+                    if (address & 3) == 3 {
+                        println!("pi aes: PI_AES_CTRL written while busy; ignored");
+                    }
+                    return;
+                }
                 self.aes_ctrl = merge_byte(self.aes_ctrl.into(), address, val).into();
                 if (address & 3) == 3 {
-                    //println!("{:#X?}", self.aes_ctrl);
-
+                    // any write acknowledges the last run's interrupt
+                    self.aes_interrupt = false;
                     if self.aes_ctrl.run() {
-                        //self.aes_busy = true;
-                        let iv = if self.aes_ctrl.chain() {
-                            &self.last_block
-                        } else {
-                            let iv = self.aes_ctrl.iv() as usize;
-                            &self.buf[iv * 16..(iv + 1) * 16]
-                        }
-                        .try_into()
-                        .unwrap();
-                        let key = &self.buf[0x4C0..0x4D0];
-                        let enc_offset = self.aes_ctrl.data() as usize;
-                        let enc_len = self.aes_ctrl.len() as usize;
-                        let enc = &self.buf[enc_offset * 16..(enc_offset + enc_len + 1) * 16];
-                        //println!("key: {key:02X?}, iv: {iv:02X?}");
-                        let dec = aes_dec_cbc(enc, key, iv, None).expect("decryption failed");
-
-                        self.last_block.copy_from_slice(
-                            &enc[(enc_offset + enc_len) * 16..(enc_offset + enc_len + 1) * 16],
-                        );
-
-                        self.buf[enc_offset * 16..(enc_offset + enc_len + 1) * 16]
-                            .copy_from_slice(&dec);
+                        self.start_aes();
                     }
                 }
             }
@@ -736,7 +1063,14 @@ impl Pi {
                 self.flash_addr = merge_byte(self.flash_addr.into(), address, val).into()
             }
 
-            0x04610000..=0x046104FF => self.buf[(address - 0x04610000) as usize] = val,
+            0x04610000..=0x046104FF => {
+                let offset = (address - 0x04610000) as usize;
+                self.buf[offset] = val;
+                // the aes key and iv
+                if (0x4C0..0x4E0).contains(&offset) {
+                    self.atb_cache.clear();
+                }
+            }
 
             0x04610500..=0x046107FF => {
                 let atb_index = ((address - 0x04610500) / 4) as usize;
@@ -745,6 +1079,7 @@ impl Pi {
                     | ((u32::from_be_bytes(self.atbu) as u64) << 32))
                     .into();
                 self.atb[atb_index] = entry;
+                self.atb_cache.clear();
                 if address & 3 == 3 {
                     println!("atb index {atb_index} = {entry:#X?}");
                 }
@@ -766,44 +1101,1042 @@ impl Pi {
         }
     }
 
-    fn atb_addr_to_block(&self, address: word) -> (usize, &[u8]) {
-        let block_vaddr = (address >> 14) as hword;
+    // the iv and data of the run in the control register, if they're in the
+    // buffer
+    fn aes_ranges(&self) -> Option<(usize, usize, usize)> {
+        let iv = self.aes_ctrl.iv() as usize * 0x10;
+        let data = self.aes_ctrl.data() as usize * 0x10;
+        let len = (self.aes_ctrl.len() as usize + 1) * 0x10;
+        let fits = |start: usize, len: usize| start + len <= self.buf.len();
+        (fits(data, len) && (self.aes_ctrl.chain() || fits(iv, 0x10))).then_some((iv, data, len))
+    }
+
+    // checks the run's ranges and starts the engine; the decryption happens
+    // when it finishes
+    fn start_aes(&mut self) {
+        match self.aes_ranges() {
+            Some((_, _, len)) => {
+                self.aes_busy = true;
+                self.aes_timer = len / 0x10 * AES_STEPS_PER_BLOCK;
+            }
+            None => {
+                println!(
+                    "pi aes: iv {:#X} or {:#X} bytes of data at {:#X} is outside the buffer",
+                    self.aes_ctrl.iv() as usize * 0x10,
+                    (self.aes_ctrl.len() as usize + 1) * 0x10,
+                    self.aes_ctrl.data() as usize * 0x10
+                );
+                self.error = true;
+            }
+        }
+    }
+
+    // advances a running decryption by one step
+    pub fn step_aes(&mut self) {
+        if !self.aes_busy {
+            return;
+        }
+        self.aes_timer = self.aes_timer.saturating_sub(1);
+        if self.aes_timer > 0 {
+            return;
+        }
+
+        // the ranges were checked when the run started, and the control
+        // register can't change while it's busy
+        let (iv, data, len) = self.aes_ranges().unwrap();
+        let iv: [byte; 0x10] = if self.aes_ctrl.chain() {
+            self.last_block
+        } else {
+            self.buf[iv..iv + 0x10].try_into().unwrap()
+        };
+        let key = &self.buf[0x4C0..0x4D0];
+        let enc = &self.buf[data..data + len];
+        let dec = aes_dec_cbc(enc, key, &iv, None).expect("decryption failed");
+
+        self.last_block.copy_from_slice(&enc[len - 0x10..]);
+        self.buf[data..data + len].copy_from_slice(&dec);
+
+        self.aes_busy = false;
+        self.aes_interrupt = self.aes_ctrl.interrupt();
+    }
+
+    pub fn aes_interrupt(&self) -> bool {
+        self.aes_interrupt
+    }
+
+    // where byte `offset` of a page (with its spare area after it) lives in the
+    // buffer selected by the flash control register
+    fn flash_buf_offset(&self, offset: usize) -> usize {
+        let buf = self.flash_ctrl.buf() as usize;
+        if offset < NAND_PAGE_SIZE {
+            buf * NAND_PAGE_SIZE + offset
+        } else {
+            0x400 + buf * NAND_SPARE_SIZE + (offset - NAND_PAGE_SIZE)
+        }
+    }
+
+    // moves the data phase between the buffer and `flash_page`, starting at
+    // `column` and stopping at the end of the spare area
+    fn flash_data_phase(&mut self, column: usize, to_buf: bool) {
+        let end = (column + self.flash_ctrl.data_phase_size() as usize).min(self.flash_page.len());
+        for offset in column..end {
+            let buf_offset = self.flash_buf_offset(offset);
+            if to_buf {
+                self.buf[buf_offset] = self.flash_page[offset];
+            } else {
+                self.flash_page[offset] = self.buf[buf_offset];
+            }
+        }
+    }
+
+    // the page containing the flash address on the selected chip, and its
+    // block's spare area
+    fn flash_page_addrs(&self) -> (usize, usize) {
+        let chip_size = self.nand_device.chip.size();
+        let page = self.flash_ctrl.device_id() as usize * chip_size
+            + (self.flash_addr.addr() as usize & !(NAND_PAGE_SIZE - 1)) % chip_size;
+        (page, page / NAND_BLOCK_SIZE * NAND_SPARE_SIZE)
+    }
 
-        let entry = self
+    // only the first page of each block has its own spare area in the spare
+    // file, so the others get one with a code matching their data; the ecc
+    // check can't see damage already in those pages, only flips made on read
+    fn load_flash_page(&mut self) {
+        let (page, spare) = self.flash_page_addrs();
+        let (data, oob) = self.flash_page.split_at_mut(NAND_PAGE_SIZE);
+        data.copy_from_slice(&self.nand[page..page + NAND_PAGE_SIZE]);
+        oob.copy_from_slice(&self.spare[spare..spare + NAND_SPARE_SIZE]);
+        if page % NAND_BLOCK_SIZE != 0 {
+            ecc::write_page(data, oob);
+        }
+    }
+
+    // flips the bits the faults ask for in the page just read
+    fn inject_read_faults(&mut self) {
+        let (page, _) = self.flash_page_addrs();
+        for (address, bit) in &self.nand_faults.flips {
+            if (page..page + NAND_PAGE_SIZE).contains(address) {
+                self.flash_page[address - page] ^= 1 << bit;
+                println!("nand fault: flipped bit {bit} of byte {address:08X} on read");
+            }
+        }
+        if self.fault_rng.chance(self.nand_faults.flip_rate) {
+            let bit = self.fault_rng.below(NAND_PAGE_SIZE * 8);
+            self.flash_page[bit / 8] ^= 1 << (bit % 8);
+            println!(
+                "nand fault: flipped bit {} of byte {:08X} on read",
+                bit % 8,
+                page + bit / 8
+            );
+        }
+    }
+
+    fn flash_fails(&mut self, block: usize, operation: &str) -> bool {
+        let fails =
+            self.nand_faults.fails(block) || self.fault_rng.chance(self.nand_faults.fail_rate);
+        if fails {
+            println!("nand fault: {operation} of block {block} failed");
+        }
+        fails
+    }
+
+    // corrects the page in place, reporting errors in the control register
+    fn check_flash_ecc(&mut self) {
+        let (data, oob) = self.flash_page.split_at_mut(NAND_PAGE_SIZE);
+        let results = ecc::correct_page(data, oob);
+        self.flash_single_error = results
+            .iter()
+            .any(|result| matches!(result, EccResult::Corrected(..) | EccResult::CodeError));
+        self.flash_double_error = results.contains(&EccResult::Uncorrectable);
+    }
+
+    // the whole command, including any address and data phases, happens at once;
+    // only the interrupt is delayed
+    fn run_flash_command(&mut self) {
+        let read = self.flash_ctrl.read_data_phase();
+        let write = self.flash_ctrl.write_data_phase();
+        let column = self.flash_addr.addr() as usize & (NAND_PAGE_SIZE - 1);
+
+        if !self.card_present || self.flash_ctrl.device_id() as usize >= self.nand_device.chips {
+            // nothing drives the bus with the card pulled, or for a chip the
+            // card doesn't have
+            if read {
+                self.flash_page.fill(0xFF);
+                self.flash_data_phase(0, true);
+            }
+            return;
+        }
+
+        let pending = self.flash_pending;
+        self.flash_pending = FlashPending::None;
+
+        match self.flash_ctrl.command() {
+            // read the first half of a page, the second half, or the spare area
+            command @ (0x00 | 0x01 | 0x50) => {
+                self.load_flash_page();
+                self.inject_read_faults();
+                let column = match command {
+                    0x00 => column,
+                    0x01 => 0x100 + (column & 0xFF),
+                    _ => NAND_PAGE_SIZE + (column & 0xF),
+                };
+                if self.flash_ctrl.ecc() {
+                    self.check_flash_ecc();
+                }
+                if read {
+                    self.flash_data_phase(column, true);
+                }
+            }
+
+            // page program: load the data, then program it on 0x10
+            0x80 => {
+                let (page, _) = self.flash_page_addrs();
+                self.flash_page.fill(0xFF);
+                if write {
+                    self.flash_data_phase(column, false);
+                }
+                if self.flash_ctrl.ecc() {
+                    let (data, oob) = self.flash_page.split_at_mut(NAND_PAGE_SIZE);
+                    ecc::write_page(data, oob);
+                }
+                self.flash_pending = FlashPending::Program(page);
+            }
+
+            0x10 => {
+                let FlashPending::Program(page) = pending else {
+                    self.flash_status |= STATUS_FAIL;
+                    return self.flash_command_done();
+                };
+                if self.flash_fails(page / NAND_BLOCK_SIZE, "program") {
+                    self.flash_status |= STATUS_FAIL;
+                    self.flash_wait_ready();
+                    return self.flash_command_done();
+                }
+                // programming can only clear bits
+                for (byte, new) in self.nand[page..page + NAND_PAGE_SIZE]
+                    .iter_mut()
+                    .zip(&self.flash_page[..NAND_PAGE_SIZE])
+                {
+                    *byte &= new;
+                }
+                if page % NAND_BLOCK_SIZE == 0 {
+                    let spare = page / NAND_BLOCK_SIZE * NAND_SPARE_SIZE;
+                    for (byte, new) in self.spare[spare..spare + NAND_SPARE_SIZE]
+                        .iter_mut()
+                        .zip(&self.flash_page[NAND_PAGE_SIZE..])
+                    {
+                        *byte &= new;
+                    }
+                }
+                self.nand_dirty.insert(page / NAND_BLOCK_SIZE);
+                self.atb_cache.clear();
+                self.flash_status &= !STATUS_FAIL;
+                self.flash_wait_ready();
+            }
+
+            // block erase: latch the block, then erase it on 0xD0
+            0x60 => {
+                let (page, _) = self.flash_page_addrs();
+                self.flash_pending = FlashPending::Erase(page & !(NAND_BLOCK_SIZE - 1));
+            }
+
+            0xD0 => {
+                let FlashPending::Erase(block) = pending else {
+                    self.flash_status |= STATUS_FAIL;
+                    return self.flash_command_done();
+                };
+                if self.flash_fails(block / NAND_BLOCK_SIZE, "erase") {
+                    self.flash_status |= STATUS_FAIL;
+                    self.flash_wait_ready();
+                    return self.flash_command_done();
+                }
+                self.nand[block..block + NAND_BLOCK_SIZE].fill(0xFF);
+                let spare = block / NAND_BLOCK_SIZE * NAND_SPARE_SIZE;
+                self.spare[spare..spare + NAND_SPARE_SIZE].fill(0xFF);
+                self.nand_dirty.insert(block / NAND_BLOCK_SIZE);
+                self.atb_cache.clear();
+                self.flash_status &= !STATUS_FAIL;
+                self.flash_wait_ready();
+            }
+
+            0x70 => {
+                if read {
+                    let status = self.flash_status;
+                    self.flash_page.fill(status);
+                    self.flash_data_phase(0, true);
+                }
+                // busy only shows up once, for code that polls instead of waiting
+                self.flash_status |= STATUS_READY;
+            }
+
+            0x90 => {
+                if read {
+                    self.flash_page.fill(0x00);
+                    self.flash_page[..2].copy_from_slice(&self.nand_device.chip.id());
+                    self.flash_data_phase(0, true);
+                }
+            }
+
+            0xFF => {
+                self.flash_status = STATUS_NOT_PROTECTED | STATUS_READY;
+            }
+
+            // a command the chip doesn't know does nothing but fail
+            command => {
+                println!("pi flash: unknown command {command:02X}; failed");
+                self.flash_status |= STATUS_FAIL;
+            }
+        }
+
+        self.flash_command_done();
+    }
+
+    // a program or erase that doesn't wait for the chip leaves it busy
+    fn flash_wait_ready(&mut self) {
+        if !self.flash_ctrl.wait_ready() {
+            self.flash_status &= !STATUS_READY;
+        }
+    }
+
+    // the chip only keeps the first half of a command around if the
+    // controller says another cycle follows
+    fn flash_command_done(&mut self) {
+        if !self.flash_ctrl.multi_cycle() {
+            self.flash_pending = FlashPending::None;
+        }
+    }
+
+    // finds the entry mapping the 16KB block at `address`, returning where the
+    // block is in the nand and the iv it decrypts with; entries are searched in
+    // order and the first match wins
+    fn atb_lookup(&self, address: word, access: AtbAccess) -> Result<AtbBlock, AtbError> {
+        let block_vaddr = address >> 14;
+
+        let (index, entry) = self
             .atb
             .iter()
             .enumerate()
-            .find(|(_, e)| block_vaddr >= e.vaddr() && block_vaddr < e.end());
+            .find(|(_, e)| (e.vaddr() as word..e.end()).contains(&block_vaddr))
+            .ok_or(AtbError::Miss(address))?;
+
+        let allowed = match access {
+            AtbAccess::Dma => ATB_PERM_DMA,
+            AtbAccess::Cpu => ATB_PERM_CPU,
+        };
+        if entry.perm() & allowed == 0 {
+            return Err(AtbError::Denied(address, index, access));
+        }
+
+        let offset = self
+            .atb_block_offset(entry, block_vaddr - entry.vaddr() as word)
+            .ok_or(AtbError::OutOfRange(address, index, entry.dev()))?;
+
+        // blocks are cbc encrypted as one stream, so each block's iv is the end
+        // of the block before it; the first block of an entry with the iv bit
+        // set (or of the first entry) starts the stream with the iv in the buffer
+        let chained = if block_vaddr != entry.vaddr() as word {
+            Some(offset - NAND_BLOCK_SIZE)
+        } else if entry.iv() || index == 0 {
+            None
+        } else {
+            let prev_entry = &self.atb[index - 1];
+            let prev_block = prev_entry.end() - 1 - prev_entry.vaddr() as word;
+            Some(
+                self.atb_block_offset(prev_entry, prev_block)
+                    .ok_or(AtbError::OutOfRange(address, index - 1, prev_entry.dev()))?,
+            )
+        };
+        let iv = match chained {
+            Some(prev) => &self.nand[prev + NAND_BLOCK_SIZE - 0x10..prev + NAND_BLOCK_SIZE],
+            None => &self.buf[0x4D0..0x4E0],
+        };
+
+        Ok(AtbBlock {
+            offset,
+            iv: iv.try_into().unwrap(),
+        })
+    }
 
-        // for now, assume this always succeeds (not a valid assumption in general)
-        let (index, entry) = entry.unwrap();
+    // where block `block` of an entry is, on the chip its dev field selects
+    fn atb_block_offset(&self, entry: &ATBEntry, block: word) -> Option<usize> {
+        let dev = entry.dev() as usize;
+        if dev >= self.nand_device.chips {
+            return None;
+        }
+        let offset = dev * self.nand_device.chip.size()
+            + (entry.paddr() as usize + block as usize) * NAND_BLOCK_SIZE;
+        (offset + NAND_BLOCK_SIZE <= (dev + 1) * self.nand_device.chip.size()).then_some(offset)
+    }
 
-        // should always succeed if above did
-        let prev_entry = &self.atb[index - 1];
+    fn atb_decrypt(&mut self, block: &AtbBlock) -> &[byte] {
+        let key: [byte; 0x10] = self.buf[0x4C0..0x4D0].try_into().unwrap();
+        let cached = self
+            .atb_cache
+            .iter()
+            .position(|(b, k, _)| b == block && *k == key);
+
+        if let Some(index) = cached {
+            let entry = self.atb_cache.remove(index).unwrap();
+            self.atb_cache.push_back(entry);
+        } else {
+            let enc = &self.nand[block.offset..block.offset + NAND_BLOCK_SIZE];
+            //println!("key: {key:02X?}, iv: {iv:02X?}");
+            let dec = aes_dec_cbc(enc, &key, &block.iv, None).expect("decryption failed");
+            if self.atb_cache.len() == ATB_CACHE_BLOCKS {
+                self.atb_cache.pop_front();
+            }
+            self.atb_cache.push_back((*block, key, dec));
+        }
 
-        let block_offset = (((block_vaddr - entry.vaddr()) + entry.paddr()) as usize) << 14;
+        &self.atb_cache.back().unwrap().2
+    }
 
-        let prev_block_offset = (prev_entry.end_block() as usize) << 14;
+    pub fn read_atb_phys_addr(&mut self, address: word) -> Result<byte, AtbError> {
+        let block = self.atb_lookup(address, AtbAccess::Cpu)?;
+        Ok(self.atb_decrypt(&block)[(address & 0x3FFF) as usize])
+    }
+}
 
-        let iv = if prev_entry.iv() {
-            &self.buf[0x4D0..0x4E0]
+impl Snapshot for Pi {
+    fn snapshot(&mut self, s: &mut Snapshotter) -> Result<(), SnapshotError> {
+        s.section("PI")?;
+        s.bits(&mut self.dram_addr)?;
+        s.bits(&mut self.cart_addr)?;
+        s.bits(&mut self.read_len)?;
+        s.bits(&mut self.write_len)?;
+        s.bits(&mut self.buffer_read_len)?;
+        s.bits(&mut self.buffer_write_len)?;
+        s.bits(&mut self.status)?;
+        s.bool(&mut self.dma_busy)?;
+        s.bool(&mut self.io_busy)?;
+        s.bool(&mut self.error)?;
+        s.bits(&mut self.dom1_latency)?;
+        s.bits(&mut self.dom1_pulse_width)?;
+        s.bits(&mut self.dom1_page_size)?;
+        s.bits(&mut self.dom1_release)?;
+        s.bits(&mut self.dom2_latency)?;
+        s.bits(&mut self.dom2_pulse_width)?;
+        s.bits(&mut self.dom2_page_size)?;
+        s.bits(&mut self.dom2_release)?;
+        s.bytes(&mut self.atbu)?;
+        s.bits(&mut self.flash_ctrl)?;
+        s.bool(&mut self.flash_double_error)?;
+        s.bool(&mut self.flash_single_error)?;
+        s.bool(&mut self.flash_interrupt)?;
+        s.bool(&mut self.flash_busy)?;
+        let (mut pending, mut pending_addr) = match self.flash_pending {
+            FlashPending::None => (0u8, 0),
+            FlashPending::Program(page) => (1, page),
+            FlashPending::Erase(block) => (2, block),
+        };
+        s.value(&mut pending)?;
+        s.usize(&mut pending_addr)?;
+        self.flash_pending = match pending {
+            0 => FlashPending::None,
+            1 => FlashPending::Program(pending_addr),
+            2 => FlashPending::Erase(pending_addr),
+            _ => return Err(SnapshotError::Invalid("unknown pending flash command")),
+        };
+        s.bytes(&mut self.flash_page)?;
+        s.value(&mut self.flash_status)?;
+        s.value(&mut self.fault_rng.0)?;
+        s.bits(&mut self.flash_config)?;
+        s.value(&mut self.flash_cycle_end_time)?;
+        s.bits(&mut self.aes_ctrl)?;
+        s.bool(&mut self.aes_interrupt)?;
+        s.bool(&mut self.aes_busy)?;
+        s.usize(&mut self.aes_timer)?;
+        s.bytes(&mut self.last_block)?;
+        s.bits(&mut self.access)?;
+        s.bits(&mut self.gpio)?;
+        s.bits(&mut self.ide_config)?;
+        s.bits(&mut self.flash_addr)?;
+        s.bytes(&mut self.buf)?;
+        for ide in &mut self.ide {
+            s.bytes(ide)?;
+        }
+        for entry in &mut self.atb {
+            s.bits64(entry)?;
+        }
+        // what's being replaced, so the host can write back whatever changed
+        let previous =
+            (s.is_loading() && s.has_nand()).then(|| (take(&mut self.nand), take(&mut self.spare)));
+        let previous_save = s
+            .is_loading()
+            .then(|| self.save.as_ref().map(|save| save.data.clone()));
+        if s.has_nand() {
+            s.vec(&mut self.nand)?;
+            s.vec(&mut self.spare)?;
+        }
+        let mut device = self.nand_device.to_bytes();
+        s.bytes(&mut device)?;
+        self.nand_device =
+            NandDevice::from_bytes(device).ok_or(SnapshotError::Invalid("unknown nand device"))?;
+        let mut has_save = self.save.is_some();
+        s.bool(&mut has_save)?;
+        self.save = if has_save {
+            let (mut address, mut data) = self
+                .save
+                .take()
+                .map(|save| (save.address, save.data))
+                .unwrap_or_default();
+            s.value(&mut address)?;
+            s.vec(&mut data)?;
+            Some(
+                SaveMemory::new(address, data)
+                    .map_err(|_| SnapshotError::Invalid("save memory outside domain 2"))?,
+            )
         } else {
-            &self.nand[(prev_block_offset + 0x3FF0)..(prev_block_offset + 0x4000)]
+            None
+        };
+        s.bool(&mut self.card_present)?;
+        if s.is_loading() {
+            self.atb_cache.clear();
+        }
+
+        if self.nand.len() != self.nand_device.size()
+            || self.spare.len() != self.nand.len() / NAND_BLOCK_SIZE * NAND_SPARE_SIZE
+        {
+            return Err(SnapshotError::Invalid("nand doesn't match its device"));
+        }
+
+        let end = match self.flash_pending {
+            FlashPending::None => 0,
+            FlashPending::Program(page) => page + NAND_PAGE_SIZE,
+            FlashPending::Erase(block) => block + NAND_BLOCK_SIZE,
         };
+        if end > self.nand.len() {
+            return Err(SnapshotError::Invalid("pending flash command out of range"));
+        }
 
-        (block_offset, iv)
+        if let Some((nand, spare)) = previous {
+            self.mark_changed_blocks(&nand, &spare);
+        }
+        if let Some(save) = previous_save {
+            self.mark_changed_save(save.as_deref());
+        }
+        Ok(())
     }
+}
 
-    pub fn read_atb_phys_addr(&mut self, address: word) -> byte {
-        let (block_offset, iv) = self.atb_addr_to_block(address);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let iv = iv.try_into().unwrap();
-        let key = &self.buf[0x4C0..0x4D0];
+    use soft_aes::aes::aes_enc_cbc;
 
-        let enc = &self.nand[block_offset..block_offset + 0x4000];
-        //println!("key: {key:02X?}, iv: {iv:02X?}");
-        let dec = aes_dec_cbc(enc, key, &iv, None).expect("decryption failed");
+    use crate::cop0::nand::NandDevice;
+
+    const BLOCK: usize = NAND_BLOCK_SIZE;
+
+    // a 2x64m card with the first and last 16 bytes of every block set to its
+    // number, so ivs show where they came from
+    fn pi() -> Pi {
+        let device: NandDevice = "2x64m".parse().unwrap();
+        let mut data = vec![0; device.size()];
+        for (number, block) in data.chunks_exact_mut(BLOCK).enumerate() {
+            block[..0x10].fill(number as byte);
+            block[BLOCK - 0x10..].fill(number as byte);
+        }
+        let spare = vec![0xFF; device.size() / BLOCK * NAND_SPARE_SIZE];
+        let mut pi = Pi::new(NandImage::split(data, spare, Some(device)).unwrap());
+        pi.buf[0x4D0..0x4E0].fill(0xEE);
+        pi
+    }
+
+    fn entry(vaddr: hword, size: u8, paddr: hword) -> ATBEntry {
+        ATBEntry::new()
+            .with_vaddr(vaddr)
+            .with_size(size)
+            .with_paddr(paddr)
+            .with_perm(ATB_PERM_DMA | ATB_PERM_CPU)
+    }
+
+    fn address(block_vaddr: word) -> word {
+        block_vaddr << 14
+    }
+
+    #[test]
+    fn multi_entry_lookup() {
+        let mut pi = pi();
+        pi.atb[0] = entry(0x4000, 1, 10).with_iv(true);
+        pi.atb[1] = entry(0x4002, 0, 20);
+        pi.atb[2] = entry(0x4003, 2, 5);
+
+        let lookup = |pi: &Pi, vaddr| pi.atb_lookup(address(vaddr), AtbAccess::Dma).unwrap();
+
+        let first = lookup(&pi, 0x4000);
+        assert_eq!(first.offset, 10 * BLOCK);
+        assert_eq!(first.iv, [0xEE; 0x10]);
+
+        // within an entry, the iv chains from the previous physical block
+        let second = lookup(&pi, 0x4001);
+        assert_eq!(second.offset, 11 * BLOCK);
+        assert_eq!(second.iv, [10; 0x10]);
+
+        // across entries, from the last block of the previous entry
+        let third = lookup(&pi, 0x4002);
+        assert_eq!(third.offset, 20 * BLOCK);
+        assert_eq!(third.iv, [11; 0x10]);
+
+        let fourth = lookup(&pi, 0x4003);
+        assert_eq!(fourth.offset, 5 * BLOCK);
+        assert_eq!(fourth.iv, [20; 0x10]);
+
+        let last = lookup(&pi, 0x4006);
+        assert_eq!(last.offset, 8 * BLOCK);
+        assert_eq!(last.iv, [7; 0x10]);
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let mut pi = pi();
+        pi.atb[0] = entry(0x4000, 0, 30).with_iv(true);
+        pi.atb[1] = entry(0x4000, 2, 40).with_iv(true);
+
+        let block = pi.atb_lookup(address(0x4000), AtbAccess::Dma).unwrap();
+        assert_eq!(block.offset, 30 * BLOCK);
+        let block = pi.atb_lookup(address(0x4001), AtbAccess::Dma).unwrap();
+        assert_eq!(block.offset, 41 * BLOCK);
+    }
+
+    #[test]
+    fn miss() {
+        let mut pi = pi();
+        pi.atb[0] = entry(0x4000, 1, 10).with_iv(true);
+        pi.atb[1] = entry(0x4004, 0, 20).with_iv(true);
+
+        for vaddr in [0x3FFF, 0x4002, 0x4003, 0x4005] {
+            assert_eq!(
+                pi.atb_lookup(address(vaddr), AtbAccess::Cpu),
+                Err(AtbError::Miss(address(vaddr)))
+            );
+        }
+        assert_eq!(
+            pi.read_atb_phys_addr(0x1000C000),
+            Err(AtbError::Miss(0x1000C000))
+        );
+        assert_eq!(pi.bus_read(0x10000000, 0xC000), None);
+        assert!(pi.error);
+    }
+
+    #[test]
+    fn first_entry_without_iv_bit() {
+        let mut pi = pi();
+        pi.atb[0] = entry(0x4000, 0, 10);
+
+        let block = pi.atb_lookup(address(0x4000), AtbAccess::Dma).unwrap();
+        assert_eq!(block.iv, [0xEE; 0x10]);
+    }
+
+    #[test]
+    fn permissions() {
+        let mut pi = pi();
+        pi.atb[0] = entry(0x4000, 0, 10).with_iv(true).with_perm(ATB_PERM_DMA);
+        pi.atb[1] = entry(0x4001, 0, 11).with_iv(true).with_perm(ATB_PERM_CPU);
+        pi.atb[2] = entry(0x4002, 0, 12).with_iv(true).with_perm(0);
+
+        assert!(pi.atb_lookup(address(0x4000), AtbAccess::Dma).is_ok());
+        assert_eq!(
+            pi.atb_lookup(address(0x4000), AtbAccess::Cpu),
+            Err(AtbError::Denied(address(0x4000), 0, AtbAccess::Cpu))
+        );
+        assert_eq!(
+            pi.atb_lookup(address(0x4001), AtbAccess::Dma),
+            Err(AtbError::Denied(address(0x4001), 1, AtbAccess::Dma))
+        );
+        assert!(pi.atb_lookup(address(0x4001), AtbAccess::Cpu).is_ok());
+        for access in [AtbAccess::Dma, AtbAccess::Cpu] {
+            assert!(pi.atb_lookup(address(0x4002), access).is_err());
+        }
+    }
+
+    #[test]
+    fn devices() {
+        let chip = 64 * 1024 * 1024;
+        let mut pi = pi();
+        pi.atb[0] = entry(0x4000, 0, 10).with_iv(true).with_dev(1);
+        pi.atb[1] = entry(0x4001, 0, 3).with_dev(0);
+        pi.atb[2] = entry(0x4002, 0, 10).with_iv(true).with_dev(2);
+        pi.atb[3] = entry(0x4003, 1, 0xFFFF).with_iv(true);
+
+        let block = pi.atb_lookup(address(0x4000), AtbAccess::Dma).unwrap();
+        assert_eq!(block.offset, chip + 10 * BLOCK);
+
+        // chaining follows the previous entry onto its chip
+        let block = pi.atb_lookup(address(0x4001), AtbAccess::Dma).unwrap();
+        assert_eq!(block.offset, 3 * BLOCK);
+        assert_eq!(block.iv, [(chip / BLOCK + 10) as byte; 0x10]);
+
+        // there's no third chip, and no block 0xFFFF on a 64m one
+        assert_eq!(
+            pi.atb_lookup(address(0x4002), AtbAccess::Dma),
+            Err(AtbError::OutOfRange(address(0x4002), 2, 2))
+        );
+        assert_eq!(
+            pi.atb_lookup(address(0x4003), AtbAccess::Dma),
+            Err(AtbError::OutOfRange(address(0x4003), 3, 0))
+        );
+    }
+
+    #[test]
+    fn cache_follows_key_and_atb_writes() {
+        let mut pi = pi();
+        pi.atb[0] = entry(0x4000, 0, 10).with_iv(true);
+        for i in 0..0x10 {
+            pi.write_phys_addr(0x046104C0 + i, i as byte);
+        }
+        let key: Vec<byte> = (0..0x10).collect();
+        let enc = aes_enc_cbc(&[0x5A; BLOCK], &key, &[0xEE; 0x10], None).unwrap();
+        pi.nand[10 * BLOCK..11 * BLOCK].copy_from_slice(&enc);
+
+        assert_eq!(pi.read_atb_phys_addr(0x10000000), Ok(0x5A));
+        assert_eq!(pi.read_atb_phys_addr(0x10003FFF), Ok(0x5A));
+        assert_eq!(pi.atb_cache.len(), 1);
+
+        pi.write_phys_addr(0x046104C0, 0xFF);
+        assert!(pi.atb_cache.is_empty());
+        assert_ne!(pi.read_atb_phys_addr(0x10000000), Ok(0x5A));
+        pi.write_phys_addr(0x046104C0, 0x00);
+        assert_eq!(pi.read_atb_phys_addr(0x10000000), Ok(0x5A));
+
+        pi.write_phys_addr(0x04610503, 0x00);
+        assert!(pi.atb_cache.is_empty());
+    }
+
+    fn write_aes_ctrl(pi: &mut Pi, ctrl: AesCtrl) {
+        for (i, val) in u32::from(ctrl).to_be_bytes().into_iter().enumerate() {
+            pi.write_phys_addr(0x04600050 + i as word, val);
+        }
+    }
+
+    #[test]
+    fn aes_runs_take_time_and_chain() {
+        let mut pi = pi();
+        let key: Vec<byte> = (0..0x10).collect();
+        let plain: Vec<byte> = (0..0x40).map(|i| i * 3).collect();
+        let enc = aes_enc_cbc(&plain, &key, &[0x11; 0x10], None).unwrap();
+        pi.buf[0x4C0..0x4D0].copy_from_slice(&key);
+        pi.buf[0x4D0..0x4E0].fill(0x11);
+        pi.buf[..0x40].copy_from_slice(&enc);
+
+        let ctrl = AesCtrl::new().with_len(1).with_run(true);
+        write_aes_ctrl(&mut pi, ctrl.with_iv(0x4D).with_interrupt(true));
+        for _ in 1..2 * AES_STEPS_PER_BLOCK {
+            pi.step_aes();
+        }
+        assert!(pi.aes_busy);
+        assert_eq!(pi.buf[..0x40], enc[..]);
+
+        pi.step_aes();
+        assert!(!pi.aes_busy);
+        assert!(pi.aes_interrupt());
+        assert_eq!(pi.buf[..0x20], plain[..0x20]);
+
+        // the second half carries on from the end of the first
+        write_aes_ctrl(&mut pi, ctrl.with_data(2).with_chain(true));
+        assert!(!pi.aes_interrupt());
+        for _ in 0..2 * AES_STEPS_PER_BLOCK {
+            pi.step_aes();
+        }
+        assert!(!pi.aes_busy);
+        assert!(!pi.aes_interrupt());
+        assert_eq!(pi.buf[..0x40], plain[..]);
+    }
+
+    #[test]
+    fn aes_ranges_outside_the_buffer() {
+        let mut pi = pi();
+        write_aes_ctrl(
+            &mut pi,
+            AesCtrl::new().with_data(0x4F).with_len(1).with_run(true),
+        );
+        assert!(!pi.aes_busy);
+        assert!(pi.error);
+
+        pi.error = false;
+        write_aes_ctrl(&mut pi, AesCtrl::new().with_iv(0x7F).with_run(true));
+        assert!(!pi.aes_busy);
+        assert!(pi.error);
+    }
+
+    #[test]
+    fn entries_past_the_end_of_the_block_numbers() {
+        let mut pi = pi();
+        pi.atb[0] = entry(0xFFFF, 15, 0).with_iv(true);
+
+        assert!(pi.atb_lookup(0xFFFF << 14, AtbAccess::Dma).is_ok());
+        assert_eq!(
+            pi.atb_lookup(0x1000_0000, AtbAccess::Dma),
+            Err(AtbError::Miss(0x1000_0000))
+        );
+    }
+
+    #[test]
+    fn cartridge_bus_dmas() {
+        let mut pi = pi();
+        pi.set_save_memory(Some(SaveMemory::new(0x08000000, vec![0; 0x8000]).unwrap()));
+
+        // domain 2 goes to the save memory, and only the bytes written are dirty
+        pi.bus_write(0x08000102, 4, &[1, 2, 3, 4]);
+        assert_eq!(pi.take_dirty_save(), Some(0x102..0x106));
+        assert_eq!(pi.take_dirty_save(), None);
+        assert_eq!(
+            pi.bus_read(0x08000100, 8).unwrap(),
+            [0, 0, 1, 2, 3, 4, 0, 0]
+        );
+
+        // unmapped space reads back the low half of the word address
+        assert_eq!(
+            pi.bus_read(0x06001234, 4).unwrap(),
+            [0x12, 0x34, 0x12, 0x34]
+        );
+        pi.bus_write(0x06001234, 2, &[1, 2]);
+        assert_eq!(pi.take_dirty_save(), None);
+
+        // the atb window can't be written
+        pi.bus_write(0x10000000, 2, &[1, 2]);
+        assert!(pi.error);
+    }
+
+    #[test]
+    fn bad_dmas_set_the_error() {
+        // rdram and the rcp registers aren't on the cartridge bus
+        let mut pi = pi();
+        assert_eq!(pi.bus_read(0x00001000, 4), None);
+        assert!(pi.error);
+        pi.error = false;
+        pi.bus_write(0x04600000, 4, &[1, 2, 3, 4]);
+        assert!(pi.error);
+
+        // nor is a transfer that runs off the end of cartridge space
+        pi.error = false;
+        assert_eq!(pi.bus_read(0x0FFFFFFE, 4), None);
+        assert!(pi.error);
+        pi.error = false;
+        assert_eq!(pi.bus_read(0xFFFFFFFE, 4), None);
+        assert!(pi.error);
+        pi.error = false;
+        pi.bus_write(0xFFFFFFFE, 4, &[1, 2, 3, 4]);
+        assert!(pi.error);
+
+        // or one that doesn't fit in the buffer
+        pi.error = false;
+        pi.buf_write(0x3FE, 4, &[1, 2, 3, 4]);
+        assert!(!pi.error);
+        assert_eq!(pi.buf_read(0x3FE, 4), Some(&[1, 2, 3, 4][..]));
+        assert_eq!(pi.buf_read(0x400, 4), None);
+        assert!(pi.error);
+        pi.error = false;
+        pi.buf_write(0x3FE, 0x400, &[0; 0x400]);
+        assert!(pi.error);
+    }
+
+    #[test]
+    fn dma_timing_follows_the_domain() {
+        let mut pi = pi();
+        pi.cart_addr = CartAddr::new().with_addr(0x08000000);
+        pi.write_len = TransferLen::new().with_len(0x7FF);
+        pi.dom2_latency = Latency::new().with_latency(5);
+        pi.dom2_pulse_width = PulseWidth::new().with_width(0x0C);
+        pi.dom2_page_size = PageSize::new().with_width(0xD);
+        pi.dom2_release = Release::new().with_width(2);
+        // one 0x8000 byte page and 0x400 halfwords
+        assert_eq!(pi.dma_steps(), 6 + 0x400 * (0x0D + 3));
+
+        // domain 1 has its own settings, and crossing a page costs its latency
+        pi.cart_addr = CartAddr::new().with_addr(0x06000002);
+        pi.write_len = TransferLen::new().with_len(3);
+        pi.dom1_latency = Latency::new().with_latency(0x40);
+        assert_eq!(pi.dma_steps(), 2 * 0x41 + 2 * 2);
+
+        // the atb window and the buffer don't use the bus
+        pi.cart_addr = CartAddr::new().with_addr(0x10000000);
+        assert_eq!(pi.dma_steps(), 1);
+
+        // nor does a transfer that runs off the end of the bus
+        pi.cart_addr = CartAddr::new().with_addr(0xFFFFFFFE);
+        assert_eq!(pi.dma_steps(), 1);
+        pi.write_len = TransferLen::new();
+        pi.buffer_write_len = TransferLen::new().with_len(0x1FF);
+        assert_eq!(pi.dma_steps(), 1);
+    }
+
+    fn flash(pi: &mut Pi, address: word, ctrl: FlashCtrl) {
+        for (i, val) in address.to_be_bytes().into_iter().enumerate() {
+            pi.write_phys_addr(0x04600070 + i as word, val);
+        }
+        for (i, val) in u32::from(ctrl.with_run(true))
+            .to_be_bytes()
+            .into_iter()
+            .enumerate()
+        {
+            pi.write_phys_addr(0x04600048 + i as word, val);
+        }
+    }
+
+    fn command(command: byte) -> FlashCtrl {
+        FlashCtrl::new().with_command(command).with_wait_ready(true)
+    }
+
+    // the page at `address` into the buffer, spare area included
+    fn read_page(pi: &mut Pi, address: word) -> Vec<byte> {
+        let ctrl = command(0x00)
+            .with_read_data_phase(true)
+            .with_data_phase_size(0x200);
+        flash(pi, address, ctrl);
+        let spare = command(0x50)
+            .with_read_data_phase(true)
+            .with_data_phase_size(0x10);
+        flash(pi, address, spare);
+        [&pi.buf[..0x200], &pi.buf[0x400..0x410]].concat()
+    }
+
+    fn status(pi: &mut Pi) -> byte {
+        flash(
+            pi,
+            0,
+            command(0x70)
+                .with_read_data_phase(true)
+                .with_data_phase_size(1),
+        );
+        pi.buf[0]
+    }
+
+    #[test]
+    fn flash_program_and_read_back() {
+        let mut pi = pi();
+        let page = 3 * BLOCK as word + 0x400;
+        flash(
+            &mut pi,
+            3 * BLOCK as word,
+            command(0x60).with_multi_cycle(true),
+        );
+        flash(&mut pi, 0, command(0xD0));
+        pi.take_dirty_blocks();
+
+        let data: Vec<byte> = (0..0x200).map(|i| i as byte).collect();
+        pi.buf[..0x200].copy_from_slice(&data);
+        let load = command(0x80)
+            .with_write_data_phase(true)
+            .with_data_phase_size(0x200)
+            .with_multi_cycle(true);
+        flash(&mut pi, page, load);
+        flash(&mut pi, page, command(0x10));
+
+        assert_eq!(status(&mut pi), STATUS_NOT_PROTECTED | STATUS_READY);
+        assert_eq!(pi.take_dirty_blocks(), [3]);
+        pi.buf.fill(0);
+        assert_eq!(read_page(&mut pi, page)[..0x200], data[..]);
+
+        // programming only clears bits
+        pi.buf[..0x200].fill(0xF0);
+        flash(&mut pi, page, load);
+        flash(&mut pi, page, command(0x10));
+        let read = read_page(&mut pi, page);
+        assert!(read[..0x200]
+            .iter()
+            .zip(&data)
+            .all(|(read, old)| *read == old & 0xF0));
+    }
+
+    #[test]
+    fn flash_erase() {
+        let mut pi = pi();
+        assert_eq!(read_page(&mut pi, 5 * BLOCK as word)[0], 5);
+
+        flash(
+            &mut pi,
+            5 * BLOCK as word + 0x1200,
+            command(0x60).with_multi_cycle(true),
+        );
+        flash(&mut pi, 0, command(0xD0));
+        assert_eq!(pi.take_dirty_blocks(), [5]);
+        assert_eq!(status(&mut pi) & STATUS_FAIL, 0);
+        assert!(pi.nand[5 * BLOCK..6 * BLOCK]
+            .iter()
+            .all(|byte| *byte == 0xFF));
+        assert_eq!(read_page(&mut pi, 5 * BLOCK as word), [0xFF; 0x210]);
+
+        // the blocks either side are untouched
+        assert_eq!(read_page(&mut pi, 4 * BLOCK as word)[0], 4);
+        assert_eq!(read_page(&mut pi, 6 * BLOCK as word)[0], 6);
+    }
+
+    #[test]
+    fn flash_status() {
+        let mut pi = pi();
+        assert_eq!(status(&mut pi), STATUS_NOT_PROTECTED | STATUS_READY);
+
+        // a confirm without its first half fails
+        flash(&mut pi, 0, command(0xD0));
+        assert_eq!(
+            status(&mut pi),
+            STATUS_NOT_PROTECTED | STATUS_READY | STATUS_FAIL
+        );
+
+        // as does a command the chip doesn't know, which leaves the nand alone
+        flash(&mut pi, 0, command(0xFF));
+        assert_eq!(status(&mut pi), STATUS_NOT_PROTECTED | STATUS_READY);
+        flash(&mut pi, 0, command(0x42));
+        assert_eq!(
+            status(&mut pi),
+            STATUS_NOT_PROTECTED | STATUS_READY | STATUS_FAIL
+        );
+        assert!(pi.take_dirty_blocks().is_empty());
+
+        // busy shows up once for an erase that doesn't wait
+        flash(&mut pi, 0, command(0xFF));
+        flash(&mut pi, 0, command(0x60).with_multi_cycle(true));
+        flash(&mut pi, 0, command(0xD0).with_wait_ready(false));
+        assert_eq!(status(&mut pi), STATUS_NOT_PROTECTED);
+        assert_eq!(status(&mut pi), STATUS_NOT_PROTECTED | STATUS_READY);
+    }
+
+    #[test]
+    fn faults_repeat_for_a_seed() {
+        let faults = NandFaults {
+            bad_blocks: vec![2],
+            fail_rate: 0.5,
+            flip_rate: 0.5,
+            seed: 99,
+            ..Default::default()
+        };
+        let run = |faults: &NandFaults| {
+            let mut pi = pi();
+            pi.set_nand_faults(faults.clone());
+            let mut results = vec![];
+            for block in 0..16 {
+                let address = block * BLOCK as word;
+                flash(&mut pi, address, command(0x60).with_multi_cycle(true));
+                flash(&mut pi, 0, command(0xD0));
+                results.push(status(&mut pi));
+                results.extend(read_page(&mut pi, address));
+            }
+            results
+        };
 
-        dec[(address & 0x3FFF) as usize]
+        let first = run(&faults);
+        assert_eq!(run(&faults), first);
+        assert_ne!(
+            run(&NandFaults {
+                seed: 100,
+                ..faults.clone()
+            }),
+            first
+        );
+
+        // the bad block is marked and never erases
+        let mut pi = pi();
+        pi.set_nand_faults(faults);
+        assert_eq!(pi.spare[2 * NAND_SPARE_SIZE + BAD_BLOCK_BYTE], 0);
+        flash(
+            &mut pi,
+            2 * BLOCK as word,
+            command(0x60).with_multi_cycle(true),
+        );
+        flash(&mut pi, 0, command(0xD0));
+        assert_eq!(status(&mut pi) & STATUS_FAIL, STATUS_FAIL);
+        assert_eq!(pi.nand[2 * BLOCK], 2);
     }
 }
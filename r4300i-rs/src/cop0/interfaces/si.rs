@@ -1,3 +1,5 @@
+use crate::cop0::mmio::{register, RegisterInfo};
+use crate::snapshot::{Snapshot, SnapshotError, Snapshotter};
 use crate::types::*;
 
 use modular_bitfield::prelude::*;
@@ -18,6 +20,17 @@ pub struct Status {
     __: B19,
 }
 
+pub const REGISTERS: &[RegisterInfo] = &[
+    register!("SI_DRAM_ADDR", 0x04800000),
+    register!("SI_PIF_ADDR_RD64B", 0x04800004),
+    register!("SI_PIF_ADDR_WR4B", 0x04800008),
+    register!("SI_CTRL", 0x0480000C),
+    register!("SI_PIF_ADDR_WR64B", 0x04800010),
+    register!("SI_PIF_ADDR_RD4B", 0x04800014),
+    register!("SI_STATUS", 0x04800018, Status, -),
+    register!("SI_CONFIG", 0x0480001C),
+];
+
 #[derive(Debug)]
 pub struct Si {
     status: Status,
@@ -30,6 +43,10 @@ impl Si {
         }
     }
 
+    pub fn registers(&self) -> Vec<(&'static str, word)> {
+        vec![("SI_STATUS", self.status.into())]
+    }
+
     pub fn read_phys_addr(&mut self, address: word) -> byte {
         match address {
             0x0480000C..=0x0480000F => {
@@ -71,3 +88,11 @@ impl Si {
         }
     }
 }
+
+impl Snapshot for Si {
+    fn snapshot(&mut self, s: &mut Snapshotter) -> Result<(), SnapshotError> {
+        s.section("SI")?;
+        s.bits(&mut self.status)?;
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+use crate::cop0::mmio::{register, RegisterInfo};
+use crate::snapshot::{Snapshot, SnapshotError, Snapshotter};
 use crate::types::*;
 
 use modular_bitfield::prelude::*;
@@ -11,6 +13,18 @@ pub struct Status {
     __: B17,
 }
 
+pub const REGISTERS: &[RegisterInfo] = &[
+    register!("SP_MEM_ADDR", 0x04040000),
+    register!("SP_DRAM_ADDR", 0x04040004),
+    register!("SP_RD_LEN", 0x04040008),
+    register!("SP_WR_LEN", 0x0404000C),
+    register!("SP_STATUS", 0x04040010, Status),
+    register!("SP_DMA_FULL", 0x04040014),
+    register!("SP_DMA_BUSY", 0x04040018),
+    register!("SP_SEMAPHORE", 0x0404001C),
+    register!("SP_PC", 0x04080000),
+];
+
 #[derive(Debug)]
 pub struct Sp {
     status: Status,
@@ -23,6 +37,10 @@ impl Sp {
         }
     }
 
+    pub fn registers(&self) -> Vec<(&'static str, word)> {
+        vec![("SP_STATUS", self.status.into())]
+    }
+
     pub fn read_phys_addr(&mut self, address: word) -> byte {
         match address {
             0x04040010..=0x04040013 => retrieve_byte(self.status.into(), address),
@@ -46,3 +64,11 @@ impl Sp {
         }
     }
 }
+
+impl Snapshot for Sp {
+    fn snapshot(&mut self, s: &mut Snapshotter) -> Result<(), SnapshotError> {
+        s.section("SP")?;
+        s.bits(&mut self.status)?;
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+use crate::cop0::mmio::{register, RegisterInfo};
+use crate::snapshot::{Snapshot, SnapshotError, Snapshotter};
 use crate::types::*;
 
 use modular_bitfield::prelude::*;
@@ -20,6 +22,12 @@ pub struct SecMode {
     __: B31,
 }
 
+// offsets from the controller's base address
+pub const REGISTERS: &[RegisterInfo] = &[
+    register!("USB_CLOCK_SEL", 0x40000, ClockSel),
+    register!("USB_SEC_MODE", 0x40010, SecMode),
+];
+
 #[derive(Debug)]
 pub struct Usb {
     base_address: word,
@@ -45,6 +53,20 @@ impl Usb {
         }
     }
 
+    pub fn registers(&self) -> Vec<(&'static str, word)> {
+        vec![
+            ("USB_CLOCK_SEL", self.clock_sel.into()),
+            ("USB_SEC_MODE", self.sec_mode.into()),
+        ]
+    }
+
+    // data arriving from the host side lands straight in the buffer ram
+    pub fn receive(&mut self, offset: usize, data: &[byte]) {
+        let start = offset.min(Self::SRAM_SIZE);
+        let end = (offset + data.len()).min(Self::SRAM_SIZE);
+        self.sram[start..end].copy_from_slice(&data[..end - start]);
+    }
+
     pub fn read_phys_addr(&mut self, address: word) -> byte {
         let int_address = address - self.base_address;
         match int_address {
@@ -96,3 +118,14 @@ impl Usb {
         }
     }
 }
+
+impl Snapshot for Usb {
+    fn snapshot(&mut self, s: &mut Snapshotter) -> Result<(), SnapshotError> {
+        s.section("USB")?;
+        s.value(&mut self.base_address)?;
+        s.bits(&mut self.clock_sel)?;
+        s.bits(&mut self.sec_mode)?;
+        s.bytes(&mut self.sram)?;
+        Ok(())
+    }
+}
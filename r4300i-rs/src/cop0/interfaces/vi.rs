@@ -1,3 +1,5 @@
+use crate::cop0::mmio::{register, RegisterInfo};
+use crate::snapshot::{Snapshot, SnapshotError, Snapshotter};
 use crate::types::*;
 
 use modular_bitfield::prelude::*;
@@ -141,6 +143,25 @@ pub struct SpanData {
     data: word,
 }
 
+pub const REGISTERS: &[RegisterInfo] = &[
+    register!("VI_CONTROL", 0x04400000, Ctrl),
+    register!("VI_ORIGIN", 0x04400004, DramAddr),
+    register!("VI_WIDTH", 0x04400008, Width),
+    register!("VI_INTR", 0x0440000C, HalfLineCount),
+    register!("VI_CURRENT", 0x04400010, HalfLineCount),
+    register!("VI_BURST", 0x04400014, Burst),
+    register!("VI_V_SYNC", 0x04400018, HalfLineCount),
+    register!("VI_H_SYNC", 0x0440001C, HSync),
+    register!("VI_LEAP", 0x04400020, Leap),
+    register!("VI_H_START", 0x04400024, Start),
+    register!("VI_V_START", 0x04400028, Start),
+    register!("VI_V_BURST", 0x0440002C, Burst),
+    register!("VI_X_SCALE", 0x04400030, Scale),
+    register!("VI_Y_SCALE", 0x04400034, Scale),
+    register!("VI_SPAN_ADDR", 0x04400038, SpanAddr),
+    register!("VI_SPAN_DATA", 0x0440003C, SpanData),
+];
+
 #[derive(Debug)]
 pub struct Vi {
     control: Ctrl,
@@ -183,6 +204,27 @@ impl Vi {
         }
     }
 
+    pub fn registers(&self) -> Vec<(&'static str, word)> {
+        vec![
+            ("VI_CONTROL", self.control.into()),
+            ("VI_ORIGIN", self.origin.into()),
+            ("VI_WIDTH", self.width.into()),
+            ("VI_INTR", self.intr.into()),
+            ("VI_CURRENT", self.current.into()),
+            ("VI_BURST", self.burst.into()),
+            ("VI_V_SYNC", self.v_sync.into()),
+            ("VI_H_SYNC", self.h_sync.into()),
+            ("VI_LEAP", self.leap.into()),
+            ("VI_H_START", self.h_start.into()),
+            ("VI_V_START", self.v_start.into()),
+            ("VI_V_BURST", self.v_burst.into()),
+            ("VI_X_SCALE", self.x_scale.into()),
+            ("VI_Y_SCALE", self.y_scale.into()),
+            ("VI_SPAN_ADDR", self.span_addr.into()),
+            ("VI_SPAN_DATA", self.span_data.into()),
+        ]
+    }
+
     pub fn frame<const N: usize>(&mut self, ram: &[byte; N]) -> bool {
         self.current
             .set_half_line((self.current.half_line() + 1) & 0x3FF);
@@ -241,83 +283,67 @@ impl Vi {
     pub fn write_phys_addr(&mut self, address: word, val: byte) {
         match address {
             0x04400000..=0x04400003 => {
-                self.control = merge_byte(self.control.into(), address, val).into();
-                println!("vi control: {:?}", self.control);
+                self.control = merge_byte(self.control.into(), address, val).into()
             }
 
             0x04400004..=0x04400007 => {
-                self.origin = merge_byte(self.origin.into(), address, val).into();
-                println!("vi origin: {:08X}", self.origin.addr());
+                self.origin = merge_byte(self.origin.into(), address, val).into()
             }
 
             0x04400008..=0x0440000B => {
-                self.width = merge_byte(self.width.into(), address, val).into();
-                println!("vi width: {:08X}", self.width.width());
+                self.width = merge_byte(self.width.into(), address, val).into()
             }
 
             0x0440000C..=0x0440000F => {
-                self.intr = merge_byte(self.intr.into(), address, val).into();
-                println!("vi intr: {:08X}", self.intr.half_line());
+                self.intr = merge_byte(self.intr.into(), address, val).into()
             }
 
             0x04400010..=0x04400013 => {
-                self.current = merge_byte(self.current.into(), address, val).into();
-                println!("vi current: {:08X}", self.current.half_line());
+                self.current = merge_byte(self.current.into(), address, val).into()
             }
 
             0x04400014..=0x04400017 => {
-                self.burst = merge_byte(self.burst.into(), address, val).into();
-                println!("vi burst: {:02X?}", self.burst);
+                self.burst = merge_byte(self.burst.into(), address, val).into()
             }
 
             0x04400018..=0x0440001B => {
-                self.v_sync = merge_byte(self.v_sync.into(), address, val).into();
-                println!("vi vsync: {:08X}", self.v_sync.half_line());
+                self.v_sync = merge_byte(self.v_sync.into(), address, val).into()
             }
 
             0x0440001C..=0x0440001F => {
-                self.h_sync = merge_byte(self.h_sync.into(), address, val).into();
-                println!("vi hsync: {:08X?}", self.h_sync);
+                self.h_sync = merge_byte(self.h_sync.into(), address, val).into()
             }
 
             0x04400020..=0x04400023 => {
-                self.leap = merge_byte(self.leap.into(), address, val).into();
-                println!("vi leap: {:08X?}", self.leap);
+                self.leap = merge_byte(self.leap.into(), address, val).into()
             }
 
             0x04400024..=0x04400027 => {
-                self.h_start = merge_byte(self.h_start.into(), address, val).into();
-                println!("vi hstart: {:08X?}", self.h_start);
+                self.h_start = merge_byte(self.h_start.into(), address, val).into()
             }
 
             0x04400028..=0x0440002B => {
-                self.v_start = merge_byte(self.v_start.into(), address, val).into();
-                println!("vi vstart: {:08X?}", self.v_start);
+                self.v_start = merge_byte(self.v_start.into(), address, val).into()
             }
 
             0x0440002C..=0x0440002F => {
-                self.v_burst = merge_byte(self.v_burst.into(), address, val).into();
-                println!("vi vburst: {:08X?}", self.v_burst);
+                self.v_burst = merge_byte(self.v_burst.into(), address, val).into()
             }
 
             0x04400030..=0x04400033 => {
-                self.x_scale = merge_byte(self.x_scale.into(), address, val).into();
-                println!("vi xscale: {:?}", self.x_scale);
+                self.x_scale = merge_byte(self.x_scale.into(), address, val).into()
             }
 
             0x04400034..=0x04400037 => {
-                self.y_scale = merge_byte(self.y_scale.into(), address, val).into();
-                println!("vi yscale: {:?}", self.y_scale);
+                self.y_scale = merge_byte(self.y_scale.into(), address, val).into()
             }
 
             0x04400038..=0x0440003B => {
-                self.span_addr = merge_byte(self.span_addr.into(), address, val).into();
-                println!("vi spanaddr: {:08X}", self.span_addr.addr());
+                self.span_addr = merge_byte(self.span_addr.into(), address, val).into()
             }
 
             0x0440003C..=0x0440003F => {
-                self.span_data = merge_byte(self.span_data.into(), address, val).into();
-                println!("vi spandata: {:08X}", self.span_data.data());
+                self.span_data = merge_byte(self.span_data.into(), address, val).into()
             }
 
             _ => {
@@ -327,3 +353,26 @@ impl Vi {
         }
     }
 }
+
+impl Snapshot for Vi {
+    fn snapshot(&mut self, s: &mut Snapshotter) -> Result<(), SnapshotError> {
+        s.section("VI")?;
+        s.bits(&mut self.control)?;
+        s.bits(&mut self.origin)?;
+        s.bits(&mut self.width)?;
+        s.bits(&mut self.intr)?;
+        s.bits(&mut self.current)?;
+        s.bits(&mut self.burst)?;
+        s.bits(&mut self.v_sync)?;
+        s.bits(&mut self.h_sync)?;
+        s.bits(&mut self.leap)?;
+        s.bits(&mut self.h_start)?;
+        s.bits(&mut self.v_start)?;
+        s.bits(&mut self.v_burst)?;
+        s.bits(&mut self.x_scale)?;
+        s.bits(&mut self.y_scale)?;
+        s.bits(&mut self.span_addr)?;
+        s.bits(&mut self.span_data)?;
+        Ok(())
+    }
+}
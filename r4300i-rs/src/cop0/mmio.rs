@@ -0,0 +1,270 @@
+use std::str::FromStr;
+
+use crate::types::*;
+
+use super::interfaces::{ai, mi, pi, si, sp, usb, vi};
+use super::virage;
+use super::Device;
+
+// what a device register is called and how to break its value into fields;
+// reads and writes can mean different things (e.g. the set/clear mask writes),
+// so each direction gets its own decoder
+#[derive(Debug, Clone, Copy)]
+pub struct RegisterInfo {
+    pub name: &'static str,
+    pub address: word,
+    pub read: Option<fn(word) -> String>,
+    pub write: Option<fn(word) -> String>,
+}
+
+macro_rules! register {
+    ($name:literal, $address:literal) => {
+        $crate::cop0::mmio::RegisterInfo {
+            name: $name,
+            address: $address,
+            read: None,
+            write: None,
+        }
+    };
+    ($name:literal, $address:literal, $ty:ty) => {
+        register!($name, $address, $ty, $ty)
+    };
+    ($name:literal, $address:literal, $read:ty, -) => {
+        $crate::cop0::mmio::RegisterInfo {
+            name: $name,
+            address: $address,
+            read: Some(|v| $crate::cop0::mmio::fields(&format!("{:?}", <$read>::from(v)))),
+            write: None,
+        }
+    };
+    ($name:literal, $address:literal, $read:ty, $write:ty) => {
+        $crate::cop0::mmio::RegisterInfo {
+            name: $name,
+            address: $address,
+            read: Some(|v| $crate::cop0::mmio::fields(&format!("{:?}", <$read>::from(v)))),
+            write: Some(|v| $crate::cop0::mmio::fields(&format!("{:?}", <$write>::from(v)))),
+        }
+    };
+}
+pub(crate) use register;
+
+// turns a bitfield's debug output, `Name { a: true, b: false, c: 3 }`, into `{a, c=0x3}`
+pub fn fields(debug: &str) -> String {
+    let (Some(start), Some(end)) = (debug.find('{'), debug.rfind('}')) else {
+        return String::new();
+    };
+
+    let mut fields = vec![];
+    let mut depth = 0;
+    let mut field_start = start + 1;
+    for (index, c) in debug[..end].char_indices().skip(start + 1) {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ',' if depth == 0 => {
+                fields.push(&debug[field_start..index]);
+                field_start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    fields.push(&debug[field_start..end]);
+
+    let fields = fields
+        .into_iter()
+        .filter_map(|field| {
+            let (name, value) = field.split_once(':')?;
+            let (name, value) = (name.trim(), value.trim());
+            match value {
+                "true" => Some(name.to_string()),
+                "false" => None,
+                _ if value.starts_with("InvalidBitPattern") => Some(format!("{name}=invalid")),
+                _ => match value.parse::<dword>() {
+                    Ok(n) => Some(format!("{name}=0x{n:X}")),
+                    Err(_) => Some(format!("{name}={value}")),
+                },
+            }
+        })
+        .collect::<Vec<_>>();
+
+    format!("{{{}}}", fields.join(", "))
+}
+
+impl Device {
+    pub fn from_phys_addr(address: word) -> Option<Self> {
+        match address {
+            0x04000000..=0x040FFFFF => Some(Self::Sp),
+            0x04300000..=0x043FFFFF => Some(Self::Mi),
+            0x04400000..=0x044FFFFF => Some(Self::Vi),
+            0x04500000..=0x045FFFFF => Some(Self::Ai),
+            0x04600000..=0x046FFFFF => Some(Self::Pi),
+            0x04800000..=0x048FFFFF => Some(Self::Si),
+            0x04900000..=0x049FFFFF => Some(Self::Usb0),
+            0x04A00000..=0x04AFFFFF => Some(Self::Usb1),
+            0x1FC00000..=0x1FCFFFFF => Some(Self::Virage),
+            _ => None,
+        }
+    }
+
+    // usb registers are listed relative to the controller
+    pub fn registers(&self) -> (word, &'static [RegisterInfo]) {
+        match self {
+            Self::Sp => (0, sp::REGISTERS),
+            Self::Mi => (0, mi::REGISTERS),
+            Self::Vi => (0, vi::REGISTERS),
+            Self::Ai => (0, ai::REGISTERS),
+            Self::Pi => (0, pi::REGISTERS),
+            Self::Si => (0, si::REGISTERS),
+            Self::Usb0 => (0x04900000, usb::REGISTERS),
+            Self::Usb1 => (0x04A00000, usb::REGISTERS),
+            Self::Virage => (0, virage::REGISTERS),
+        }
+    }
+}
+
+// the register at a physical address, and how far into it the address is
+pub fn lookup(address: word) -> Option<(Device, &'static RegisterInfo, word)> {
+    let device = Device::from_phys_addr(address)?;
+    let (base, registers) = device.registers();
+    let offset = address.wrapping_sub(base);
+    registers
+        .iter()
+        .find(|reg| (reg.address..reg.address + 4).contains(&offset))
+        .map(|reg| (device, reg, offset - reg.address))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmioMode {
+    All,
+    Secure,
+    NonSecure,
+}
+
+impl FromStr for MmioMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "all" => Ok(Self::All),
+            "secure" => Ok(Self::Secure),
+            "non-secure" => Ok(Self::NonSecure),
+            _ => Err(format!("unknown mode: {s}")),
+        }
+    }
+}
+
+// which register accesses get printed
+#[derive(Debug, Clone)]
+pub struct MmioLog {
+    pub devices: Vec<Device>,
+    pub mode: MmioMode,
+}
+
+impl MmioLog {
+    pub fn matches(&self, device: Device, secure: bool) -> bool {
+        let mode = match self.mode {
+            MmioMode::All => true,
+            MmioMode::Secure => secure,
+            MmioMode::NonSecure => !secure,
+        };
+        mode && (self.devices.is_empty() || self.devices.contains(&device))
+    }
+}
+
+// e.g. `pi: PI_FLASH_CTRL <- 0x80008A10 {run, command=0x0, ...}`
+pub fn describe(
+    device: Device,
+    reg: &RegisterInfo,
+    offset: word,
+    write: bool,
+    bytes: &[byte],
+) -> String {
+    let value = bytes.iter().fold(0, |acc, b| (acc << 8) | *b as dword);
+    let width = bytes.len() * 2;
+    let arrow = if write { "<-" } else { "->" };
+    let decode = if write { reg.write } else { reg.read };
+
+    match decode {
+        Some(decode) if offset == 0 && bytes.len() == 4 => format!(
+            "{device}: {} {arrow} 0x{value:08X} {}",
+            reg.name,
+            decode(value as word)
+        ),
+        _ if offset == 0 => format!("{device}: {} {arrow} 0x{value:0width$X}", reg.name),
+        _ => format!("{device}: {}+{offset} {arrow} 0x{value:0width$X}", reg.name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitfield_fields() {
+        assert_eq!(
+            fields("FlashCtrl { run: true, busy: false, command: 144, inner: X { a: 1 } }"),
+            "{run, command=0x90, inner=X { a: 1 }}"
+        );
+        assert_eq!(fields("Empty {  }"), "{}");
+        assert_eq!(fields("no fields"), "");
+        assert_eq!(
+            fields("R { mode: Err(InvalidBitPattern { invalid_bytes: 3 }) }"),
+            "{mode=Err(InvalidBitPattern { invalid_bytes: 3 })}"
+        );
+        assert_eq!(
+            fields("R { mode: InvalidBitPattern { invalid_bytes: 3 } }"),
+            "{mode=invalid}"
+        );
+    }
+
+    #[test]
+    fn register_lookup() {
+        let (device, reg, offset) = lookup(0x04600072).unwrap();
+        assert_eq!(device, Device::Pi);
+        assert_eq!(reg.name, "PI_FLASH_ADDR");
+        assert_eq!(offset, 2);
+
+        // both usb controllers share a register list
+        let (device, usb0, _) = lookup(0x04940010).unwrap();
+        assert_eq!(device, Device::Usb0);
+        let (device, usb1, _) = lookup(0x04A40010).unwrap();
+        assert_eq!(device, Device::Usb1);
+        assert_eq!(usb0.name, "USB_SEC_MODE");
+        assert_eq!(usb1.name, "USB_SEC_MODE");
+
+        assert!(lookup(0x04100000).is_none());
+        assert!(lookup(0x0460FFFC).is_none());
+    }
+
+    #[test]
+    fn descriptions() {
+        let (device, reg, _) = lookup(0x04600070).unwrap();
+        assert_eq!(
+            describe(device, reg, 0, true, &[0x00, 0x00, 0x40, 0x00]),
+            "pi: PI_FLASH_ADDR <- 0x00004000 {addr=0x4000}"
+        );
+        assert_eq!(
+            describe(device, reg, 2, false, &[0x40]),
+            "pi: PI_FLASH_ADDR+2 -> 0x40"
+        );
+    }
+
+    #[test]
+    fn log_modes() {
+        let log = MmioLog {
+            devices: vec![Device::Pi],
+            mode: "secure".parse().unwrap(),
+        };
+        assert!(log.matches(Device::Pi, true));
+        assert!(!log.matches(Device::Pi, false));
+        assert!(!log.matches(Device::Mi, true));
+
+        let log = MmioLog {
+            devices: vec![],
+            mode: "Non-Secure".parse().unwrap(),
+        };
+        assert!(log.matches(Device::Mi, false));
+        assert!(!log.matches(Device::Mi, true));
+        assert!("some".parse::<MmioMode>().is_err());
+    }
+}
@@ -1,18 +1,28 @@
-use std::fmt::Debug;
+use std::fmt::{Debug, Display};
 use std::iter::IntoIterator;
 use std::mem::size_of;
+use std::ops::Range;
+use std::str::FromStr;
 
+use crate::snapshot::{Snapshot, SnapshotError, Snapshotter};
 use crate::{is_ksegdm, k2_to_phys, kdm_to_phys, BOOTROM_BASE};
 use crate::{types::*, ExceptionType, SecureTrapType};
 use crate::{Exception, R4300i};
 
 use num_traits::ops::bytes::{FromBytes, ToBytes};
 
+pub mod faults;
 mod interfaces;
+pub mod mmio;
+pub mod nand;
 pub mod registers;
+pub mod save;
 pub mod tlb;
 mod virage;
 
+pub use interfaces::pi::{NAND_BLOCK_SIZE, NAND_SPARE_SIZE};
+
+use faults::NandFaults;
 use interfaces::ai::Ai;
 use interfaces::mi::Mi;
 use interfaces::pi::{Dma, Pi};
@@ -20,7 +30,10 @@ use interfaces::si::Si;
 use interfaces::sp::Sp;
 use interfaces::usb::Usb;
 use interfaces::vi::Vi;
+use mmio::MmioLog;
+use nand::{NandDevice, NandImage};
 use registers::*;
+use save::SaveMemory;
 use tlb::TLBEntry;
 use virage::Virage;
 
@@ -46,6 +59,54 @@ impl ResetType {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Device {
+    Sp,
+    Mi,
+    Vi,
+    Ai,
+    Pi,
+    Si,
+    Usb0,
+    Usb1,
+    Virage,
+}
+
+impl Display for Device {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Self::Sp => "sp",
+            Self::Mi => "mi",
+            Self::Vi => "vi",
+            Self::Ai => "ai",
+            Self::Pi => "pi",
+            Self::Si => "si",
+            Self::Usb0 => "usb0",
+            Self::Usb1 => "usb1",
+            Self::Virage => "virage",
+        })
+    }
+}
+
+impl FromStr for Device {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sp" => Ok(Self::Sp),
+            "mi" => Ok(Self::Mi),
+            "vi" => Ok(Self::Vi),
+            "ai" => Ok(Self::Ai),
+            "pi" => Ok(Self::Pi),
+            "si" => Ok(Self::Si),
+            "usb0" => Ok(Self::Usb0),
+            "usb1" => Ok(Self::Usb1),
+            "virage" => Ok(Self::Virage),
+            _ => Err(format!("unknown device: {s}")),
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Register {
@@ -266,6 +327,10 @@ impl State {
         self.tlb[index] = entry;
     }
 
+    pub fn get_tlb(&self) -> &[TLBEntry] {
+        &self.tlb
+    }
+
     pub fn read_tlb_entry_regs(&mut self, index: usize) {
         let tlb = self.tlb[index];
         self.set_reg(Register::PageMask, tlb.page_mask());
@@ -318,6 +383,20 @@ pub struct Cop0 {
     usb1: Usb,
 
     flash_intr_timer: usize,
+    dma_timer: usize,
+
+    mmio_log: Option<MmioLog>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TlbLookup {
+    Hit(word),
+    Miss,
+    // the matching entry isn't valid, or isn't dirty for a write
+    Invalid,
+    Modified,
+    // more than one entry matched, which shuts the tlb down
+    Multiple,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -328,6 +407,13 @@ pub enum TLBResult<T> {
     SecureTrap(SecureTrapType),
 }
 
+// what a read is for, which decides the bus error it raises
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadKind {
+    Load,
+    Fetch,
+}
+
 impl Cop0 {
     const RAM_SIZE: usize = 0x00800000;
 
@@ -337,22 +423,25 @@ impl Cop0 {
         v0: Vec<byte>,
         v1: Vec<byte>,
         v2: Vec<byte>,
-        nand: Vec<byte>,
-        spare: Vec<byte>,
+        nand: NandImage,
     ) -> Self {
         Self {
             state: State::new(reset_type),
-            ram: Box::new([0; Self::RAM_SIZE]),
+            ram: vec![0; Self::RAM_SIZE]
+                .try_into()
+                .expect("ram already sized"),
             virage: Virage::new(bootrom, v0, v1, v2),
             sp: Sp::new(),
             mi: Mi::new(),
             vi: Vi::new(),
             ai: Ai::new(),
-            pi: Pi::new(nand, spare),
+            pi: Pi::new(nand),
             si: Si::new(),
             usb0: Usb::new(0x04900000),
             usb1: Usb::new(0x04A00000),
             flash_intr_timer: 0,
+            dma_timer: 0,
+            mmio_log: None,
         }
     }
 
@@ -372,12 +461,44 @@ impl Cop0 {
         self.virage.retrieve_v2()
     }
 
-    pub fn retrieve_nand(&self) -> Vec<byte> {
+    pub fn retrieve_nand(&self) -> NandImage {
         self.pi.retrieve_nand()
     }
 
-    pub fn retrieve_spare(&self) -> Vec<byte> {
-        self.pi.retrieve_spare()
+    pub fn nand_device(&self) -> NandDevice {
+        self.pi.nand_device()
+    }
+
+    pub fn nand_faults(&self) -> &NandFaults {
+        self.pi.nand_faults()
+    }
+
+    pub fn set_nand_faults(&mut self, faults: NandFaults) {
+        self.pi.set_nand_faults(faults);
+    }
+
+    pub fn take_dirty_nand_blocks(&mut self) -> Vec<usize> {
+        self.pi.take_dirty_blocks()
+    }
+
+    pub fn get_nand_block(&self, block: usize) -> (&[byte], &[byte]) {
+        self.pi.nand_block(block)
+    }
+
+    pub fn set_nand_block(&mut self, block: usize, data: &[byte], spare: &[byte]) {
+        self.pi.set_nand_block(block, data, spare);
+    }
+
+    pub fn save_memory(&self) -> Option<&SaveMemory> {
+        self.pi.save_memory()
+    }
+
+    pub fn set_save_memory(&mut self, save: Option<SaveMemory>) {
+        self.pi.set_save_memory(save);
+    }
+
+    pub fn take_dirty_save(&mut self) -> Option<Range<usize>> {
+        self.pi.take_dirty_save()
     }
 
     pub fn get_bootram(&self) -> &[byte] {
@@ -388,10 +509,32 @@ impl Cop0 {
         self.ram.as_ref()
     }
 
+    pub fn get_pi_buffer(&self) -> &[byte] {
+        self.pi.get_buffer()
+    }
+
     pub fn get_mi_mapping(&self) -> bool {
         self.mi.get_sec_mode_map()
     }
 
+    pub fn get_secure_mode(&self) -> bool {
+        self.mi.is_secure_mode()
+    }
+
+    pub fn get_device_registers(&self, device: Device) -> Vec<(&'static str, word)> {
+        match device {
+            Device::Sp => self.sp.registers(),
+            Device::Mi => self.mi.registers(),
+            Device::Vi => self.vi.registers(),
+            Device::Ai => self.ai.registers(),
+            Device::Pi => self.pi.registers(),
+            Device::Si => self.si.registers(),
+            Device::Usb0 => self.usb0.registers(),
+            Device::Usb1 => self.usb1.registers(),
+            Device::Virage => self.virage.registers(),
+        }
+    }
+
     pub fn set_secure_trap(&mut self, trap: SecureTrapType) {
         self.mi.set_secure_trap(trap);
     }
@@ -408,70 +551,146 @@ impl Cop0 {
         self.mi.trigger_md_intr();
     }
 
-    fn virt_to_phys(&mut self, address: word, write: bool) -> TLBResult<word> {
-        if is_ksegdm(address) {
-            TLBResult::Ok(kdm_to_phys(address))
-        } else {
-            let mut hits = 0;
+    pub fn mmio_log(&self) -> Option<&MmioLog> {
+        self.mmio_log.as_ref()
+    }
+
+    pub fn set_mmio_log(&mut self, log: Option<MmioLog>) {
+        self.mmio_log = log;
+    }
+
+    // accesses are logged whole, after they complete, so an 8-byte access to two
+    // registers shows up as two lines
+    fn log_mmio(&mut self, address: word, write: bool, bytes: &[byte]) {
+        let secure = self.mi.is_secure_mode();
+
+        for (index, chunk) in bytes.chunks(4).enumerate() {
+            let address = address.wrapping_add(index as word * 4);
+            let TLBResult::Ok(phys) = self.virt_to_phys(address, write) else {
+                return;
+            };
+            let Some((device, reg, offset)) = mmio::lookup(phys) else {
+                continue;
+            };
+            if self
+                .mmio_log
+                .as_ref()
+                .is_some_and(|log| log.matches(device, secure))
+            {
+                println!("{}", mmio::describe(device, reg, offset, write, chunk));
+            }
+        }
+    }
 
-            let mut p_addr = 0;
+    pub fn set_button(&mut self, pressed: bool) -> bool {
+        self.mi.set_button(pressed)
+    }
 
-            for i in 0..State::NUM_TLB_ENTRIES {
-                let entry = self.state.get_tlb_entry(i);
+    pub fn card_present(&self) -> bool {
+        self.pi.card_present()
+    }
 
-                let mask = entry.page_mask();
-                let hi = entry.entry_hi();
-                let lo0 = entry.entry_lo_0();
-                let lo1 = entry.entry_lo_1();
+    pub fn set_card_present(&mut self, present: bool) {
+        self.pi.set_card_present(present);
+    }
 
-                let mask = mask.mask() as word;
+    pub fn usb_receive(&mut self, controller: usize, offset: usize, data: &[byte]) {
+        match controller {
+            0 => self.usb0.receive(offset, data),
+            1 => self.usb1.receive(offset, data),
+            _ => unreachable!(),
+        }
+        self.mi.trigger_usb_intr(controller);
+    }
 
-                let size = State::page_size(mask);
+    // what the tlb makes of an address, without any of the side effects of a
+    // refill or an exception
+    fn tlb_lookup(&self, address: word, write: bool) -> TlbLookup {
+        let mut hits = 0;
 
-                let asid = (address >> 24) as byte;
-                let address = address & 0x00FFFFFF;
+        let mut p_addr = 0;
 
-                let vpn = address & ((!mask) << 13);
-                let lo = if (address & size) > 0 { lo1 } else { lo0 };
+        for i in 0..State::NUM_TLB_ENTRIES {
+            let entry = self.state.get_tlb_entry(i);
 
-                if (vpn >> 1) == hi.vpn() && (lo.g() || asid == hi.asid()) {
-                    hits += 1;
-                    if hits > 1 {
-                        let mut status: Status = self.state.get_reg(Register::Status);
-                        status.set_ts(true);
-                        self.state.set_reg(Register::Status, status);
+            let mask = entry.page_mask();
+            let hi = entry.entry_hi();
+            let lo0 = entry.entry_lo_0();
+            let lo1 = entry.entry_lo_1();
 
-                        return TLBResult::Shutdown;
-                    }
+            let mask = mask.mask() as word;
 
-                    if !lo.v() {
-                        self.state
-                            .set_reg(Register::BadVAddr, BadVAddr::new().with_bad_vaddr(address));
-
-                        return TLBResult::Exception(Exception::new_tlb(
-                            if write {
-                                ExceptionType::TLBMissWrite
-                            } else {
-                                ExceptionType::TLBMissRead
-                            },
-                            true,
-                        ));
-                    }
+            let size = State::page_size(mask);
 
-                    if !lo.d() && write {
-                        self.state
-                            .set_reg(Register::BadVAddr, BadVAddr::new().with_bad_vaddr(address));
+            let asid = (address >> 24) as byte;
+            let address = address & 0x00FFFFFF;
 
-                        return TLBResult::Exception(Exception::new(
-                            ExceptionType::TLBModification,
-                        ));
-                    }
+            let vpn = address & ((!mask) << 13);
+            let lo = if (address & size) > 0 { lo1 } else { lo0 };
+
+            if (vpn >> 1) == hi.vpn() && (lo.g() || asid == hi.asid()) {
+                hits += 1;
+                if hits > 1 {
+                    return TlbLookup::Multiple;
+                }
 
-                    p_addr = (lo.pfn() as word).wrapping_mul(size) | (address ^ vpn);
+                if !lo.v() {
+                    return TlbLookup::Invalid;
                 }
+
+                if !lo.d() && write {
+                    return TlbLookup::Modified;
+                }
+
+                p_addr = (lo.pfn() as word).wrapping_mul(size) | (address ^ vpn);
             }
+        }
 
-            if hits == 0 {
+        if hits == 0 {
+            TlbLookup::Miss
+        } else {
+            TlbLookup::Hit(p_addr)
+        }
+    }
+
+    fn virt_to_phys(&mut self, address: word, write: bool) -> TLBResult<word> {
+        if is_ksegdm(address) {
+            return TLBResult::Ok(kdm_to_phys(address));
+        }
+
+        match self.tlb_lookup(address, write) {
+            TlbLookup::Hit(p_addr) => TLBResult::Ok(p_addr),
+            TlbLookup::Multiple => {
+                let mut status: Status = self.state.get_reg(Register::Status);
+                status.set_ts(true);
+                self.state.set_reg(Register::Status, status);
+
+                TLBResult::Shutdown
+            }
+            TlbLookup::Invalid => {
+                self.state.set_reg(
+                    Register::BadVAddr,
+                    BadVAddr::new().with_bad_vaddr(address & 0x00FFFFFF),
+                );
+
+                TLBResult::Exception(Exception::new_tlb(
+                    if write {
+                        ExceptionType::TLBMissWrite
+                    } else {
+                        ExceptionType::TLBMissRead
+                    },
+                    true,
+                ))
+            }
+            TlbLookup::Modified => {
+                self.state.set_reg(
+                    Register::BadVAddr,
+                    BadVAddr::new().with_bad_vaddr(address & 0x00FFFFFF),
+                );
+
+                TLBResult::Exception(Exception::new(ExceptionType::TLBModification))
+            }
+            TlbLookup::Miss => {
                 self.state
                     .set_reg(Register::BadVAddr, BadVAddr::new().with_bad_vaddr(address));
 
@@ -484,13 +703,33 @@ impl Cop0 {
                 } else {
                     ExceptionType::TLBMissRead
                 }))
-            } else {
-                TLBResult::Ok(p_addr)
             }
         }
     }
 
-    fn read_byte(&mut self, address: word) -> TLBResult<byte> {
+    // a byte for the debugger: only memory that's actually backed, reached
+    // through the tlb without refilling it, and never a device register, so
+    // looking can't change the machine
+    pub fn peek_byte(&mut self, address: word) -> Option<byte> {
+        let address = if is_ksegdm(address) {
+            kdm_to_phys(address)
+        } else {
+            match self.tlb_lookup(address, false) {
+                TlbLookup::Hit(p_addr) => p_addr,
+                _ => return None,
+            }
+        };
+
+        match address {
+            0x00000000..=0x03EFFFFF => self.ram.get(address as usize).copied(),
+            0x04B00000..=0x0FFFFFFF => self.pi.peek_cart(address),
+            0x10000000..=0x1FBFFFFF => self.pi.read_atb_phys_addr(address).ok(),
+            0x1FC00000..=0x1FCFFFFF => self.virage.peek(address),
+            _ => None,
+        }
+    }
+
+    fn read_byte(&mut self, address: word, kind: ReadKind) -> TLBResult<byte> {
         let address = match self.virt_to_phys(address, false) {
             TLBResult::Ok(a) => a,
             TLBResult::Shutdown => return TLBResult::Shutdown,
@@ -508,6 +747,27 @@ impl Cop0 {
             return TLBResult::SecureTrap(SecureTrapType::App);
         }
 
+        // cartridge domain 1 goes through the atb, which can refuse the read
+        if (0x10000000..0x1FC00000).contains(&address) {
+            return match self.pi.read_atb_phys_addr(address) {
+                Ok(val) => TLBResult::Ok(val),
+                Err(e) => {
+                    let secure = self.mi.is_secure_mode();
+                    if self
+                        .mmio_log
+                        .as_ref()
+                        .is_some_and(|log| log.matches(Device::Pi, secure))
+                    {
+                        println!("pi: bus error: {e}");
+                    }
+                    TLBResult::Exception(Exception::new(match kind {
+                        ReadKind::Load => ExceptionType::BusErrorLS,
+                        ReadKind::Fetch => ExceptionType::BusErrorF,
+                    }))
+                }
+            };
+        }
+
         TLBResult::Ok(self.read_phys_addr(address))
     }
 
@@ -530,7 +790,7 @@ impl Cop0 {
         TLBResult::Ok(())
     }
 
-    pub fn read<T: FromBytes>(&mut self, address: word) -> TLBResult<T>
+    pub fn read<T: FromBytes>(&mut self, address: word, kind: ReadKind) -> TLBResult<T>
     where
         <T as FromBytes>::Bytes: Sized + TryFrom<Vec<u8>>,
         <<T as FromBytes>::Bytes as TryFrom<Vec<u8>>>::Error: Debug,
@@ -544,7 +804,7 @@ impl Cop0 {
         let mut bytes = vec![0u8; size];
 
         for (index, b) in bytes.iter_mut().enumerate() {
-            *b = match self.read_byte(address.wrapping_add(index as word)) {
+            *b = match self.read_byte(address.wrapping_add(index as word), kind) {
                 TLBResult::Ok(b) => b,
                 TLBResult::Shutdown => return TLBResult::Shutdown,
                 TLBResult::Exception(e) => return TLBResult::Exception(e),
@@ -552,6 +812,10 @@ impl Cop0 {
             };
         }
 
+        if self.mmio_log.is_some() {
+            self.log_mmio(address, false, &bytes);
+        }
+
         let bytes = bytes.try_into().expect("should never fail");
 
         TLBResult::Ok(T::from_be_bytes(&bytes))
@@ -568,6 +832,7 @@ impl Cop0 {
         }
 
         let bytes = val.to_be_bytes();
+        let logged = self.mmio_log.is_some().then(|| bytes.as_ref().to_vec());
 
         for (index, b) in bytes.into_iter().enumerate() {
             match self.write_byte(address + index as word, b) {
@@ -578,6 +843,10 @@ impl Cop0 {
             }
         }
 
+        if let Some(bytes) = logged {
+            self.log_mmio(address, true, &bytes);
+        }
+
         TLBResult::Ok(())
     }
 
@@ -585,6 +854,17 @@ impl Cop0 {
         let dma_queued = self.pi.dma_queued();
 
         if dma_queued {
+            // the transfer happens all at once, when the bus would have finished it
+            if self.dma_timer == 0 {
+                self.dma_timer = self.pi.dma_steps();
+            }
+
+            self.dma_timer -= 1;
+
+            if self.dma_timer != 0 {
+                return false;
+            }
+
             match self.pi.dma_params() {
                 Dma::Read(dram_addr, pi_addr, len) => {
                     self.pi.bus_write(
@@ -594,8 +874,10 @@ impl Cop0 {
                     );
                 }
                 Dma::Write(dram_addr, pi_addr, len) => {
-                    self.ram[dram_addr as usize..(dram_addr + len) as usize]
-                        .copy_from_slice(&self.pi.bus_read(pi_addr, len));
+                    if let Some(data) = self.pi.bus_read(pi_addr, len) {
+                        self.ram[dram_addr as usize..(dram_addr + len) as usize]
+                            .copy_from_slice(&data);
+                    }
                 }
                 Dma::BufRead(dram_addr, pi_addr, len) => {
                     let len = len.min(0x400);
@@ -608,8 +890,10 @@ impl Cop0 {
                 Dma::BufWrite(dram_addr, pi_addr, len) => {
                     let len = len.min(0x400);
                     println!("dram_addr: {dram_addr:08X}, pi_addr: {pi_addr:08X}, len: {len:08X}");
-                    self.ram[dram_addr as usize..(dram_addr + len) as usize]
-                        .copy_from_slice(self.pi.buf_read(pi_addr, len));
+                    if let Some(data) = self.pi.buf_read(pi_addr, len) {
+                        self.ram[dram_addr as usize..(dram_addr + len) as usize]
+                            .copy_from_slice(data);
+                    }
                 }
             }
 
@@ -643,26 +927,37 @@ impl Cop0 {
         }
     }
 
+    pub fn aes(&mut self) -> bool {
+        self.pi.step_aes();
+        self.mi.set_aes_intr(self.pi.aes_interrupt())
+    }
+
     pub fn module(&mut self) -> bool {
         self.mi.md_intr()
     }
 
+    pub fn button(&mut self) -> bool {
+        self.mi.button_intr()
+    }
+
+    pub fn usb(&mut self) -> bool {
+        self.mi.usb_intr()
+    }
+
     fn read_phys_addr(&mut self, address: word) -> byte {
         // should come back and tidy this up later
         // probably won't
 
         if (0x00000000..0x03F00000).contains(&address) {
             self.ram[address as usize]
-        } else if (0x03F00000..0x04000000).contains(&address) {
-            todo!()
         } else if (0x04000000..0x04100000).contains(&address) {
             self.sp.read_phys_addr(address)
-        } else if (0x04100000..0x04200000).contains(&address) {
-            // dp
-            todo!()
-        } else if (0x04200000..0x04300000).contains(&address) {
-            // dp span
-            todo!()
+        } else if (0x03F00000..0x04000000).contains(&address)
+            || (0x04100000..0x04300000).contains(&address)
+        {
+            // the rdram registers, the dp and the dp span aren't emulated
+            println!("unimplemented read: {:08X}", address);
+            0
         } else if (0x04300000..0x04400000).contains(&address) {
             self.mi.read_phys_addr(address)
         } else if (0x04400000..0x04500000).contains(&address) {
@@ -681,21 +976,11 @@ impl Cop0 {
             self.usb0.read_phys_addr(address)
         } else if (0x04A00000..0x04B00000).contains(&address) {
             self.usb1.read_phys_addr(address)
-        } else if (0x04B00000..0x05000000).contains(&address) {
-            // open bus
-            todo!()
-        } else if (0x05000000..0x06000000).contains(&address) {
-            // cartridge domain 2
-            todo!()
-        } else if (0x06000000..0x08000000).contains(&address) {
-            // cartridge domain 1
-            todo!()
-        } else if (0x08000000..0x10000000).contains(&address) {
-            // cartridge domain 2
-            todo!()
+        } else if (0x04B00000..0x10000000).contains(&address) {
+            // open bus, then cartridge domains 2, 1 and 2
+            self.pi.read_cart_phys_addr(address)
         } else if (0x10000000..0x1FC00000).contains(&address) {
-            // cartridge domain 1
-            self.pi.read_atb_phys_addr(address)
+            unreachable!("cartridge domain 1 is read through the atb")
         } else if (0x1FC00000..0x1FD00000).contains(&address) {
             self.virage.read_phys_addr(address)
         } else {
@@ -707,16 +992,13 @@ impl Cop0 {
     fn write_phys_addr(&mut self, address: word, val: byte) {
         if (0x00000000..0x03F00000).contains(&address) {
             self.ram[address as usize] = val;
-        } else if (0x03F00000..0x04000000).contains(&address) {
-            todo!()
         } else if (0x04000000..0x04100000).contains(&address) {
             self.sp.write_phys_addr(address, val);
-        } else if (0x04100000..0x04200000).contains(&address) {
-            // dp
-            todo!()
-        } else if (0x04200000..0x04300000).contains(&address) {
-            // dp span
-            todo!()
+        } else if (0x03F00000..0x04000000).contains(&address)
+            || (0x04100000..0x04300000).contains(&address)
+        {
+            // the rdram registers, the dp and the dp span aren't emulated
+            println!("ignored write: {:08X} {:02X}", address, val);
         } else if (0x04300000..0x04400000).contains(&address) {
             self.mi.write_phys_addr(address, val);
             if self.mi.mapping_changed {
@@ -738,21 +1020,12 @@ impl Cop0 {
             self.usb0.write_phys_addr(address, val);
         } else if (0x04A00000..0x04B00000).contains(&address) {
             self.usb1.write_phys_addr(address, val);
-        } else if (0x04B00000..0x05000000).contains(&address) {
-            // open bus
-            todo!()
-        } else if (0x05000000..0x06000000).contains(&address) {
-            // cartridge domain 2
-            todo!()
-        } else if (0x06000000..0x08000000).contains(&address) {
-            // cartridge domain 1
-            todo!()
-        } else if (0x08000000..0x10000000).contains(&address) {
-            // cartridge domain 2
-            todo!()
+        } else if (0x04B00000..0x10000000).contains(&address) {
+            // open bus, then cartridge domains 2, 1 and 2
+            self.pi.write_cart_phys_addr(address, val);
         } else if (0x10000000..0x1FC00000).contains(&address) {
-            // cartridge domain 1
-            todo!()
+            // cartridge domain 1, which the atb only maps for reading
+            println!("ignored atb write: {:08X} {:02X}", address, val);
         } else if (0x1FC00000..0x1FD00000).contains(&address) {
             self.virage.write_phys_addr(address, val);
         } else {
@@ -760,3 +1033,100 @@ impl Cop0 {
         }
     }
 }
+
+impl Snapshot for State {
+    fn snapshot(&mut self, s: &mut Snapshotter) -> Result<(), SnapshotError> {
+        s.section("CP0")?;
+        for reg in &mut self.registers {
+            s.value(reg)?;
+        }
+        s.bool(&mut self.coc)?;
+        for entry in &mut self.tlb {
+            let mut bytes = entry.into_bytes();
+            s.bytes(&mut bytes)?;
+            *entry = TLBEntry::from_bytes(bytes);
+        }
+        Ok(())
+    }
+}
+
+impl Snapshot for Cop0 {
+    fn snapshot(&mut self, s: &mut Snapshotter) -> Result<(), SnapshotError> {
+        self.state.snapshot(s)?;
+        s.section("RAM")?;
+        s.bytes(&mut self.ram[..])?;
+        self.virage.snapshot(s)?;
+        self.sp.snapshot(s)?;
+        self.mi.snapshot(s)?;
+        self.vi.snapshot(s)?;
+        self.ai.snapshot(s)?;
+        self.pi.snapshot(s)?;
+        self.si.snapshot(s)?;
+        self.usb0.snapshot(s)?;
+        self.usb1.snapshot(s)?;
+        s.usize(&mut self.flash_intr_timer)?;
+        s.usize(&mut self.dma_timer)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cop0() -> Cop0 {
+        let device: NandDevice = "64m".parse().unwrap();
+        let blocks = device.size() / NAND_BLOCK_SIZE;
+        let nand = NandImage::split(
+            vec![0xFF; device.size()],
+            vec![0xFF; blocks * NAND_SPARE_SIZE],
+            Some(device),
+        )
+        .unwrap();
+        Cop0::new(ResetType::Cold, vec![], vec![], vec![], vec![], nand)
+    }
+
+    #[test]
+    fn peeks_leave_the_machine_alone() {
+        let mut cop0 = cop0();
+        cop0.ram[0x100] = 0x12;
+        assert_eq!(cop0.peek_byte(0x80000100), Some(0x12));
+        assert_eq!(cop0.peek_byte(0xBFC00000), Some(0));
+
+        // past the end of ram, the dp and a device register
+        assert_eq!(cop0.peek_byte(0xA0800000), None);
+        assert_eq!(cop0.peek_byte(0xA4100000), None);
+        assert_eq!(cop0.peek_byte(0xA4600010), None);
+
+        // a tlb miss doesn't touch the refill registers
+        let bad_vaddr = BadVAddr::new().with_bad_vaddr(0x1234);
+        cop0.state.set_reg(Register::BadVAddr, bad_vaddr);
+        assert_eq!(cop0.peek_byte(0x00400000), None);
+        assert_eq!(cop0.state.get_reg_raw(Register::BadVAddr), 0x1234);
+        assert_eq!(cop0.state.get_reg_raw(Register::Context), 0);
+    }
+
+    #[test]
+    fn atb_misses_raise_the_bus_error_for_the_access() {
+        // nothing is mapped in the atb yet
+        let mut cop0 = cop0();
+        assert_eq!(
+            cop0.read::<word>(0xB0000000, ReadKind::Load),
+            TLBResult::Exception(Exception::new(ExceptionType::BusErrorLS))
+        );
+        assert_eq!(
+            cop0.read::<word>(0xB0000000, ReadKind::Fetch),
+            TLBResult::Exception(Exception::new(ExceptionType::BusErrorF))
+        );
+    }
+
+    #[test]
+    fn unemulated_registers_read_as_zero() {
+        // the rdram registers, the dp and the dp span
+        let mut cop0 = cop0();
+        for address in [0xA3F00000, 0xA4100000, 0xA4200000] {
+            assert_eq!(cop0.write::<word>(address, 0x12345678), TLBResult::Ok(()));
+            assert_eq!(cop0.read::<word>(address, ReadKind::Load), TLBResult::Ok(0));
+        }
+    }
+}
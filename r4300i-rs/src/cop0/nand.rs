@@ -0,0 +1,419 @@
+use std::fmt;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::cop0::{NAND_BLOCK_SIZE, NAND_SPARE_SIZE};
+use crate::types::*;
+
+// the flash parts cards are built from; both are small-block chips with 512
+// byte pages, a 16 byte spare area per page and 32 pages to a block, so only
+// their size and id differ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NandChip {
+    // 64MB
+    K9F1208,
+    // 128MB
+    K9K1G08,
+}
+
+impl NandChip {
+    pub fn size(self) -> usize {
+        match self {
+            Self::K9F1208 => 64 * 1024 * 1024,
+            Self::K9K1G08 => 128 * 1024 * 1024,
+        }
+    }
+
+    // maker and device code, as read back by 0x90
+    pub fn id(self) -> [byte; 2] {
+        match self {
+            Self::K9F1208 => [0xEC, 0x76],
+            Self::K9K1G08 => [0xEC, 0x79],
+        }
+    }
+
+    fn from_byte(value: byte) -> Option<Self> {
+        match value {
+            0 => Some(Self::K9F1208),
+            1 => Some(Self::K9K1G08),
+            _ => None,
+        }
+    }
+
+    fn to_byte(self) -> byte {
+        match self {
+            Self::K9F1208 => 0,
+            Self::K9K1G08 => 1,
+        }
+    }
+}
+
+impl FromStr for NandChip {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "64m" | "k9f1208" => Ok(Self::K9F1208),
+            "128m" | "k9k1g08" => Ok(Self::K9K1G08),
+            _ => Err(format!("unknown nand chip: {s} (expected 64m or 128m)")),
+        }
+    }
+}
+
+impl fmt::Display for NandChip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::K9F1208 => write!(f, "64m"),
+            Self::K9K1G08 => write!(f, "128m"),
+        }
+    }
+}
+
+// a card: one or more identical chips, picked with the flash control
+// register's device id and laid out one after another in the nand image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NandDevice {
+    pub chip: NandChip,
+    pub chips: usize,
+}
+
+impl NandDevice {
+    // the device id field is two bits wide
+    pub const MAX_CHIPS: usize = 4;
+
+    pub fn size(&self) -> usize {
+        self.chip.size() * self.chips
+    }
+
+    // the card with exactly this much data, preferring fewer, bigger chips
+    pub fn for_size(len: usize) -> Option<Self> {
+        (1..=Self::MAX_CHIPS)
+            .flat_map(|chips| {
+                [NandChip::K9F1208, NandChip::K9K1G08].map(|chip| Self { chip, chips })
+            })
+            .find(|device| device.size() == len)
+    }
+
+    pub(crate) fn to_bytes(self) -> [byte; 2] {
+        [self.chip.to_byte(), self.chips as byte]
+    }
+
+    pub(crate) fn from_bytes(bytes: [byte; 2]) -> Option<Self> {
+        let chips = bytes[1] as usize;
+        if !(1..=Self::MAX_CHIPS).contains(&chips) {
+            return None;
+        }
+        Some(Self {
+            chip: NandChip::from_byte(bytes[0])?,
+            chips,
+        })
+    }
+}
+
+// "64m", "128m", or "2x64m" for several chips
+impl FromStr for NandDevice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (chips, chip) = match s.split_once(['x', 'X']) {
+            Some((chips, chip)) if chips.chars().all(|c| c.is_ascii_digit()) => (
+                chips
+                    .parse()
+                    .map_err(|_| format!("invalid chip count: {chips}"))?,
+                chip,
+            ),
+            _ => (1, s),
+        };
+        if !(1..=Self::MAX_CHIPS).contains(&chips) {
+            return Err(format!(
+                "a card has 1 to {} chips, not {chips}",
+                Self::MAX_CHIPS
+            ));
+        }
+        Ok(Self {
+            chip: chip.parse()?,
+            chips,
+        })
+    }
+}
+
+impl fmt::Display for NandDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.chips > 1 {
+            write!(f, "{}x", self.chips)?;
+        }
+        write!(f, "{}", self.chip)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum NandImageError {
+    #[error("{0:#X} bytes isn't a whole number of {NAND_BLOCK_SIZE:#X} byte blocks")]
+    Split(usize),
+    #[error("{0:#X} bytes isn't a whole number of {COMBINED_BLOCK_SIZE:#X} byte blocks (data and spare area)")]
+    Combined(usize),
+    #[error("{0:#X} bytes is the size of a combined image, which has no spare file")]
+    LooksCombined(usize),
+    #[error("{0:#X} bytes is the size of a split image, which needs its spare file")]
+    LooksSplit(usize),
+    #[error("{0:#X} bytes of data doesn't match the size of any card")]
+    UnknownSize(usize),
+    #[error("{len:#X} bytes of data doesn't match a {device} card ({:#X} bytes)", device.size())]
+    DeviceSize { len: usize, device: NandDevice },
+    #[error("spare data is {len:#X} bytes, but {blocks} blocks need {:#X}", blocks * NAND_SPARE_SIZE)]
+    SpareSize { len: usize, blocks: usize },
+}
+
+// dumps come either split, as the data plus a file with one spare area per
+// block, or combined, with each block's spare area following its data
+pub const COMBINED_BLOCK_SIZE: usize = NAND_BLOCK_SIZE + NAND_SPARE_SIZE;
+
+// a card's contents: the data, one spare area per block, and the chips they
+// sit on; the sizes always match the device
+#[derive(Debug, Clone)]
+pub struct NandImage {
+    pub(crate) data: Vec<byte>,
+    pub(crate) spare: Vec<byte>,
+    pub(crate) device: NandDevice,
+}
+
+impl NandImage {
+    // a split image if there's spare data, otherwise a combined one; without a
+    // device, the size of the data picks one
+    pub fn load(
+        data: Vec<byte>,
+        spare: Option<Vec<byte>>,
+        device: Option<NandDevice>,
+    ) -> Result<Self, NandImageError> {
+        match spare {
+            Some(spare) => Self::split(data, spare, device),
+            None => Self::combined(&data, device),
+        }
+    }
+
+    pub fn split(
+        data: Vec<byte>,
+        spare: Vec<byte>,
+        device: Option<NandDevice>,
+    ) -> Result<Self, NandImageError> {
+        let len = data.len();
+        if len.is_multiple_of(COMBINED_BLOCK_SIZE)
+            && NandDevice::for_size(len / COMBINED_BLOCK_SIZE * NAND_BLOCK_SIZE).is_some()
+        {
+            return Err(NandImageError::LooksCombined(len));
+        }
+        if !len.is_multiple_of(NAND_BLOCK_SIZE) {
+            return Err(NandImageError::Split(len));
+        }
+
+        let device = Self::device_for(len, device)?;
+        let blocks = len / NAND_BLOCK_SIZE;
+        if spare.len() != blocks * NAND_SPARE_SIZE {
+            return Err(NandImageError::SpareSize {
+                len: spare.len(),
+                blocks,
+            });
+        }
+        Ok(Self {
+            data,
+            spare,
+            device,
+        })
+    }
+
+    pub fn combined(image: &[byte], device: Option<NandDevice>) -> Result<Self, NandImageError> {
+        if NandDevice::for_size(image.len()).is_some() {
+            return Err(NandImageError::LooksSplit(image.len()));
+        }
+        if !image.len().is_multiple_of(COMBINED_BLOCK_SIZE) {
+            return Err(NandImageError::Combined(image.len()));
+        }
+
+        let blocks = image.len() / COMBINED_BLOCK_SIZE;
+        let device = Self::device_for(blocks * NAND_BLOCK_SIZE, device)?;
+        let mut data = Vec::with_capacity(blocks * NAND_BLOCK_SIZE);
+        let mut spare = Vec::with_capacity(blocks * NAND_SPARE_SIZE);
+        for block in image.chunks_exact(COMBINED_BLOCK_SIZE) {
+            let (block_data, block_spare) = block.split_at(NAND_BLOCK_SIZE);
+            data.extend_from_slice(block_data);
+            spare.extend_from_slice(block_spare);
+        }
+        Ok(Self {
+            data,
+            spare,
+            device,
+        })
+    }
+
+    fn device_for(len: usize, device: Option<NandDevice>) -> Result<NandDevice, NandImageError> {
+        match device {
+            Some(device) if device.size() == len => Ok(device),
+            Some(device) => Err(NandImageError::DeviceSize { len, device }),
+            None => NandDevice::for_size(len).ok_or(NandImageError::UnknownSize(len)),
+        }
+    }
+
+    pub fn device(&self) -> NandDevice {
+        self.device
+    }
+
+    pub fn blocks(&self) -> usize {
+        self.data.len() / NAND_BLOCK_SIZE
+    }
+
+    // a block's data and spare area
+    pub fn block(&self, block: usize) -> Option<(&[byte], &[byte])> {
+        (block < self.blocks()).then(|| {
+            (
+                &self.data[block * NAND_BLOCK_SIZE..(block + 1) * NAND_BLOCK_SIZE],
+                &self.spare[block * NAND_SPARE_SIZE..(block + 1) * NAND_SPARE_SIZE],
+            )
+        })
+    }
+
+    pub fn block_mut(&mut self, block: usize) -> Option<(&mut [byte], &mut [byte])> {
+        (block < self.blocks()).then(|| {
+            (
+                &mut self.data[block * NAND_BLOCK_SIZE..(block + 1) * NAND_BLOCK_SIZE],
+                &mut self.spare[block * NAND_SPARE_SIZE..(block + 1) * NAND_SPARE_SIZE],
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: usize = 1024 * 1024;
+
+    #[test]
+    fn devices() {
+        let device: NandDevice = "2x64m".parse().unwrap();
+        assert_eq!(
+            device,
+            NandDevice {
+                chip: NandChip::K9F1208,
+                chips: 2
+            }
+        );
+        assert_eq!(device.size(), 128 * MB);
+        assert_eq!(device.to_string(), "2x64m");
+        assert_eq!("K9K1G08".parse::<NandDevice>().unwrap().to_string(), "128m");
+
+        for bad in ["5x64m", "0x64m", "256m", "x64m"] {
+            assert!(bad.parse::<NandDevice>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn sizes_pick_the_fewest_chips() {
+        let device = |s: &str| s.parse::<NandDevice>().ok();
+        assert_eq!(NandDevice::for_size(64 * MB), device("64m"));
+        assert_eq!(NandDevice::for_size(128 * MB), device("128m"));
+        assert_eq!(NandDevice::for_size(192 * MB), device("3x64m"));
+        assert_eq!(NandDevice::for_size(256 * MB), device("2x128m"));
+        assert_eq!(NandDevice::for_size(512 * MB), device("4x128m"));
+        assert_eq!(NandDevice::for_size(32 * MB), None);
+        assert_eq!(NandDevice::for_size(640 * MB), None);
+    }
+
+    #[test]
+    fn ids_and_snapshot_bytes() {
+        assert_eq!(NandChip::K9F1208.id(), [0xEC, 0x76]);
+        assert_eq!(NandChip::K9K1G08.id(), [0xEC, 0x79]);
+
+        let device: NandDevice = "3x128m".parse().unwrap();
+        assert_eq!(NandDevice::from_bytes(device.to_bytes()), Some(device));
+        assert_eq!(NandDevice::from_bytes([0, 0]), None);
+        assert_eq!(NandDevice::from_bytes([0, 5]), None);
+        assert_eq!(NandDevice::from_bytes([2, 1]), None);
+    }
+
+    fn blocks(device: &str) -> usize {
+        device.parse::<NandDevice>().unwrap().size() / NAND_BLOCK_SIZE
+    }
+
+    #[test]
+    fn split_images() {
+        let blocks = blocks("64m");
+        let image = NandImage::load(
+            vec![0; blocks * NAND_BLOCK_SIZE],
+            Some(vec![0; blocks * NAND_SPARE_SIZE]),
+            None,
+        )
+        .unwrap();
+        assert_eq!(image.device().to_string(), "64m");
+        assert_eq!(image.blocks(), blocks);
+
+        assert!(matches!(
+            NandImage::split(vec![0; 32 * MB], vec![0; 32 * MB / 0x400], None),
+            Err(NandImageError::UnknownSize(_))
+        ));
+        assert!(matches!(
+            NandImage::split(vec![0; 64 * MB + 1], vec![], None),
+            Err(NandImageError::Split(_))
+        ));
+        assert!(matches!(
+            NandImage::split(vec![0; 64 * MB], vec![0; 0x10], None),
+            Err(NandImageError::SpareSize { len: 0x10, .. })
+        ));
+        assert!(matches!(
+            NandImage::split(
+                vec![0; 64 * MB],
+                vec![0; blocks * NAND_SPARE_SIZE],
+                Some("128m".parse().unwrap())
+            ),
+            Err(NandImageError::DeviceSize { .. })
+        ));
+
+        // a combined image given with a spare file
+        assert!(matches!(
+            NandImage::split(vec![0; blocks * COMBINED_BLOCK_SIZE], vec![], None),
+            Err(NandImageError::LooksCombined(_))
+        ));
+    }
+
+    #[test]
+    fn combined_images() {
+        let blocks = blocks("2x64m");
+        let mut combined = vec![0; blocks * COMBINED_BLOCK_SIZE];
+        for (number, block) in combined.chunks_exact_mut(COMBINED_BLOCK_SIZE).enumerate() {
+            block[..NAND_BLOCK_SIZE].fill(number as byte);
+            block[NAND_BLOCK_SIZE..].fill(!number as byte);
+        }
+
+        // the same card as the split image with the spare areas pulled out
+        let image = NandImage::load(combined, None, None).unwrap();
+        assert_eq!(image.device().to_string(), "128m");
+        assert_eq!(image.blocks(), blocks);
+        for number in [0, 1, blocks - 1] {
+            let (data, spare) = image.block(number).unwrap();
+            assert!(data.iter().all(|byte| *byte == number as byte));
+            assert!(spare.iter().all(|byte| *byte == !number as byte));
+        }
+        assert!(image.block(blocks).is_none());
+
+        let image = NandImage::combined(
+            &vec![0; blocks * COMBINED_BLOCK_SIZE],
+            Some("2x64m".parse().unwrap()),
+        )
+        .unwrap();
+        assert_eq!(image.device().to_string(), "2x64m");
+
+        // a split image given without its spare file
+        assert!(matches!(
+            NandImage::combined(&vec![0; 64 * MB], None),
+            Err(NandImageError::LooksSplit(_))
+        ));
+        assert!(matches!(
+            NandImage::combined(&vec![0; COMBINED_BLOCK_SIZE + 1], None),
+            Err(NandImageError::Combined(_))
+        ));
+        assert!(matches!(
+            NandImage::combined(&vec![0; 3 * COMBINED_BLOCK_SIZE], None),
+            Err(NandImageError::UnknownSize(_))
+        ));
+    }
+}
@@ -0,0 +1,57 @@
+use std::ops::Range;
+
+use thiserror::Error;
+
+use crate::types::*;
+
+// the cartridge domain 2 windows, where carts keep their sram or flashram
+pub const DOMAIN2: [Range<word>; 2] = [0x05000000..0x06000000, 0x08000000..0x10000000];
+
+#[derive(Debug, Error)]
+pub enum SaveMemoryError {
+    #[error("save memory is empty")]
+    Empty,
+    #[error("{len:#X} bytes of save memory at {address:08X} doesn't fit in cartridge domain 2")]
+    Domain { address: word, len: usize },
+}
+
+// memory backing part of domain 2, read and written a byte at a time like
+// sram; a flashram's command interface isn't modelled, only its contents
+#[derive(Debug, Clone)]
+pub struct SaveMemory {
+    pub(crate) address: word,
+    pub(crate) data: Vec<byte>,
+}
+
+impl SaveMemory {
+    pub fn new(address: word, data: Vec<byte>) -> Result<Self, SaveMemoryError> {
+        if data.is_empty() {
+            return Err(SaveMemoryError::Empty);
+        }
+        let end = address as u64 + data.len() as u64;
+        if !DOMAIN2
+            .iter()
+            .any(|window| window.contains(&address) && end <= window.end as u64)
+        {
+            return Err(SaveMemoryError::Domain {
+                address,
+                len: data.len(),
+            });
+        }
+        Ok(Self { address, data })
+    }
+
+    pub fn address(&self) -> word {
+        self.address
+    }
+
+    pub fn data(&self) -> &[byte] {
+        &self.data
+    }
+
+    // where a bus address falls in the data, if it does
+    pub(crate) fn offset(&self, address: word) -> Option<usize> {
+        let offset = address.checked_sub(self.address)? as usize;
+        (offset < self.data.len()).then_some(offset)
+    }
+}
@@ -1,5 +1,7 @@
 use std::fs::write;
 
+use crate::cop0::mmio::{register, RegisterInfo};
+use crate::snapshot::{Snapshot, SnapshotError, Snapshotter};
 use crate::types::*;
 
 use modular_bitfield::prelude::*;
@@ -75,6 +77,36 @@ pub struct Ctrl {
     pub bypass: bool,
 }
 
+pub const REGISTERS: &[RegisterInfo] = &[
+    register!("V0_CRSTO0", 0x1FC88000),
+    register!("V0_CRSTO1", 0x1FC88004),
+    register!("V0_CRM0", 0x1FC88008),
+    register!("V0_CRM1", 0x1FC8800C),
+    register!("V0_CRM2", 0x1FC88010),
+    register!("V0_CRM3", 0x1FC88014),
+    register!("V0_CTRL", 0x1FC8C000, Ctrl),
+    register!("V0_NMS", 0x1FC8D000, Ctrl),
+    register!("V0_CP", 0x1FC8E000, Ctrl),
+    register!("V1_CRSTO0", 0x1FC98000),
+    register!("V1_CRSTO1", 0x1FC98004),
+    register!("V1_CRM0", 0x1FC98008),
+    register!("V1_CRM1", 0x1FC9800C),
+    register!("V1_CRM2", 0x1FC98010),
+    register!("V1_CRM3", 0x1FC98014),
+    register!("V1_CTRL", 0x1FC9C000, Ctrl),
+    register!("V1_NMS", 0x1FC9D000, Ctrl),
+    register!("V1_CP", 0x1FC9E000, Ctrl),
+    register!("V2_CRSTO0", 0x1FCA8000),
+    register!("V2_CRSTO1", 0x1FCA8004),
+    register!("V2_CRM0", 0x1FCA8008),
+    register!("V2_CRM1", 0x1FCA800C),
+    register!("V2_CRM2", 0x1FCA8010),
+    register!("V2_CRM3", 0x1FCA8014),
+    register!("V2_CTRL", 0x1FCAC000, Ctrl),
+    register!("V2_NMS", 0x1FCAD000, Ctrl),
+    register!("V2_CP", 0x1FCAE000, Ctrl),
+];
+
 #[derive(Debug)]
 pub struct Virage01 {
     config: ControllerConfig,
@@ -335,6 +367,20 @@ impl Virage {
         &self.bootram
     }
 
+    pub fn registers(&self) -> Vec<(&'static str, word)> {
+        vec![
+            ("V0_CTRL", self.v0.ctrl.into()),
+            ("V0_NMS", self.v0.nms.into()),
+            ("V0_CP", self.v0.cp.into()),
+            ("V1_CTRL", self.v1.ctrl.into()),
+            ("V1_NMS", self.v1.nms.into()),
+            ("V1_CP", self.v1.cp.into()),
+            ("V2_CTRL", self.v2.ctrl.into()),
+            ("V2_NMS", self.v2.nms.into()),
+            ("V2_CP", self.v2.cp.into()),
+        ]
+    }
+
     pub fn set_mapping(&mut self, mapping: bool) {
         let (rom, ram) = if mapping {
             (0x1FC00000, 0x1FC20000)
@@ -345,26 +391,33 @@ impl Virage {
         self.bootram_start = ram;
     }
 
-    fn _read_phys_addr(&mut self, address: word) -> byte {
+    // the rom and srams, but not the config and control registers
+    pub fn peek(&self, address: word) -> Option<byte> {
         if (self.bootrom_start..self.bootrom_start + Self::BOOTROM_SIZE as word).contains(&address)
         {
-            self.bootrom[(address - self.bootrom_start) as usize]
+            Some(self.bootrom[(address - self.bootrom_start) as usize])
         } else if (self.bootram_start..self.bootram_start + Self::BOOTRAM_SIZE as word)
             .contains(&address)
         {
-            self.bootram[(address - self.bootram_start) as usize]
+            Some(self.bootram[(address - self.bootram_start) as usize])
         } else if (0x1FC40000..0x1FC48000).contains(&address) {
-            self.sram[(address - 0x1FC40000) as usize]
+            Some(self.sram[(address - 0x1FC40000) as usize])
         } else if (0x1FC80000..0x1FC80040).contains(&address) {
-            self.v0.sram[(address - 0x1FC80000) as usize]
+            Some(self.v0.sram[(address - 0x1FC80000) as usize])
         } else if (0x1FC90000..0x1FC90040).contains(&address) {
-            self.v1.sram[(address - 0x1FC90000) as usize]
+            Some(self.v1.sram[(address - 0x1FC90000) as usize])
         } else if (0x1FCA0000..0x1FCA0100).contains(&address) {
-            self.v2.sram[(address - 0x1FCA0000) as usize]
+            Some(self.v2.sram[(address - 0x1FCA0000) as usize])
         } else {
+            None
+        }
+    }
+
+    fn _read_phys_addr(&mut self, address: word) -> byte {
+        self.peek(address).unwrap_or_else(|| {
             eprintln!("unmapped virage read: {address:08X}");
             unimplemented!();
-        }
+        })
     }
 
     pub fn read_phys_addr(&mut self, address: word) -> byte {
@@ -449,3 +502,58 @@ impl Virage {
         }
     }
 }
+
+impl Snapshot for ControllerConfig {
+    fn snapshot(&mut self, s: &mut Snapshotter) -> Result<(), SnapshotError> {
+        for crsto in &mut self.crsto {
+            s.value(crsto)?;
+        }
+        for crm in &mut self.crm {
+            s.value(crm)?;
+        }
+        Ok(())
+    }
+}
+
+impl Snapshot for Virage01 {
+    fn snapshot(&mut self, s: &mut Snapshotter) -> Result<(), SnapshotError> {
+        s.section("V01")?;
+        self.config.snapshot(s)?;
+        s.bytes(&mut self.data)?;
+        s.bytes(&mut self.sram)?;
+        s.bits(&mut self.ctrl)?;
+        s.bits(&mut self.nms)?;
+        s.bits(&mut self.cp)?;
+        s.value(&mut self.command)?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Virage2 {
+    fn snapshot(&mut self, s: &mut Snapshotter) -> Result<(), SnapshotError> {
+        s.section("V2")?;
+        self.config.snapshot(s)?;
+        s.bytes(&mut self.data)?;
+        s.bytes(&mut self.sram)?;
+        s.bits(&mut self.ctrl)?;
+        s.bits(&mut self.nms)?;
+        s.bits(&mut self.cp)?;
+        s.value(&mut self.command)?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Virage {
+    fn snapshot(&mut self, s: &mut Snapshotter) -> Result<(), SnapshotError> {
+        s.section("VIRG")?;
+        s.bytes(&mut self.bootrom)?;
+        s.bytes(&mut self.bootram)?;
+        s.bytes(&mut self.sram)?;
+        s.value(&mut self.bootrom_start)?;
+        s.value(&mut self.bootram_start)?;
+        self.v0.snapshot(s)?;
+        self.v1.snapshot(s)?;
+        self.v2.snapshot(s)?;
+        Ok(())
+    }
+}
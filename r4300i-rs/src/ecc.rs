@@ -0,0 +1,189 @@
+use crate::types::*;
+
+// smartmedia-style hamming code: 22 bits of line and column parity for every
+// 256 bytes, so a 512 byte page carries two 3 byte codes in its spare area
+pub const ECC_BLOCK_SIZE: usize = 0x100;
+
+// where each half's code lives in a page's 16 byte spare area
+pub const ECC_OFFSETS: [usize; 2] = [13, 8];
+
+// the low two bits of the last byte aren't part of the code, and are left set
+const UNUSED_BITS: byte = 0x03;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EccResult {
+    Ok,
+    // a flipped data bit, fixed in place
+    Corrected(usize, byte),
+    // a flipped bit in the stored code; the data is fine
+    CodeError,
+    Uncorrectable,
+}
+
+fn parity(value: byte) -> byte {
+    (value.count_ones() & 1) as byte
+}
+
+// the code for 256 bytes, stored inverted so erased flash has a valid code
+pub fn calculate(data: &[byte]) -> [byte; 3] {
+    debug_assert_eq!(data.len(), ECC_BLOCK_SIZE);
+
+    // bit 2n is the parity of bytes with bit n of their index clear, bit 2n+1 of
+    // those with it set
+    let mut lines = 0u16;
+    let mut columns = 0;
+    for (index, value) in data.iter().enumerate() {
+        columns ^= value;
+        if parity(*value) != 0 {
+            for bit in 0..8 {
+                lines ^= 1 << (bit * 2 + (index >> bit & 1));
+            }
+        }
+    }
+
+    let column_parity = [
+        parity(columns & 0x55),
+        parity(columns & 0xAA),
+        parity(columns & 0x33),
+        parity(columns & 0xCC),
+        parity(columns & 0x0F),
+        parity(columns & 0xF0),
+    ]
+    .iter()
+    .enumerate()
+    .fold(0, |acc, (bit, p)| acc | p << (bit + 2));
+
+    [
+        !(lines as byte),
+        !((lines >> 8) as byte),
+        !column_parity | UNUSED_BITS,
+    ]
+}
+
+// checks 256 bytes against their stored code, fixing a single flipped bit
+pub fn correct(data: &mut [byte], stored: [byte; 3]) -> EccResult {
+    let calculated = calculate(data);
+    let syndrome = [
+        stored[0] ^ calculated[0],
+        stored[1] ^ calculated[1],
+        (stored[2] ^ calculated[2]) & !UNUSED_BITS,
+    ];
+    let lines = syndrome[0] as u16 | (syndrome[1] as u16) << 8;
+    let columns = syndrome[2] >> 2;
+
+    match syndrome.iter().map(|b| b.count_ones()).sum::<u32>() {
+        0 => EccResult::Ok,
+        1 => EccResult::CodeError,
+        // one data bit flips exactly one bit of every parity pair
+        11 if (0..8).all(|pair| (lines >> (pair * 2) & 3).count_ones() == 1)
+            && (0..3).all(|pair| (columns >> (pair * 2) & 3).count_ones() == 1) =>
+        {
+            let index = (0..8).fold(0, |acc, bit| acc | (lines >> (bit * 2 + 1) & 1) << bit);
+            let bit = (0..3).fold(0, |acc, bit| acc | (columns >> (bit * 2 + 1) & 1) << bit);
+            data[index as usize] ^= 1 << bit;
+            EccResult::Corrected(index as usize, bit)
+        }
+        _ => EccResult::Uncorrectable,
+    }
+}
+
+// writes both halves' codes into a page's spare area
+pub fn write_page(data: &[byte], spare: &mut [byte]) {
+    for (half, offset) in ECC_OFFSETS.iter().enumerate() {
+        let code = calculate(&data[half * ECC_BLOCK_SIZE..(half + 1) * ECC_BLOCK_SIZE]);
+        spare[*offset..*offset + 3].copy_from_slice(&code);
+    }
+}
+
+// checks and corrects both halves of a page
+pub fn correct_page(data: &mut [byte], spare: &[byte]) -> [EccResult; 2] {
+    let mut results = [EccResult::Ok; 2];
+    for (half, offset) in ECC_OFFSETS.iter().enumerate() {
+        let stored = spare[*offset..*offset + 3].try_into().unwrap();
+        results[half] = correct(
+            &mut data[half * ECC_BLOCK_SIZE..(half + 1) * ECC_BLOCK_SIZE],
+            stored,
+        );
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data() -> Vec<byte> {
+        (0..ECC_BLOCK_SIZE).map(|i| (i * 7 + 3) as byte).collect()
+    }
+
+    #[test]
+    fn erased_pages_pass() {
+        let mut page = [0xFF; 0x200];
+        let spare = [0xFF; 0x10];
+        assert_eq!(correct_page(&mut page, &spare), [EccResult::Ok; 2]);
+        assert_eq!(calculate(&[0xFF; ECC_BLOCK_SIZE]), [0xFF; 3]);
+    }
+
+    #[test]
+    fn single_data_bits_are_corrected() {
+        let code = calculate(&data());
+        for index in 0..ECC_BLOCK_SIZE {
+            for bit in 0..8 {
+                let mut flipped = data();
+                flipped[index] ^= 1 << bit;
+                assert_eq!(
+                    correct(&mut flipped, code),
+                    EccResult::Corrected(index, bit)
+                );
+                assert_eq!(flipped, data());
+            }
+        }
+    }
+
+    #[test]
+    fn code_bits() {
+        let code = calculate(&data());
+        for index in 0..3 {
+            for bit in 0..8 {
+                let mut stored = code;
+                stored[index] ^= 1 << bit;
+                let mut unchanged = data();
+                let expected = match (index, bit) {
+                    (2, 0 | 1) => EccResult::Ok,
+                    _ => EccResult::CodeError,
+                };
+                assert_eq!(correct(&mut unchanged, stored), expected);
+                assert_eq!(unchanged, data());
+            }
+        }
+    }
+
+    #[test]
+    fn double_bits_are_uncorrectable() {
+        let code = calculate(&data());
+        for (first, second) in [(0, 1), (0, 8 * 0xFF + 7), (100, 1000), (9, 17)] {
+            let mut flipped = data();
+            flipped[first / 8] ^= 1 << (first % 8);
+            flipped[second / 8] ^= 1 << (second % 8);
+            let before = flipped.clone();
+            assert_eq!(correct(&mut flipped, code), EccResult::Uncorrectable);
+            assert_eq!(flipped, before);
+        }
+    }
+
+    #[test]
+    fn pages() {
+        let mut page = [data(), data().iter().map(|b| !b).collect()].concat();
+        let mut spare = [0xFF; 0x10];
+        write_page(&page, &mut spare);
+        assert_eq!(spare[13..16], calculate(&page[..0x100]));
+        assert_eq!(spare[8..11], calculate(&page[0x100..]));
+
+        page[0x123] ^= 0x10;
+        spare[13] ^= 0x01;
+        assert_eq!(
+            correct_page(&mut page, &spare),
+            [EccResult::CodeError, EccResult::Corrected(0x23, 4)]
+        );
+    }
+}
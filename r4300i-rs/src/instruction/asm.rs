@@ -0,0 +1,814 @@
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::cop0;
+use crate::types::*;
+use crate::Register;
+
+use super::{C0Format, FbFormat, FmFormat, FrFormat, IFormat, JFormat, RFormat};
+
+#[derive(Debug, Error)]
+pub enum AsmError {
+    #[error("line {0}: unknown instruction \"{1}\"")]
+    UnknownInstruction(usize, String),
+    #[error("line {0}: wrong number of operands for \"{1}\"")]
+    OperandCount(usize, String),
+    #[error("line {0}: invalid operand \"{1}\"")]
+    InvalidOperand(usize, String),
+    #[error("line {0}: \"{1}\" is out of range")]
+    OutOfRange(usize, String),
+    #[error("line {0}: undefined label \"{1}\"")]
+    UndefinedLabel(usize, String),
+    #[error("line {0}: duplicate label \"{1}\"")]
+    DuplicateLabel(usize, String),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Shape {
+    None,       // no operands
+    Code,       // [code]
+    Shift,      // rd, rt, sa
+    ShiftV,     // rd, rt, rs
+    R3,         // rd, rs, rt
+    Rs,         // rs
+    Rd,         // rd
+    RsRt,       // rs, rt
+    Jalr,       // [rd,] rs
+    Branch2,    // rs, rt, target
+    Branch1,    // rs, target
+    TrapImm,    // rs, imm
+    ArithImm,   // rt, rs, imm
+    LogicImm,   // rt, rs, imm
+    Lui,        // rt, imm
+    Mem,        // rt, offset(base)
+    FpuMem,     // ft, offset(base)
+    Cache,      // op, offset(base)
+    Jump,       // target
+    Cop0Move,   // rt, cop0 register
+    Cop1Move,   // rt, fs
+    Cop1Ctrl,   // rt, fcr
+    Bc1,        // [cc,] target
+    Fpu3,       // fd, fs, ft
+    Fpu2,       // fd, fs
+    FpuCompare, // fs, ft
+}
+
+const INSTRUCTIONS: [(&str, Shape, word); 129] = [
+    ("nop", Shape::None, 0x00000000),
+    ("sll", Shape::Shift, 0x00000000),
+    ("srl", Shape::Shift, 0x00000002),
+    ("sra", Shape::Shift, 0x00000003),
+    ("sllv", Shape::ShiftV, 0x00000004),
+    ("srlv", Shape::ShiftV, 0x00000006),
+    ("srav", Shape::ShiftV, 0x00000007),
+    ("jr", Shape::Rs, 0x00000008),
+    ("jalr", Shape::Jalr, 0x00000009),
+    ("syscall", Shape::Code, 0x0000000C),
+    ("break", Shape::Code, 0x0000000D),
+    ("sync", Shape::None, 0x0000000F),
+    ("mfhi", Shape::Rd, 0x00000010),
+    ("mthi", Shape::Rs, 0x00000011),
+    ("mflo", Shape::Rd, 0x00000012),
+    ("mtlo", Shape::Rs, 0x00000013),
+    ("dsllv", Shape::ShiftV, 0x00000014),
+    ("dsrlv", Shape::ShiftV, 0x00000016),
+    ("dsrav", Shape::ShiftV, 0x00000017),
+    ("mult", Shape::RsRt, 0x00000018),
+    ("multu", Shape::RsRt, 0x00000019),
+    ("div", Shape::RsRt, 0x0000001A),
+    ("divu", Shape::RsRt, 0x0000001B),
+    ("dmult", Shape::RsRt, 0x0000001C),
+    ("dmultu", Shape::RsRt, 0x0000001D),
+    ("ddiv", Shape::RsRt, 0x0000001E),
+    ("ddivu", Shape::RsRt, 0x0000001F),
+    ("add", Shape::R3, 0x00000020),
+    ("addu", Shape::R3, 0x00000021),
+    ("sub", Shape::R3, 0x00000022),
+    ("subu", Shape::R3, 0x00000023),
+    ("and", Shape::R3, 0x00000024),
+    ("or", Shape::R3, 0x00000025),
+    ("xor", Shape::R3, 0x00000026),
+    ("nor", Shape::R3, 0x00000027),
+    ("slt", Shape::R3, 0x0000002A),
+    ("sltu", Shape::R3, 0x0000002B),
+    ("dadd", Shape::R3, 0x0000002C),
+    ("daddu", Shape::R3, 0x0000002D),
+    ("dsub", Shape::R3, 0x0000002E),
+    ("dsubu", Shape::R3, 0x0000002F),
+    ("tge", Shape::RsRt, 0x00000030),
+    ("tgeu", Shape::RsRt, 0x00000031),
+    ("tlt", Shape::RsRt, 0x00000032),
+    ("tltu", Shape::RsRt, 0x00000033),
+    ("teq", Shape::RsRt, 0x00000034),
+    ("tne", Shape::RsRt, 0x00000036),
+    ("dsll", Shape::Shift, 0x00000038),
+    ("dsrl", Shape::Shift, 0x0000003A),
+    ("dsra", Shape::Shift, 0x0000003B),
+    ("dsll32", Shape::Shift, 0x0000003C),
+    ("dsrl32", Shape::Shift, 0x0000003E),
+    ("dsra32", Shape::Shift, 0x0000003F),
+    ("bltz", Shape::Branch1, 0x04000000),
+    ("bgez", Shape::Branch1, 0x04010000),
+    ("bltzl", Shape::Branch1, 0x04020000),
+    ("bgezl", Shape::Branch1, 0x04030000),
+    ("tgei", Shape::TrapImm, 0x04080000),
+    ("tgeiu", Shape::TrapImm, 0x04090000),
+    ("tlti", Shape::TrapImm, 0x040A0000),
+    ("tltiu", Shape::TrapImm, 0x040B0000),
+    ("teqi", Shape::TrapImm, 0x040C0000),
+    ("tnei", Shape::TrapImm, 0x040E0000),
+    ("bltzal", Shape::Branch1, 0x04100000),
+    ("bgezal", Shape::Branch1, 0x04110000),
+    ("bltzall", Shape::Branch1, 0x04120000),
+    ("bgezall", Shape::Branch1, 0x04130000),
+    ("j", Shape::Jump, 0x08000000),
+    ("jal", Shape::Jump, 0x0C000000),
+    ("beq", Shape::Branch2, 0x10000000),
+    ("bne", Shape::Branch2, 0x14000000),
+    ("blez", Shape::Branch1, 0x18000000),
+    ("bgtz", Shape::Branch1, 0x1C000000),
+    ("addi", Shape::ArithImm, 0x20000000),
+    ("addiu", Shape::ArithImm, 0x24000000),
+    ("slti", Shape::ArithImm, 0x28000000),
+    ("sltiu", Shape::ArithImm, 0x2C000000),
+    ("andi", Shape::LogicImm, 0x30000000),
+    ("ori", Shape::LogicImm, 0x34000000),
+    ("xori", Shape::LogicImm, 0x38000000),
+    ("lui", Shape::Lui, 0x3C000000),
+    ("mfc0", Shape::Cop0Move, 0x40000000),
+    ("mtc0", Shape::Cop0Move, 0x40800000),
+    ("tlbwi", Shape::None, 0x42000002),
+    ("eret", Shape::None, 0x42000018),
+    ("mfc1", Shape::Cop1Move, 0x44000000),
+    ("dmfc1", Shape::Cop1Move, 0x44200000),
+    ("cfc1", Shape::Cop1Ctrl, 0x44400000),
+    ("mtc1", Shape::Cop1Move, 0x44800000),
+    ("dmtc1", Shape::Cop1Move, 0x44A00000),
+    ("ctc1", Shape::Cop1Ctrl, 0x44C00000),
+    ("bc1f", Shape::Bc1, 0x45000000),
+    ("bc1t", Shape::Bc1, 0x45010000),
+    ("bc1fl", Shape::Bc1, 0x45020000),
+    ("bc1tl", Shape::Bc1, 0x45030000),
+    ("beql", Shape::Branch2, 0x50000000),
+    ("bnel", Shape::Branch2, 0x54000000),
+    ("blezl", Shape::Branch1, 0x58000000),
+    ("bgtzl", Shape::Branch1, 0x5C000000),
+    ("daddi", Shape::ArithImm, 0x60000000),
+    ("daddiu", Shape::ArithImm, 0x64000000),
+    ("ldl", Shape::Mem, 0x68000000),
+    ("ldr", Shape::Mem, 0x6C000000),
+    ("lb", Shape::Mem, 0x80000000),
+    ("lh", Shape::Mem, 0x84000000),
+    ("lwl", Shape::Mem, 0x88000000),
+    ("lw", Shape::Mem, 0x8C000000),
+    ("lbu", Shape::Mem, 0x90000000),
+    ("lhu", Shape::Mem, 0x94000000),
+    ("lwr", Shape::Mem, 0x98000000),
+    ("lwu", Shape::Mem, 0x9C000000),
+    ("sb", Shape::Mem, 0xA0000000),
+    ("sh", Shape::Mem, 0xA4000000),
+    ("swl", Shape::Mem, 0xA8000000),
+    ("sw", Shape::Mem, 0xAC000000),
+    ("sdl", Shape::Mem, 0xB0000000),
+    ("sdr", Shape::Mem, 0xB4000000),
+    ("swr", Shape::Mem, 0xB8000000),
+    ("cache", Shape::Cache, 0xBC000000),
+    ("ll", Shape::Mem, 0xC0000000),
+    ("lwc1", Shape::FpuMem, 0xC4000000),
+    ("lld", Shape::Mem, 0xD0000000),
+    ("ldc1", Shape::FpuMem, 0xD4000000),
+    ("ld", Shape::Mem, 0xDC000000),
+    ("sc", Shape::Mem, 0xE0000000),
+    ("swc1", Shape::FpuMem, 0xE4000000),
+    ("scd", Shape::Mem, 0xF0000000),
+    ("sdc1", Shape::FpuMem, 0xF4000000),
+    ("sd", Shape::Mem, 0xFC000000),
+];
+
+// these take a .s, .d, .w or .l format suffix
+const FPU_INSTRUCTIONS: [(&str, Shape, word); 20] = [
+    ("add", Shape::Fpu3, 0x44000000),
+    ("sub", Shape::Fpu3, 0x44000001),
+    ("mul", Shape::Fpu3, 0x44000002),
+    ("div", Shape::Fpu3, 0x44000003),
+    ("sqrt", Shape::Fpu2, 0x44000004),
+    ("abs", Shape::Fpu2, 0x44000005),
+    ("mov", Shape::Fpu2, 0x44000006),
+    ("neg", Shape::Fpu2, 0x44000007),
+    ("round.l", Shape::Fpu2, 0x44000008),
+    ("trunc.l", Shape::Fpu2, 0x44000009),
+    ("ceil.l", Shape::Fpu2, 0x4400000A),
+    ("floor.l", Shape::Fpu2, 0x4400000B),
+    ("round.w", Shape::Fpu2, 0x4400000C),
+    ("trunc.w", Shape::Fpu2, 0x4400000D),
+    ("ceil.w", Shape::Fpu2, 0x4400000E),
+    ("floor.w", Shape::Fpu2, 0x4400000F),
+    ("cvt.s", Shape::Fpu2, 0x44000020),
+    ("cvt.d", Shape::Fpu2, 0x44000021),
+    ("cvt.w", Shape::Fpu2, 0x44000024),
+    ("cvt.l", Shape::Fpu2, 0x44000025),
+];
+
+fn parse_int(s: &str) -> Option<i64> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    let value = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => s.parse().ok()?,
+    };
+    Some(if negative { -value } else { value })
+}
+
+// by name, or by number as $n or rn
+fn parse_reg(s: &str) -> Option<u8> {
+    let name = s.strip_prefix('$').unwrap_or(s);
+    if let Some(r) = Register::NAMES.iter().position(|n| *n == name) {
+        return Some(r as u8);
+    }
+    match s.strip_prefix('$').or_else(|| s.strip_prefix('r'))?.parse() {
+        Ok(r) if r < 32 => Some(r),
+        _ => None,
+    }
+}
+
+fn is_label(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+#[derive(Debug)]
+struct Line<'a> {
+    number: usize,
+    address: word,
+    mnemonic: String,
+    operands: Vec<&'a str>,
+}
+
+impl Line<'_> {
+    fn invalid(&self, operand: &str) -> AsmError {
+        AsmError::InvalidOperand(self.number, operand.into())
+    }
+
+    fn out_of_range(&self, operand: &str) -> AsmError {
+        AsmError::OutOfRange(self.number, operand.into())
+    }
+
+    fn expect(&self, counts: &[usize]) -> Result<(), AsmError> {
+        if counts.contains(&self.operands.len()) {
+            Ok(())
+        } else {
+            Err(AsmError::OperandCount(self.number, self.mnemonic.clone()))
+        }
+    }
+
+    fn reg(&self, index: usize) -> Result<u8, AsmError> {
+        let operand = self.operands[index];
+        parse_reg(operand).ok_or_else(|| self.invalid(operand))
+    }
+
+    fn numbered(&self, index: usize, prefix: &str) -> Result<u8, AsmError> {
+        let operand = self.operands[index];
+        let name = operand.strip_prefix('$').unwrap_or(operand);
+        match name.strip_prefix(prefix).map(str::parse::<u8>) {
+            Some(Ok(r)) if r < 32 => Ok(r),
+            _ => Err(self.invalid(operand)),
+        }
+    }
+
+    fn fpr(&self, index: usize) -> Result<u8, AsmError> {
+        self.numbered(index, "f")
+    }
+
+    fn fcr(&self, index: usize) -> Result<u8, AsmError> {
+        self.numbered(index, "fcr")
+    }
+
+    fn cop0_reg(&self, index: usize) -> Result<u8, AsmError> {
+        let operand = self.operands[index];
+        if let Some(r) = (0..32)
+            .find(|r| format!("{:?}", cop0::Register::from(*r)).eq_ignore_ascii_case(operand))
+        {
+            return Ok(r);
+        }
+        match parse_int(operand) {
+            Some(r @ 0..=31) => Ok(r as u8),
+            _ => Err(self.invalid(operand)),
+        }
+    }
+
+    fn int(&self, operand: &str, min: i64, max: i64) -> Result<i64, AsmError> {
+        match parse_int(operand) {
+            Some(value) if (min..=max).contains(&value) => Ok(value),
+            Some(_) => Err(self.out_of_range(operand)),
+            None => Err(self.invalid(operand)),
+        }
+    }
+
+    // immediates may be given signed or unsigned
+    fn imm(&self, index: usize) -> Result<hword, AsmError> {
+        Ok(self.int(self.operands[index], -0x8000, 0xFFFF)? as hword)
+    }
+
+    fn uimm(&self, index: usize) -> Result<hword, AsmError> {
+        Ok(self.int(self.operands[index], 0, 0xFFFF)? as hword)
+    }
+
+    fn small(&self, index: usize, max: i64) -> Result<u8, AsmError> {
+        Ok(self.int(self.operands[index], 0, max)? as u8)
+    }
+
+    // offset(base)
+    fn mem(&self, index: usize) -> Result<(hword, u8), AsmError> {
+        let operand = self.operands[index];
+        let (offset, base) = operand
+            .strip_suffix(')')
+            .and_then(|s| s.split_once('('))
+            .ok_or_else(|| self.invalid(operand))?;
+        let offset = match offset.trim() {
+            "" => 0,
+            offset => self.int(offset, -0x8000, 0x7FFF)? as hword,
+        };
+        let base = parse_reg(base.trim()).ok_or_else(|| self.invalid(operand))?;
+        Ok((offset, base))
+    }
+}
+
+struct Assembler {
+    labels: HashMap<String, word>,
+}
+
+impl Assembler {
+    fn target(&self, line: &Line, index: usize) -> Result<word, AsmError> {
+        let operand = line.operands[index];
+        if let Some(offset) = operand.strip_prefix(".+") {
+            return Ok(line
+                .address
+                .wrapping_add(line.int(offset, 0, word::MAX as i64)? as word));
+        }
+        if let Some(offset) = operand.strip_prefix(".-") {
+            return Ok(line
+                .address
+                .wrapping_sub(line.int(offset, 0, word::MAX as i64)? as word));
+        }
+        if let Some(value) = parse_int(operand) {
+            return match value {
+                0..=0xFFFFFFFF => Ok(value as word),
+                _ => Err(line.out_of_range(operand)),
+            };
+        }
+        if !is_label(operand) {
+            return Err(line.invalid(operand));
+        }
+        self.labels
+            .get(operand)
+            .copied()
+            .ok_or_else(|| AsmError::UndefinedLabel(line.number, operand.into()))
+    }
+
+    fn branch_offset(&self, line: &Line, index: usize) -> Result<hword, AsmError> {
+        let target = self.target(line, index)?;
+        let offset = target.wrapping_sub(line.address.wrapping_add(4)) as sword;
+        if offset % 4 != 0 || !(-0x20000..0x20000).contains(&offset) {
+            return Err(line.out_of_range(line.operands[index]));
+        }
+        Ok((offset >> 2) as hword)
+    }
+
+    fn jump_target(&self, line: &Line, index: usize) -> Result<word, AsmError> {
+        let target = self.target(line, index)?;
+        if target % 4 != 0 || (target ^ line.address.wrapping_add(4)) & 0xF0000000 != 0 {
+            return Err(line.out_of_range(line.operands[index]));
+        }
+        Ok((target >> 2) & 0x03FFFFFF)
+    }
+
+    fn lookup(line: &Line) -> Result<(Shape, word), AsmError> {
+        if let Some((_, shape, base)) = INSTRUCTIONS.iter().find(|(n, _, _)| *n == line.mnemonic) {
+            return Ok((*shape, *base));
+        }
+
+        let unknown = || AsmError::UnknownInstruction(line.number, line.mnemonic.clone());
+
+        let (stem, format) = line.mnemonic.rsplit_once('.').ok_or_else(unknown)?;
+        let format: word = match format {
+            "s" => 16,
+            "d" => 17,
+            "w" => 20,
+            "l" => 21,
+            _ => return Err(unknown()),
+        };
+
+        if let Some(cond) = stem.strip_prefix("c.") {
+            let cond = super::disasm::FCOMPARE_CONDITIONS
+                .iter()
+                .position(|c| *c == cond)
+                .ok_or_else(unknown)?;
+            return Ok((Shape::FpuCompare, 0x44000030 | cond as word | format << 21));
+        }
+
+        FPU_INSTRUCTIONS
+            .iter()
+            .find(|(n, _, _)| *n == stem)
+            .map(|(_, shape, base)| (*shape, base | format << 21))
+            .ok_or_else(unknown)
+    }
+
+    fn encode(&self, line: &Line) -> Result<word, AsmError> {
+        let (shape, base) = Self::lookup(line)?;
+
+        Ok(match shape {
+            Shape::None => {
+                line.expect(&[0])?;
+                base
+            }
+
+            Shape::Code => {
+                line.expect(&[0, 1])?;
+                match line.operands.first() {
+                    Some(_) => base | (line.int(line.operands[0], 0, 0xFFFFF)? as word) << 6,
+                    None => base,
+                }
+            }
+
+            Shape::Shift => {
+                line.expect(&[3])?;
+                RFormat::from(base)
+                    .with_dest(line.reg(0)?)
+                    .with_source2(line.reg(1)?)
+                    .with_shift_amt(line.small(2, 31)?)
+                    .into()
+            }
+
+            Shape::ShiftV => {
+                line.expect(&[3])?;
+                RFormat::from(base)
+                    .with_dest(line.reg(0)?)
+                    .with_source2(line.reg(1)?)
+                    .with_source1(line.reg(2)?)
+                    .into()
+            }
+
+            Shape::R3 => {
+                line.expect(&[3])?;
+                RFormat::from(base)
+                    .with_dest(line.reg(0)?)
+                    .with_source1(line.reg(1)?)
+                    .with_source2(line.reg(2)?)
+                    .into()
+            }
+
+            Shape::Rs => {
+                line.expect(&[1])?;
+                RFormat::from(base).with_source1(line.reg(0)?).into()
+            }
+
+            Shape::Rd => {
+                line.expect(&[1])?;
+                RFormat::from(base).with_dest(line.reg(0)?).into()
+            }
+
+            Shape::RsRt => {
+                line.expect(&[2])?;
+                RFormat::from(base)
+                    .with_source1(line.reg(0)?)
+                    .with_source2(line.reg(1)?)
+                    .into()
+            }
+
+            Shape::Jalr => {
+                line.expect(&[1, 2])?;
+                let (rd, rs) = match line.operands.len() {
+                    1 => (Register::Ra as u8, line.reg(0)?),
+                    _ => (line.reg(0)?, line.reg(1)?),
+                };
+                RFormat::from(base).with_dest(rd).with_source1(rs).into()
+            }
+
+            Shape::Branch2 => {
+                line.expect(&[3])?;
+                IFormat::from(base)
+                    .with_source1(line.reg(0)?)
+                    .with_source2(line.reg(1)?)
+                    .with_imm(self.branch_offset(line, 2)?)
+                    .into()
+            }
+
+            Shape::Branch1 => {
+                line.expect(&[2])?;
+                IFormat::from(base)
+                    .with_source1(line.reg(0)?)
+                    .with_imm(self.branch_offset(line, 1)?)
+                    .into()
+            }
+
+            Shape::TrapImm => {
+                line.expect(&[2])?;
+                IFormat::from(base)
+                    .with_source1(line.reg(0)?)
+                    .with_imm(line.imm(1)?)
+                    .into()
+            }
+
+            Shape::ArithImm => {
+                line.expect(&[3])?;
+                IFormat::from(base)
+                    .with_source2(line.reg(0)?)
+                    .with_source1(line.reg(1)?)
+                    .with_imm(line.imm(2)?)
+                    .into()
+            }
+
+            Shape::LogicImm => {
+                line.expect(&[3])?;
+                IFormat::from(base)
+                    .with_source2(line.reg(0)?)
+                    .with_source1(line.reg(1)?)
+                    .with_imm(line.uimm(2)?)
+                    .into()
+            }
+
+            Shape::Lui => {
+                line.expect(&[2])?;
+                IFormat::from(base)
+                    .with_source2(line.reg(0)?)
+                    .with_imm(line.uimm(1)?)
+                    .into()
+            }
+
+            Shape::Mem | Shape::FpuMem | Shape::Cache => {
+                line.expect(&[2])?;
+                let rt = match shape {
+                    Shape::Mem => line.reg(0)?,
+                    Shape::FpuMem => line.fpr(0)?,
+                    _ => line.small(0, 31)?,
+                };
+                let (offset, base_reg) = line.mem(1)?;
+                IFormat::from(base)
+                    .with_source2(rt)
+                    .with_source1(base_reg)
+                    .with_imm(offset)
+                    .into()
+            }
+
+            Shape::Jump => {
+                line.expect(&[1])?;
+                JFormat::from(base)
+                    .with_target(self.jump_target(line, 0)?)
+                    .into()
+            }
+
+            Shape::Cop0Move => {
+                line.expect(&[2])?;
+                C0Format::from(base)
+                    .with_source(line.reg(0)?)
+                    .with_dest(line.cop0_reg(1)?)
+                    .into()
+            }
+
+            Shape::Cop1Move | Shape::Cop1Ctrl => {
+                line.expect(&[2])?;
+                let fs = match shape {
+                    Shape::Cop1Move => line.fpr(1)?,
+                    _ => line.fcr(1)?,
+                };
+                FmFormat::from(base)
+                    .with_gpr(line.reg(0)?)
+                    .with_fpr(fs)
+                    .into()
+            }
+
+            Shape::Bc1 => {
+                line.expect(&[1, 2])?;
+                let (cc, target) = match line.operands.len() {
+                    1 => (0, 0),
+                    _ => (line.small(0, 7)?, 1),
+                };
+                FbFormat::from(base)
+                    .with_cc(cc)
+                    .with_offset(self.branch_offset(line, target)?)
+                    .into()
+            }
+
+            Shape::Fpu3 => {
+                line.expect(&[3])?;
+                FrFormat::from(base)
+                    .with_dest(line.fpr(0)?)
+                    .with_source1(line.fpr(1)?)
+                    .with_source2(line.fpr(2)?)
+                    .into()
+            }
+
+            Shape::Fpu2 => {
+                line.expect(&[2])?;
+                FrFormat::from(base)
+                    .with_dest(line.fpr(0)?)
+                    .with_source1(line.fpr(1)?)
+                    .into()
+            }
+
+            Shape::FpuCompare => {
+                line.expect(&[2])?;
+                FrFormat::from(base)
+                    .with_source1(line.fpr(0)?)
+                    .with_source2(line.fpr(1)?)
+                    .into()
+            }
+        })
+    }
+}
+
+// assembles one instruction per line, with labels ("name:"), comments ("#" or ";")
+// and .word directives; the first instruction is placed at base
+pub fn assemble(source: &str, base: word) -> Result<Vec<word>, AsmError> {
+    let mut labels = HashMap::new();
+    let mut lines = vec![];
+    let mut address = base;
+
+    for (index, text) in source.lines().enumerate() {
+        let number = index + 1;
+        let mut text = text.split(['#', ';']).next().unwrap_or("").trim();
+
+        while let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if !is_label(label) {
+                break;
+            }
+            if labels.insert(label.to_string(), address).is_some() {
+                return Err(AsmError::DuplicateLabel(number, label.into()));
+            }
+            text = rest.trim();
+        }
+
+        if text.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, operands) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let operands = match operands.trim() {
+            "" => vec![],
+            operands => operands.split(',').map(str::trim).collect(),
+        };
+        let line = Line {
+            number,
+            address,
+            mnemonic: mnemonic.to_lowercase(),
+            operands,
+        };
+
+        address = address.wrapping_add(match line.mnemonic.as_str() {
+            ".word" => line.operands.len() as word * 4,
+            _ => 4,
+        });
+        lines.push(line);
+    }
+
+    let assembler = Assembler { labels };
+    let mut program = vec![];
+
+    for line in &lines {
+        match line.mnemonic.as_str() {
+            ".word" => {
+                for index in 0..line.operands.len() {
+                    program.push(assembler.target(line, index)?);
+                }
+            }
+            _ => program.push(assembler.encode(line)?),
+        }
+    }
+
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::instruction::Instruction;
+
+    const BASE: word = 0x80001000;
+
+    fn operands(shape: Shape) -> &'static str {
+        match shape {
+            Shape::None => "",
+            Shape::Code => "0x123",
+            Shape::Shift => "t0, t1, 4",
+            Shape::ShiftV | Shape::R3 => "t0, t1, t2",
+            Shape::Rs => "a0",
+            Shape::Rd => "v0",
+            Shape::RsRt => "a0, a1",
+            Shape::Jalr => "t0, a0",
+            Shape::Branch2 => "a0, a1, .+0x10",
+            Shape::Branch1 => "a0, .-0x8",
+            Shape::TrapImm => "a0, -0x4",
+            Shape::ArithImm => "t0, a0, -0x20",
+            Shape::LogicImm => "t0, a0, 0xFFFF",
+            Shape::Lui => "at, 0x8000",
+            Shape::Mem => "t0, 0x1C(sp)",
+            Shape::FpuMem => "f4, -0x10(a0)",
+            Shape::Cache => "0x3, 0x0(a0)",
+            Shape::Jump => "0x80001234",
+            Shape::Cop0Move => "t0, Status",
+            Shape::Cop1Move => "t0, f2",
+            Shape::Cop1Ctrl => "t0, fcr31",
+            Shape::Bc1 => ".+0x8",
+            Shape::Fpu3 => "f0, f2, f4",
+            Shape::Fpu2 => "f0, f2",
+            Shape::FpuCompare => "f2, f4",
+        }
+    }
+
+    fn assemble_one(source: &str) -> word {
+        match assemble(source, BASE) {
+            Ok(program) => program[0],
+            Err(e) => panic!("{source}: {e}"),
+        }
+    }
+
+    #[test]
+    fn every_instruction_round_trips() {
+        for (name, shape, _) in INSTRUCTIONS {
+            let source = format!("{name} {}", operands(shape));
+            let value = assemble_one(&source);
+
+            // nop is sll zero, zero, 0
+            let instr = Instruction::new(value);
+            let mnemonic = if name == "nop" { "sll" } else { name };
+            assert_eq!(instr.mnemonic(), mnemonic, "{source}");
+            assert_eq!(instr.encode(), Some(value), "{source}");
+
+            // and the disassembly assembles back to the same word
+            let text = instr.disassemble(BASE).to_string();
+            assert_eq!(assemble_one(&text), value, "{source} -> {text}");
+        }
+    }
+
+    #[test]
+    fn fpu_formats() {
+        assert_eq!(assemble_one("add.s f0, f2, f4"), 0x46041000);
+        assert_eq!(assemble_one("sqrt.d f0, f2"), 0x46201004);
+        assert_eq!(assemble_one("c.eq.d f2, f4"), 0x46241032);
+        assert_eq!(assemble_one("cvt.s.w f0, f2"), 0x46801020);
+        assert_eq!(
+            Instruction::new(0x46801020).disassemble(BASE).to_string(),
+            "cvt.s.w f0, f2"
+        );
+    }
+
+    #[test]
+    fn branches_are_relative_to_base() {
+        let source = "
+            start:  beq a0, zero, end
+                    nop
+            loop:   bne a0, a1, loop
+                    j start
+            end:    jal loop
+        ";
+        assert_eq!(
+            assemble(source, 0x80001000).unwrap(),
+            [0x10800003, 0x00000000, 0x1485FFFF, 0x08000400, 0x0C000402]
+        );
+        // branches don't move, but jumps follow the region
+        assert_eq!(
+            assemble(source, 0xBFC00000).unwrap(),
+            [0x10800003, 0x00000000, 0x1485FFFF, 0x0BF00000, 0x0FF00002]
+        );
+
+        let beq = Instruction::new(0x10800003);
+        assert_eq!(
+            beq.disassemble(0x80001000).to_string(),
+            "beq     a0, zero, 0x80001010"
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(
+            assemble("bogus a0", BASE),
+            Err(AsmError::UnknownInstruction(1, _))
+        ));
+        assert!(matches!(
+            assemble("nop\naddiu t0, a0, 0x10000", BASE),
+            Err(AsmError::OutOfRange(2, _))
+        ));
+        assert!(matches!(
+            assemble("b nowhere", BASE),
+            Err(AsmError::UnknownInstruction(1, _))
+        ));
+        assert!(matches!(
+            assemble("beq a0, a1, nowhere", BASE),
+            Err(AsmError::UndefinedLabel(1, _))
+        ));
+        assert!(matches!(
+            assemble("a: nop\na: nop", BASE),
+            Err(AsmError::DuplicateLabel(2, _))
+        ));
+        assert!(matches!(
+            assemble("j 0x90000000", BASE),
+            Err(AsmError::OutOfRange(1, _))
+        ));
+    }
+}
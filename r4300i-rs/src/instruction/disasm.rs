@@ -0,0 +1,646 @@
+use std::fmt::{Display, Formatter, Result};
+
+use crate::cop0;
+use crate::types::*;
+use crate::Register;
+
+use super::{FbFormat, FrFormat, IFormat, Instruction, JFormat, RFormat};
+
+pub(super) const FCOMPARE_CONDITIONS: [&str; 16] = [
+    "f", "un", "eq", "ueq", "olt", "ult", "ole", "ule", "sf", "ngle", "seq", "ngl", "lt", "nge",
+    "le", "ngt",
+];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Disassembly<'a> {
+    instr: &'a Instruction,
+    pc: Option<word>,
+}
+
+fn reg(r: u8) -> Register {
+    r.into()
+}
+
+fn signed_hex(imm: hword) -> String {
+    let imm = imm as shword;
+    if imm < 0 {
+        format!("-0x{:X}", -(imm as sword))
+    } else {
+        format!("0x{imm:X}")
+    }
+}
+
+fn fmt_suffix(format: u8) -> &'static str {
+    match format {
+        16 => "s",
+        17 => "d",
+        20 => "w",
+        21 => "l",
+        _ => "?",
+    }
+}
+
+impl Disassembly<'_> {
+    fn branch_target(&self, imm: hword) -> String {
+        let offset = (sign_extend_hword(imm) << 2).wrapping_add(4);
+        match self.pc {
+            Some(pc) => format!("0x{:08X}", pc.wrapping_add(offset)),
+            None => match offset as sword {
+                offset if offset < 0 => format!(".-0x{:X}", -offset),
+                offset => format!(".+0x{offset:X}"),
+            },
+        }
+    }
+
+    fn jump_target(&self, target: word) -> String {
+        match self.pc {
+            Some(pc) => format!(
+                "0x{:08X}",
+                (pc.wrapping_add(4) & 0xF0000000) | (target << 2)
+            ),
+            None => format!("0x{:07X}", target << 2),
+        }
+    }
+
+    fn r(&self, f: &mut Formatter<'_>, name: &str, dec: &RFormat) -> Result {
+        let (rd, rs, rt) = (reg(dec.dest()), reg(dec.source1()), reg(dec.source2()));
+        match self.instr {
+            Instruction::Sll(_) if u32::from(*dec) == 0 => write!(f, "nop"),
+
+            Instruction::Sll(_)
+            | Instruction::Srl(_)
+            | Instruction::Sra(_)
+            | Instruction::Dsll(_)
+            | Instruction::Dsrl(_)
+            | Instruction::Dsra(_)
+            | Instruction::Dsll32(_)
+            | Instruction::Dsrl32(_)
+            | Instruction::Dsra32(_) => write!(f, "{name:<8}{rd}, {rt}, {}", dec.shift_amt()),
+
+            Instruction::Sllv(_)
+            | Instruction::Srlv(_)
+            | Instruction::Srav(_)
+            | Instruction::Dsllv(_)
+            | Instruction::Dsrlv(_)
+            | Instruction::Dsrav(_) => write!(f, "{name:<8}{rd}, {rt}, {rs}"),
+
+            Instruction::Jr(_) | Instruction::Mthi(_) | Instruction::Mtlo(_) => {
+                write!(f, "{name:<8}{rs}")
+            }
+
+            Instruction::Jalr(_) if rd == Register::Ra => write!(f, "{name:<8}{rs}"),
+            Instruction::Jalr(_) => write!(f, "{name:<8}{rd}, {rs}"),
+
+            Instruction::Syscall(_) | Instruction::Break(_) => {
+                let code = (u32::from(*dec) >> 6) & 0xFFFFF;
+                if code == 0 {
+                    write!(f, "{name}")
+                } else {
+                    write!(f, "{name:<8}0x{code:X}")
+                }
+            }
+
+            Instruction::Sync(_) => write!(f, "{name}"),
+
+            Instruction::Mfhi(_) | Instruction::Mflo(_) => write!(f, "{name:<8}{rd}"),
+
+            Instruction::Mult(_)
+            | Instruction::Multu(_)
+            | Instruction::Div(_)
+            | Instruction::Divu(_)
+            | Instruction::Dmult(_)
+            | Instruction::Dmultu(_)
+            | Instruction::Ddiv(_)
+            | Instruction::Ddivu(_)
+            | Instruction::Tge(_)
+            | Instruction::Tgeu(_)
+            | Instruction::Tlt(_)
+            | Instruction::Tltu(_)
+            | Instruction::Teq(_)
+            | Instruction::Tne(_) => write!(f, "{name:<8}{rs}, {rt}"),
+
+            _ => write!(f, "{name:<8}{rd}, {rs}, {rt}"),
+        }
+    }
+
+    fn i(&self, f: &mut Formatter<'_>, name: &str, dec: &IFormat) -> Result {
+        let (rs, rt) = (reg(dec.source1()), reg(dec.source2()));
+        let imm = dec.imm();
+        match self.instr {
+            Instruction::Beq(_)
+            | Instruction::Bne(_)
+            | Instruction::Beql(_)
+            | Instruction::Bnel(_) => {
+                write!(f, "{name:<8}{rs}, {rt}, {}", self.branch_target(imm))
+            }
+
+            Instruction::Bltz(_)
+            | Instruction::Bgez(_)
+            | Instruction::Bltzl(_)
+            | Instruction::Bgezl(_)
+            | Instruction::Bltzal(_)
+            | Instruction::Bgezal(_)
+            | Instruction::Bltzall(_)
+            | Instruction::Bgezall(_)
+            | Instruction::Blez(_)
+            | Instruction::Bgtz(_)
+            | Instruction::Blezl(_)
+            | Instruction::Bgtzl(_) => write!(f, "{name:<8}{rs}, {}", self.branch_target(imm)),
+
+            Instruction::Tgei(_)
+            | Instruction::Tgeiu(_)
+            | Instruction::Tlti(_)
+            | Instruction::Tltiu(_)
+            | Instruction::Teqi(_)
+            | Instruction::Tnei(_) => write!(f, "{name:<8}{rs}, {}", signed_hex(imm)),
+
+            Instruction::Addi(_)
+            | Instruction::Addiu(_)
+            | Instruction::Slti(_)
+            | Instruction::Sltiu(_)
+            | Instruction::Daddi(_)
+            | Instruction::Daddiu(_) => write!(f, "{name:<8}{rt}, {rs}, {}", signed_hex(imm)),
+
+            Instruction::Andi(_) | Instruction::Ori(_) | Instruction::Xori(_) => {
+                write!(f, "{name:<8}{rt}, {rs}, 0x{imm:X}")
+            }
+
+            Instruction::Lui(_) => write!(f, "{name:<8}{rt}, 0x{imm:X}"),
+
+            Instruction::Lwc1(_)
+            | Instruction::Ldc1(_)
+            | Instruction::Swc1(_)
+            | Instruction::Sdc1(_) => {
+                write!(f, "{name:<8}f{}, {}({rs})", dec.source2(), signed_hex(imm))
+            }
+
+            Instruction::Cache(_) => {
+                write!(
+                    f,
+                    "{name:<8}0x{:X}, {}({rs})",
+                    dec.source2(),
+                    signed_hex(imm)
+                )
+            }
+
+            _ => write!(f, "{name:<8}{rt}, {}({rs})", signed_hex(imm)),
+        }
+    }
+
+    fn j(&self, f: &mut Formatter<'_>, name: &str, dec: &JFormat) -> Result {
+        write!(f, "{name:<8}{}", self.jump_target(dec.target()))
+    }
+
+    fn fb(&self, f: &mut Formatter<'_>, name: &str, dec: &FbFormat) -> Result {
+        if dec.cc() == 0 {
+            write!(f, "{name:<8}{}", self.branch_target(dec.offset()))
+        } else {
+            write!(
+                f,
+                "{name:<8}{}, {}",
+                dec.cc(),
+                self.branch_target(dec.offset())
+            )
+        }
+    }
+
+    fn fr(&self, f: &mut Formatter<'_>, name: &str, dec: &FrFormat) -> Result {
+        let (fd, fs, ft) = (dec.dest(), dec.source1(), dec.source2());
+        let fmt = fmt_suffix(dec.format());
+        match self.instr {
+            Instruction::Addf(_)
+            | Instruction::Subf(_)
+            | Instruction::Mulf(_)
+            | Instruction::Divf(_) => {
+                let name = format!("{name}.{fmt}");
+                write!(f, "{name:<8}f{fd}, f{fs}, f{ft}")
+            }
+
+            Instruction::Fcompare(_) => {
+                let name = format!(
+                    "c.{}.{fmt}",
+                    FCOMPARE_CONDITIONS[(dec.function() & 0xF) as usize]
+                );
+                write!(f, "{name:<8}f{fs}, f{ft}")
+            }
+
+            _ => {
+                let name = format!("{name}.{fmt}");
+                write!(f, "{name:<8}f{fd}, f{fs}")
+            }
+        }
+    }
+}
+
+impl Display for Disassembly<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let name = self.instr.mnemonic();
+        match self.instr {
+            Instruction::None => write!(f, "(invalid)"),
+
+            Instruction::Sll(dec)
+            | Instruction::Srl(dec)
+            | Instruction::Sra(dec)
+            | Instruction::Sllv(dec)
+            | Instruction::Srlv(dec)
+            | Instruction::Srav(dec)
+            | Instruction::Jr(dec)
+            | Instruction::Jalr(dec)
+            | Instruction::Syscall(dec)
+            | Instruction::Break(dec)
+            | Instruction::Sync(dec)
+            | Instruction::Mfhi(dec)
+            | Instruction::Mthi(dec)
+            | Instruction::Mflo(dec)
+            | Instruction::Mtlo(dec)
+            | Instruction::Dsllv(dec)
+            | Instruction::Dsrlv(dec)
+            | Instruction::Dsrav(dec)
+            | Instruction::Mult(dec)
+            | Instruction::Multu(dec)
+            | Instruction::Div(dec)
+            | Instruction::Divu(dec)
+            | Instruction::Dmult(dec)
+            | Instruction::Dmultu(dec)
+            | Instruction::Ddiv(dec)
+            | Instruction::Ddivu(dec)
+            | Instruction::Add(dec)
+            | Instruction::Addu(dec)
+            | Instruction::Sub(dec)
+            | Instruction::Subu(dec)
+            | Instruction::And(dec)
+            | Instruction::Or(dec)
+            | Instruction::Xor(dec)
+            | Instruction::Nor(dec)
+            | Instruction::Slt(dec)
+            | Instruction::Sltu(dec)
+            | Instruction::Dadd(dec)
+            | Instruction::Daddu(dec)
+            | Instruction::Dsub(dec)
+            | Instruction::Dsubu(dec)
+            | Instruction::Tge(dec)
+            | Instruction::Tgeu(dec)
+            | Instruction::Tlt(dec)
+            | Instruction::Tltu(dec)
+            | Instruction::Teq(dec)
+            | Instruction::Tne(dec)
+            | Instruction::Dsll(dec)
+            | Instruction::Dsrl(dec)
+            | Instruction::Dsra(dec)
+            | Instruction::Dsll32(dec)
+            | Instruction::Dsrl32(dec)
+            | Instruction::Dsra32(dec) => self.r(f, name, dec),
+
+            Instruction::Bltz(dec)
+            | Instruction::Bgez(dec)
+            | Instruction::Bltzl(dec)
+            | Instruction::Bgezl(dec)
+            | Instruction::Tgei(dec)
+            | Instruction::Tgeiu(dec)
+            | Instruction::Tlti(dec)
+            | Instruction::Tltiu(dec)
+            | Instruction::Teqi(dec)
+            | Instruction::Tnei(dec)
+            | Instruction::Bltzal(dec)
+            | Instruction::Bgezal(dec)
+            | Instruction::Bltzall(dec)
+            | Instruction::Bgezall(dec)
+            | Instruction::Beq(dec)
+            | Instruction::Bne(dec)
+            | Instruction::Blez(dec)
+            | Instruction::Bgtz(dec)
+            | Instruction::Addi(dec)
+            | Instruction::Addiu(dec)
+            | Instruction::Slti(dec)
+            | Instruction::Sltiu(dec)
+            | Instruction::Andi(dec)
+            | Instruction::Ori(dec)
+            | Instruction::Xori(dec)
+            | Instruction::Lui(dec)
+            | Instruction::Beql(dec)
+            | Instruction::Bnel(dec)
+            | Instruction::Blezl(dec)
+            | Instruction::Bgtzl(dec)
+            | Instruction::Daddi(dec)
+            | Instruction::Daddiu(dec)
+            | Instruction::Ldl(dec)
+            | Instruction::Ldr(dec)
+            | Instruction::Lb(dec)
+            | Instruction::Lh(dec)
+            | Instruction::Lwl(dec)
+            | Instruction::Lw(dec)
+            | Instruction::Lbu(dec)
+            | Instruction::Lhu(dec)
+            | Instruction::Lwr(dec)
+            | Instruction::Lwu(dec)
+            | Instruction::Sb(dec)
+            | Instruction::Sh(dec)
+            | Instruction::Swl(dec)
+            | Instruction::Sw(dec)
+            | Instruction::Sdl(dec)
+            | Instruction::Sdr(dec)
+            | Instruction::Swr(dec)
+            | Instruction::Cache(dec)
+            | Instruction::Ll(dec)
+            | Instruction::Lwc1(dec)
+            | Instruction::Lld(dec)
+            | Instruction::Ldc1(dec)
+            | Instruction::Ld(dec)
+            | Instruction::Sc(dec)
+            | Instruction::Swc1(dec)
+            | Instruction::Scd(dec)
+            | Instruction::Sdc1(dec)
+            | Instruction::Sd(dec) => self.i(f, name, dec),
+
+            Instruction::Mfc0(dec) | Instruction::Mtc0(dec) => write!(
+                f,
+                "{name:<8}{}, {:?}",
+                reg(dec.source()),
+                cop0::Register::from(dec.dest())
+            ),
+
+            Instruction::Tlbwi(_) | Instruction::Eret(_) => write!(f, "{name}"),
+
+            Instruction::Mfc1(dec)
+            | Instruction::Dmfc1(dec)
+            | Instruction::Mtc1(dec)
+            | Instruction::Dmtc1(dec) => write!(f, "{name:<8}{}, f{}", reg(dec.gpr()), dec.fpr()),
+
+            Instruction::Cfc1(dec) | Instruction::Ctc1(dec) => {
+                write!(f, "{name:<8}{}, fcr{}", reg(dec.gpr()), dec.fpr())
+            }
+
+            Instruction::Bc1f(dec)
+            | Instruction::Bc1t(dec)
+            | Instruction::Bc1fl(dec)
+            | Instruction::Bc1tl(dec) => self.fb(f, name, dec),
+
+            Instruction::Addf(dec)
+            | Instruction::Subf(dec)
+            | Instruction::Mulf(dec)
+            | Instruction::Divf(dec)
+            | Instruction::Sqrtf(dec)
+            | Instruction::Absf(dec)
+            | Instruction::Movf(dec)
+            | Instruction::Negf(dec)
+            | Instruction::Roundl(dec)
+            | Instruction::Truncl(dec)
+            | Instruction::Ceill(dec)
+            | Instruction::Floorl(dec)
+            | Instruction::Roundw(dec)
+            | Instruction::Truncw(dec)
+            | Instruction::Ceilw(dec)
+            | Instruction::Floorw(dec)
+            | Instruction::Cvts(dec)
+            | Instruction::Cvtd(dec)
+            | Instruction::Cvtw(dec)
+            | Instruction::Cvtl(dec)
+            | Instruction::Fcompare(dec) => self.fr(f, name, dec),
+
+            Instruction::J(dec) | Instruction::Jal(dec) => self.j(f, name, dec),
+        }
+    }
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        Disassembly {
+            instr: self,
+            pc: None,
+        }
+        .fmt(f)
+    }
+}
+
+impl Instruction {
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::None => "(invalid)",
+            Instruction::Sll(_) => "sll",
+            Instruction::Srl(_) => "srl",
+            Instruction::Sra(_) => "sra",
+            Instruction::Sllv(_) => "sllv",
+            Instruction::Srlv(_) => "srlv",
+            Instruction::Srav(_) => "srav",
+            Instruction::Jr(_) => "jr",
+            Instruction::Jalr(_) => "jalr",
+            Instruction::Syscall(_) => "syscall",
+            Instruction::Break(_) => "break",
+            Instruction::Sync(_) => "sync",
+            Instruction::Mfhi(_) => "mfhi",
+            Instruction::Mthi(_) => "mthi",
+            Instruction::Mflo(_) => "mflo",
+            Instruction::Mtlo(_) => "mtlo",
+            Instruction::Dsllv(_) => "dsllv",
+            Instruction::Dsrlv(_) => "dsrlv",
+            Instruction::Dsrav(_) => "dsrav",
+            Instruction::Mult(_) => "mult",
+            Instruction::Multu(_) => "multu",
+            Instruction::Div(_) => "div",
+            Instruction::Divu(_) => "divu",
+            Instruction::Dmult(_) => "dmult",
+            Instruction::Dmultu(_) => "dmultu",
+            Instruction::Ddiv(_) => "ddiv",
+            Instruction::Ddivu(_) => "ddivu",
+            Instruction::Add(_) => "add",
+            Instruction::Addu(_) => "addu",
+            Instruction::Sub(_) => "sub",
+            Instruction::Subu(_) => "subu",
+            Instruction::And(_) => "and",
+            Instruction::Or(_) => "or",
+            Instruction::Xor(_) => "xor",
+            Instruction::Nor(_) => "nor",
+            Instruction::Slt(_) => "slt",
+            Instruction::Sltu(_) => "sltu",
+            Instruction::Dadd(_) => "dadd",
+            Instruction::Daddu(_) => "daddu",
+            Instruction::Dsub(_) => "dsub",
+            Instruction::Dsubu(_) => "dsubu",
+            Instruction::Tge(_) => "tge",
+            Instruction::Tgeu(_) => "tgeu",
+            Instruction::Tlt(_) => "tlt",
+            Instruction::Tltu(_) => "tltu",
+            Instruction::Teq(_) => "teq",
+            Instruction::Tne(_) => "tne",
+            Instruction::Dsll(_) => "dsll",
+            Instruction::Dsrl(_) => "dsrl",
+            Instruction::Dsra(_) => "dsra",
+            Instruction::Dsll32(_) => "dsll32",
+            Instruction::Dsrl32(_) => "dsrl32",
+            Instruction::Dsra32(_) => "dsra32",
+            Instruction::Bltz(_) => "bltz",
+            Instruction::Bgez(_) => "bgez",
+            Instruction::Bltzl(_) => "bltzl",
+            Instruction::Bgezl(_) => "bgezl",
+            Instruction::Tgei(_) => "tgei",
+            Instruction::Tgeiu(_) => "tgeiu",
+            Instruction::Tlti(_) => "tlti",
+            Instruction::Tltiu(_) => "tltiu",
+            Instruction::Teqi(_) => "teqi",
+            Instruction::Tnei(_) => "tnei",
+            Instruction::Bltzal(_) => "bltzal",
+            Instruction::Bgezal(_) => "bgezal",
+            Instruction::Bltzall(_) => "bltzall",
+            Instruction::Bgezall(_) => "bgezall",
+            Instruction::Mfc0(_) => "mfc0",
+            Instruction::Mtc0(_) => "mtc0",
+            Instruction::Tlbwi(_) => "tlbwi",
+            Instruction::Eret(_) => "eret",
+            Instruction::Mfc1(_) => "mfc1",
+            Instruction::Dmfc1(_) => "dmfc1",
+            Instruction::Cfc1(_) => "cfc1",
+            Instruction::Mtc1(_) => "mtc1",
+            Instruction::Dmtc1(_) => "dmtc1",
+            Instruction::Ctc1(_) => "ctc1",
+            Instruction::Bc1f(_) => "bc1f",
+            Instruction::Bc1t(_) => "bc1t",
+            Instruction::Bc1fl(_) => "bc1fl",
+            Instruction::Bc1tl(_) => "bc1tl",
+            Instruction::Addf(_) => "add",
+            Instruction::Subf(_) => "sub",
+            Instruction::Mulf(_) => "mul",
+            Instruction::Divf(_) => "div",
+            Instruction::Sqrtf(_) => "sqrt",
+            Instruction::Absf(_) => "abs",
+            Instruction::Movf(_) => "mov",
+            Instruction::Negf(_) => "neg",
+            Instruction::Roundl(_) => "round.l",
+            Instruction::Truncl(_) => "trunc.l",
+            Instruction::Ceill(_) => "ceil.l",
+            Instruction::Floorl(_) => "floor.l",
+            Instruction::Roundw(_) => "round.w",
+            Instruction::Truncw(_) => "trunc.w",
+            Instruction::Ceilw(_) => "ceil.w",
+            Instruction::Floorw(_) => "floor.w",
+            Instruction::Cvts(_) => "cvt.s",
+            Instruction::Cvtd(_) => "cvt.d",
+            Instruction::Cvtw(_) => "cvt.w",
+            Instruction::Cvtl(_) => "cvt.l",
+            Instruction::Fcompare(_) => "c",
+            Instruction::J(_) => "j",
+            Instruction::Jal(_) => "jal",
+            Instruction::Beq(_) => "beq",
+            Instruction::Bne(_) => "bne",
+            Instruction::Blez(_) => "blez",
+            Instruction::Bgtz(_) => "bgtz",
+            Instruction::Addi(_) => "addi",
+            Instruction::Addiu(_) => "addiu",
+            Instruction::Slti(_) => "slti",
+            Instruction::Sltiu(_) => "sltiu",
+            Instruction::Andi(_) => "andi",
+            Instruction::Ori(_) => "ori",
+            Instruction::Xori(_) => "xori",
+            Instruction::Lui(_) => "lui",
+            Instruction::Beql(_) => "beql",
+            Instruction::Bnel(_) => "bnel",
+            Instruction::Blezl(_) => "blezl",
+            Instruction::Bgtzl(_) => "bgtzl",
+            Instruction::Daddi(_) => "daddi",
+            Instruction::Daddiu(_) => "daddiu",
+            Instruction::Ldl(_) => "ldl",
+            Instruction::Ldr(_) => "ldr",
+            Instruction::Lb(_) => "lb",
+            Instruction::Lh(_) => "lh",
+            Instruction::Lwl(_) => "lwl",
+            Instruction::Lw(_) => "lw",
+            Instruction::Lbu(_) => "lbu",
+            Instruction::Lhu(_) => "lhu",
+            Instruction::Lwr(_) => "lwr",
+            Instruction::Lwu(_) => "lwu",
+            Instruction::Sb(_) => "sb",
+            Instruction::Sh(_) => "sh",
+            Instruction::Swl(_) => "swl",
+            Instruction::Sw(_) => "sw",
+            Instruction::Sdl(_) => "sdl",
+            Instruction::Sdr(_) => "sdr",
+            Instruction::Swr(_) => "swr",
+            Instruction::Cache(_) => "cache",
+            Instruction::Ll(_) => "ll",
+            Instruction::Lwc1(_) => "lwc1",
+            Instruction::Lld(_) => "lld",
+            Instruction::Ldc1(_) => "ldc1",
+            Instruction::Ld(_) => "ld",
+            Instruction::Sc(_) => "sc",
+            Instruction::Swc1(_) => "swc1",
+            Instruction::Scd(_) => "scd",
+            Instruction::Sdc1(_) => "sdc1",
+            Instruction::Sd(_) => "sd",
+        }
+    }
+
+    // resolves branch and jump targets relative to the given pc
+    pub fn disassemble(&self, pc: word) -> Disassembly<'_> {
+        Disassembly {
+            instr: self,
+            pc: Some(pc),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dis(value: word) -> String {
+        Instruction::new(value).to_string()
+    }
+
+    fn dis_at(value: word, pc: word) -> String {
+        Instruction::new(value).disassemble(pc).to_string()
+    }
+
+    #[test]
+    fn r_format() {
+        assert_eq!(dis(0x00000000), "nop");
+        assert_eq!(dis(0x00851021), "addu    v0, a0, a1");
+        assert_eq!(dis(0x00094100), "sll     t0, t1, 4");
+        assert_eq!(dis(0x03E00008), "jr      ra");
+        assert_eq!(dis(0x0109001A), "div     t0, t1");
+        assert_eq!(dis(0x0000000D), "break");
+    }
+
+    #[test]
+    fn i_format() {
+        assert_eq!(dis(0x27BDFFE0), "addiu   sp, sp, -0x20");
+        assert_eq!(dis(0x8FBF001C), "lw      ra, 0x1C(sp)");
+        assert_eq!(dis(0x3C018000), "lui     at, 0x8000");
+        assert_eq!(dis(0x34210001), "ori     at, at, 0x1");
+        assert_eq!(dis(0xBC830000), "cache   0x3, 0x0(a0)");
+    }
+
+    #[test]
+    fn branch_and_jump_targets() {
+        assert_eq!(dis(0x10800003), "beq     a0, zero, .+0x10");
+        assert_eq!(
+            dis_at(0x10800003, 0x80001000),
+            "beq     a0, zero, 0x80001010"
+        );
+        assert_eq!(dis(0x1480FFFE), "bne     a0, zero, .-0x4");
+        assert_eq!(
+            dis_at(0x1480FFFE, 0x80001000),
+            "bne     a0, zero, 0x80000FFC"
+        );
+
+        // jumps stay in the 256MB region of the delay slot
+        assert_eq!(dis(0x0800048D), "j       0x0001234");
+        assert_eq!(dis_at(0x0800048D, 0x80001000), "j       0x80001234");
+        assert_eq!(dis_at(0x0C00048D, 0xBFC00000), "jal     0xB0001234");
+    }
+
+    #[test]
+    fn cop0() {
+        assert_eq!(dis(0x40086000), "mfc0    t0, Status");
+        assert_eq!(dis(0x40886000), "mtc0    t0, Status");
+        assert_eq!(dis(0x42000018), "eret");
+        assert_eq!(dis(0x42000002), "tlbwi");
+    }
+
+    #[test]
+    fn cop1() {
+        assert_eq!(dis(0x44081000), "mfc1    t0, f2");
+        assert_eq!(dis(0x44C8F800), "ctc1    t0, fcr31");
+        assert_eq!(dis(0xC4840010), "lwc1    f4, 0x10(a0)");
+        assert_eq!(dis(0x46801020), "cvt.s.w f0, f2");
+        assert_eq!(dis_at(0x45010002, 0x80001000), "bc1t    0x8000100C");
+    }
+}
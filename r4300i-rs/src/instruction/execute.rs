@@ -78,8 +78,8 @@ pub fn get_instruction_function(instr: &Instruction) -> InstructionFunction {
         Instruction::Tltiu(_) => todo!(),
         Instruction::Teqi(_) => todo!(),
         Instruction::Tnei(_) => todo!(),
-        Instruction::Bltzal(_) => todo!(),
-        Instruction::Bgezal(_) => todo!(),
+        Instruction::Bltzal(_) => bltzal,
+        Instruction::Bgezal(_) => bgezal,
         Instruction::Bltzall(_) => todo!(),
         Instruction::Bgezall(_) => todo!(),
         Instruction::Mfc0(_) => mfc0,
@@ -168,6 +168,31 @@ pub fn get_instruction_function(instr: &Instruction) -> InstructionFunction {
     }
 }
 
+// the delay slot function an instruction queues when it executes
+pub fn get_delay_slot_function(instr: &Instruction) -> Option<DelaySlotFunction> {
+    match instr {
+        Instruction::Jr(_) | Instruction::Jalr(_) => Some(JUMP_REGISTER_FUNCTION),
+        Instruction::J(_) | Instruction::Jal(_) => Some(JUMP_FUNCTION),
+        Instruction::Bltz(_)
+        | Instruction::Bgez(_)
+        | Instruction::Bltzl(_)
+        | Instruction::Bgezl(_)
+        | Instruction::Bltzal(_)
+        | Instruction::Bgezal(_)
+        | Instruction::Beq(_)
+        | Instruction::Bne(_)
+        | Instruction::Blez(_)
+        | Instruction::Bgtz(_)
+        | Instruction::Beql(_)
+        | Instruction::Bnel(_) => Some(BRANCH_FUNCTION),
+        Instruction::Tlbwi(_) => Some(TLBWI_FUNCTION),
+        Instruction::Eret(_) => Some(ERET_FUNCTION),
+        Instruction::Cfc1(_) => Some(CFC1_FUNCTION),
+        Instruction::Ctc1(_) => Some(CTC1_FUNCTION),
+        _ => None,
+    }
+}
+
 macro_rules! get_instr {
     ($i:expr, $p:pat) => {
         match $i {
@@ -333,6 +358,10 @@ fn jr(instr: &Instruction, cpu: &mut R4300i) {
         unreachable!()
     };
 
+    if Register::from(dec.source1()) == Register::Ra {
+        cpu.call_stack.ret(get_reg!(cpu, dec.source1(), word));
+    }
+
     delay_slot!(
         cpu,
         JUMP_REGISTER_FUNCTION,
@@ -346,6 +375,11 @@ fn jalr(instr: &Instruction, cpu: &mut R4300i) {
         unreachable!()
     };
 
+    cpu.call_stack.call(
+        cpu.state.get_pc() as word,
+        get_reg!(cpu, dec.source1(), word),
+    );
+
     link!(cpu, dec.dest(), 8);
 
     delay_slot!(
@@ -715,6 +749,45 @@ fn bgezl(instr: &Instruction, cpu: &mut R4300i) {
     )
 }
 
+// bal is bgezal with zero, so these are the only way a call can be conditional
+fn bltzal(instr: &Instruction, cpu: &mut R4300i) {
+    let Instruction::Bltzal(dec) = instr else {
+        unreachable!()
+    };
+
+    let offset = sign_extend_hword_twice(dec.imm()) << 2;
+    let cond = get_reg!(cpu, dec.source1(), sword) < 0;
+
+    let pc = cpu.state.get_pc();
+    if cond {
+        cpu.call_stack
+            .call(pc as word, pc.wrapping_add(4).wrapping_add(offset) as word);
+    }
+
+    link!(cpu, Register::Ra, 8);
+
+    delay_slot!(cpu, BRANCH_FUNCTION, offset, cond)
+}
+
+fn bgezal(instr: &Instruction, cpu: &mut R4300i) {
+    let Instruction::Bgezal(dec) = instr else {
+        unreachable!()
+    };
+
+    let offset = sign_extend_hword_twice(dec.imm()) << 2;
+    let cond = get_reg!(cpu, dec.source1(), sword) >= 0;
+
+    let pc = cpu.state.get_pc();
+    if cond {
+        cpu.call_stack
+            .call(pc as word, pc.wrapping_add(4).wrapping_add(offset) as word);
+    }
+
+    link!(cpu, Register::Ra, 8);
+
+    delay_slot!(cpu, BRANCH_FUNCTION, offset, cond)
+}
+
 // is mfc0 supposed to do the store at I + 1???? i don't know!!!! the manual says so, but ares says no
 fn mfc0(instr: &Instruction, cpu: &mut R4300i) {
     let Instruction::Mfc0(dec) = instr else {
@@ -763,55 +836,95 @@ fn mtc0(instr: &Instruction, cpu: &mut R4300i) {
     set_cop0_reg!(cpu, dec.dest(), get_reg!(cpu, dec.source(), _));
 }
 
+const TLBWI_FUNCTION: DelaySlotFunction =
+    |instr: &Instruction, cpu: &mut R4300i, _: dword, _: bool| {
+        let Instruction::Tlbwi(_) = instr else {
+            unreachable!()
+        };
+
+        let index = cpu
+            .cop0
+            .state
+            .get_reg::<registers::Index>(crate::cop0::Register::Index);
+
+        let page_mask = cpu
+            .cop0
+            .state
+            .get_reg::<registers::PageMask>(crate::cop0::Register::PageMask);
+        let entry_hi = cpu
+            .cop0
+            .state
+            .get_reg::<registers::EntryHi>(crate::cop0::Register::EntryHi);
+        let entry_lo_0 = cpu
+            .cop0
+            .state
+            .get_reg::<registers::EntryLo>(crate::cop0::Register::EntryLo0);
+        let entry_lo_1 = cpu
+            .cop0
+            .state
+            .get_reg::<registers::EntryLo>(crate::cop0::Register::EntryLo1);
+
+        let entry = TLBEntry::new()
+            .with_page_mask(page_mask)
+            .with_entry_hi(entry_hi)
+            .with_entry_lo_0(entry_lo_0)
+            .with_entry_lo_1(entry_lo_1);
+
+        println!("writing tlb entry 0x{:02X}: {entry:?}", index.index());
+
+        cpu.cop0.state.set_tlb_entry(index.index() as _, entry);
+        advance_pc!(cpu);
+    };
+
 fn tlbwi(instr: &Instruction, cpu: &mut R4300i) {
     let Instruction::Tlbwi(dec) = instr else {
         unreachable!()
     };
 
-    delay_slot!(
-        cpu,
-        |instr: &Instruction, cpu: &mut R4300i, _: dword, _: bool| {
-            let Instruction::Tlbwi(_) = instr else {
-                unreachable!()
-            };
+    delay_slot!(cpu, TLBWI_FUNCTION, 0, true)
+}
 
-            let index = cpu
-                .cop0
-                .state
-                .get_reg::<registers::Index>(crate::cop0::Register::Index);
+const ERET_FUNCTION: DelaySlotFunction =
+    |instr: &Instruction, cpu: &mut R4300i, _: dword, _: bool| {
+        let Instruction::Eret(_) = instr else {
+            unreachable!()
+        };
 
-            let page_mask = cpu
-                .cop0
-                .state
-                .get_reg::<registers::PageMask>(crate::cop0::Register::PageMask);
-            let entry_hi = cpu
-                .cop0
-                .state
-                .get_reg::<registers::EntryHi>(crate::cop0::Register::EntryHi);
-            let entry_lo_0 = cpu
+        let mut status = cpu
+            .cop0
+            .state
+            .get_reg::<crate::cop0::registers::Status>(crate::cop0::Register::Status);
+
+        if status.erl() {
+            status.set_erl(false);
+
+            let epc = cpu
                 .cop0
                 .state
-                .get_reg::<registers::EntryLo>(crate::cop0::Register::EntryLo0);
-            let entry_lo_1 = cpu
+                .get_reg::<crate::cop0::registers::ErrorEpc>(crate::cop0::Register::ErrorEpc);
+
+            cpu.state.set_pc(sign_extend_word(epc.error_epc()));
+
+            println!("eret from error, pc = {:016X}", cpu.state.get_pc());
+        } else {
+            status.set_exl(false);
+
+            let epc = cpu
                 .cop0
                 .state
-                .get_reg::<registers::EntryLo>(crate::cop0::Register::EntryLo1);
+                .get_reg::<crate::cop0::registers::Epc>(crate::cop0::Register::Epc);
 
-            let entry = TLBEntry::new()
-                .with_page_mask(page_mask)
-                .with_entry_hi(entry_hi)
-                .with_entry_lo_0(entry_lo_0)
-                .with_entry_lo_1(entry_lo_1);
+            cpu.state.set_pc(sign_extend_word(epc.epc()));
 
-            println!("writing tlb entry 0x{:02X}: {entry:?}", index.index());
+            println!("eret from non-error, pc = {:016X}", cpu.state.get_pc());
+        }
 
-            cpu.cop0.state.set_tlb_entry(index.index() as _, entry);
-            advance_pc!(cpu);
-        },
-        0,
-        true
-    )
-}
+        cpu.call_stack.eret();
+
+        cpu.cop0
+            .state
+            .set_reg(crate::cop0::Register::Status, status);
+    };
 
 fn eret(instr: &Instruction, cpu: &mut R4300i) {
     let Instruction::Eret(_) = instr else {
@@ -820,50 +933,18 @@ fn eret(instr: &Instruction, cpu: &mut R4300i) {
 
     cpu.is_branch_likely = true;
 
-    delay_slot!(
-        cpu,
-        |instr: &Instruction, cpu: &mut R4300i, _: dword, _: bool| {
-            let Instruction::Eret(_) = instr else {
-                unreachable!()
-            };
-
-            let mut status = cpu
-                .cop0
-                .state
-                .get_reg::<crate::cop0::registers::Status>(crate::cop0::Register::Status);
-
-            if status.erl() {
-                status.set_erl(false);
-
-                let epc = cpu
-                    .cop0
-                    .state
-                    .get_reg::<crate::cop0::registers::ErrorEpc>(crate::cop0::Register::ErrorEpc);
-
-                cpu.state.set_pc(sign_extend_word(epc.error_epc()));
-
-                println!("eret from error, pc = {:016X}", cpu.state.get_pc());
-            } else {
-                status.set_exl(false);
-
-                let epc = cpu
-                    .cop0
-                    .state
-                    .get_reg::<crate::cop0::registers::Epc>(crate::cop0::Register::Epc);
-
-                cpu.state.set_pc(sign_extend_word(epc.epc()));
+    delay_slot!(cpu, ERET_FUNCTION, 0, false);
+}
 
-                println!("eret from non-error, pc = {:016X}", cpu.state.get_pc());
-            }
+const CFC1_FUNCTION: DelaySlotFunction =
+    |instr: &Instruction, cpu: &mut R4300i, delay_slot_target: dword, _: bool| {
+        let Instruction::Cfc1(dec) = instr else {
+            unreachable!()
+        };
 
-            cpu.cop0
-                .state
-                .set_reg(crate::cop0::Register::Status, status);
-        },
-        0,
-        false
-    );
-}
+        set_reg!(cpu, dec.gpr(), sign_extend_word(delay_slot_target as _));
+        advance_pc!(cpu);
+    };
 
 fn cfc1(instr: &Instruction, cpu: &mut R4300i) {
     let Instruction::Cfc1(dec) = instr else {
@@ -872,37 +953,28 @@ fn cfc1(instr: &Instruction, cpu: &mut R4300i) {
 
     delay_slot!(
         cpu,
-        |instr: &Instruction, cpu: &mut R4300i, delay_slot_target: dword, _: bool| {
-            let Instruction::Cfc1(dec) = instr else {
-                unreachable!()
-            };
-
-            set_reg!(cpu, dec.gpr(), sign_extend_word(delay_slot_target as _));
-            advance_pc!(cpu);
-        },
+        CFC1_FUNCTION,
         get_cop1_control_reg!(cpu, dec.fpr(), _),
         true
     )
 }
 
+const CTC1_FUNCTION: DelaySlotFunction =
+    |instr: &Instruction, cpu: &mut R4300i, delay_slot_target: dword, _: bool| {
+        let Instruction::Ctc1(dec) = instr else {
+            unreachable!()
+        };
+
+        set_cop1_control_reg!(cpu, dec.fpr(), delay_slot_target as _);
+        advance_pc!(cpu);
+    };
+
 fn ctc1(instr: &Instruction, cpu: &mut R4300i) {
     let Instruction::Ctc1(dec) = instr else {
         unreachable!()
     };
 
-    delay_slot!(
-        cpu,
-        |instr: &Instruction, cpu: &mut R4300i, delay_slot_target: dword, _: bool| {
-            let Instruction::Ctc1(dec) = instr else {
-                unreachable!()
-            };
-
-            set_cop1_control_reg!(cpu, dec.fpr(), delay_slot_target as _);
-            advance_pc!(cpu);
-        },
-        get_reg!(cpu, dec.gpr(), dword),
-        true
-    )
+    delay_slot!(cpu, CTC1_FUNCTION, get_reg!(cpu, dec.gpr(), dword), true)
 }
 
 fn j(instr: &Instruction, cpu: &mut R4300i) {
@@ -918,6 +990,12 @@ fn jal(instr: &Instruction, cpu: &mut R4300i) {
         unreachable!()
     };
 
+    let pc = cpu.state.get_pc();
+    cpu.call_stack.call(
+        pc as word,
+        ((pc.wrapping_add(4) & 0xF0000000) | (dec.target() as dword) << 2) as word,
+    );
+
     link!(cpu, Register::Ra, 8);
 
     delay_slot!(cpu, JUMP_FUNCTION, dec.target() as dword, true)
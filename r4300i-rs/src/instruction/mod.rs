@@ -3,6 +3,8 @@ use crate::R4300i;
 
 use modular_bitfield::prelude::*;
 
+pub mod asm;
+pub mod disasm;
 pub mod execute;
 
 use execute::get_instruction_function;
@@ -732,6 +734,167 @@ impl Instruction {
         }
     }
 
+    // the inverse of new; invalid instructions have no encoding
+    pub fn encode(&self) -> Option<word> {
+        Some(match self {
+            Instruction::None => return None,
+
+            Instruction::Sll(dec)
+            | Instruction::Srl(dec)
+            | Instruction::Sra(dec)
+            | Instruction::Sllv(dec)
+            | Instruction::Srlv(dec)
+            | Instruction::Srav(dec)
+            | Instruction::Jr(dec)
+            | Instruction::Jalr(dec)
+            | Instruction::Syscall(dec)
+            | Instruction::Break(dec)
+            | Instruction::Sync(dec)
+            | Instruction::Mfhi(dec)
+            | Instruction::Mthi(dec)
+            | Instruction::Mflo(dec)
+            | Instruction::Mtlo(dec)
+            | Instruction::Dsllv(dec)
+            | Instruction::Dsrlv(dec)
+            | Instruction::Dsrav(dec)
+            | Instruction::Mult(dec)
+            | Instruction::Multu(dec)
+            | Instruction::Div(dec)
+            | Instruction::Divu(dec)
+            | Instruction::Dmult(dec)
+            | Instruction::Dmultu(dec)
+            | Instruction::Ddiv(dec)
+            | Instruction::Ddivu(dec)
+            | Instruction::Add(dec)
+            | Instruction::Addu(dec)
+            | Instruction::Sub(dec)
+            | Instruction::Subu(dec)
+            | Instruction::And(dec)
+            | Instruction::Or(dec)
+            | Instruction::Xor(dec)
+            | Instruction::Nor(dec)
+            | Instruction::Slt(dec)
+            | Instruction::Sltu(dec)
+            | Instruction::Dadd(dec)
+            | Instruction::Daddu(dec)
+            | Instruction::Dsub(dec)
+            | Instruction::Dsubu(dec)
+            | Instruction::Tge(dec)
+            | Instruction::Tgeu(dec)
+            | Instruction::Tlt(dec)
+            | Instruction::Tltu(dec)
+            | Instruction::Teq(dec)
+            | Instruction::Tne(dec)
+            | Instruction::Dsll(dec)
+            | Instruction::Dsrl(dec)
+            | Instruction::Dsra(dec)
+            | Instruction::Dsll32(dec)
+            | Instruction::Dsrl32(dec)
+            | Instruction::Dsra32(dec) => u32::from(*dec),
+
+            Instruction::Bltz(dec)
+            | Instruction::Bgez(dec)
+            | Instruction::Bltzl(dec)
+            | Instruction::Bgezl(dec)
+            | Instruction::Tgei(dec)
+            | Instruction::Tgeiu(dec)
+            | Instruction::Tlti(dec)
+            | Instruction::Tltiu(dec)
+            | Instruction::Teqi(dec)
+            | Instruction::Tnei(dec)
+            | Instruction::Bltzal(dec)
+            | Instruction::Bgezal(dec)
+            | Instruction::Bltzall(dec)
+            | Instruction::Bgezall(dec)
+            | Instruction::Beq(dec)
+            | Instruction::Bne(dec)
+            | Instruction::Blez(dec)
+            | Instruction::Bgtz(dec)
+            | Instruction::Addi(dec)
+            | Instruction::Addiu(dec)
+            | Instruction::Slti(dec)
+            | Instruction::Sltiu(dec)
+            | Instruction::Andi(dec)
+            | Instruction::Ori(dec)
+            | Instruction::Xori(dec)
+            | Instruction::Lui(dec)
+            | Instruction::Beql(dec)
+            | Instruction::Bnel(dec)
+            | Instruction::Blezl(dec)
+            | Instruction::Bgtzl(dec)
+            | Instruction::Daddi(dec)
+            | Instruction::Daddiu(dec)
+            | Instruction::Ldl(dec)
+            | Instruction::Ldr(dec)
+            | Instruction::Lb(dec)
+            | Instruction::Lh(dec)
+            | Instruction::Lwl(dec)
+            | Instruction::Lw(dec)
+            | Instruction::Lbu(dec)
+            | Instruction::Lhu(dec)
+            | Instruction::Lwr(dec)
+            | Instruction::Lwu(dec)
+            | Instruction::Sb(dec)
+            | Instruction::Sh(dec)
+            | Instruction::Swl(dec)
+            | Instruction::Sw(dec)
+            | Instruction::Sdl(dec)
+            | Instruction::Sdr(dec)
+            | Instruction::Swr(dec)
+            | Instruction::Cache(dec)
+            | Instruction::Ll(dec)
+            | Instruction::Lwc1(dec)
+            | Instruction::Lld(dec)
+            | Instruction::Ldc1(dec)
+            | Instruction::Ld(dec)
+            | Instruction::Sc(dec)
+            | Instruction::Swc1(dec)
+            | Instruction::Scd(dec)
+            | Instruction::Sdc1(dec)
+            | Instruction::Sd(dec) => u32::from(*dec),
+
+            Instruction::Mfc0(dec) | Instruction::Mtc0(dec) => u32::from(*dec),
+
+            Instruction::Tlbwi(dec) | Instruction::Eret(dec) => u32::from(*dec),
+
+            Instruction::Mfc1(dec)
+            | Instruction::Dmfc1(dec)
+            | Instruction::Cfc1(dec)
+            | Instruction::Mtc1(dec)
+            | Instruction::Dmtc1(dec)
+            | Instruction::Ctc1(dec) => u32::from(*dec),
+
+            Instruction::Bc1f(dec)
+            | Instruction::Bc1t(dec)
+            | Instruction::Bc1fl(dec)
+            | Instruction::Bc1tl(dec) => u32::from(*dec),
+
+            Instruction::Addf(dec)
+            | Instruction::Subf(dec)
+            | Instruction::Mulf(dec)
+            | Instruction::Divf(dec)
+            | Instruction::Sqrtf(dec)
+            | Instruction::Absf(dec)
+            | Instruction::Movf(dec)
+            | Instruction::Negf(dec)
+            | Instruction::Roundl(dec)
+            | Instruction::Truncl(dec)
+            | Instruction::Ceill(dec)
+            | Instruction::Floorl(dec)
+            | Instruction::Roundw(dec)
+            | Instruction::Truncw(dec)
+            | Instruction::Ceilw(dec)
+            | Instruction::Floorw(dec)
+            | Instruction::Cvts(dec)
+            | Instruction::Cvtd(dec)
+            | Instruction::Cvtw(dec)
+            | Instruction::Cvtl(dec)
+            | Instruction::Fcompare(dec) => u32::from(*dec),
+
+            Instruction::J(dec) | Instruction::Jal(dec) => u32::from(*dec),
+        })
+    }
+
     pub fn execute(&self, cpu: &mut R4300i) {
         get_instruction_function(self)(self, cpu);
     }
@@ -1,15 +1,34 @@
 use std::collections::VecDeque;
-use std::fmt::Debug;
+use std::fmt::{Debug, Display};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
 
 use num_traits::{FromBytes, ToBytes};
-
-mod cop0;
-mod instruction;
+use thiserror::Error;
+
+pub mod callstack;
+pub mod cop0;
+pub mod ecc;
+pub mod instruction;
+pub mod snapshot;
+pub mod stimulus;
+pub mod trace;
 pub mod types;
 
-use cop0::{Cop0, ResetType};
-use instruction::execute::{DelaySlot, InstructionFunction};
+use callstack::{CallStack, Frame};
+use cop0::faults::NandFaults;
+use cop0::mmio::MmioLog;
+use cop0::nand::{NandDevice, NandImage};
+use cop0::save::SaveMemory;
+use cop0::tlb::TLBEntry;
+use cop0::{Cop0, Device, ReadKind, ResetType};
+use instruction::asm::{assemble, AsmError};
+use instruction::execute::{get_delay_slot_function, DelaySlot, InstructionFunction};
 use instruction::Instruction;
+use snapshot::{Snapshot, SnapshotError, Snapshotter};
+use stimulus::Stimulus;
+use trace::Tracer;
 use types::*;
 
 pub const BOOTROM_BASE: word = 0xBFC00000;
@@ -167,6 +186,20 @@ impl From<u8> for Register {
     }
 }
 
+impl Register {
+    const NAMES: [&'static str; 32] = [
+        "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3", "t0", "t1", "t2", "t3", "t4", "t5", "t6",
+        "t7", "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "t8", "t9", "k0", "k1", "gp", "sp",
+        "fp", "ra",
+    ];
+}
+
+impl Display for Register {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(Self::NAMES[*self as usize])
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum FpRegister {
@@ -440,6 +473,14 @@ pub enum SecureTrapType {
     App,
 }
 
+// unimplemented instructions and registers panic; stepping through `try_step`
+// turns that into an error so the frontend can say how the guest got there
+#[derive(Debug, Error)]
+pub enum EmuError {
+    #[error("{message} (pc {pc:08X})")]
+    Panic { pc: word, message: String },
+}
+
 #[derive(Debug)]
 pub struct R4300i {
     state: State,
@@ -459,6 +500,7 @@ pub struct R4300i {
     pub halted: bool,
 
     logging: bool,
+    tracer: Option<Tracer>,
 
     prev_instruction: Option<Instruction>,
     cur_instruction: Option<Instruction>,
@@ -466,6 +508,14 @@ pub struct R4300i {
     exception: Exception,
 
     video_timer: word,
+
+    random_seed: dword,
+
+    call_stack: CallStack,
+
+    // (pc, opcode) of the most recently fetched instructions, oldest first
+    history: VecDeque<(word, word)>,
+    history_len: usize,
 }
 
 impl R4300i {
@@ -485,8 +535,7 @@ impl R4300i {
         v0: Vec<byte>,
         v1: Vec<byte>,
         v2: Vec<byte>,
-        nand: Vec<byte>,
-        spare: Vec<byte>,
+        nand: NandImage,
     ) -> Self {
         let did_cold_reset = false;
         let did_soft_reset = false;
@@ -504,7 +553,7 @@ impl R4300i {
 
         Self {
             state,
-            cop0: Cop0::new(reset_type, bootrom, v0, v1, v2, nand, spare),
+            cop0: Cop0::new(reset_type, bootrom, v0, v1, v2, nand),
             coc0: false,
             coc1: false,
             delay_slot: VecDeque::new(),
@@ -515,10 +564,15 @@ impl R4300i {
             running: false,
             halted: false,
             logging: false,
+            tracer: None,
             prev_instruction: None,
             cur_instruction: None,
             exception: Exception::default(),
             video_timer: 0,
+            random_seed: 0,
+            call_stack: CallStack::new(),
+            history: VecDeque::new(),
+            history_len: 0,
         }
     }
 
@@ -543,10 +597,75 @@ impl R4300i {
         self.logging = false;
     }
 
+    pub fn start_trace(&mut self) {
+        self.tracer.get_or_insert_with(Tracer::new);
+    }
+
+    // any records not yet flushed are dropped
+    pub fn stop_trace(&mut self) {
+        self.tracer = None;
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.tracer.is_some()
+    }
+
+    pub fn flush_trace<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        match &mut self.tracer {
+            Some(tracer) => tracer.flush(out),
+            None => Ok(()),
+        }
+    }
+
+    pub fn set_mmio_log(&mut self, log: Option<MmioLog>) {
+        self.cop0.set_mmio_log(log);
+    }
+
+    fn traced_registers(&self) -> [dword; trace::NUM_TRACED] {
+        let mut registers = [0; trace::NUM_TRACED];
+        registers[..32].copy_from_slice(&self.state.registers);
+        registers[trace::HI as usize] = self.state.hi;
+        registers[trace::LO as usize] = self.state.lo;
+        registers
+    }
+
     pub fn trigger_interrupt(&mut self) {
         self.cop0.trigger_md_intr();
     }
 
+    // Random normally starts at 31 on reset; a seed just moves the starting point
+    pub fn seed_random(&mut self, seed: dword) {
+        self.random_seed = seed;
+        self.apply_random_seed();
+    }
+
+    fn apply_random_seed(&mut self) {
+        let wired: cop0::registers::Wired = self.cop0.state.get_reg(cop0::Register::Wired);
+        let span = 32 - wired.wired() as dword;
+        let random =
+            cop0::registers::Random::new().with_random(31 - (self.random_seed % span) as u8);
+        self.cop0.state.set_reg(cop0::Register::Random, random);
+    }
+
+    pub fn apply(&mut self, stimulus: &Stimulus) {
+        match stimulus {
+            Stimulus::Reset => self.reset(),
+            Stimulus::Button(pressed) => {
+                if self.cop0.set_button(*pressed) {
+                    self.secure_trap(SecureTrapType::Button);
+                }
+            }
+            Stimulus::Card(present) => {
+                self.cop0.set_card_present(*present);
+                self.cop0.trigger_md_intr();
+            }
+            Stimulus::Interrupt => self.trigger_interrupt(),
+            Stimulus::Usb(controller, offset, data) => {
+                self.cop0.usb_receive(*controller, *offset, data)
+            }
+        }
+    }
+
     pub fn step(&mut self) {
         self.did_cold_reset = false;
         self.did_soft_reset = false;
@@ -560,7 +679,21 @@ impl R4300i {
             let coc0buf = self.cop0.state.get_coc();
             let coc1buf = false; // TODO cop1
 
-            self.fetch_instruction();
+            let pc = self.state.get_pc();
+            let opcode = self.fetch_instruction();
+            if let (true, Some(opcode)) = (self.history_len != 0, opcode) {
+                if self.history.len() == self.history_len {
+                    self.history.pop_front();
+                }
+                self.history.push_back((pc as word, opcode));
+            }
+            if let (true, Some(opcode)) = (self.is_tracing(), opcode) {
+                let registers = self.traced_registers();
+                if let Some(tracer) = &mut self.tracer {
+                    tracer.begin(pc as word, opcode, registers);
+                }
+            }
+
             self.execute_instruction();
 
             self.coc0 = coc0buf;
@@ -568,6 +701,13 @@ impl R4300i {
 
             self.handle_exception();
 
+            if self.is_tracing() {
+                let registers = self.traced_registers();
+                if let Some(tracer) = &mut self.tracer {
+                    tracer.end(registers);
+                }
+            }
+
             let mut random: cop0::registers::Random =
                 self.cop0.state.get_reg(cop0::Register::Random);
             let wired: cop0::registers::Wired = self.cop0.state.get_reg(cop0::Register::Wired);
@@ -602,7 +742,17 @@ impl R4300i {
             self.video_timer = 0;
         }
 
-        if self.cop0.dma() || self.cop0.card() || vi_intr || self.cop0.module() {
+        // stepped every time, so it finishes no matter what else is pending
+        let aes_intr = self.cop0.aes();
+
+        if self.cop0.dma()
+            || self.cop0.card()
+            || aes_intr
+            || vi_intr
+            || self.cop0.module()
+            || self.cop0.button()
+            || self.cop0.usb()
+        {
             cause.set_ip(cause.ip() | 0x04);
         } else {
             cause.set_ip(cause.ip() & !0x04);
@@ -625,14 +775,44 @@ impl R4300i {
         }
     }
 
+    // a panic partway through a step leaves the machine in an unknown state, so
+    // the caller should stop running it
+    pub fn try_step(&mut self) -> Result<(), EmuError> {
+        let pc = self.get_pc() as word;
+        panic::catch_unwind(AssertUnwindSafe(|| self.step())).map_err(|payload| {
+            let message = match payload.downcast::<String>() {
+                Ok(message) => *message,
+                Err(payload) => match payload.downcast::<&str>() {
+                    Ok(message) => message.to_string(),
+                    Err(_) => "unknown panic".into(),
+                },
+            };
+            EmuError::Panic { pc, message }
+        })
+    }
+
     pub fn read<T>(&mut self, address: word) -> Option<T>
     where
-        T: FromBytes,
+        T: FromBytes + ToBytes,
         <T as FromBytes>::Bytes: TryFrom<Vec<u8>>,
         <<T as FromBytes>::Bytes as TryFrom<Vec<u8>>>::Error: Debug,
     {
-        match self.cop0.read(address) {
-            cop0::TLBResult::Ok(d) => Some(d),
+        self.read_as(address, ReadKind::Load)
+    }
+
+    fn read_as<T>(&mut self, address: word, kind: ReadKind) -> Option<T>
+    where
+        T: FromBytes + ToBytes,
+        <T as FromBytes>::Bytes: TryFrom<Vec<u8>>,
+        <<T as FromBytes>::Bytes as TryFrom<Vec<u8>>>::Error: Debug,
+    {
+        match self.cop0.read::<T>(address, kind) {
+            cop0::TLBResult::Ok(d) => {
+                if let Some(tracer) = &mut self.tracer {
+                    tracer.access(false, address, d.to_be_bytes().as_ref());
+                }
+                Some(d)
+            }
             cop0::TLBResult::Shutdown => {
                 self.halt();
                 None
@@ -650,13 +830,39 @@ impl R4300i {
         }
     }
 
+    // a read for the debugger, which leaves the guest untouched: none for
+    // unaligned, unmapped or unbacked addresses and for device registers
+    pub fn peek<T>(&mut self, address: word) -> Option<T>
+    where
+        T: FromBytes,
+        <T as FromBytes>::Bytes: TryFrom<Vec<u8>>,
+        <<T as FromBytes>::Bytes as TryFrom<Vec<u8>>>::Error: Debug,
+    {
+        let size = size_of::<T>() as word;
+        if !address.is_multiple_of(size) {
+            return None;
+        }
+
+        let bytes = (0..size)
+            .map(|index| self.cop0.peek_byte(address.wrapping_add(index)))
+            .collect::<Option<Vec<_>>>()?;
+
+        let bytes = bytes.try_into().expect("should never fail");
+        Some(T::from_be_bytes(&bytes))
+    }
+
     pub fn write<T>(&mut self, address: word, val: T)
     where
         T: ToBytes,
         <T as ToBytes>::Bytes: IntoIterator<Item = byte>,
     {
+        let traced = self.tracer.is_some().then(|| val.to_be_bytes());
         match self.cop0.write(address, val) {
-            cop0::TLBResult::Ok(_) => {}
+            cop0::TLBResult::Ok(_) => {
+                if let (Some(tracer), Some(bytes)) = (&mut self.tracer, traced) {
+                    tracer.access(true, address, bytes.as_ref());
+                }
+            }
             cop0::TLBResult::Shutdown => self.halt(),
             cop0::TLBResult::Exception(e) => {
                 println!("exception at {:016X}", self.get_pc());
@@ -667,16 +873,14 @@ impl R4300i {
         }
     }
 
-    fn fetch_instruction(&mut self) {
+    fn fetch_instruction(&mut self) -> Option<word> {
         let pc = self.state.get_pc();
 
-        let opcode: word = match self.read(pc as _) {
-            Some(o) => o,
-            None => return,
-        };
+        let opcode: word = self.read_as(pc as _, ReadKind::Fetch)?;
 
         self.prev_instruction = self.cur_instruction;
         self.cur_instruction = Some(Instruction::new(opcode));
+        Some(opcode)
     }
 
     fn execute_instruction(&mut self) {
@@ -697,9 +901,9 @@ impl R4300i {
         if run_instruction {
             if self.logging {
                 println!(
-                    "Executing instruction {:016X}: {:X?}",
+                    "Executing instruction {:016X}: {}",
                     self.state.get_pc(),
-                    instr
+                    instr.disassemble(self.state.get_pc() as _)
                 );
             }
 
@@ -783,17 +987,7 @@ impl R4300i {
         match self.exception.exception {
             ExceptionType::ColdReset => {
                 self.did_cold_reset = true;
-                self.cop0 = Cop0::new(
-                    ResetType::Cold,
-                    self.cop0.retrieve_bootrom(),
-                    self.cop0.retrieve_v0(),
-                    self.cop0.retrieve_v1(),
-                    self.cop0.retrieve_v2(),
-                    self.cop0.retrieve_nand(),
-                    self.cop0.retrieve_spare(),
-                );
-                self.state = State::new();
-                self.state.set_pc(Self::RESET_PC);
+                self.cold_reset();
             }
 
             ExceptionType::SoftReset | ExceptionType::NMI => {
@@ -808,6 +1002,11 @@ impl R4300i {
                     cop0::registers::ErrorEpc::new().with_error_epc(self.state.get_pc() as word),
                 );
 
+                self.call_stack.exception(
+                    self.exception.exception,
+                    self.state.get_pc() as word,
+                    Self::RESET_PC as word,
+                );
                 self.state.set_pc(Self::RESET_PC);
             }
 
@@ -831,6 +1030,8 @@ impl R4300i {
                     cop0::registers::ErrorEpc::new().with_error_epc(epc),
                 );
 
+                self.call_stack
+                    .exception(self.exception.exception, epc, Self::SK_ENTER as word);
                 self.state.set_pc(Self::SK_ENTER);
             }
 
@@ -868,12 +1069,52 @@ impl R4300i {
                         Self::OTHER_ADD
                     },
                 );
+                self.call_stack.exception(
+                    self.exception.exception,
+                    epc,
+                    self.state.get_pc() as word,
+                );
             }
         }
 
         self.exception = Exception::default();
     }
 
+    fn cold_reset(&mut self) {
+        let card_present = self.cop0.card_present();
+        let mmio_log = self.cop0.mmio_log().cloned();
+        let nand_faults = self.cop0.nand_faults().clone();
+        let save = self.cop0.save_memory().cloned();
+        self.cop0 = Cop0::new(
+            ResetType::Cold,
+            self.cop0.retrieve_bootrom(),
+            self.cop0.retrieve_v0(),
+            self.cop0.retrieve_v1(),
+            self.cop0.retrieve_v2(),
+            self.cop0.retrieve_nand(),
+        );
+        self.cop0.set_card_present(card_present);
+        self.cop0.set_mmio_log(mmio_log);
+        self.cop0.set_nand_faults(nand_faults);
+        self.cop0.set_save_memory(save);
+        self.state = State::new();
+        self.state.set_pc(Self::RESET_PC);
+        self.call_stack.clear();
+        self.apply_random_seed();
+    }
+
+    pub fn reset(&mut self) {
+        self.cold_reset();
+
+        self.delay_slot.clear();
+        self.is_branch_likely = false;
+        self.prev_instruction = None;
+        self.cur_instruction = None;
+        self.exception = Exception::default();
+        self.video_timer = 0;
+        self.halted = false;
+    }
+
     pub fn secure_trap(&mut self, trap: SecureTrapType) {
         self.throw_exception(Exception::new(ExceptionType::Trap));
         self.cop0.set_secure_trap(trap);
@@ -887,6 +1128,12 @@ impl R4300i {
         self.state.set_pc(pc);
     }
 
+    // carries on at pc, dropping any branch that was waiting on a delay slot
+    pub fn jump(&mut self, pc: dword) {
+        self.delay_slot.clear();
+        self.state.set_pc(pc);
+    }
+
     pub fn get_bootram(&self) -> &[byte] {
         self.cop0.get_bootram()
     }
@@ -895,11 +1142,309 @@ impl R4300i {
         self.cop0.get_ram()
     }
 
+    pub fn get_pi_buffer(&self) -> &[byte] {
+        self.cop0.get_pi_buffer()
+    }
+
+    pub fn get_nand_device(&self) -> NandDevice {
+        self.cop0.nand_device()
+    }
+
+    pub fn set_nand_faults(&mut self, faults: NandFaults) {
+        self.cop0.set_nand_faults(faults);
+    }
+
+    // nand blocks programmed or erased since the last call, for writing back
+    pub fn take_dirty_nand_blocks(&mut self) -> Vec<usize> {
+        self.cop0.take_dirty_nand_blocks()
+    }
+
+    pub fn get_nand_block(&self, block: usize) -> (&[byte], &[byte]) {
+        self.cop0.get_nand_block(block)
+    }
+
+    // marks the block dirty, as if the guest had programmed it
+    pub fn set_nand_block(&mut self, block: usize, data: &[byte], spare: &[byte]) {
+        self.cop0.set_nand_block(block, data, spare);
+    }
+
+    // the cartridge save memory on domain 2, if there is any
+    pub fn get_save_memory(&self) -> Option<&SaveMemory> {
+        self.cop0.save_memory()
+    }
+
+    pub fn set_save_memory(&mut self, save: Option<SaveMemory>) {
+        self.cop0.set_save_memory(save);
+    }
+
+    // the bytes of save memory written since the last call, for writing back
+    pub fn take_dirty_save(&mut self) -> Option<Range<usize>> {
+        self.cop0.take_dirty_save()
+    }
+
+    // the sram contents of virage 0, 1 or 2
+    pub fn get_virage(&self, index: usize) -> Vec<byte> {
+        match index {
+            0 => self.cop0.retrieve_v0(),
+            1 => self.cop0.retrieve_v1(),
+            _ => self.cop0.retrieve_v2(),
+        }
+    }
+
+    // keeps the last `len` fetched instructions around for crash reports
+    pub fn set_history_len(&mut self, len: usize) {
+        self.history_len = len;
+        while self.history.len() > len {
+            self.history.pop_front();
+        }
+    }
+
+    // oldest first
+    pub fn history(&self) -> Vec<(word, word)> {
+        self.history.iter().copied().collect()
+    }
+
     pub fn get_mi_mapping(&self) -> bool {
         self.cop0.get_mi_mapping()
     }
 
+    pub fn get_secure_mode(&self) -> bool {
+        self.cop0.get_secure_mode()
+    }
+
     pub fn get_reg(&self, reg: byte) -> dword {
         self.state.get_reg(reg.into())
     }
+
+    pub fn set_reg(&mut self, reg: byte, val: dword) {
+        self.state.set_reg(reg.into(), val);
+    }
+
+    pub fn get_hi(&self) -> dword {
+        self.state.get_hi()
+    }
+
+    pub fn get_lo(&self) -> dword {
+        self.state.get_lo()
+    }
+
+    pub fn get_cop0_reg(&self, reg: byte) -> word {
+        self.cop0.state.get_reg_raw(reg.into())
+    }
+
+    pub fn get_tlb(&self) -> &[TLBEntry] {
+        self.cop0.state.get_tlb()
+    }
+
+    // the shadow call stack, innermost frame first
+    pub fn backtrace(&self) -> Vec<Frame> {
+        self.call_stack.frames().copied().collect()
+    }
+
+    pub fn get_device_registers(&self, device: Device) -> Vec<(&'static str, word)> {
+        self.cop0.get_device_registers(device)
+    }
+
+    pub fn save_state(&mut self) -> Vec<byte> {
+        self.save_snapshot(Snapshotter::save())
+    }
+
+    // everything but the nand and spare areas, for a host that keeps track of
+    // those itself; see set_nand_block
+    pub fn save_state_without_nand(&mut self) -> Vec<byte> {
+        self.save_snapshot(Snapshotter::save().without_nand())
+    }
+
+    fn save_snapshot(&mut self, mut s: Snapshotter) -> Vec<byte> {
+        s.header()
+            .and_then(|_| self.snapshot(&mut s))
+            .expect("saving a snapshot can't fail");
+        s.into_saved()
+    }
+
+    // on error the machine is left partially loaded, and should be reset or discarded
+    pub fn load_state(&mut self, data: &[byte]) -> Result<(), SnapshotError> {
+        self.load_snapshot(Snapshotter::load(data))
+    }
+
+    // the nand is left as it is
+    pub fn load_state_without_nand(&mut self, data: &[byte]) -> Result<(), SnapshotError> {
+        self.load_snapshot(Snapshotter::load(data).without_nand())
+    }
+
+    fn load_snapshot(&mut self, mut s: Snapshotter) -> Result<(), SnapshotError> {
+        s.header()?;
+        self.snapshot(&mut s)?;
+        match s.remaining() {
+            0 => Ok(()),
+            rest => Err(SnapshotError::TrailingData(rest)),
+        }
+    }
+
+    // assembles source and writes it to memory, returning the number of words written
+    pub fn assemble_into(&mut self, address: word, source: &str) -> Result<usize, AsmError> {
+        let program = assemble(source, address)?;
+        for (index, instr) in program.iter().enumerate() {
+            self.write(address.wrapping_add(index as word * 4), *instr);
+        }
+        Ok(program.len())
+    }
+}
+
+const EXCEPTION_TYPES: [ExceptionType; 36] = [
+    ExceptionType::Interrupt,
+    ExceptionType::TLBModification,
+    ExceptionType::TLBMissRead,
+    ExceptionType::TLBMissWrite,
+    ExceptionType::AddressErrorRead,
+    ExceptionType::AddressErrorWrite,
+    ExceptionType::BusErrorF,
+    ExceptionType::BusErrorLS,
+    ExceptionType::Syscall,
+    ExceptionType::Breakpoint,
+    ExceptionType::ReservedInstruction,
+    ExceptionType::CoprocessorUnusable,
+    ExceptionType::ArithmeticOverflow,
+    ExceptionType::Trap,
+    ExceptionType::E14,
+    ExceptionType::FloatingPoint,
+    ExceptionType::E16,
+    ExceptionType::E17,
+    ExceptionType::E18,
+    ExceptionType::E19,
+    ExceptionType::E20,
+    ExceptionType::E21,
+    ExceptionType::E22,
+    ExceptionType::Watch,
+    ExceptionType::E24,
+    ExceptionType::E25,
+    ExceptionType::E26,
+    ExceptionType::E27,
+    ExceptionType::E28,
+    ExceptionType::E29,
+    ExceptionType::E30,
+    ExceptionType::E31,
+    ExceptionType::ColdReset,
+    ExceptionType::SoftReset,
+    ExceptionType::NMI,
+    ExceptionType::None,
+];
+
+const FPU_EXCEPTION_BITS: [FpuExceptionBit; 6] = [
+    FpuExceptionBit::InexactOperation,
+    FpuExceptionBit::Underflow,
+    FpuExceptionBit::Overflow,
+    FpuExceptionBit::DivisionByZero,
+    FpuExceptionBit::InvalidOperation,
+    FpuExceptionBit::UnimplementedOperation,
+];
+
+impl Snapshot for State {
+    fn snapshot(&mut self, s: &mut Snapshotter) -> Result<(), SnapshotError> {
+        s.section("CPU")?;
+        s.value(&mut self.pc)?;
+        s.value(&mut self.hi)?;
+        s.value(&mut self.lo)?;
+        s.bool(&mut self.llbit)?;
+        for reg in &mut self.registers {
+            s.value(reg)?;
+        }
+        for reg in &mut self.fp_registers {
+            s.value(reg)?;
+        }
+        for reg in &mut self.fp_control_registers {
+            s.value(reg)?;
+        }
+        Ok(())
+    }
+}
+
+impl Snapshot for Exception {
+    fn snapshot(&mut self, s: &mut Snapshotter) -> Result<(), SnapshotError> {
+        let mut exception = self.exception as byte;
+        s.value(&mut exception)?;
+        self.exception = *EXCEPTION_TYPES
+            .get(exception as usize)
+            .ok_or(SnapshotError::Invalid("exception type out of range"))?;
+
+        s.bool(&mut self.tlb_invalid)?;
+
+        let mut fpu_exception_bit = self.fpu_exception_bit as byte;
+        s.value(&mut fpu_exception_bit)?;
+        self.fpu_exception_bit = *FPU_EXCEPTION_BITS
+            .get(fpu_exception_bit as usize)
+            .ok_or(SnapshotError::Invalid("fpu exception bit out of range"))?;
+
+        Ok(())
+    }
+}
+
+// instructions are stored as their opcode, and decoded again on load
+fn snapshot_instruction(
+    s: &mut Snapshotter,
+    instr: &mut Option<Instruction>,
+) -> Result<(), SnapshotError> {
+    let (mut kind, mut opcode): (byte, word) = match instr {
+        None => (0, 0),
+        Some(Instruction::None) => (1, 0),
+        Some(instr) => (2, instr.encode().unwrap_or_default()),
+    };
+    s.value(&mut kind)?;
+    s.value(&mut opcode)?;
+    *instr = match kind {
+        0 => None,
+        1 => Some(Instruction::None),
+        2 => Some(Instruction::new(opcode)),
+        _ => return Err(SnapshotError::Invalid("instruction kind out of range")),
+    };
+    Ok(())
+}
+
+impl Snapshot for R4300i {
+    fn snapshot(&mut self, s: &mut Snapshotter) -> Result<(), SnapshotError> {
+        self.state.snapshot(s)?;
+
+        s.bool(&mut self.coc0)?;
+        s.bool(&mut self.coc1)?;
+
+        snapshot_instruction(s, &mut self.prev_instruction)?;
+        snapshot_instruction(s, &mut self.cur_instruction)?;
+
+        // between steps, any queued delay slot was queued by the current instruction,
+        // so only its operands need storing
+        let mut len = self.delay_slot.len();
+        s.usize(&mut len)?;
+        let loading = s.is_loading();
+        if loading {
+            let function = self
+                .cur_instruction
+                .as_ref()
+                .and_then(get_delay_slot_function);
+            self.delay_slot.clear();
+            for _ in 0..len {
+                let function =
+                    function.ok_or(SnapshotError::Invalid("delay slot without a branch"))?;
+                self.delay_slot.push_back((function, 0, false));
+            }
+        }
+        for (_, target, cond) in &mut self.delay_slot {
+            s.value(target)?;
+            s.bool(cond)?;
+        }
+        s.bool(&mut self.is_branch_likely)?;
+
+        s.bool(&mut self.did_cold_reset)?;
+        s.bool(&mut self.did_soft_reset)?;
+        s.bool(&mut self.did_nmi)?;
+
+        s.bool(&mut self.running)?;
+        s.bool(&mut self.halted)?;
+
+        self.exception.snapshot(s)?;
+        s.value(&mut self.video_timer)?;
+        s.value(&mut self.random_seed)?;
+        self.call_stack.snapshot(s)?;
+
+        self.cop0.snapshot(s)
+    }
 }
@@ -0,0 +1,314 @@
+use num_traits::{FromBytes, ToBytes};
+use thiserror::Error;
+
+use crate::types::*;
+
+pub const MAGIC: [byte; 8] = *b"NIMUSNAP";
+pub const VERSION: word = 9;
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("not a snapshot file")]
+    BadMagic,
+    #[error("unsupported snapshot version {0} (expected {VERSION})")]
+    Version(word),
+    #[error("snapshot is truncated")]
+    Truncated,
+    #[error("snapshot has {0} bytes of trailing data")]
+    TrailingData(usize),
+    #[error("snapshot section {0:?} is missing or out of order")]
+    Section(String),
+    #[error("invalid snapshot: {0}")]
+    Invalid(&'static str),
+}
+
+// walks the machine state in a fixed order, either appending it to a buffer or
+// overwriting it from one, so saving and loading can never disagree on layout
+#[derive(Debug)]
+pub struct Snapshotter<'a> {
+    buf: Buf<'a>,
+    // the nand and spare areas can be left out by a host that keeps its own copy
+    nand: bool,
+}
+
+#[derive(Debug)]
+enum Buf<'a> {
+    Save(Vec<byte>),
+    Load(&'a [byte]),
+}
+
+impl<'a> Snapshotter<'a> {
+    pub fn save() -> Self {
+        Self {
+            buf: Buf::Save(vec![]),
+            nand: true,
+        }
+    }
+
+    pub fn load(data: &'a [byte]) -> Self {
+        Self {
+            buf: Buf::Load(data),
+            nand: true,
+        }
+    }
+
+    pub fn without_nand(self) -> Self {
+        Self {
+            nand: false,
+            ..self
+        }
+    }
+
+    pub fn has_nand(&self) -> bool {
+        self.nand
+    }
+
+    pub fn into_saved(self) -> Vec<byte> {
+        match self.buf {
+            Buf::Save(buf) => buf,
+            Buf::Load(_) => panic!("a loading snapshotter has nothing saved"),
+        }
+    }
+
+    // bytes that haven't been loaded yet
+    pub fn remaining(&self) -> usize {
+        match self.buf {
+            Buf::Save(_) => 0,
+            Buf::Load(buf) => buf.len(),
+        }
+    }
+
+    pub fn header(&mut self) -> Result<(), SnapshotError> {
+        let mut magic = MAGIC;
+        self.bytes(&mut magic)?;
+        if magic != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+
+        let mut version = VERSION;
+        self.value(&mut version)?;
+        if version != VERSION {
+            return Err(SnapshotError::Version(version));
+        }
+
+        let mut nand = self.nand;
+        self.bool(&mut nand)?;
+        if nand != self.nand {
+            return Err(SnapshotError::Invalid(if nand {
+                "snapshot includes the nand"
+            } else {
+                "snapshot leaves out the nand"
+            }));
+        }
+
+        Ok(())
+    }
+
+    pub fn is_loading(&self) -> bool {
+        matches!(self.buf, Buf::Load(_))
+    }
+
+    pub fn bytes(&mut self, data: &mut [byte]) -> Result<(), SnapshotError> {
+        match &mut self.buf {
+            Buf::Save(buf) => buf.extend_from_slice(data),
+            Buf::Load(buf) => {
+                if buf.len() < data.len() {
+                    return Err(SnapshotError::Truncated);
+                }
+                let (head, tail) = buf.split_at(data.len());
+                data.copy_from_slice(head);
+                *buf = tail;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn value<T, const N: usize>(&mut self, val: &mut T) -> Result<(), SnapshotError>
+    where
+        T: ToBytes<Bytes = [byte; N]> + FromBytes<Bytes = [byte; N]>,
+    {
+        let mut bytes = val.to_le_bytes();
+        self.bytes(&mut bytes)?;
+        *val = T::from_le_bytes(&bytes);
+        Ok(())
+    }
+
+    pub fn bool(&mut self, val: &mut bool) -> Result<(), SnapshotError> {
+        let mut b = *val as byte;
+        self.value(&mut b)?;
+        *val = match b {
+            0 => false,
+            1 => true,
+            _ => return Err(SnapshotError::Invalid("bool out of range")),
+        };
+        Ok(())
+    }
+
+    pub fn usize(&mut self, val: &mut usize) -> Result<(), SnapshotError> {
+        let mut d = *val as dword;
+        self.value(&mut d)?;
+        *val = d
+            .try_into()
+            .map_err(|_| SnapshotError::Invalid("size out of range"))?;
+        Ok(())
+    }
+
+    // bitfields are stored as their raw representation
+    pub fn bits<T>(&mut self, val: &mut T) -> Result<(), SnapshotError>
+    where
+        T: Copy + Into<word> + From<word>,
+    {
+        let mut w: word = (*val).into();
+        self.value(&mut w)?;
+        *val = w.into();
+        Ok(())
+    }
+
+    pub fn bits64<T>(&mut self, val: &mut T) -> Result<(), SnapshotError>
+    where
+        T: Copy + Into<dword> + From<dword>,
+    {
+        let mut d: dword = (*val).into();
+        self.value(&mut d)?;
+        *val = d.into();
+        Ok(())
+    }
+
+    pub fn vec(&mut self, val: &mut Vec<byte>) -> Result<(), SnapshotError> {
+        let mut len = val.len();
+        self.usize(&mut len)?;
+        if let Buf::Load(buf) = self.buf {
+            if buf.len() < len {
+                return Err(SnapshotError::Truncated);
+            }
+            val.resize(len, 0);
+        }
+        self.bytes(val)
+    }
+
+    // a marker between components, so a layout mismatch is caught where it happens
+    pub fn section(&mut self, name: &str) -> Result<(), SnapshotError> {
+        let mut tag = [0; 4];
+        tag[..name.len()].copy_from_slice(name.as_bytes());
+        let expected = tag;
+        self.bytes(&mut tag)?;
+        if tag != expected {
+            return Err(SnapshotError::Section(name.into()));
+        }
+        Ok(())
+    }
+}
+
+pub trait Snapshot {
+    fn snapshot(&mut self, s: &mut Snapshotter) -> Result<(), SnapshotError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SnapshotError;
+    use crate::cop0::faults::NandFaults;
+    use crate::cop0::nand::{NandDevice, NandImage};
+    use crate::cop0::save::SaveMemory;
+    use crate::cop0::{NAND_BLOCK_SIZE, NAND_SPARE_SIZE};
+    use crate::instruction::asm::assemble;
+    use crate::R4300i;
+
+    // stores a counter to ram and save memory, forever
+    const PROGRAM: &str = "
+        lui t0, 0xA000
+        lui t1, 0xA800
+        addiu t2, zero, 0x55
+    loop:
+        sw t2, 0x100(t0)
+        sb t2, 0x10(t1)
+        beq zero, zero, loop
+        addiu t2, t2, 1
+    ";
+
+    fn machine() -> R4300i {
+        let bootrom = assemble(PROGRAM, 0xBFC00000)
+            .unwrap()
+            .iter()
+            .flat_map(|instr| instr.to_be_bytes())
+            .collect();
+        let device: NandDevice = "64m".parse().unwrap();
+        let blocks = device.size() / NAND_BLOCK_SIZE;
+        let nand = NandImage::split(
+            vec![0xFF; device.size()],
+            vec![0xFF; blocks * NAND_SPARE_SIZE],
+            Some(device),
+        )
+        .unwrap();
+
+        let mut cpu = R4300i::new(bootrom, vec![], vec![], vec![], nand);
+        cpu.set_save_memory(Some(
+            SaveMemory::new(0x08000000, vec![0xFF; 0x8000]).unwrap(),
+        ));
+        cpu
+    }
+
+    #[test]
+    fn save_load_save_round_trips() {
+        let mut cpu = machine();
+        cpu.set_nand_faults(NandFaults {
+            bad_blocks: vec![3],
+            flip_rate: 0.5,
+            seed: 7,
+            ..Default::default()
+        });
+        cpu.seed_random(5);
+        cpu.start();
+        for _ in 0..100 {
+            cpu.step();
+        }
+        cpu.take_dirty_nand_blocks();
+        cpu.take_dirty_save();
+        let saved = cpu.save_state();
+
+        let mut restored = machine();
+        restored.load_state(&saved).unwrap();
+        assert_eq!(restored.save_state(), saved);
+        assert_eq!(restored.get_ram(), cpu.get_ram());
+
+        // what changed underneath the fresh machine needs writing back
+        assert_eq!(restored.take_dirty_save(), Some(0x10..0x11));
+        assert_eq!(restored.take_dirty_nand_blocks(), vec![3]);
+
+        // and both carry on the same way
+        for _ in 0..100 {
+            cpu.step();
+            restored.step();
+        }
+        assert_eq!(restored.save_state(), cpu.save_state());
+    }
+
+    #[test]
+    fn snapshots_without_the_nand() {
+        let mut cpu = machine();
+        cpu.start();
+        for _ in 0..10 {
+            cpu.step();
+        }
+        let saved = cpu.save_state_without_nand();
+        assert!(saved.len() < cpu.save_state().len() - cpu.get_nand_device().size());
+
+        // the nand is left alone, so nothing needs writing back
+        let mut restored = machine();
+        restored.set_nand_block(2, &[0; NAND_BLOCK_SIZE], &[0; NAND_SPARE_SIZE]);
+        assert_eq!(restored.take_dirty_nand_blocks(), [2]);
+        restored.load_state_without_nand(&saved).unwrap();
+        assert!(restored.take_dirty_nand_blocks().is_empty());
+        assert!(restored.get_nand_block(2).0.iter().all(|byte| *byte == 0));
+        assert_eq!(restored.get_ram(), cpu.get_ram());
+
+        // and the two kinds can't be mixed up
+        assert!(matches!(
+            restored.load_state(&saved),
+            Err(SnapshotError::Invalid("snapshot leaves out the nand"))
+        ));
+        assert!(matches!(
+            restored.load_state_without_nand(&cpu.save_state()),
+            Err(SnapshotError::Invalid("snapshot includes the nand"))
+        ));
+    }
+}
@@ -0,0 +1,13 @@
+use crate::types::*;
+
+// everything that can reach the machine from outside; the emulator itself never
+// looks at host time, so a run is fully reproduced by replaying these at the same
+// instruction counts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stimulus {
+    Reset,
+    Button(bool),
+    Card(bool),
+    Interrupt,
+    Usb(usize, usize, Vec<byte>),
+}
@@ -0,0 +1,354 @@
+use std::fmt::{Display, Formatter};
+use std::io::{self, Write};
+
+use thiserror::Error;
+
+use crate::instruction::Instruction;
+use crate::types::*;
+use crate::Register;
+
+pub const MAGIC: [byte; 8] = *b"NIMUTRCE";
+pub const VERSION: word = 1;
+
+// hi and lo are traced after the 32 gprs
+pub const HI: byte = 32;
+pub const LO: byte = 33;
+pub const NUM_TRACED: usize = 34;
+
+#[derive(Debug, Error)]
+pub enum TraceError {
+    #[error("not a trace file")]
+    BadMagic,
+    #[error("unsupported trace version {0} (expected {VERSION})")]
+    Version(word),
+    #[error("trace is truncated")]
+    Truncated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub write: bool,
+    pub size: byte,
+    pub address: word,
+    pub value: dword,
+}
+
+// one executed step: the instruction, the registers it changed and the memory it touched
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub pc: word,
+    pub opcode: word,
+    pub registers: Vec<(byte, dword)>,
+    pub accesses: Vec<Access>,
+}
+
+impl Record {
+    fn encode(&self, out: &mut Vec<byte>) {
+        out.extend_from_slice(&self.pc.to_le_bytes());
+        out.extend_from_slice(&self.opcode.to_le_bytes());
+
+        out.push(self.registers.len() as byte);
+        for (reg, val) in &self.registers {
+            out.push(*reg);
+            out.extend_from_slice(&val.to_le_bytes());
+        }
+
+        out.push(self.accesses.len() as byte);
+        for access in &self.accesses {
+            out.push(((access.write as byte) << 7) | access.size);
+            out.extend_from_slice(&access.address.to_le_bytes());
+            out.extend_from_slice(&access.value.to_le_bytes());
+        }
+    }
+
+    fn decode(data: &mut &[byte]) -> Result<Self, TraceError> {
+        let pc = word::from_le_bytes(take(data)?);
+        let opcode = word::from_le_bytes(take(data)?);
+
+        let [len] = take(data)?;
+        let registers = (0..len)
+            .map(|_| {
+                let [reg] = take(data)?;
+                Ok((reg, dword::from_le_bytes(take(data)?)))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let [len] = take(data)?;
+        let accesses = (0..len)
+            .map(|_| {
+                let [kind] = take(data)?;
+                Ok(Access {
+                    write: kind & 0x80 != 0,
+                    size: kind & 0x7F,
+                    address: word::from_le_bytes(take(data)?),
+                    value: dword::from_le_bytes(take(data)?),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            pc,
+            opcode,
+            registers,
+            accesses,
+        })
+    }
+}
+
+fn take<const N: usize>(data: &mut &[byte]) -> Result<[byte; N], TraceError> {
+    if data.len() < N {
+        return Err(TraceError::Truncated);
+    }
+    let (head, tail) = data.split_at(N);
+    *data = tail;
+    Ok(head.try_into().unwrap())
+}
+
+impl Display for Record {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let instr = Instruction::new(self.opcode);
+        write!(
+            f,
+            "{:08X}: {:08X}  {:<32}",
+            self.pc,
+            self.opcode,
+            instr.disassemble(self.pc).to_string()
+        )?;
+
+        for (reg, val) in &self.registers {
+            match *reg {
+                HI => write!(f, " hi={val:016X}")?,
+                LO => write!(f, " lo={val:016X}")?,
+                r => write!(f, " {}={val:016X}", Register::from(r))?,
+            }
+        }
+
+        for access in &self.accesses {
+            let width = access.size as usize * 2;
+            write!(
+                f,
+                " {}{} {:08X}={:0width$X}",
+                if access.write { "w" } else { "r" },
+                access.size,
+                access.address,
+                access.value
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn header() -> Vec<byte> {
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out
+}
+
+#[derive(Debug)]
+pub struct Reader<'a> {
+    data: &'a [byte],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(mut data: &'a [byte]) -> Result<Self, TraceError> {
+        if take::<8>(&mut data).map_err(|_| TraceError::BadMagic)? != MAGIC {
+            return Err(TraceError::BadMagic);
+        }
+        let version = word::from_le_bytes(take(&mut data)?);
+        if version != VERSION {
+            return Err(TraceError::Version(version));
+        }
+        Ok(Self { data })
+    }
+}
+
+impl Iterator for Reader<'_> {
+    type Item = Result<Record, TraceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            None
+        } else {
+            Some(Record::decode(&mut self.data))
+        }
+    }
+}
+
+// collects records as the cpu runs; only the encoded form is kept, so a trace
+// costs a few bytes per instruction
+#[derive(Debug)]
+pub struct Tracer {
+    buf: Vec<byte>,
+    before: [dword; NUM_TRACED],
+    record: Option<Record>,
+}
+
+impl Default for Tracer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tracer {
+    pub fn new() -> Self {
+        Self {
+            buf: vec![],
+            before: [0; NUM_TRACED],
+            record: None,
+        }
+    }
+
+    pub(crate) fn begin(&mut self, pc: word, opcode: word, registers: [dword; NUM_TRACED]) {
+        self.before = registers;
+        self.record = Some(Record {
+            pc,
+            opcode,
+            registers: vec![],
+            accesses: vec![],
+        });
+    }
+
+    pub(crate) fn access(&mut self, write: bool, address: word, bytes: &[byte]) {
+        if let Some(record) = &mut self.record {
+            let value = bytes.iter().fold(0, |acc, b| (acc << 8) | *b as dword);
+            record.accesses.push(Access {
+                write,
+                size: bytes.len() as byte,
+                address,
+                value,
+            });
+        }
+    }
+
+    pub(crate) fn end(&mut self, registers: [dword; NUM_TRACED]) {
+        let Some(mut record) = self.record.take() else {
+            return;
+        };
+
+        record.registers = (0..NUM_TRACED)
+            .filter(|&r| registers[r] != self.before[r])
+            .map(|r| (r as byte, registers[r]))
+            .collect();
+        record.encode(&mut self.buf);
+    }
+
+    pub(crate) fn flush<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        out.write_all(&self.buf)?;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(tracer: &mut Tracer) -> Vec<byte> {
+        let mut out = header();
+        tracer.flush(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn headers() {
+        let data = header();
+        assert_eq!(&data[..8], b"NIMUTRCE");
+        assert_eq!(data[8..], VERSION.to_le_bytes());
+        assert_eq!(Reader::new(&data).unwrap().count(), 0);
+
+        assert!(matches!(Reader::new(b"NIMU"), Err(TraceError::BadMagic)));
+        assert!(matches!(
+            Reader::new(b"NIMUTRCE\x02\0\0\0"),
+            Err(TraceError::Version(2))
+        ));
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let mut tracer = Tracer::new();
+        let mut before = [0; NUM_TRACED];
+        before[8] = 1;
+
+        // addiu t0, t0, 1, then sw t0, 0x10(sp) with hi changed along the way
+        tracer.begin(0xBFC00000, 0x25080001, before);
+        let mut after = before;
+        after[8] = 2;
+        tracer.end(after);
+
+        tracer.begin(0xBFC00004, 0xAFA80010, after);
+        tracer.access(true, 0x80000010, &[0, 0, 0, 2]);
+        tracer.access(false, 0x80000020, &[0xAB]);
+        after[HI as usize] = 0x1234;
+        tracer.end(after);
+
+        let data = trace(&mut tracer);
+        // the header, then pc, opcode, registers (9 bytes each) and accesses (13 each)
+        assert_eq!(
+            data.len(),
+            12 + 4 + 4 + 1 + 9 + 1 + 4 + 4 + 1 + 9 + 1 + 2 * 13
+        );
+
+        let records = Reader::new(&data)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            records,
+            [
+                Record {
+                    pc: 0xBFC00000,
+                    opcode: 0x25080001,
+                    registers: vec![(8, 2)],
+                    accesses: vec![],
+                },
+                Record {
+                    pc: 0xBFC00004,
+                    opcode: 0xAFA80010,
+                    registers: vec![(HI, 0x1234)],
+                    accesses: vec![
+                        Access {
+                            write: true,
+                            size: 4,
+                            address: 0x80000010,
+                            value: 2,
+                        },
+                        Access {
+                            write: false,
+                            size: 1,
+                            address: 0x80000020,
+                            value: 0xAB,
+                        },
+                    ],
+                },
+            ]
+        );
+
+        assert_eq!(
+            records[1].to_string(),
+            format!(
+                "BFC00004: AFA80010  {:<32} hi=0000000000001234 w4 80000010=00000002 r1 80000020=AB",
+                "sw      t0, 0x10(sp)"
+            )
+        );
+
+        // a record cut short is reported rather than misread
+        let mut records = Reader::new(&data[..data.len() - 1]).unwrap();
+        assert!(records.next().unwrap().is_ok());
+        assert!(matches!(records.next(), Some(Err(TraceError::Truncated))));
+    }
+
+    #[test]
+    fn flushing_empties_the_tracer() {
+        let mut tracer = Tracer::new();
+        tracer.begin(0, 0, [0; NUM_TRACED]);
+        tracer.end([0; NUM_TRACED]);
+        assert_eq!(trace(&mut tracer).len(), 12 + 10);
+        assert_eq!(trace(&mut tracer).len(), 12);
+
+        // accesses outside a record are dropped
+        tracer.access(true, 0, &[0]);
+        tracer.end([0; NUM_TRACED]);
+        assert_eq!(trace(&mut tracer).len(), 12);
+    }
+}